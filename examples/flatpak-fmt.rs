@@ -0,0 +1,44 @@
+//! Formats Flatpak manifests in their canonical layout.
+//!
+//! Usage: `cargo run --example flatpak-fmt -- [--check] <manifest>...`
+//!
+//! With `--check`, the manifests are not modified, and the command exits with
+//! a non-zero status if any of them is not formatted.
+use std::process::exit;
+
+use flatpak_rs::formatter::format_file;
+
+fn main() {
+    let mut check = false;
+    let mut manifest_paths: Vec<String> = vec![];
+    for arg in std::env::args().skip(1) {
+        if arg == "--check" {
+            check = true;
+        } else {
+            manifest_paths.push(arg);
+        }
+    }
+    if manifest_paths.is_empty() {
+        eprintln!("Usage: flatpak-fmt [--check] <manifest>...");
+        exit(2);
+    }
+
+    let mut success = true;
+    for manifest_path in manifest_paths {
+        match format_file(&manifest_path, check) {
+            Ok(true) => {}
+            Ok(false) if check => {
+                println!("{} is not formatted.", manifest_path);
+                success = false;
+            }
+            Ok(false) => println!("Formatted {}.", manifest_path),
+            Err(e) => {
+                eprintln!("{}", e);
+                success = false;
+            }
+        }
+    }
+    if !success {
+        exit(1);
+    }
+}
@@ -1,12 +1,21 @@
 use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path;
 
 use serde::{Deserialize, Serialize};
 
-use crate::format::FlatpakManifestFormat;
-use crate::module::{FlatpakBuildOptions, FlatpakModule, FlatpakModuleItem};
+use crate::architecture::FlatpakArchitecture;
+use crate::build_env::{FlatpakBuildContext, FlatpakBuildEnv};
+use crate::build_plan::{FlatpakBuildPlan, FlatpakBuildStep, FlatpakBuildStepKind};
+use crate::cleanup::{get_cleanup_listing_step, get_cleanup_step};
+use crate::error::FlatpakError;
+use crate::extra_fields::FlatpakExtraFields;
+use crate::format::{FlatpakManifestFormat, FlatpakParseOptions};
+use crate::manifest_type::FlatpakManifestType;
+use crate::module::{FlatpakBuildOptions, FlatpakManifestFile, FlatpakModule, FlatpakModuleItem};
 use crate::source::FlatpakSourceType;
+use crate::syntax::FlatpakManifestPath;
 
 /// Main structure for a Flatpak application manifest.
 /// See `man flatpak-manifest` for the flatpak manifest specs.
@@ -215,8 +224,57 @@ pub struct FlatpakApplication {
     /// json or yaml file that contains a module. See below for details.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub modules: Vec<FlatpakModuleItem>,
+
+    /// The fields that are not supported by this library, including the
+    /// extension fields prefixed with `x-`. They are preserved when dumping the manifest.
+    #[serde(flatten)]
+    pub extra_fields: FlatpakExtraFields,
 }
 impl FlatpakApplication {
+    /// The names of the fields supported in application manifests.
+    pub const FIELD_NAMES: &'static [&'static str] = &[
+        "app-name",
+        "app-id",
+        "id",
+        "branch",
+        "default-branch",
+        "collection-id",
+        "runtime",
+        "runtime-version",
+        "sdk",
+        "sdk-extensions",
+        "var",
+        "metadata",
+        "build-runtime",
+        "build-extension",
+        "base",
+        "base-version",
+        "base-extensions",
+        "separate-locales",
+        "appstream-compose",
+        "inherit-extensions",
+        "inherit-sdk-extensions",
+        "build-options",
+        "command",
+        "tags",
+        "add-extensions",
+        "add-build-extensions",
+        "cleanup",
+        "cleanup-commands",
+        "cleanup-platform",
+        "cleanup-platform-commands",
+        "prepare-platform-commands",
+        "finish-args",
+        "rename-desktop-file",
+        "rename-appdata-file",
+        "rename-icon",
+        "appdata-license",
+        "copy-icon",
+        "desktop-file-name-prefix",
+        "desktop-file-name-suffix",
+        "modules",
+    ];
+
     pub fn get_id(&self) -> String {
         if !self.app_id.is_empty() {
             return self.app_id.to_string();
@@ -224,29 +282,34 @@ impl FlatpakApplication {
         return self.id.to_string();
     }
 
-    pub fn load_from_file(path: String) -> Result<FlatpakApplication, String> {
+    pub fn load_from_file(path: String) -> Result<FlatpakApplication, FlatpakError> {
+        FlatpakApplication::load_from_file_with_options(path, &FlatpakParseOptions::default())
+    }
+
+    pub fn load_from_file_with_options(
+        path: String,
+        options: &FlatpakParseOptions,
+    ) -> Result<FlatpakApplication, FlatpakError> {
         let file_path = path::Path::new(&path);
         if !file_path.is_file() {
-            return Err(format!("{} is not a file.", path));
+            return Err(FlatpakError::Io {
+                path,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file"),
+            });
         }
 
         let manifest_format = match FlatpakManifestFormat::from_path(&path) {
             Some(f) => f,
-            None => return Err(format!("{} is not a Flatpak application manifest.", path)),
+            None => return Err(FlatpakError::UnsupportedExtension(path)),
         };
 
         let manifest_content = match fs::read_to_string(file_path) {
             Ok(content) => content,
-            Err(e) => {
-                return Err(format!("Could not read application manifest at {}: {}", &path, e));
-            }
+            Err(e) => return Err(FlatpakError::Io { path, source: e }),
         };
-        match FlatpakApplication::parse(manifest_format, &manifest_content) {
+        match FlatpakApplication::parse_with_options(manifest_format, &manifest_content, options) {
             Ok(m) => Ok(m),
-            Err(e) => Err(format!(
-                "Failed to parse Flatpak application manifest at {}: {}",
-                path, e
-            )),
+            Err(e) => Err(e.in_file(path)),
         }
     }
 
@@ -258,17 +321,26 @@ impl FlatpakApplication {
         crate::reverse_dns::is_reverse_dns(&path)
     }
 
-    pub fn parse(format: FlatpakManifestFormat, manifest_content: &str) -> Result<FlatpakApplication, String> {
-        let mut flatpak_manifest: FlatpakApplication = match format.parse(manifest_content) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(format!(
-                    "Failed to parse the Flatpak application manifest: {}.",
-                    e
-                ));
-            }
+    pub fn parse(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+    ) -> Result<FlatpakApplication, FlatpakError> {
+        FlatpakApplication::parse_with_options(format, manifest_content, &FlatpakParseOptions::default())
+    }
+
+    pub fn parse_with_options(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+        options: &FlatpakParseOptions,
+    ) -> Result<FlatpakApplication, FlatpakError> {
+        let mut flatpak_manifest: FlatpakApplication = format.parse(manifest_content)?;
+        let missing_field = |field: &str| {
+            FlatpakError::missing_field(FlatpakManifestType::Application, field).at_path(
+                &format,
+                manifest_content,
+                &FlatpakManifestPath::default(),
+            )
         };
-        flatpak_manifest.format = format;
 
         // From https://docs.flatpak.org/en/latest/manifests.html#basic-properties:
         // Each manifest file should specify basic information about the application that is to be built,
@@ -276,30 +348,105 @@ impl FlatpakApplication {
         // That being said, the `command` field is not required by manifests that declare an
         // extension to be build, using the `build-extension` field.
         if flatpak_manifest.app_id.is_empty() && flatpak_manifest.id.is_empty() {
-            return Err(
-                "Required top-level field id (or app-id) is missing from Flatpak manifest.".to_string(),
-            );
+            return Err(missing_field("id"));
         }
         if flatpak_manifest.runtime.is_empty() {
-            return Err("Required top-level field runtime is missing from Flatpak manifest.".to_string());
+            return Err(missing_field("runtime"));
         }
         if flatpak_manifest.runtime_version.is_empty() {
-            return Err(
-                "Required top-level field runtime-version is missing from Flatpak manifest.".to_string(),
-            );
+            return Err(missing_field("runtime-version"));
         }
         if flatpak_manifest.sdk.is_empty() {
-            return Err("Required top-level field sdk is missing from Flatpak manifest.".to_string());
+            return Err(missing_field("sdk"));
+        }
+        let errors = crate::module::get_module_items_validation_errors(
+            &flatpak_manifest.modules,
+            &FlatpakManifestPath::default().join_key("modules"),
+        );
+        if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
+            return Err(e);
         }
+        if options.strict {
+            let errors = flatpak_manifest.get_unknown_field_errors();
+            if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
+                return Err(e);
+            }
+        }
+        flatpak_manifest.format = format;
 
         Ok(flatpak_manifest)
     }
 
-    pub fn dump(&self) -> Result<String, String> {
-        match self.format.dump(self) {
-            Ok(d) => Ok(d),
-            Err(e) => return Err(format!("Failed to dump the Flatpak manifest: {}.", e)),
+    /// Gets an error for each field of the manifest that is not supported by this library,
+    /// with the path of the field. Extension fields prefixed with `x-` are ignored.
+    pub fn get_unknown_field_errors(&self) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        let root_path = FlatpakManifestPath::default();
+        let mut errors = self
+            .extra_fields
+            .get_unknown_field_errors(&root_path, FlatpakApplication::FIELD_NAMES);
+        if let Some(build_options) = &self.build_options {
+            errors.append(&mut build_options.get_unknown_field_errors(&root_path.join_key("build-options")));
         }
+        errors.append(&mut crate::module::get_module_items_unknown_field_errors(
+            &self.modules,
+            &root_path.join_key("modules"),
+        ));
+        for (extensions_key, extensions) in [
+            ("add-extensions", &self.add_extensions),
+            ("add-build-extensions", &self.add_build_extensions),
+        ] {
+            for (extension_name, extension) in extensions {
+                errors
+                    .append(&mut extension.get_unknown_field_errors(
+                        &root_path.join_key(extensions_key).join_key(extension_name),
+                    ));
+            }
+        }
+        errors
+    }
+
+    pub fn dump(&self) -> Result<String, FlatpakError> {
+        self.format.dump(self)
+    }
+
+    /// Returns a copy of the application in which all the external modules and sources
+    /// are loaded and inlined. Paths are resolved relative to `manifest_path`, which should be
+    /// the path of the application manifest. Nested includes are resolved relative to the
+    /// file that contains them.
+    pub fn resolve(&self, manifest_path: &str) -> Result<FlatpakApplication, FlatpakError> {
+        let mut include_stack: Vec<path::PathBuf> = vec![];
+        if let Ok(canonical_path) = fs::canonicalize(manifest_path) {
+            include_stack.push(canonical_path);
+        }
+
+        let mut resolved_application = self.clone();
+        resolved_application.modules =
+            crate::module::resolve_module_items(&self.modules, manifest_path, &mut include_stack)?;
+        Ok(resolved_application)
+    }
+
+    /// Moves the inline modules of the application to their own manifest files, in `modules_dir`,
+    /// and replaces them with paths to those files. `modules_dir` is relative to `manifest_path`,
+    /// which should be the path of the application manifest.
+    ///
+    /// If `module_names` is provided, only the top-level modules with those names are moved.
+    /// The new files use the format of the application manifest, unless another `format` is
+    /// requested. The files are not written to disk, and are returned instead.
+    pub fn externalize_modules(
+        &mut self,
+        manifest_path: &str,
+        modules_dir: &str,
+        module_names: Option<Vec<String>>,
+        format: Option<FlatpakManifestFormat>,
+    ) -> Result<Vec<FlatpakManifestFile>, FlatpakError> {
+        let format = format.unwrap_or_else(|| self.format.clone());
+        crate::module::externalize_module_items(
+            &mut self.modules,
+            manifest_path,
+            modules_dir,
+            module_names,
+            format,
+        )
     }
 
     pub fn get_urls(
@@ -365,6 +512,287 @@ impl FlatpakApplication {
         }
         all_modules
     }
+
+    /// Returns a copy of the application as it is built for the architecture. The modules and
+    /// sources that are not built for the architecture are removed, and the architecture-specific
+    /// build options are resolved. See [`FlatpakModule::filter_for_arch`].
+    pub fn filter_for_arch(&self, arch: &FlatpakArchitecture) -> FlatpakApplication {
+        let mut filtered_application = self.clone();
+        filtered_application.build_options = self.build_options.as_ref().map(|b| b.resolve_for_arch(arch));
+        filtered_application.modules = crate::module::filter_module_items_for_arch(&self.modules, arch);
+        filtered_application
+    }
+
+    /// Gets the architectures, among `arches`, for which each inline module is built. The modules
+    /// are identified by their path in the manifest, for example `modules[2].modules[0]`, and are
+    /// listed in the order in which they are defined, parent modules first. A nested module is not
+    /// built if its parent module is not built.
+    pub fn get_modules_arches(
+        &self,
+        arches: &[FlatpakArchitecture],
+    ) -> Vec<(FlatpakManifestPath, Vec<FlatpakArchitecture>)> {
+        crate::module::get_module_items_arches(
+            &self.modules,
+            &FlatpakManifestPath::default().join_key("modules"),
+            arches,
+        )
+    }
+
+    /// Gets the effective build options of a module for an architecture. The module is addressed
+    /// by the names of the modules leading to it, starting from a top-level module. The build
+    /// options of the application and of all the enclosing modules are inherited, the options of
+    /// the innermost modules taking precedence.
+    ///
+    /// Only inline modules can be addressed, so external modules should be loaded with
+    /// [`FlatpakApplication::resolve`] first.
+    pub fn get_module_build_options(
+        &self,
+        module_names: &[&str],
+        arch: &FlatpakArchitecture,
+    ) -> Result<FlatpakBuildOptions, FlatpakError> {
+        let (_, build_options) = self.get_module_with_build_options(module_names, arch)?;
+        Ok(build_options)
+    }
+
+    fn get_module_with_build_options(
+        &self,
+        module_names: &[&str],
+        arch: &FlatpakArchitecture,
+    ) -> Result<(&FlatpakModule, FlatpakBuildOptions), FlatpakError> {
+        let mut build_options = match &self.build_options {
+            Some(b) => b.resolve_for_arch(arch),
+            None => FlatpakBuildOptions::default(),
+        };
+        let mut modules = &self.modules;
+        let mut found_module = None;
+        for (module_name_index, module_name) in module_names.iter().enumerate() {
+            let module = modules.iter().find_map(|m| match m {
+                FlatpakModuleItem::Description(d) if d.name == *module_name => Some(d),
+                _ => None,
+            });
+            let module = match module {
+                Some(m) => m,
+                None => {
+                    return Err(FlatpakError::PathNotFound(
+                        module_names[..=module_name_index].join("/"),
+                    ))
+                }
+            };
+            if let Some(module_build_options) = &module.build_options {
+                build_options = build_options.merge(&module_build_options.resolve_for_arch(arch));
+            }
+            modules = &module.modules;
+            found_module = Some(module);
+        }
+        match found_module {
+            Some(m) => Ok((m, build_options)),
+            None => Err(FlatpakError::PathNotFound(String::new())),
+        }
+    }
+
+    /// Gets the environment variables seen by a module when it is built. The module is
+    /// addressed by the names of the modules leading to it, as in
+    /// [`FlatpakApplication::get_module_build_options`].
+    pub fn get_module_build_env(
+        &self,
+        module_names: &[&str],
+        context: &FlatpakBuildContext,
+    ) -> Result<FlatpakBuildEnv, FlatpakError> {
+        let build_options = self.get_module_build_options(module_names, &context.arch)?;
+        Ok(FlatpakBuildEnv::from_build_options(&build_options, context))
+    }
+
+    /// Gets the plan to build and install a module from its source directory, with the
+    /// effective build options of the module. The module is addressed by the names of the
+    /// modules leading to it, as in [`FlatpakApplication::get_module_build_options`].
+    pub fn get_module_build_plan(
+        &self,
+        module_names: &[&str],
+        source_dir: &str,
+        context: &FlatpakBuildContext,
+    ) -> Result<FlatpakBuildPlan, FlatpakError> {
+        let (module, build_options) = self.get_module_with_build_options(module_names, &context.arch)?;
+        module.get_build_plan_with_build_options(source_dir, &build_options, context)
+    }
+
+    /// Gets the plan to build the whole application, in the order used by flatpak-builder:
+    /// * all the inline modules built for the architecture, nested modules first. Each module
+    ///   is built from the `{build_dir}/{module path}` directory, where the module path is the
+    ///   path of the module in the manifest, for example `modules[2].modules[0]`;
+    /// * the `cleanup-commands`, run in the installation directory;
+    /// * the `cleanup` patterns of the modules and of the application, applied in the
+    ///   installation directory;
+    /// * when building a runtime, the `prepare-platform-commands`, the `cleanup-platform`
+    ///   patterns of the modules and of the application, and then the `cleanup-platform-commands`,
+    ///   run in the `/usr` directory of the platform.
+    ///
+    /// The patterns of a module only apply to the files installed by that module, which are not
+    /// known before the build, so the matching files are only listed, see
+    /// [`crate::cleanup::get_cleanup_listing_step`]. The patterns of the application are applied
+    /// to the whole prefix, see [`crate::cleanup::get_cleanup_step`].
+    /// External modules should be loaded with [`FlatpakApplication::resolve`] first.
+    pub fn get_build_plan(
+        &self,
+        build_dir: &str,
+        context: &FlatpakBuildContext,
+    ) -> Result<FlatpakBuildPlan, FlatpakError> {
+        let build_options = match &self.build_options {
+            Some(b) => b.resolve_for_arch(&context.arch),
+            None => FlatpakBuildOptions::default(),
+        };
+        let mut modules: Vec<(FlatpakManifestPath, FlatpakModule, FlatpakBuildOptions)> = vec![];
+        add_modules_to_build(
+            &self.modules,
+            &FlatpakManifestPath::default().join_key("modules"),
+            &build_options,
+            &context.arch,
+            &mut modules,
+        );
+
+        let mut plan = FlatpakBuildPlan::default();
+        for (module_path, module, module_build_options) in &modules {
+            let source_dir = format!("{}/{}", build_dir, module_path);
+            let module_plan =
+                module.get_build_plan_with_build_options(&source_dir, module_build_options, context)?;
+            plan.steps.extend(module_plan.steps);
+        }
+
+        let mut application_plan = FlatpakBuildPlan::default();
+        for cleanup_command in &self.cleanup_commands {
+            application_plan.add_step(FlatpakBuildStep::shell(
+                FlatpakBuildStepKind::Cleanup,
+                cleanup_command,
+                &context.dest,
+            ));
+        }
+        let module_cleanup_patterns = modules.iter().flat_map(|(_, m, _)| &m.cleanup);
+        for cleanup_pattern in module_cleanup_patterns {
+            if let Some(step) =
+                get_cleanup_listing_step(FlatpakBuildStepKind::Cleanup, cleanup_pattern, &context.dest)
+            {
+                application_plan.add_step(step);
+            }
+        }
+        for cleanup_pattern in &self.cleanup {
+            if let Some(step) = get_cleanup_step(FlatpakBuildStepKind::Cleanup, cleanup_pattern, &context.dest)
+            {
+                application_plan.add_step(step);
+            }
+        }
+        if self.build_runtime == Some(true) {
+            for platform_command in &self.prepare_platform_commands {
+                application_plan.add_step(FlatpakBuildStep::shell(
+                    FlatpakBuildStepKind::PreparePlatform,
+                    platform_command,
+                    "/usr",
+                ));
+            }
+            let module_cleanup_platform_patterns = modules.iter().flat_map(|(_, m, _)| &m.cleanup_platform);
+            for cleanup_pattern in module_cleanup_platform_patterns {
+                if let Some(step) =
+                    get_cleanup_listing_step(FlatpakBuildStepKind::CleanupPlatform, cleanup_pattern, "/usr")
+                {
+                    application_plan.add_step(step);
+                }
+            }
+            for cleanup_pattern in &self.cleanup_platform {
+                if let Some(step) =
+                    get_cleanup_step(FlatpakBuildStepKind::CleanupPlatform, cleanup_pattern, "/usr")
+                {
+                    application_plan.add_step(step);
+                }
+            }
+            for platform_command in &self.cleanup_platform_commands {
+                application_plan.add_step(FlatpakBuildStep::shell(
+                    FlatpakBuildStepKind::CleanupPlatform,
+                    platform_command,
+                    "/usr",
+                ));
+            }
+        }
+        application_plan.set_env(&FlatpakBuildEnv::from_build_options(&build_options, context));
+        plan.steps.extend(application_plan.steps);
+        Ok(plan)
+    }
+
+    /// Gets the effective build options of all the inline modules for an architecture, with the
+    /// names of the modules leading to each module. The modules are returned in the order in
+    /// which they are built, nested modules first. See [`FlatpakApplication::get_module_build_options`].
+    pub fn get_all_modules_build_options(
+        &self,
+        arch: &FlatpakArchitecture,
+    ) -> Vec<(Vec<String>, FlatpakBuildOptions)> {
+        let build_options = match &self.build_options {
+            Some(b) => b.resolve_for_arch(arch),
+            None => FlatpakBuildOptions::default(),
+        };
+        let mut all_build_options = vec![];
+        add_modules_build_options(&self.modules, &[], &build_options, arch, &mut all_build_options);
+        all_build_options
+    }
+}
+
+/// Adds the inline modules built for the architecture, with their path in the manifest and
+/// their effective build options, in the order in which they are built.
+fn add_modules_to_build(
+    modules: &[FlatpakModuleItem],
+    modules_path: &FlatpakManifestPath,
+    parent_build_options: &FlatpakBuildOptions,
+    arch: &FlatpakArchitecture,
+    modules_to_build: &mut Vec<(FlatpakManifestPath, FlatpakModule, FlatpakBuildOptions)>,
+) {
+    for (module_index, module) in modules.iter().enumerate() {
+        let module = match module {
+            FlatpakModuleItem::Description(d) => d,
+            FlatpakModuleItem::Path(_) => continue,
+        };
+        let filtered_module = match module.filter_for_arch(arch) {
+            Some(m) => m,
+            None => continue,
+        };
+        let module_path = modules_path.join_index(module_index);
+        let build_options = match &filtered_module.build_options {
+            Some(b) => parent_build_options.merge(b),
+            None => parent_build_options.clone(),
+        };
+        // The nested modules are filtered again, so that they keep their index in the manifest.
+        add_modules_to_build(
+            &module.modules,
+            &module_path.join_key("modules"),
+            &build_options,
+            arch,
+            modules_to_build,
+        );
+        modules_to_build.push((module_path, filtered_module, build_options));
+    }
+}
+
+fn add_modules_build_options(
+    modules: &[FlatpakModuleItem],
+    parent_module_names: &[String],
+    parent_build_options: &FlatpakBuildOptions,
+    arch: &FlatpakArchitecture,
+    all_build_options: &mut Vec<(Vec<String>, FlatpakBuildOptions)>,
+) {
+    for module in modules {
+        let module = match module {
+            FlatpakModuleItem::Description(d) => d,
+            FlatpakModuleItem::Path(_) => continue,
+        };
+        let module_names = [parent_module_names, &[module.name.to_string()]].concat();
+        let build_options = match &module.build_options {
+            Some(b) => parent_build_options.merge(&b.resolve_for_arch(arch)),
+            None => parent_build_options.clone(),
+        };
+        add_modules_build_options(
+            &module.modules,
+            &module_names,
+            &build_options,
+            arch,
+            all_build_options,
+        );
+        all_build_options.push((module_names, build_options));
+    }
 }
 
 #[derive(Clone)]
@@ -385,6 +813,7 @@ impl FlatpakApplication {
 pub struct FlatpakExtension {
     /// The directory where the extension is mounted. If the extension point is for an application,
     /// this path is relative to /app, otherwise it is relative to /usr.
+    #[serde(rename = "directory")]
     pub extension_directory: String,
 
     /// If this is true, then the data created in the extension directory is omitted from the result,
@@ -455,11 +884,49 @@ pub struct FlatpakExtension {
     /// If this is not specified, it defaults to the branch of the application or
     /// runtime that the extension point is for.
     pub versions: Option<String>,
+
+    /// The fields that are not supported by this library, including the
+    /// extension fields prefixed with `x-`. They are preserved when dumping the manifest.
+    #[serde(flatten)]
+    pub extra_fields: FlatpakExtraFields,
+}
+impl FlatpakExtension {
+    /// The names of the fields supported in extensions.
+    pub const FIELD_NAMES: &'static [&'static str] = &[
+        "directory",
+        "bundle",
+        "remove-after-build",
+        "autodelete",
+        "no-autodownload",
+        "subdirectories",
+        "add-ld-path",
+        "download-if",
+        "enable-if",
+        "merge-dirs",
+        "subdirectory-suffix",
+        "locale-subset",
+        "version",
+        "versions",
+    ];
+
+    /// Gets an error for each field of the extension that is not supported by this library,
+    /// with the path of the field. `path` is the path of the extension in the manifest.
+    pub fn get_unknown_field_errors(
+        &self,
+        path: &FlatpakManifestPath,
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        self.extra_fields
+            .get_unknown_field_errors(path, FlatpakExtension::FIELD_NAMES)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::extra_fields::FlatpakExtraFields;
+    use crate::module::FlatpakBuildOptions;
+    use crate::source::{FlatpakSource, FlatpakSourceItem};
+    use crate::utils::TestDir;
 
     #[test]
     #[should_panic]
@@ -475,7 +942,18 @@ mod tests {
             sdk: org.gnome.Sdk
             command: flatpak-rs
         "###;
-        assert!(FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).is_err());
+        match FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest) {
+            Err(FlatpakError::MissingField { field, .. }) => assert_eq!(field, "id"),
+            _ => panic!("The id field should be reported as missing."),
+        }
+    }
+
+    #[test]
+    pub fn test_load_missing_file() {
+        match FlatpakApplication::load_from_file("/does/not/exist/com.example.App.yaml".to_string()) {
+            Err(FlatpakError::Io { path, .. }) => assert_eq!(path, "/does/not/exist/com.example.App.yaml"),
+            _ => panic!("Loading a missing file should return an I/O error."),
+        }
     }
 
     #[test]
@@ -538,6 +1016,355 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn test_parse_invalid_nested_source() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            modules:
+              - shared-modules/linux-audio/lv2.json
+              - name: flatpak-rs
+                sources:
+                  - type: dir
+                    path: .
+                modules:
+                  - name: libfoo
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libfoo.git
+                      - https://github.com/louib/libfoo/archive/v0.1.0.tar.gz
+                      - type: archive
+                        sha256: 0000000000000000000000000000000000000000000000000000000000000000
+        "###;
+        let error = FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap_err();
+        let errors = match error {
+            FlatpakError::Multiple(errors) => errors,
+            e => panic!("Expected multiple errors, got {}", e),
+        };
+        let error_paths: Vec<String> = errors
+            .iter()
+            .map(|e| e.get_location().unwrap().path.to_string())
+            .collect();
+        assert_eq!(
+            error_paths,
+            vec![
+                "modules[1].modules[0].sources[1]",
+                "modules[1].modules[0].sources[2]"
+            ]
+        );
+        assert_eq!(errors[1].get_location().unwrap().line, 18);
+    }
+
+    #[test]
+    pub fn test_resolve_external_modules() {
+        let test_dir = TestDir::new("resolve-application");
+        fs::create_dir_all(test_dir.join("shared-modules/lv2/serd")).unwrap();
+
+        fs::write(
+            test_dir.join("net.louib.flatpak-rs.yaml"),
+            r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            command: flatpak-rs
+            modules:
+              - shared-modules/lv2/lv2.json
+              - name: flatpak-rs
+                buildsystem: simple
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+            "###,
+        )
+        .unwrap();
+        fs::write(
+            test_dir.join("shared-modules/lv2/lv2.json"),
+            r###"
+            {
+                "name": "lv2",
+                "sources": [ "lv2-sources.json" ],
+                "modules": [ "serd/serd.json" ]
+            }
+            "###,
+        )
+        .unwrap();
+        fs::write(
+            test_dir.join("shared-modules/lv2/lv2-sources.json"),
+            r###"
+            [
+                { "type": "archive", "url": "https://lv2plug.in/spec/lv2-1.18.2.tar.bz2" },
+                { "type": "patch", "path": "lv2.patch" }
+            ]
+            "###,
+        )
+        .unwrap();
+        fs::write(
+            test_dir.join("shared-modules/lv2/serd/serd.json"),
+            r###"
+            {
+                "name": "serd",
+                "sources": [ { "type": "archive", "url": "https://download.drobilla.net/serd-0.30.10.tar.bz2" } ]
+            }
+            "###,
+        )
+        .unwrap();
+
+        let manifest_path = test_dir.join("net.louib.flatpak-rs.yaml");
+        let manifest_path = manifest_path.to_str().unwrap();
+        let application = FlatpakApplication::load_from_file(manifest_path.to_string()).unwrap();
+        let application = application.resolve(manifest_path).unwrap();
+
+        let all_modules = application.get_all_modules_recursively();
+        assert_eq!(all_modules.len(), 3);
+        for module in &all_modules {
+            assert!(matches!(module, FlatpakModuleItem::Description(_)));
+        }
+        assert_eq!(application.get_max_depth(), 2);
+        assert_eq!(
+            application.get_urls(false, Some(vec![FlatpakSourceType::Archive])),
+            vec![
+                "https://download.drobilla.net/serd-0.30.10.tar.bz2",
+                "https://lv2plug.in/spec/lv2-1.18.2.tar.bz2",
+            ]
+        );
+
+        let lv2_module = match &application.modules[0] {
+            FlatpakModuleItem::Description(m) => m,
+            FlatpakModuleItem::Path(_) => panic!("The module should have been resolved."),
+        };
+        assert_eq!(lv2_module.sources.len(), 2);
+        assert!(lv2_module
+            .manifest_path
+            .as_ref()
+            .unwrap()
+            .ends_with("shared-modules/lv2/lv2.json"));
+        match &lv2_module.sources[1] {
+            FlatpakSourceItem::Description(d) => {
+                assert!(d.manifest_path.as_ref().unwrap().ends_with("lv2-sources.json"))
+            }
+            FlatpakSourceItem::Path(_) => panic!("The source should have been resolved."),
+        }
+        match &lv2_module.modules[0] {
+            FlatpakModuleItem::Description(m) => {
+                assert!(m.manifest_path.as_ref().unwrap().ends_with("lv2/serd/serd.json"))
+            }
+            FlatpakModuleItem::Path(_) => panic!("The module should have been resolved."),
+        }
+    }
+
+    #[test]
+    pub fn test_externalize_modules() {
+        let test_dir = TestDir::new("externalize-application");
+        fs::create_dir_all(test_dir.join("modules")).unwrap();
+        fs::write(test_dir.join("modules/flatpak-rs.yaml"), "{}").unwrap();
+
+        let application_manifest = r###"
+            {
+                "app-id": "net.louib.flatpak-rs",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "3.36",
+                "sdk": "org.gnome.Sdk",
+                "modules": [
+                    "shared-modules/linux-audio/lv2.json",
+                    {
+                        "name": "libfoo",
+                        "sources": [ { "type": "dir", "path": "libfoo" } ]
+                    },
+                    {
+                        "name": "flatpak-rs",
+                        "sources": [ { "type": "dir", "path": "." } ]
+                    }
+                ]
+            }
+        "###;
+        let manifest_path = test_dir.join("net.louib.flatpak-rs.json");
+        let manifest_path = manifest_path.to_str().unwrap();
+        let mut application =
+            FlatpakApplication::parse(FlatpakManifestFormat::JSON, application_manifest).unwrap();
+
+        let files = application
+            .externalize_modules(manifest_path, "modules", None, Some(FlatpakManifestFormat::YAML))
+            .unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[0].path.ends_with("modules/libfoo.yaml"));
+        assert!(files[1].path.ends_with("modules/flatpak-rs-2.yaml"));
+        assert!(files[1].content.contains("name: flatpak-rs"));
+        // The paths are rebased on the modules directory.
+        assert!(files[0].content.contains("path: ../libfoo"));
+        assert!(files[1].content.contains("path: .."));
+
+        let module_paths: Vec<String> = application
+            .modules
+            .iter()
+            .map(|m| match m {
+                FlatpakModuleItem::Path(p) => p.to_string(),
+                FlatpakModuleItem::Description(_) => panic!("All modules should be externalized."),
+            })
+            .collect();
+        assert_eq!(
+            module_paths,
+            vec![
+                "shared-modules/linux-audio/lv2.json",
+                "modules/libfoo.yaml",
+                "modules/flatpak-rs-2.yaml",
+            ]
+        );
+        // Nothing was written to disk.
+        assert!(!test_dir.join("modules/libfoo.yaml").exists());
+    }
+
+    fn normalize_resolved_paths(module: &mut FlatpakModule) {
+        module.manifest_path = None;
+        for source in module.sources.iter_mut() {
+            let source = match source {
+                FlatpakSourceItem::Description(s) => s,
+                FlatpakSourceItem::Path(_) => panic!("The source should have been resolved."),
+            };
+            let base_dir = path::Path::new(source.manifest_path.as_ref().unwrap())
+                .parent()
+                .unwrap();
+            let canonicalize = |p: &String| {
+                fs::canonicalize(base_dir.join(p))
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            };
+            source.path = source.path.as_ref().map(canonicalize);
+            source.paths = source
+                .paths
+                .as_ref()
+                .map(|paths| paths.iter().map(canonicalize).collect());
+            source.manifest_path = None;
+        }
+        for child in module.modules.iter_mut() {
+            match child {
+                FlatpakModuleItem::Description(m) => normalize_resolved_paths(m),
+                FlatpakModuleItem::Path(_) => panic!("The module should have been resolved."),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_externalize_modules_round_trip() {
+        let test_dir = TestDir::new("externalize-round-trip");
+        fs::create_dir_all(test_dir.join("libfoo")).unwrap();
+        fs::create_dir_all(test_dir.join("libbar")).unwrap();
+        fs::create_dir_all(test_dir.join("patches")).unwrap();
+        fs::write(test_dir.join("patches/libfoo.patch"), "").unwrap();
+
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            modules:
+              - name: libfoo
+                sources:
+                  - type: dir
+                    path: libfoo
+                  - type: patch
+                    paths:
+                      - patches/libfoo.patch
+                modules:
+                  - name: libbar
+                    sources:
+                      - type: dir
+                        path: ./libbar/
+              - name: flatpak-rs
+                sources:
+                  - type: dir
+                    path: .
+        "###;
+        let manifest_path = test_dir.join("net.louib.flatpak-rs.yaml");
+        let manifest_path = manifest_path.to_str().unwrap();
+        fs::write(manifest_path, application_manifest).unwrap();
+        let application = FlatpakApplication::load_from_file(manifest_path.to_string()).unwrap();
+
+        let mut externalized_application = application.clone();
+        let files = externalized_application
+            .externalize_modules(manifest_path, "modules/shared", None, None)
+            .unwrap();
+        for file in &files {
+            file.write().unwrap();
+        }
+
+        let mut resolved_application = application.resolve(manifest_path).unwrap();
+        let mut resolved_externalized_application = externalized_application.resolve(manifest_path).unwrap();
+        for application in [&mut resolved_application, &mut resolved_externalized_application] {
+            for module in application.modules.iter_mut() {
+                match module {
+                    FlatpakModuleItem::Description(m) => normalize_resolved_paths(m),
+                    FlatpakModuleItem::Path(_) => panic!("The module should have been resolved."),
+                }
+            }
+        }
+        assert_eq!(
+            serde_json::to_value(&resolved_externalized_application).unwrap(),
+            serde_json::to_value(&resolved_application).unwrap(),
+        );
+    }
+
+    #[test]
+    pub fn test_externalize_modules_outside_of_manifest_dir() {
+        let mut application = FlatpakApplication::parse(
+            FlatpakManifestFormat::YAML,
+            r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            modules:
+              - name: libfoo
+                sources:
+                  - type: dir
+                    path: libfoo
+        "###,
+        )
+        .unwrap();
+        assert!(application
+            .externalize_modules("net.louib.flatpak-rs.yaml", "../modules", None, None)
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_externalize_selected_modules() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            modules:
+              - name: libfoo
+                sources:
+                  - type: dir
+                    path: libfoo
+              - name: flatpak-rs
+                sources:
+                  - type: dir
+                    path: .
+        "###;
+        let mut application =
+            FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap();
+        let files = application
+            .externalize_modules(
+                "/does/not/exist/net.louib.flatpak-rs.yaml",
+                "modules",
+                Some(vec!["libfoo".to_string()]),
+                None,
+            )
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "/does/not/exist/modules/libfoo.yaml");
+        assert!(matches!(&application.modules[0], FlatpakModuleItem::Path(p) if p == "modules/libfoo.yaml"));
+        assert!(matches!(
+            &application.modules[1],
+            FlatpakModuleItem::Description(_)
+        ));
+    }
+
     #[test]
     pub fn test_parse_add_extensions() {
         let application_manifest = r###"
@@ -840,6 +1667,110 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn test_parse_json_with_inline_comments() {
+        let application_manifest = r###"
+            {
+              "app-id": "org.gnome.Lollypop", // The application id.
+              "runtime": "org.gnome.Platform",
+              "runtime-version": "40", /* Updated
+              for GNOME 40 */ "sdk": "org.gnome.Sdk",
+              "command": "lollypop",
+              "modules": [
+                {
+                  "name": "gst-plugins-ugly",
+                  "cleanup": [ "/lib/*.la", /* "/share", */ "/share/gtk-doc/*" ],
+                  "sources": [{
+                    "type": "archive", // Release tarball.
+                    "url": "https://gstreamer.freedesktop.org/src/gst-plugins-ugly/gst-plugins-ugly-1.16.2.tar.xz"
+                  }]
+                }
+              ]
+            }
+        "###;
+        match FlatpakApplication::parse(FlatpakManifestFormat::JSON, application_manifest) {
+            Err(e) => std::panic::panic_any(e),
+            Ok(app) => {
+                assert_eq!(app.sdk, "org.gnome.Sdk");
+                let module = match &app.modules[0] {
+                    FlatpakModuleItem::Description(m) => m,
+                    FlatpakModuleItem::Path(_) => panic!("The module should be inlined."),
+                };
+                assert_eq!(module.cleanup, vec!["/lib/*.la", "/share/gtk-doc/*"]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_preserve_extra_fields() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            command: flatpak-rs
+            x-run-args:
+              - --verbose
+            x-d-bus-activatable: true
+            some-new-builder-option: enabled
+            build-options:
+              cflags: -O2
+              x-sanitizers: [address]
+            modules:
+              - name: flatpak-rs
+                x-team-owner: desktop
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+                    x-mirror: https://gitlab.com/louib/flatpak-rs.git
+        "###;
+        let application = FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap();
+        assert_eq!(
+            application.extra_fields.keys().collect::<Vec<&String>>(),
+            vec!["x-run-args", "x-d-bus-activatable", "some-new-builder-option"]
+        );
+        assert_eq!(
+            application.extra_fields.get_unknown_field_names(),
+            vec!["some-new-builder-option"]
+        );
+
+        for format in [FlatpakManifestFormat::YAML, FlatpakManifestFormat::JSON] {
+            let mut application = application.clone();
+            application.format = format.clone();
+            let application = FlatpakApplication::parse(format, &application.dump().unwrap()).unwrap();
+
+            let run_args: Option<Vec<String>> = application
+                .extra_fields
+                .get_extension_field("x-run-args")
+                .unwrap();
+            assert_eq!(run_args, Some(vec!["--verbose".to_string()]));
+            assert_eq!(
+                application.extra_fields.keys().collect::<Vec<&String>>(),
+                vec!["x-run-args", "x-d-bus-activatable", "some-new-builder-option"]
+            );
+
+            let build_options = application.build_options.unwrap();
+            let sanitizers: Option<Vec<String>> = build_options
+                .extra_fields
+                .get_extension_field("x-sanitizers")
+                .unwrap();
+            assert_eq!(sanitizers, Some(vec!["address".to_string()]));
+
+            let module = match &application.modules[0] {
+                FlatpakModuleItem::Description(m) => m,
+                FlatpakModuleItem::Path(_) => panic!("The module should be inlined."),
+            };
+            let team_owner: Option<String> = module.extra_fields.get_extension_field("x-team-owner").unwrap();
+            assert_eq!(team_owner, Some("desktop".to_string()));
+
+            let source = match &module.sources[0] {
+                FlatpakSourceItem::Description(s) => s,
+                FlatpakSourceItem::Path(_) => panic!("The source should be inlined."),
+            };
+            assert_eq!(source.extra_fields.get_extension_field_names(), vec!["x-mirror"]);
+        }
+    }
+
     #[test]
     pub fn test_parse_extension() {
         let extension_manifest = r###"
@@ -1054,4 +1985,435 @@ mod tests {
             }
         }
     }
+
+    fn assert_field_names_are_known<T>(field_names: &[&str], get_extra_fields: fn(&T) -> &FlatpakExtraFields)
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        for field_name in field_names {
+            // A field that is not supported would be accepted with any value in the extra fields.
+            let manifest = format!("{}: null", field_name);
+            if let Ok(manifest) = serde_yaml::from_str::<T>(&manifest) {
+                assert!(
+                    !get_extra_fields(&manifest).contains_key(*field_name),
+                    "{} is not a known field",
+                    field_name
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_field_names() {
+        assert_field_names_are_known::<FlatpakApplication>(FlatpakApplication::FIELD_NAMES, |a| {
+            &a.extra_fields
+        });
+        assert_field_names_are_known::<FlatpakModule>(FlatpakModule::FIELD_NAMES, |m| &m.extra_fields);
+        assert_field_names_are_known::<FlatpakBuildOptions>(FlatpakBuildOptions::FIELD_NAMES, |b| {
+            &b.extra_fields
+        });
+        assert_field_names_are_known::<FlatpakSource>(FlatpakSource::FIELD_NAMES, |s| &s.extra_fields);
+        assert_field_names_are_known::<FlatpakExtension>(FlatpakExtension::FIELD_NAMES, |e| &e.extra_fields);
+    }
+
+    #[test]
+    pub fn test_parse_strict() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            command: flatpak-rs
+            x-run-args:
+              - --verbose
+            build-options:
+              arch:
+                aarch64:
+                  cflag: -O1
+            modules:
+              - name: flatpak-rs
+                buildsytem: meson
+                config-opt:
+                  - -Dtests=false
+                x-team-owner: desktop
+                sources:
+                  - type: archive
+                    url: https://github.com/louib/flatpak-rs/archive/v0.18.0.tar.gz
+                    sha-256: 5e1cbd8c7b4d5e6c0f0b5a5b6c1e0b0c1c9e8a2f3d4e5f6a7b8c9d0e1f2a3b4c
+            add-extensions:
+              net.louib.flatpak-rs.Plugin:
+                directory: plugins
+                no-auto-download: true
+        "###;
+        assert!(FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).is_ok());
+
+        let error = FlatpakApplication::parse_with_options(
+            FlatpakManifestFormat::YAML,
+            application_manifest,
+            &FlatpakParseOptions::strict(),
+        )
+        .unwrap_err();
+        let errors = match error {
+            FlatpakError::Multiple(errors) => errors,
+            e => panic!("Expected multiple errors, got {}", e),
+        };
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                "12:19 (build-options.arch.aarch64.cflag): Unknown field cflag. Did you mean cflags?",
+                "15:17 (modules[0].buildsytem): Unknown field buildsytem. Did you mean buildsystem?",
+                "16:17 (modules[0].config-opt): Unknown field config-opt. Did you mean config-opts?",
+                "22:21 (modules[0].sources[0].sha-256): Unknown field sha-256. Did you mean sha256?",
+                "26:17 (add-extensions.net.louib.flatpak-rs.Plugin.no-auto-download): Unknown field no-auto-download. Did you mean no-autodownload?",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_get_module_build_options() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            build-options:
+              cflags: -O2
+              env:
+                V: "1"
+              arch:
+                aarch64:
+                  cflags: -march=armv8-a
+            modules:
+              - name: dependencies
+                build-options:
+                  cflags: -g
+                  config-opts: [--disable-docs]
+                modules:
+                  - name: libfoo
+                    build-options:
+                      cflags: -O3
+                      cflags-override: true
+                      env:
+                        V: "2"
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libfoo.git
+                  - name: libbar
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libbar.git
+                sources: []
+              - shared-modules/linux-audio/lv2.json
+              - name: flatpak-rs
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+        "###;
+        let application = FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap();
+
+        let build_options = application
+            .get_module_build_options(&["dependencies", "libbar"], &FlatpakArchitecture::AARCH64)
+            .unwrap();
+        assert_eq!(build_options.cflags, "-O2 -march=armv8-a -g");
+        assert_eq!(build_options.config_opts, vec!["--disable-docs"]);
+
+        let build_options = application
+            .get_module_build_options(&["dependencies", "libfoo"], &FlatpakArchitecture::X86)
+            .unwrap();
+        assert_eq!(build_options.cflags, "-O3");
+        assert_eq!(build_options.env.get("V"), Some("2"));
+
+        let build_options = application
+            .get_module_build_options(&["flatpak-rs"], &FlatpakArchitecture::X86)
+            .unwrap();
+        assert_eq!(build_options.cflags, "-O2");
+        assert!(build_options.config_opts.is_empty());
+
+        let mut context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::AARCH64);
+        context.n_jobs = 2;
+        let build_plan = application
+            .get_module_build_plan(&["dependencies", "libbar"], "/run/build/libbar", &context)
+            .unwrap();
+        let configure_step = build_plan
+            .get_steps(FlatpakBuildStepKind::Configure)
+            .pop()
+            .unwrap();
+        assert_eq!(
+            configure_step.get_command_line(),
+            "./configure --prefix=/app --disable-docs"
+        );
+        assert_eq!(configure_step.env.get("CFLAGS"), Some("-O2 -march=armv8-a -g"));
+        assert_eq!(configure_step.env.get("V"), Some("1"));
+
+        assert!(matches!(
+            application.get_module_build_options(&["dependencies", "libbaz"], &FlatpakArchitecture::X86),
+            Err(FlatpakError::PathNotFound(path)) if path == "dependencies/libbaz"
+        ));
+
+        let all_build_options = application.get_all_modules_build_options(&FlatpakArchitecture::AARCH64);
+        let all_cflags: Vec<(String, String)> = all_build_options
+            .iter()
+            .map(|(module_names, build_options)| (module_names.join("/"), build_options.cflags.to_string()))
+            .collect();
+        assert_eq!(
+            all_cflags,
+            vec![
+                ("dependencies/libfoo".to_string(), "-O3".to_string()),
+                (
+                    "dependencies/libbar".to_string(),
+                    "-O2 -march=armv8-a -g".to_string()
+                ),
+                ("dependencies".to_string(), "-O2 -march=armv8-a -g".to_string()),
+                ("flatpak-rs".to_string(), "-O2 -march=armv8-a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_filter_for_arch() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            build-options:
+              cflags: -O2
+              arch:
+                aarch64:
+                  cflags: -march=armv8-a
+            modules:
+              - name: intel-media-driver
+                only-arches: [x86_64]
+                modules:
+                  - name: gmmlib
+                    sources:
+                      - type: git
+                        url: https://github.com/intel/gmmlib.git
+                sources:
+                  - type: git
+                    url: https://github.com/intel/media-driver.git
+              - name: legacy-codec
+                disabled: true
+                sources:
+                  - type: git
+                    url: https://github.com/louib/legacy-codec.git
+              - name: flatpak-rs
+                build-options:
+                  arch:
+                    aarch64:
+                      config-opts: [--disable-simd]
+                modules:
+                  - name: libfoo
+                    skip-arches: [aarch64]
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libfoo.git
+                  - name: libfoo
+                    only-arches: [aarch64]
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libfoo.git
+                        branch: aarch64
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+                  - type: file
+                    only-arches: [aarch64]
+                    path: aarch64.patch
+                  - type: file
+                    skip-arches: [aarch64]
+                    path: x86_64.patch
+              - shared-modules/linux-audio/lv2.json
+        "###;
+        let application = FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap();
+
+        let aarch64_application = application.filter_for_arch(&FlatpakArchitecture::AARCH64);
+        assert_eq!(aarch64_application.modules.len(), 2);
+        assert_eq!(
+            aarch64_application.build_options.as_ref().unwrap().cflags,
+            "-O2 -march=armv8-a"
+        );
+        let module = match &aarch64_application.modules[0] {
+            FlatpakModuleItem::Description(m) => m,
+            FlatpakModuleItem::Path(_) => panic!("The module should be inlined."),
+        };
+        assert_eq!(module.name, "flatpak-rs");
+        assert_eq!(module.modules.len(), 1);
+        assert_eq!(module.sources.len(), 2);
+        let build_options = module.build_options.as_ref().unwrap();
+        assert_eq!(build_options.config_opts, vec!["--disable-simd"]);
+        assert!(build_options.arch.is_empty());
+        assert!(matches!(
+            &aarch64_application.modules[1],
+            FlatpakModuleItem::Path(p) if p == "shared-modules/linux-audio/lv2.json"
+        ));
+
+        let x86_application = application.filter_for_arch(&FlatpakArchitecture::X86);
+        assert_eq!(x86_application.modules.len(), 3);
+        assert_eq!(x86_application.build_options.as_ref().unwrap().cflags, "-O2");
+
+        let modules_arches =
+            application.get_modules_arches(&[FlatpakArchitecture::X86, FlatpakArchitecture::AARCH64]);
+        let modules_arches: Vec<(String, Vec<FlatpakArchitecture>)> = modules_arches
+            .into_iter()
+            .map(|(module_path, arches)| (module_path.to_string(), arches))
+            .collect();
+        assert_eq!(
+            modules_arches,
+            vec![
+                ("modules[0]".to_string(), vec![FlatpakArchitecture::X86]),
+                (
+                    "modules[0].modules[0]".to_string(),
+                    vec![FlatpakArchitecture::X86]
+                ),
+                ("modules[1]".to_string(), vec![]),
+                (
+                    "modules[2]".to_string(),
+                    vec![FlatpakArchitecture::X86, FlatpakArchitecture::AARCH64]
+                ),
+                (
+                    "modules[2].modules[0]".to_string(),
+                    vec![FlatpakArchitecture::X86]
+                ),
+                (
+                    "modules[2].modules[1]".to_string(),
+                    vec![FlatpakArchitecture::AARCH64]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_get_build_plan() {
+        let application_manifest = r###"
+            id: org.louib.Sdk
+            runtime: org.freedesktop.Platform
+            runtime-version: "23.08"
+            sdk: org.freedesktop.Sdk
+            build-runtime: true
+            cleanup:
+              - /include
+              - "*.la"
+            cleanup-commands:
+              - rm -rf /usr/share/doc
+            prepare-platform-commands:
+              - cp -r /usr/share/fonts /usr/lib/fonts
+            cleanup-platform:
+              - /lib/*/debug
+            cleanup-platform-commands:
+              - /usr/bin/ldconfig
+            modules:
+              - name: flatpak-rs
+                buildsystem: simple
+                build-commands:
+                  - install -D flatpak-rs /usr/bin/flatpak-rs
+                post-install:
+                  - flatpak-rs --version
+                cleanup:
+                  - share/man
+                cleanup-platform:
+                  - /bin/flatpak-rs
+                modules:
+                  - name: libfoo
+                    only-arches: [aarch64]
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libfoo.git
+                  - name: libbar
+                    buildsystem: simple
+                    build-commands:
+                      - make install
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libbar.git
+                  - name: libbar
+                    buildsystem: simple
+                    build-commands:
+                      - make install-data
+                    sources:
+                      - type: git
+                        url: https://github.com/louib/libbar-data.git
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+        "###;
+        let application = FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest).unwrap();
+        let mut context = FlatpakBuildContext::new("org.louib.Sdk", FlatpakArchitecture::X86);
+        context.dest = "/usr".to_string();
+
+        let build_plan = application.get_build_plan("/run/build", &context).unwrap();
+        let steps: Vec<(FlatpakBuildStepKind, &str, String)> = build_plan
+            .steps
+            .iter()
+            .map(|s| (s.kind, s.working_dir.as_str(), s.get_command_line()))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (
+                    FlatpakBuildStepKind::Build,
+                    "/run/build/modules[0].modules[1]",
+                    "/bin/sh -c 'make install'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Build,
+                    "/run/build/modules[0].modules[2]",
+                    "/bin/sh -c 'make install-data'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Build,
+                    "/run/build/modules[0]",
+                    "/bin/sh -c 'install -D flatpak-rs /usr/bin/flatpak-rs'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::PostInstall,
+                    "/run/build/modules[0]",
+                    "/bin/sh -c 'flatpak-rs --version'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Cleanup,
+                    "/usr",
+                    "/bin/sh -c 'rm -rf /usr/share/doc'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Cleanup,
+                    "/usr",
+                    "find . -mindepth 1 -path '*/share/man' -prune -print".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Cleanup,
+                    "/usr",
+                    "find . -mindepth 1 -maxdepth 1 -path ./include -prune -exec rm -rf '{}' +".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::Cleanup,
+                    "/usr",
+                    "find . -mindepth 1 -name '*.la' -prune -exec rm -rf '{}' +".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::PreparePlatform,
+                    "/usr",
+                    "/bin/sh -c 'cp -r /usr/share/fonts /usr/lib/fonts'".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::CleanupPlatform,
+                    "/usr",
+                    "find . -mindepth 2 -maxdepth 2 -path ./bin/flatpak-rs -prune -print".to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::CleanupPlatform,
+                    "/usr",
+                    "find . -mindepth 3 -maxdepth 3 -path './lib/*/debug' -prune -exec rm -rf '{}' +"
+                        .to_string()
+                ),
+                (
+                    FlatpakBuildStepKind::CleanupPlatform,
+                    "/usr",
+                    "/bin/sh -c /usr/bin/ldconfig".to_string()
+                ),
+            ]
+        );
+        assert_eq!(build_plan.steps[11].env.get("FLATPAK_ID"), Some("org.louib.Sdk"));
+    }
 }
@@ -1,4 +1,9 @@
-use serde::{Deserialize, Deserializer, Serializer};
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::FlatpakError;
+use crate::syntax::FlatpakManifestPath;
 
 pub const I386: &str = "i386";
 pub const X86: &str = "x86_64";
@@ -7,15 +12,18 @@ pub const ARM: &str = "arm";
 pub const ARMEB: &str = "armeb";
 pub const MIPSEL: &str = "mipsel";
 pub const MIPS64EL: &str = "mips64el";
+pub const PPC64LE: &str = "ppc64le";
+pub const RISCV64: &str = "riscv64";
+pub const LOONGARCH64: &str = "loongarch64";
 
 #[derive(Clone)]
-#[derive(Deserialize)]
+#[derive(Copy)]
 #[derive(Debug)]
 #[derive(Hash)]
 #[derive(PartialEq)]
-// FIXME I could not find a complete list of the architectures supported
-// by flatpak-builder. There might be others, and some in the list might not
-// be accurate.
+#[derive(Eq)]
+/// The architectures supported by Flatpak. They are serialized using the names
+/// used by Flatpak, and some common aliases of those names are also accepted.
 pub enum FlatpakArchitecture {
     I386,
     X86,
@@ -27,48 +35,224 @@ pub enum FlatpakArchitecture {
     MIPSEL,
     /// MIPS-64 little endian
     MIPS64EL,
+    /// PowerPC-64 little endian
+    PPC64LE,
+    RISCV64,
+    LOONGARCH64,
 }
 
-impl Default for FlatpakArchitecture {
-    fn default() -> Self {
-        FlatpakArchitecture::X86
+/// All the architectures supported by Flatpak.
+pub const ALL_ARCHITECTURES: &[FlatpakArchitecture] = &[
+    FlatpakArchitecture::I386,
+    FlatpakArchitecture::X86,
+    FlatpakArchitecture::AARCH64,
+    FlatpakArchitecture::ARM,
+    FlatpakArchitecture::ARMEB,
+    FlatpakArchitecture::MIPSEL,
+    FlatpakArchitecture::MIPS64EL,
+    FlatpakArchitecture::PPC64LE,
+    FlatpakArchitecture::RISCV64,
+    FlatpakArchitecture::LOONGARCH64,
+];
+
+impl fmt::Display for FlatpakArchitecture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arch = match &self {
+            FlatpakArchitecture::X86 => X86,
+            FlatpakArchitecture::I386 => I386,
+            FlatpakArchitecture::AARCH64 => AARCH64,
+            FlatpakArchitecture::ARM => ARM,
+            FlatpakArchitecture::ARMEB => ARMEB,
+            FlatpakArchitecture::MIPSEL => MIPSEL,
+            FlatpakArchitecture::MIPS64EL => MIPS64EL,
+            FlatpakArchitecture::PPC64LE => PPC64LE,
+            FlatpakArchitecture::RISCV64 => RISCV64,
+            FlatpakArchitecture::LOONGARCH64 => LOONGARCH64,
+        };
+        write!(f, "{}", arch)
     }
 }
 impl FlatpakArchitecture {
-    pub fn to_string(&self) -> String {
-        match &self {
-            FlatpakArchitecture::X86 => X86.to_string(),
-            FlatpakArchitecture::I386 => I386.to_string(),
-            FlatpakArchitecture::AARCH64 => AARCH64.to_string(),
-            FlatpakArchitecture::ARM => ARM.to_string(),
-            FlatpakArchitecture::ARMEB => ARMEB.to_string(),
-            FlatpakArchitecture::MIPSEL => MIPSEL.to_string(),
-            FlatpakArchitecture::MIPS64EL => MIPS64EL.to_string(),
+    /// Gets an architecture from its Flatpak name, or from one of its aliases
+    /// (for example `amd64` or `arm64`).
+    pub fn from_string(arch: &str) -> Result<FlatpakArchitecture, FlatpakError> {
+        match arch {
+            X86 | "amd64" | "x64" => Ok(FlatpakArchitecture::X86),
+            I386 | "i486" | "i586" | "i686" | "x86" => Ok(FlatpakArchitecture::I386),
+            AARCH64 | "arm64" | "armv8" => Ok(FlatpakArchitecture::AARCH64),
+            ARM | "armhf" | "armv7" | "armv7l" | "armv7hl" => Ok(FlatpakArchitecture::ARM),
+            ARMEB => Ok(FlatpakArchitecture::ARMEB),
+            MIPSEL => Ok(FlatpakArchitecture::MIPSEL),
+            MIPS64EL => Ok(FlatpakArchitecture::MIPS64EL),
+            PPC64LE | "ppc64el" | "powerpc64le" => Ok(FlatpakArchitecture::PPC64LE),
+            RISCV64 | "riscv64gc" => Ok(FlatpakArchitecture::RISCV64),
+            LOONGARCH64 | "loong64" => Ok(FlatpakArchitecture::LOONGARCH64),
+            _ => Err(FlatpakError::invalid_value("architecture", arch)),
         }
     }
 
-    pub fn from_string(arch: &str) -> Result<FlatpakArchitecture, String> {
-        if arch == X86 {
-            return Ok(FlatpakArchitecture::X86);
-        }
-        if arch == I386 {
-            return Ok(FlatpakArchitecture::I386);
-        }
-        if arch == AARCH64 {
-            return Ok(FlatpakArchitecture::AARCH64);
-        }
-        if arch == ARM {
-            return Ok(FlatpakArchitecture::ARM);
+    /// Returns `true` if `arch_name` is the Flatpak name of the architecture, or one of its aliases.
+    pub fn is_named(&self, arch_name: &str) -> bool {
+        FlatpakArchitecture::from_string(arch_name).ok().as_ref() == Some(self)
+    }
+
+    /// Gets the architecture of the host, as reported by the Rust standard library.
+    pub fn host() -> Result<FlatpakArchitecture, FlatpakError> {
+        let little_endian = cfg!(target_endian = "little");
+        match std::env::consts::ARCH {
+            "x86_64" => Ok(FlatpakArchitecture::X86),
+            "x86" => Ok(FlatpakArchitecture::I386),
+            "aarch64" => Ok(FlatpakArchitecture::AARCH64),
+            "arm" if little_endian => Ok(FlatpakArchitecture::ARM),
+            "arm" => Ok(FlatpakArchitecture::ARMEB),
+            "mips" if little_endian => Ok(FlatpakArchitecture::MIPSEL),
+            "mips64" if little_endian => Ok(FlatpakArchitecture::MIPS64EL),
+            "powerpc64" if little_endian => Ok(FlatpakArchitecture::PPC64LE),
+            "riscv64" => Ok(FlatpakArchitecture::RISCV64),
+            "loongarch64" => Ok(FlatpakArchitecture::LOONGARCH64),
+            arch => Err(FlatpakError::invalid_value("architecture", arch)),
         }
-        if arch == ARMEB {
-            return Ok(FlatpakArchitecture::ARMEB);
+    }
+
+    /// Gets the GNU triplet of the architecture, as used for the multiarch library
+    /// directories and by the cross-compilation toolchains.
+    pub fn get_gnu_triplet(&self) -> &'static str {
+        match &self {
+            FlatpakArchitecture::X86 => "x86_64-linux-gnu",
+            FlatpakArchitecture::I386 => "i386-linux-gnu",
+            FlatpakArchitecture::AARCH64 => "aarch64-linux-gnu",
+            FlatpakArchitecture::ARM => "arm-linux-gnueabihf",
+            FlatpakArchitecture::ARMEB => "armeb-linux-gnueabihf",
+            FlatpakArchitecture::MIPSEL => "mipsel-linux-gnu",
+            FlatpakArchitecture::MIPS64EL => "mips64el-linux-gnuabi64",
+            FlatpakArchitecture::PPC64LE => "powerpc64le-linux-gnu",
+            FlatpakArchitecture::RISCV64 => "riscv64-linux-gnu",
+            FlatpakArchitecture::LOONGARCH64 => "loongarch64-linux-gnu",
         }
-        if arch == MIPSEL {
-            return Ok(FlatpakArchitecture::MIPSEL);
+    }
+
+    /// Gets the Rust target triple of the architecture, if Rust supports building
+    /// for this architecture with the GNU libc.
+    pub fn get_rust_target_triple(&self) -> Option<&'static str> {
+        match &self {
+            FlatpakArchitecture::X86 => Some("x86_64-unknown-linux-gnu"),
+            FlatpakArchitecture::I386 => Some("i686-unknown-linux-gnu"),
+            FlatpakArchitecture::AARCH64 => Some("aarch64-unknown-linux-gnu"),
+            FlatpakArchitecture::ARM => Some("armv7-unknown-linux-gnueabihf"),
+            FlatpakArchitecture::ARMEB => None,
+            FlatpakArchitecture::MIPSEL => Some("mipsel-unknown-linux-gnu"),
+            FlatpakArchitecture::MIPS64EL => Some("mips64el-unknown-linux-gnuabi64"),
+            FlatpakArchitecture::PPC64LE => Some("powerpc64le-unknown-linux-gnu"),
+            FlatpakArchitecture::RISCV64 => Some("riscv64gc-unknown-linux-gnu"),
+            FlatpakArchitecture::LOONGARCH64 => Some("loongarch64-unknown-linux-gnu"),
         }
-        if arch == MIPS64EL {
-            return Ok(FlatpakArchitecture::MIPS64EL);
+    }
+}
+/// Gets an error for each name of `arch_names` that is not the name or an alias of an
+/// architecture supported by Flatpak. `path` is the path of the array of names in the manifest.
+pub(crate) fn get_unknown_arch_errors(
+    arch_names: &[String],
+    path: &FlatpakManifestPath,
+) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+    arch_names
+        .iter()
+        .enumerate()
+        .filter_map(
+            |(arch_index, arch_name)| match FlatpakArchitecture::from_string(arch_name) {
+                Ok(_) => None,
+                Err(e) => Some((path.join_index(arch_index), e)),
+            },
+        )
+        .collect()
+}
+
+impl Serialize for FlatpakArchitecture {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+impl<'de> Deserialize<'de> for FlatpakArchitecture {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let arch = String::deserialize(deserializer)?;
+        FlatpakArchitecture::from_string(&arch).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_architecture_names() {
+        for arch in ALL_ARCHITECTURES {
+            assert_eq!(
+                &FlatpakArchitecture::from_string(&arch.to_string()).unwrap(),
+                arch
+            );
         }
-        Err(format!("Invalid architecture {}.", arch))
+        assert_eq!(
+            FlatpakArchitecture::from_string("amd64").unwrap(),
+            FlatpakArchitecture::X86
+        );
+        assert_eq!(
+            FlatpakArchitecture::from_string("arm64").unwrap(),
+            FlatpakArchitecture::AARCH64
+        );
+        assert_eq!(
+            FlatpakArchitecture::from_string("ppc64el").unwrap(),
+            FlatpakArchitecture::PPC64LE
+        );
+        assert!(matches!(
+            FlatpakArchitecture::from_string("sparc64"),
+            Err(FlatpakError::InvalidValue { .. })
+        ));
+        assert!(FlatpakArchitecture::X86.is_named("amd64"));
+        assert!(!FlatpakArchitecture::X86.is_named("arm64"));
+        assert!(!FlatpakArchitecture::X86.is_named("s390x"));
+
+        let arch_names = vec!["amd64".to_string(), "s390x".to_string()];
+        let errors = get_unknown_arch_errors(&arch_names, &FlatpakManifestPath::parse("only-arches").unwrap());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0.to_string(), "only-arches[1]");
+    }
+
+    #[test]
+    pub fn test_serialize_architecture() {
+        let arches: Vec<FlatpakArchitecture> = serde_yaml::from_str("[x86_64, arm64, i686]").unwrap();
+        assert_eq!(
+            arches,
+            vec![
+                FlatpakArchitecture::X86,
+                FlatpakArchitecture::AARCH64,
+                FlatpakArchitecture::I386
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&arches).unwrap(),
+            "[\"x86_64\",\"aarch64\",\"i386\"]"
+        );
+        let error = serde_yaml::from_str::<Vec<FlatpakArchitecture>>("[x86_64, sparc64]").unwrap_err();
+        assert!(error.to_string().contains("sparc64"));
+    }
+
+    #[test]
+    pub fn test_triples() {
+        assert_eq!(
+            FlatpakArchitecture::AARCH64.get_gnu_triplet(),
+            "aarch64-linux-gnu"
+        );
+        assert_eq!(
+            FlatpakArchitecture::RISCV64.get_rust_target_triple(),
+            Some("riscv64gc-unknown-linux-gnu")
+        );
+        assert_eq!(FlatpakArchitecture::ARMEB.get_rust_target_triple(), None);
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(FlatpakArchitecture::host().unwrap(), FlatpakArchitecture::X86);
     }
 }
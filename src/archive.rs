@@ -3,6 +3,8 @@ use serde::{Deserialize, Deserializer, Serializer};
 use lazy_static::lazy_static;
 use regex::Regex;
 
+use crate::error::FlatpakError;
+
 lazy_static! {
     static ref PROJECT_NAME_REGEX: Regex = Regex::new(r"([0-9a-zA-Z_-]+)-[0-9]+.[0-9]+.[0-9]+").unwrap();
 }
@@ -16,6 +18,7 @@ pub const TAR_LZIP: &str = "tar-lzip";
 pub const TAR_LZMA: &str = "tar-lzma";
 pub const TAR_LZOP: &str = "tar-lzop";
 pub const TAR_XZ: &str = "tar-xz";
+pub const TAR_ZSTD: &str = "tar-zst";
 pub const ZIP: &str = "zip";
 pub const SEVENZIP: &str = "7z";
 
@@ -34,6 +37,7 @@ pub enum FlatpakArchiveType {
     TarLzma,
     TarLzop,
     TarXz,
+    TarZstd,
     Zip,
     SevenZip,
 }
@@ -56,10 +60,11 @@ impl FlatpakArchiveType {
             FlatpakArchiveType::TarLzma => TAR_LZMA.to_string(),
             FlatpakArchiveType::TarLzop => TAR_LZOP.to_string(),
             FlatpakArchiveType::TarXz => TAR_XZ.to_string(),
+            FlatpakArchiveType::TarZstd => TAR_ZSTD.to_string(),
             FlatpakArchiveType::Zip => ZIP.to_string(),
         }
     }
-    pub fn from_string(archive_type: &str) -> Result<FlatpakArchiveType, String> {
+    pub fn from_string(archive_type: &str) -> Result<FlatpakArchiveType, FlatpakError> {
         if archive_type == RPM {
             return Ok(FlatpakArchiveType::Rpm);
         }
@@ -96,7 +101,10 @@ impl FlatpakArchiveType {
         if archive_type == TAR_LZOP {
             return Ok(FlatpakArchiveType::TarLzop);
         }
-        Err(format!("Invalid archive type {}.", archive_type))
+        if archive_type == TAR_ZSTD {
+            return Ok(FlatpakArchiveType::TarZstd);
+        }
+        Err(FlatpakError::invalid_value("archive type", archive_type))
     }
 
     /// Detects the archive type from a path or a URL, using
@@ -130,6 +138,9 @@ impl FlatpakArchiveType {
         if path.ends_with(".tar.xz") || path.ends_with(".txz") {
             return Some(FlatpakArchiveType::TarXz);
         }
+        if path.ends_with(".tar.zst") || path.ends_with(".tzst") {
+            return Some(FlatpakArchiveType::TarZstd);
+        }
         if path.ends_with(".zip") {
             return Some(FlatpakArchiveType::Zip);
         }
@@ -142,6 +153,29 @@ impl FlatpakArchiveType {
         None
     }
 
+    /// Returns `true` if the archive is a tarball, compressed or not.
+    pub fn is_tar(&self) -> bool {
+        !matches!(
+            self,
+            FlatpakArchiveType::Rpm | FlatpakArchiveType::Zip | FlatpakArchiveType::SevenZip
+        )
+    }
+
+    /// Gets the flag used by tar to decompress the archive, like flatpak-builder does.
+    pub fn get_tar_decompress_flag(&self) -> Option<&'static str> {
+        match &self {
+            FlatpakArchiveType::TarGzip => Some("-z"),
+            FlatpakArchiveType::TarCompress => Some("-Z"),
+            FlatpakArchiveType::TarBzip2 => Some("-j"),
+            FlatpakArchiveType::TarLzip => Some("--lzip"),
+            FlatpakArchiveType::TarLzma => Some("--lzma"),
+            FlatpakArchiveType::TarLzop => Some("--lzop"),
+            FlatpakArchiveType::TarXz => Some("-J"),
+            FlatpakArchiveType::TarZstd => Some("--zstd"),
+            _ => None,
+        }
+    }
+
     pub fn serialize<S>(x: &Option<FlatpakArchiveType>, s: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
@@ -160,7 +194,7 @@ impl FlatpakArchiveType {
 
         match FlatpakArchiveType::from_string(&buf) {
             Ok(b) => Ok(Some(b)),
-            Err(e) => Err(e).map_err(serde::de::Error::custom),
+            Err(e) => Err(serde::de::Error::custom(e)),
         }
     }
 }
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::architecture::FlatpakArchitecture;
+use crate::module::FlatpakBuildOptions;
+
+/// The default installation prefix of Flatpak applications.
+pub const DEFAULT_DEST: &str = "/app";
+
+#[derive(Clone)]
+#[derive(Debug)]
+/// The context in which a module is built.
+pub struct FlatpakBuildContext {
+    /// The id of the application being built.
+    pub app_id: String,
+
+    /// The architecture the module is built for.
+    pub arch: FlatpakArchitecture,
+
+    /// The directory in which the application is installed. Defaults to `/app`.
+    pub dest: String,
+
+    /// The number of parallel jobs used for the build.
+    pub n_jobs: usize,
+}
+impl FlatpakBuildContext {
+    pub fn new(app_id: &str, arch: FlatpakArchitecture) -> FlatpakBuildContext {
+        FlatpakBuildContext {
+            app_id: app_id.to_string(),
+            arch,
+            dest: DEFAULT_DEST.to_string(),
+            n_jobs: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[serde(transparent)]
+/// The environment variables of a module build. A variable with a `None` value is
+/// removed from the environment of the build.
+pub struct FlatpakBuildEnv {
+    pub variables: BTreeMap<String, Option<String>>,
+}
+impl FlatpakBuildEnv {
+    /// Computes the environment seen by a module built with the given effective build
+    /// options, like flatpak-builder does:
+    /// * the `FLATPAK_ID`, `FLATPAK_ARCH` and `FLATPAK_BUILDER_N_JOBS` variables are set from
+    ///   the build context, and `FLATPAK_DEST` is the `prefix` of the build options, or the
+    ///   destination of the build context if the build options do not define one;
+    /// * `CFLAGS`, `CPPFLAGS`, `CXXFLAGS` and `LDFLAGS` are set from the build options,
+    ///   when they are not empty;
+    /// * `PATH`, `LD_LIBRARY_PATH` and `PKG_CONFIG_PATH` are only set when the build options
+    ///   have paths to prepend or append to them, which are added to their default values in
+    ///   the build sandbox;
+    /// * the `env` entries are applied last, and entries with a null value unset the variable.
+    pub fn from_build_options(
+        build_options: &FlatpakBuildOptions,
+        context: &FlatpakBuildContext,
+    ) -> FlatpakBuildEnv {
+        let dest = match build_options.prefix.is_empty() {
+            true => &context.dest,
+            false => &build_options.prefix,
+        };
+        let mut build_env = FlatpakBuildEnv::default();
+        build_env.set("FLATPAK_ID", &context.app_id);
+        build_env.set("FLATPAK_ARCH", &context.arch.to_string());
+        build_env.set("FLATPAK_DEST", dest);
+        build_env.set("FLATPAK_BUILDER_N_JOBS", &context.n_jobs.to_string());
+
+        for (variable_name, flags) in [
+            ("CFLAGS", &build_options.cflags),
+            ("CPPFLAGS", &build_options.cppflags),
+            ("CXXFLAGS", &build_options.cxxflags),
+            ("LDFLAGS", &build_options.ldflags),
+        ] {
+            if !flags.is_empty() {
+                build_env.set(variable_name, flags);
+            }
+        }
+
+        let default_path = get_default_path(dest, &["/bin"]);
+        let default_pkg_config_path = get_default_path(dest, &["/lib/pkgconfig", "/share/pkgconfig"]);
+        for (variable_name, default_value, prepend_path, append_path) in [
+            (
+                "PATH",
+                default_path.as_str(),
+                &build_options.prepend_path,
+                &build_options.append_path,
+            ),
+            (
+                "LD_LIBRARY_PATH",
+                "",
+                &build_options.prepend_ld_library_path,
+                &build_options.append_ld_library_path,
+            ),
+            (
+                "PKG_CONFIG_PATH",
+                default_pkg_config_path.as_str(),
+                &build_options.prepend_pkg_config_path,
+                &build_options.append_pkg_config_path,
+            ),
+        ] {
+            if prepend_path.is_empty() && append_path.is_empty() {
+                continue;
+            }
+            let path = join_paths(&[prepend_path, default_value, append_path]);
+            if !path.is_empty() {
+                build_env.set(variable_name, &path);
+            }
+        }
+
+        for (variable_name, variable_value) in build_options.env.iter() {
+            match variable_value {
+                Some(v) => build_env.set(variable_name, v),
+                None => build_env.unset(variable_name),
+            }
+        }
+        build_env
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    /// Gets the value of a variable. Returns `None` if the variable is not set, or is unset.
+    pub fn get(&self, variable_name: &str) -> Option<&str> {
+        match self.variables.get(variable_name) {
+            Some(Some(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, variable_name: &str, variable_value: &str) {
+        self.variables
+            .insert(variable_name.to_string(), Some(variable_value.to_string()));
+    }
+
+    /// Marks a variable to be removed from the environment of the build.
+    pub fn unset(&mut self, variable_name: &str) {
+        self.variables.insert(variable_name.to_string(), None);
+    }
+
+    /// Applies the environment to a command.
+    pub fn apply(&self, command: &mut Command) {
+        for (variable_name, variable_value) in &self.variables {
+            match variable_value {
+                Some(v) => command.env(variable_name, v),
+                None => command.env_remove(variable_name),
+            };
+        }
+    }
+
+    /// Applies the environment to all the commands, for example the commands returned by
+    /// [`crate::module::FlatpakModule::get_commands`].
+    pub fn apply_to_commands(&self, commands: &mut [Command]) {
+        for command in commands {
+            self.apply(command);
+        }
+    }
+}
+
+/// Gets the default value of a path variable in the build sandbox, which contains the
+/// directories of the build prefix, followed by the ones of `/usr`.
+fn get_default_path(prefix: &str, directories: &[&str]) -> String {
+    let mut paths: Vec<String> = vec![];
+    for prefix in [prefix, "/usr"] {
+        for directory in directories {
+            let path = format!("{}{}", prefix, directory);
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    paths.join(":")
+}
+
+/// Joins paths with `:`, ignoring the empty paths and the leading and trailing colons,
+/// so that the result never contains empty path elements.
+fn join_paths(paths: &[&str]) -> String {
+    paths
+        .iter()
+        .flat_map(|p| p.split(':'))
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FlatpakManifestFormat;
+
+    #[test]
+    pub fn test_build_env() {
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse(
+                r###"
+                cflags: -O2 -g
+                ldflags: -Wl,--as-needed
+                prepend-path: /app/sdk/bin
+                append-path: "/usr/lib/sdk/rust/bin:"
+                append-ld-library-path: /app/sdk/lib
+                prepend-pkg-config-path: /app/sdk/lib/pkgconfig
+                env:
+                  CXXFLAGS: -O3
+                  PYTHONPATH: /app/lib/python3/site-packages
+                  LANG: null
+            "###,
+            )
+            .unwrap();
+        let mut context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::AARCH64);
+        context.n_jobs = 4;
+
+        let build_env = FlatpakBuildEnv::from_build_options(&build_options, &context);
+        assert_eq!(build_env.get("FLATPAK_ID"), Some("net.louib.flatpak-rs"));
+        assert_eq!(build_env.get("FLATPAK_ARCH"), Some("aarch64"));
+        assert_eq!(build_env.get("FLATPAK_DEST"), Some("/app"));
+        assert_eq!(build_env.get("FLATPAK_BUILDER_N_JOBS"), Some("4"));
+        assert_eq!(build_env.get("CFLAGS"), Some("-O2 -g"));
+        assert_eq!(build_env.get("CPPFLAGS"), None);
+        assert_eq!(build_env.get("CXXFLAGS"), Some("-O3"));
+        assert_eq!(build_env.get("LDFLAGS"), Some("-Wl,--as-needed"));
+        assert_eq!(
+            build_env.get("PATH"),
+            Some("/app/sdk/bin:/app/bin:/usr/bin:/usr/lib/sdk/rust/bin")
+        );
+        assert_eq!(build_env.get("LD_LIBRARY_PATH"), Some("/app/sdk/lib"));
+        assert_eq!(
+            build_env.get("PKG_CONFIG_PATH"),
+            Some("/app/sdk/lib/pkgconfig:/app/lib/pkgconfig:/app/share/pkgconfig:/usr/lib/pkgconfig:/usr/share/pkgconfig")
+        );
+        assert_eq!(
+            build_env.get("PYTHONPATH"),
+            Some("/app/lib/python3/site-packages")
+        );
+        assert_eq!(build_env.variables.get("LANG"), Some(&None));
+
+        let mut commands = vec![Command::new("make")];
+        build_env.apply_to_commands(&mut commands);
+        let command_env: BTreeMap<_, _> = commands[0].get_envs().collect();
+        assert_eq!(
+            command_env.get(std::ffi::OsStr::new("CFLAGS")),
+            Some(&Some(std::ffi::OsStr::new("-O2 -g")))
+        );
+        assert_eq!(command_env.get(std::ffi::OsStr::new("LANG")), Some(&None));
+    }
+
+    #[test]
+    pub fn test_build_env_without_build_options() {
+        let context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::X86);
+        let build_env = FlatpakBuildEnv::from_build_options(&FlatpakBuildOptions::default(), &context);
+        assert_eq!(build_env.get("FLATPAK_DEST"), Some("/app"));
+        assert_eq!(build_env.get("PATH"), None);
+        assert_eq!(build_env.get("LD_LIBRARY_PATH"), None);
+        assert_eq!(build_env.get("PKG_CONFIG_PATH"), None);
+        assert_eq!(build_env.get("CFLAGS"), None);
+        assert_eq!(
+            build_env.variables.keys().collect::<Vec<_>>(),
+            vec![
+                "FLATPAK_ARCH",
+                "FLATPAK_BUILDER_N_JOBS",
+                "FLATPAK_DEST",
+                "FLATPAK_ID"
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_build_env_with_prefix() {
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse(
+                r###"
+                prefix: /usr
+                append-pkg-config-path: /usr/lib/sdk/llvm/lib/pkgconfig
+            "###,
+            )
+            .unwrap();
+        let context = FlatpakBuildContext::new("org.louib.Sdk", FlatpakArchitecture::X86);
+        let build_env = FlatpakBuildEnv::from_build_options(&build_options, &context);
+        assert_eq!(build_env.get("FLATPAK_DEST"), Some("/usr"));
+        assert_eq!(build_env.get("PATH"), None);
+        assert_eq!(
+            build_env.get("PKG_CONFIG_PATH"),
+            Some("/usr/lib/pkgconfig:/usr/share/pkgconfig:/usr/lib/sdk/llvm/lib/pkgconfig")
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::build_env::FlatpakBuildEnv;
+use crate::error::FlatpakError;
+use crate::format::FlatpakManifestFormat;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+#[derive(Hash)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[serde(rename_all = "kebab-case")]
+/// The phases of a module build, in the order in which they are run.
+pub enum FlatpakBuildStepKind {
+    Configure,
+    Build,
+    Install,
+    PostInstall,
+    Test,
+    /// The cleanup of the application, after all the modules were built.
+    Cleanup,
+    /// The preparation of the platform, when building a runtime.
+    PreparePlatform,
+    /// The cleanup of the platform, when building a runtime.
+    CleanupPlatform,
+}
+impl FlatpakBuildStepKind {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            FlatpakBuildStepKind::Configure => "configure",
+            FlatpakBuildStepKind::Build => "build",
+            FlatpakBuildStepKind::Install => "install",
+            FlatpakBuildStepKind::PostInstall => "post-install",
+            FlatpakBuildStepKind::Test => "test",
+            FlatpakBuildStepKind::Cleanup => "cleanup",
+            FlatpakBuildStepKind::PreparePlatform => "prepare-platform",
+            FlatpakBuildStepKind::CleanupPlatform => "cleanup-platform",
+        }
+    }
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[serde(rename_all = "kebab-case")]
+/// A single program invocation of a build plan.
+pub struct FlatpakBuildStep {
+    pub kind: FlatpakBuildStepKind,
+
+    pub program: String,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+
+    /// The directory in which the program is run.
+    pub working_dir: String,
+
+    /// The environment of the program, on top of the environment of the caller.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "FlatpakBuildEnv::is_empty")]
+    pub env: FlatpakBuildEnv,
+
+    /// The arguments passed to `flatpak build` when the step is run in the build sandbox,
+    /// from the `build-args` or `test-args` of the build options. They are not used when
+    /// the step is run directly.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flatpak_build_args: Vec<String>,
+}
+impl FlatpakBuildStep {
+    pub fn new(
+        kind: FlatpakBuildStepKind,
+        program: &str,
+        args: &[&str],
+        working_dir: &str,
+    ) -> FlatpakBuildStep {
+        FlatpakBuildStep {
+            kind,
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            working_dir: working_dir.to_string(),
+            env: FlatpakBuildEnv::default(),
+            flatpak_build_args: vec![],
+        }
+    }
+
+    /// Creates a step running a shell command with `/bin/sh -c`, like flatpak-builder does
+    /// for the `build-commands` and `post-install` commands.
+    pub fn shell(kind: FlatpakBuildStepKind, shell_command: &str, working_dir: &str) -> FlatpakBuildStep {
+        FlatpakBuildStep::new(kind, "/bin/sh", &["-c", shell_command], working_dir)
+    }
+
+    /// Gets the command line of the step, quoted for a POSIX shell.
+    pub fn get_command_line(&self) -> String {
+        std::iter::once(&self.program)
+            .chain(self.args.iter())
+            .map(|a| shell_quote(a))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command.current_dir(&self.working_dir);
+        self.env.apply(&mut command);
+        command
+    }
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+/// The ordered steps needed to build and install a module or an application. Unlike a list of
+/// [`std::process::Command`], a build plan can be inspected, compared, serialized
+/// and rendered as a shell script before being run.
+pub struct FlatpakBuildPlan {
+    pub steps: Vec<FlatpakBuildStep>,
+}
+impl FlatpakBuildPlan {
+    pub fn add_step(&mut self, step: FlatpakBuildStep) {
+        self.steps.push(step);
+    }
+
+    /// Gets the steps of a specific phase of the build.
+    pub fn get_steps(&self, kind: FlatpakBuildStepKind) -> Vec<&FlatpakBuildStep> {
+        self.steps.iter().filter(|s| s.kind == kind).collect()
+    }
+
+    /// Applies an environment to all the steps of the plan. The variables already set on a
+    /// step take precedence.
+    pub fn set_env(&mut self, build_env: &FlatpakBuildEnv) {
+        for step in &mut self.steps {
+            for (variable_name, variable_value) in &build_env.variables {
+                step.env
+                    .variables
+                    .entry(variable_name.to_string())
+                    .or_insert_with(|| variable_value.clone());
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, FlatpakError> {
+        FlatpakManifestFormat::JSON.dump(self)
+    }
+
+    /// Renders the plan as a POSIX shell script. Every step is run in a subshell, so that
+    /// the working directory and the environment of a step do not leak into the next steps,
+    /// and the script stops at the first failing step.
+    pub fn to_shell_script(&self) -> String {
+        let mut script = String::from("#!/bin/sh\nset -e\n");
+        for step in &self.steps {
+            script.push_str(&format!("\n# {}\n(\n", step.kind.get_name()));
+            script.push_str(&format!("    cd {}\n", shell_quote(&step.working_dir)));
+            for (variable_name, variable_value) in &step.env.variables {
+                match variable_value {
+                    Some(v) => script.push_str(&format!("    export {}={}\n", variable_name, shell_quote(v))),
+                    None => script.push_str(&format!("    unset {}\n", variable_name)),
+                }
+            }
+            script.push_str(&format!("    exec {}\n)\n", step.get_command_line()));
+        }
+        script
+    }
+
+    pub fn to_commands(&self) -> Vec<Command> {
+        self.steps.iter().map(|s| s.to_command()).collect()
+    }
+}
+
+/// Quotes a word for a POSIX shell. Words made only of characters that have no special
+/// meaning for the shell are left as is.
+pub(crate) fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_build_plan() {
+        let mut plan = FlatpakBuildPlan::default();
+        plan.add_step(FlatpakBuildStep::new(
+            FlatpakBuildStepKind::Configure,
+            "./configure",
+            &["--prefix=/app", "--with-name=flatpak rs"],
+            "/run/build/flatpak-rs",
+        ));
+        plan.add_step(FlatpakBuildStep::shell(
+            FlatpakBuildStepKind::Build,
+            "echo 'building'",
+            "/run/build/flatpak-rs",
+        ));
+        let mut build_env = FlatpakBuildEnv::default();
+        build_env.set("CFLAGS", "-O2 -g");
+        build_env.unset("LANG");
+        plan.set_env(&build_env);
+
+        assert_eq!(plan.get_steps(FlatpakBuildStepKind::Build).len(), 1);
+        assert_eq!(plan.get_steps(FlatpakBuildStepKind::Install).len(), 0);
+        assert_eq!(
+            plan.steps[0].get_command_line(),
+            "./configure --prefix=/app '--with-name=flatpak rs'"
+        );
+        assert_eq!(
+            plan.steps[1].get_command_line(),
+            "/bin/sh -c 'echo '\\''building'\\'''"
+        );
+
+        let script = plan.to_shell_script();
+        assert!(script.starts_with("#!/bin/sh\nset -e\n"));
+        assert!(script.contains(
+            "# configure\n(\n    cd /run/build/flatpak-rs\n    export CFLAGS='-O2 -g'\n    unset LANG\n"
+        ));
+
+        let plan_json = plan.to_json().unwrap();
+        assert!(plan_json.contains("\"kind\": \"configure\""));
+        assert!(plan_json.contains("\"LANG\": null"));
+        let parsed_plan: FlatpakBuildPlan = serde_json::from_str(&plan_json).unwrap();
+        assert_eq!(parsed_plan, plan);
+
+        let commands = plan.to_commands();
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].get_program(), "./configure");
+        assert_eq!(
+            commands[0].get_current_dir(),
+            Some(std::path::Path::new("/run/build/flatpak-rs"))
+        );
+    }
+}
@@ -1,16 +1,18 @@
 use serde::{Deserialize, Deserializer, Serializer};
 
+use crate::error::FlatpakError;
+
 pub const AUTOTOOLS: &str = "autotools";
 pub const CMAKE: &str = "cmake";
 pub const CMAKE_NINJA: &str = "cmake-ninja";
 pub const MESON: &str = "meson";
 pub const QMAKE: &str = "qmake";
 pub const SIMPLE: &str = "simple";
-#[cfg(feature = "extended_build_sytems")]
+#[cfg(feature = "extended_build_systems")]
 pub const CABAL: &str = "cabal";
-#[cfg(feature = "extended_build_sytems")]
+#[cfg(feature = "extended_build_systems")]
 pub const MAKE: &str = "make";
-#[cfg(feature = "extended_build_sytems")]
+#[cfg(feature = "extended_build_systems")]
 pub const CARGO: &str = "cargo";
 
 #[derive(Clone)]
@@ -25,11 +27,11 @@ pub enum FlatpakBuildSystem {
     QMake,
     Meson,
     Simple,
-    #[cfg(feature = "extended_build_sytems")]
+    #[cfg(feature = "extended_build_systems")]
     Cabal,
-    #[cfg(feature = "extended_build_sytems")]
+    #[cfg(feature = "extended_build_systems")]
     Make,
-    #[cfg(feature = "extended_build_sytems")]
+    #[cfg(feature = "extended_build_systems")]
     Cargo,
 }
 
@@ -47,16 +49,16 @@ impl FlatpakBuildSystem {
             FlatpakBuildSystem::QMake => QMAKE.to_string(),
             FlatpakBuildSystem::Meson => MESON.to_string(),
             FlatpakBuildSystem::Simple => SIMPLE.to_string(),
-            #[cfg(feature = "extended_build_sytems")]
+            #[cfg(feature = "extended_build_systems")]
             FlatpakBuildSystem::Cabal => CABAL.to_string(),
-            #[cfg(feature = "extended_build_sytems")]
+            #[cfg(feature = "extended_build_systems")]
             FlatpakBuildSystem::Make => MAKE.to_string(),
-            #[cfg(feature = "extended_build_sytems")]
+            #[cfg(feature = "extended_build_systems")]
             FlatpakBuildSystem::Cargo => CARGO.to_string(),
         }
     }
 
-    pub fn from_string(build_sys: &str) -> Result<FlatpakBuildSystem, String> {
+    pub fn from_string(build_sys: &str) -> Result<FlatpakBuildSystem, FlatpakError> {
         if build_sys == AUTOTOOLS {
             return Ok(FlatpakBuildSystem::Autotools);
         }
@@ -75,29 +77,29 @@ impl FlatpakBuildSystem {
         if build_sys == SIMPLE {
             return Ok(FlatpakBuildSystem::Simple);
         }
-        #[cfg(feature = "extended_build_sytems")]
+        #[cfg(feature = "extended_build_systems")]
         if build_sys == CABAL {
             return Ok(FlatpakBuildSystem::Cabal);
         }
-        #[cfg(feature = "extended_build_sytems")]
+        #[cfg(feature = "extended_build_systems")]
         if build_sys == MAKE {
             return Ok(FlatpakBuildSystem::Make);
         }
-        #[cfg(feature = "extended_build_sytems")]
+        #[cfg(feature = "extended_build_systems")]
         if build_sys == CARGO {
             return Ok(FlatpakBuildSystem::Cargo);
         }
-        Err(format!("Invalid build system {}.", build_sys))
+        Err(FlatpakError::invalid_value("build system", build_sys))
     }
 
     pub fn serialize<S>(x: &Option<FlatpakBuildSystem>, s: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        if let Some(build_system) = x {
-            return s.serialize_str(&build_system.to_string());
+        match x {
+            Some(build_system) => s.serialize_str(&build_system.to_string()),
+            None => s.serialize_none(),
         }
-        panic!("This should not happen.");
     }
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<FlatpakBuildSystem>, D::Error>
@@ -108,7 +110,7 @@ impl FlatpakBuildSystem {
 
         match FlatpakBuildSystem::from_string(&buf) {
             Ok(b) => Ok(Some(b)),
-            Err(e) => Err(e).map_err(serde::de::Error::custom),
+            Err(e) => Err(serde::de::Error::custom(e)),
         }
     }
 }
@@ -0,0 +1,179 @@
+use std::fmt;
+use std::io;
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Hash)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+/// The checksum algorithms supported by the archive, file and extra-data sources.
+pub enum FlatpakChecksumType {
+    MD5,
+    SHA1,
+    SHA256,
+    SHA512,
+}
+
+/// All the checksum algorithms, from the weakest to the strongest.
+pub const ALL_CHECKSUM_TYPES: &[FlatpakChecksumType] = &[
+    FlatpakChecksumType::MD5,
+    FlatpakChecksumType::SHA1,
+    FlatpakChecksumType::SHA256,
+    FlatpakChecksumType::SHA512,
+];
+
+impl fmt::Display for FlatpakChecksumType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let checksum_type = match &self {
+            FlatpakChecksumType::MD5 => "md5",
+            FlatpakChecksumType::SHA1 => "sha1",
+            FlatpakChecksumType::SHA256 => "sha256",
+            FlatpakChecksumType::SHA512 => "sha512",
+        };
+        write!(f, "{}", checksum_type)
+    }
+}
+impl FlatpakChecksumType {
+    /// Gets the length of the hex digests of the algorithm.
+    pub fn get_digest_length(&self) -> usize {
+        match self {
+            FlatpakChecksumType::MD5 => 32,
+            FlatpakChecksumType::SHA1 => 40,
+            FlatpakChecksumType::SHA256 => 64,
+            FlatpakChecksumType::SHA512 => 128,
+        }
+    }
+
+    /// Checks that a digest is a lowercase hex digest of the algorithm.
+    pub fn is_valid_digest(&self, digest: &str) -> bool {
+        digest.len() == self.get_digest_length()
+            && digest
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    }
+
+    /// Computes the hex digest of the content.
+    pub fn compute(&self, content: &[u8]) -> String {
+        let mut hasher = FlatpakChecksumHasher::new(&[*self]);
+        hasher.update(content);
+        hasher.finalize().remove(0).1
+    }
+}
+
+#[derive(Clone)]
+#[derive(Default)]
+/// Computes the digests of a byte stream for multiple checksum algorithms at once, so
+/// that a file only has to be read once. The content can be written to the hasher
+/// using [`std::io::Write`].
+pub struct FlatpakChecksumHasher {
+    md5: Option<Md5>,
+    sha1: Option<Sha1>,
+    sha256: Option<Sha256>,
+    sha512: Option<Sha512>,
+    size: u64,
+}
+impl FlatpakChecksumHasher {
+    pub fn new(checksum_types: &[FlatpakChecksumType]) -> FlatpakChecksumHasher {
+        let mut hasher = FlatpakChecksumHasher::default();
+        for checksum_type in checksum_types {
+            match checksum_type {
+                FlatpakChecksumType::MD5 => hasher.md5 = Some(Md5::new()),
+                FlatpakChecksumType::SHA1 => hasher.sha1 = Some(Sha1::new()),
+                FlatpakChecksumType::SHA256 => hasher.sha256 = Some(Sha256::new()),
+                FlatpakChecksumType::SHA512 => hasher.sha512 = Some(Sha512::new()),
+            }
+        }
+        hasher
+    }
+
+    pub fn update(&mut self, content: &[u8]) {
+        if let Some(md5) = &mut self.md5 {
+            md5.update(content);
+        }
+        if let Some(sha1) = &mut self.sha1 {
+            sha1.update(content);
+        }
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(content);
+        }
+        if let Some(sha512) = &mut self.sha512 {
+            sha512.update(content);
+        }
+        self.size += content.len() as u64;
+    }
+
+    /// Gets the number of bytes hashed so far.
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Gets the hex digests of the content, in the order of [`ALL_CHECKSUM_TYPES`].
+    pub fn finalize(self) -> Vec<(FlatpakChecksumType, String)> {
+        let mut digests: Vec<(FlatpakChecksumType, String)> = vec![];
+        if let Some(md5) = self.md5 {
+            digests.push((FlatpakChecksumType::MD5, to_hex(&md5.finalize())));
+        }
+        if let Some(sha1) = self.sha1 {
+            digests.push((FlatpakChecksumType::SHA1, to_hex(&sha1.finalize())));
+        }
+        if let Some(sha256) = self.sha256 {
+            digests.push((FlatpakChecksumType::SHA256, to_hex(&sha256.finalize())));
+        }
+        if let Some(sha512) = self.sha512 {
+            digests.push((FlatpakChecksumType::SHA512, to_hex(&sha512.finalize())));
+        }
+        digests
+    }
+}
+impl io::Write for FlatpakChecksumHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_compute_checksums() {
+        assert_eq!(
+            FlatpakChecksumType::MD5.compute(b"flatpak"),
+            "9cb6e3099d15ec27d79a619bce76e802"
+        );
+        assert_eq!(
+            FlatpakChecksumType::SHA256.compute(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+
+        let mut hasher = FlatpakChecksumHasher::new(ALL_CHECKSUM_TYPES);
+        io::copy(&mut &b"flatpak"[..], &mut hasher).unwrap();
+        assert_eq!(hasher.get_size(), 7);
+        let digests = hasher.finalize();
+        assert_eq!(digests.len(), 4);
+        assert_eq!(digests[1].0, FlatpakChecksumType::SHA1);
+        assert_eq!(digests[1].1, FlatpakChecksumType::SHA1.compute(b"flatpak"));
+        assert_eq!(digests[3].1.len(), 128);
+
+        for checksum_type in ALL_CHECKSUM_TYPES {
+            assert!(checksum_type.is_valid_digest(&checksum_type.compute(b"flatpak")));
+        }
+        assert!(!FlatpakChecksumType::MD5.is_valid_digest("9CB6E3099D15EC27D79A619BCE76E802"));
+        assert!(!FlatpakChecksumType::MD5.is_valid_digest("../../../../../../../../../../etc"));
+        assert!(!FlatpakChecksumType::SHA1.is_valid_digest("9cb6e3099d15ec27d79a619bce76e802"));
+    }
+}
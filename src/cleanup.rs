@@ -0,0 +1,366 @@
+use std::fs;
+use std::path;
+
+use crate::application::FlatpakApplication;
+use crate::build_plan::{FlatpakBuildStep, FlatpakBuildStepKind};
+use crate::error::FlatpakError;
+use crate::module::FlatpakModule;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+/// Evaluates the `cleanup`, `cleanup-platform` and `ensure-writable` patterns the way
+/// flatpak-builder does:
+/// * a pattern starting with `/` is matched against the full path of the file, relative
+///   to the installation prefix;
+/// * any other pattern is matched against the basename of the file, or against the
+///   trailing components of its path when the pattern contains a `/`;
+/// * a pattern matching a directory also matches all the files inside of it.
+///
+/// The patterns of a module only apply to the files installed by that module, so the
+/// files given to the matcher should be the ones installed by the module.
+pub struct FlatpakCleanupMatcher {
+    pub patterns: Vec<String>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+/// The result of applying cleanup patterns to a list of installed files.
+pub struct FlatpakCleanupReport {
+    /// The files that would be removed, in the order in which they were given.
+    pub removed_files: Vec<String>,
+
+    /// The patterns that do not match any file. They are usually stale cleanup rules.
+    pub unused_patterns: Vec<String>,
+
+    /// The patterns matching every installed file whatever its name, like `*`. They are
+    /// usually mistakes.
+    pub wildcard_patterns: Vec<String>,
+}
+
+impl FlatpakCleanupMatcher {
+    pub fn new(patterns: &[String]) -> FlatpakCleanupMatcher {
+        FlatpakCleanupMatcher {
+            patterns: patterns.to_vec(),
+        }
+    }
+
+    /// Gets the matcher of the `cleanup` patterns of a module.
+    pub fn from_module(module: &FlatpakModule) -> FlatpakCleanupMatcher {
+        FlatpakCleanupMatcher::new(&module.cleanup)
+    }
+
+    /// Gets the matcher of the `cleanup-platform` patterns of a module.
+    pub fn from_module_platform(module: &FlatpakModule) -> FlatpakCleanupMatcher {
+        FlatpakCleanupMatcher::new(&module.cleanup_platform)
+    }
+
+    /// Gets the matcher of the `ensure-writable` patterns of a module.
+    pub fn from_module_ensure_writable(module: &FlatpakModule) -> FlatpakCleanupMatcher {
+        FlatpakCleanupMatcher::new(&module.ensure_writable)
+    }
+
+    /// Gets the matcher of the `cleanup` patterns of an application, which apply to the files
+    /// installed by all the modules.
+    pub fn from_application(application: &FlatpakApplication) -> FlatpakCleanupMatcher {
+        FlatpakCleanupMatcher::new(&application.cleanup)
+    }
+
+    /// Returns `true` if the file at this path, relative to the installation prefix, is
+    /// matched by any of the patterns.
+    pub fn matches(&self, file_path: &str) -> bool {
+        self.patterns.iter().any(|p| matches_path_pattern(file_path, p))
+    }
+
+    /// Gets the files matched by the patterns, among files relative to the installation prefix.
+    pub fn get_matching_files<'a>(&self, file_paths: &'a [String]) -> Vec<&'a str> {
+        file_paths
+            .iter()
+            .filter(|f| self.matches(f))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Applies the patterns to the files installed by a module, relative to the installation prefix.
+    pub fn check(&self, file_paths: &[String]) -> FlatpakCleanupReport {
+        let mut report = FlatpakCleanupReport {
+            removed_files: self
+                .get_matching_files(file_paths)
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            ..FlatpakCleanupReport::default()
+        };
+        for pattern in &self.patterns {
+            let matching_files_count = file_paths
+                .iter()
+                .filter(|f| matches_path_pattern(f, pattern))
+                .count();
+            if matching_files_count == 0 {
+                report.unused_patterns.push(pattern.to_string());
+            } else if is_wildcard_pattern(pattern) {
+                report.wildcard_patterns.push(pattern.to_string());
+            }
+        }
+        report
+    }
+
+    /// Applies the patterns to all the files of an installation directory.
+    pub fn check_dir(&self, dir_path: &str) -> Result<FlatpakCleanupReport, FlatpakError> {
+        let mut file_paths: Vec<String> = vec![];
+        add_dir_files(path::Path::new(dir_path), "", &mut file_paths)?;
+        file_paths.sort();
+        Ok(self.check(&file_paths))
+    }
+}
+
+fn add_dir_files(
+    dir_path: &path::Path,
+    prefix: &str,
+    file_paths: &mut Vec<String>,
+) -> Result<(), FlatpakError> {
+    let io_error = |e| FlatpakError::Io {
+        path: dir_path.to_string_lossy().to_string(),
+        source: e,
+    };
+    for entry in fs::read_dir(dir_path).map_err(io_error)? {
+        let entry = entry.map_err(io_error)?;
+        let file_path = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type().map_err(io_error)?;
+        if file_type.is_dir() {
+            add_dir_files(&entry.path(), &file_path, file_paths)?;
+        } else {
+            file_paths.push(file_path);
+        }
+    }
+    Ok(())
+}
+
+/// Gets a build step removing the files matching a cleanup pattern from the installation
+/// prefix `prefix`, with the same semantics as [`matches_path_pattern`]. The files are not
+/// restricted to the ones installed by a module, so this should only be used for the patterns
+/// of the application. Returns `None` if the pattern cannot match any file.
+pub fn get_cleanup_step(kind: FlatpakBuildStepKind, pattern: &str, prefix: &str) -> Option<FlatpakBuildStep> {
+    let mut args = get_find_args(pattern)?;
+    args.extend(["-exec", "rm", "-rf", "{}", "+"].iter().map(|a| a.to_string()));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Some(FlatpakBuildStep::new(kind, "find", &args, prefix))
+}
+
+/// Gets a build step listing, without removing them, the files matching the cleanup pattern of
+/// a module in the installation prefix `prefix`. The files installed by a module are only known
+/// once it is built, so the listed files can include files installed by other modules.
+/// Returns `None` if the pattern cannot match any file.
+pub fn get_cleanup_listing_step(
+    kind: FlatpakBuildStepKind,
+    pattern: &str,
+    prefix: &str,
+) -> Option<FlatpakBuildStep> {
+    let mut args = get_find_args(pattern)?;
+    args.push("-print".to_string());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    Some(FlatpakBuildStep::new(kind, "find", &args, prefix))
+}
+
+/// Gets the arguments of a `find` command selecting the files matching a cleanup pattern,
+/// without descending into the matching directories. The installation prefix itself is never
+/// selected.
+fn get_find_args(pattern: &str) -> Option<Vec<String>> {
+    let pattern_components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    if pattern_components.is_empty() {
+        return None;
+    }
+    let path_pattern = pattern_components.join("/");
+    let mut args: Vec<String> = vec![".".to_string(), "-mindepth".to_string()];
+    if pattern.starts_with('/') {
+        // The wildcards of `find -path` also match `/`, so the depth is fixed to prevent
+        // them from matching multiple components.
+        let depth = pattern_components.len().to_string();
+        args.extend([depth.clone(), "-maxdepth".to_string(), depth]);
+        args.extend(["-path".to_string(), format!("./{}", path_pattern)]);
+    } else if pattern_components.len() == 1 {
+        args.extend(["1".to_string(), "-name".to_string(), path_pattern]);
+    } else {
+        args.extend([
+            "1".to_string(),
+            "-path".to_string(),
+            format!("*/{}", path_pattern),
+        ]);
+    }
+    args.push("-prune".to_string());
+    Some(args)
+}
+
+/// Matches a path, relative to the installation prefix, against a cleanup pattern.
+pub fn matches_path_pattern(file_path: &str, pattern: &str) -> bool {
+    let path_components: Vec<&str> = file_path.split('/').filter(|c| !c.is_empty()).collect();
+    let pattern_components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    if pattern_components.is_empty() {
+        return false;
+    }
+    if pattern.starts_with('/') {
+        return matches_path_prefix(&path_components, &pattern_components);
+    }
+    (0..path_components.len()).any(|i| matches_path_prefix(&path_components[i..], &pattern_components))
+}
+
+/// Returns `true` if the pattern matches any file, whatever its path. Patterns like `*/*`
+/// only match the files nested in a directory, so they are not considered wildcards.
+fn is_wildcard_pattern(pattern: &str) -> bool {
+    let pattern_components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    pattern_components.len() == 1 && pattern_components[0].chars().all(|c| c == '*')
+}
+
+/// Returns `true` if the leading components of the path match all the components of the pattern.
+fn matches_path_prefix(path_components: &[&str], pattern_components: &[&str]) -> bool {
+    if pattern_components.len() > path_components.len() {
+        return false;
+    }
+    pattern_components
+        .iter()
+        .zip(path_components.iter())
+        .all(|(p, c)| matches_glob(p.as_bytes(), c.as_bytes()))
+}
+
+/// Matches a single path component against a shell glob supporting `*`, `?` and `[...]`.
+fn matches_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(b'*') => (0..=name.len()).any(|i| matches_glob(&pattern[1..], &name[i..])),
+        Some(b'?') => !name.is_empty() && matches_glob(&pattern[1..], &name[1..]),
+        Some(b'[') => {
+            let class_end = match pattern.iter().skip(2).position(|c| *c == b']') {
+                Some(i) => i + 2,
+                None => return !name.is_empty() && name[0] == b'[' && matches_glob(&pattern[1..], &name[1..]),
+            };
+            if name.is_empty() {
+                return false;
+            }
+            let (negated, class) = match pattern[1] {
+                b'!' | b'^' => (true, &pattern[2..class_end]),
+                _ => (false, &pattern[1..class_end]),
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    in_class |= class[i] <= name[0] && name[0] <= class[i + 2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == name[0];
+                    i += 1;
+                }
+            }
+            in_class != negated && matches_glob(&pattern[class_end + 1..], &name[1..])
+        }
+        Some(c) => !name.is_empty() && name[0] == *c && matches_glob(&pattern[1..], &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FlatpakManifestFormat;
+    use crate::utils::TestDir;
+
+    #[test]
+    pub fn test_matches_path_pattern() {
+        assert!(matches_path_pattern("/lib/libfoo.la", "*.la"));
+        assert!(matches_path_pattern("lib/libfoo.la", "*.la"));
+        assert!(!matches_path_pattern("/lib/libfoo.so", "*.la"));
+        assert!(matches_path_pattern("/include/foo/foo.h", "/include"));
+        assert!(!matches_path_pattern("/share/include/foo.h", "/include"));
+        assert!(matches_path_pattern("/share/include/foo.h", "include"));
+        assert!(matches_path_pattern("/share/man/man1/foo.1", "/share/man"));
+        assert!(matches_path_pattern(
+            "/share/gtk-doc/html/index.html",
+            "/share/gtk-doc"
+        ));
+        assert!(matches_path_pattern(
+            "/lib/pkgconfig/foo.pc",
+            "/lib/pkgconfig/*.pc"
+        ));
+        assert!(!matches_path_pattern("/lib/libfoo.a", "/lib/*.so"));
+        assert!(matches_path_pattern("/bin/foo-1", "/bin/foo-[0-9]"));
+        assert!(!matches_path_pattern("/bin/foo-a", "/bin/foo-[!a-z]"));
+        assert!(matches_path_pattern("/bin/foo", "/bin/fo?"));
+        assert!(!matches_path_pattern("/bin/foo", ""));
+
+        assert!(is_wildcard_pattern("*"));
+        assert!(is_wildcard_pattern("/*"));
+        assert!(!is_wildcard_pattern("*/*"));
+        assert!(!is_wildcard_pattern("*.la"));
+    }
+
+    #[test]
+    pub fn test_get_cleanup_step() {
+        let get_command_line = |step: Option<FlatpakBuildStep>| step.map(|s| s.get_command_line());
+        assert_eq!(
+            get_command_line(get_cleanup_step(FlatpakBuildStepKind::Cleanup, "*", "/app")),
+            Some("find . -mindepth 1 -name '*' -prune -exec rm -rf '{}' +".to_string())
+        );
+        assert_eq!(
+            get_command_line(get_cleanup_step(
+                FlatpakBuildStepKind::Cleanup,
+                "/lib/*.a",
+                "/app"
+            )),
+            Some("find . -mindepth 2 -maxdepth 2 -path './lib/*.a' -prune -exec rm -rf '{}' +".to_string())
+        );
+        assert_eq!(
+            get_command_line(get_cleanup_listing_step(
+                FlatpakBuildStepKind::Cleanup,
+                "share/man",
+                "/app"
+            )),
+            Some("find . -mindepth 1 -path '*/share/man' -prune -print".to_string())
+        );
+        assert_eq!(
+            get_command_line(get_cleanup_step(FlatpakBuildStepKind::Cleanup, "/", "/app")),
+            None
+        );
+    }
+
+    #[test]
+    pub fn test_check_cleanup() {
+        let module_manifest = r###"
+            name: libfoo
+            cleanup:
+              - /include
+              - "*.la"
+              - /share/doc
+              - "*"
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+        let matcher = FlatpakCleanupMatcher::from_module(&module);
+        let installed_files = vec![
+            "/include/foo.h".to_string(),
+            "/lib/libfoo.la".to_string(),
+            "/lib/libfoo.so".to_string(),
+        ];
+        let report = matcher.check(&installed_files);
+        assert_eq!(report.removed_files, installed_files);
+        assert_eq!(report.unused_patterns, vec!["/share/doc"]);
+        assert_eq!(report.wildcard_patterns, vec!["*"]);
+
+        let matcher = FlatpakCleanupMatcher::new(&["/include".to_string(), "*.la".to_string()]);
+        assert_eq!(
+            matcher.get_matching_files(&installed_files),
+            vec!["/include/foo.h", "/lib/libfoo.la"]
+        );
+
+        let test_dir = TestDir::new("cleanup");
+        fs::create_dir_all(test_dir.join("include")).unwrap();
+        fs::create_dir_all(test_dir.join("lib")).unwrap();
+        fs::write(test_dir.join("include").join("foo.h"), "").unwrap();
+        fs::write(test_dir.join("lib").join("libfoo.so"), "").unwrap();
+        let report = matcher.check_dir(test_dir.to_str().unwrap()).unwrap();
+        assert_eq!(report.removed_files, vec!["/include/foo.h"]);
+        assert_eq!(report.unused_patterns, vec!["*.la"]);
+    }
+}
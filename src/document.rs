@@ -0,0 +1,1007 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path;
+
+use serde::Serialize;
+use serde_yaml::Value;
+
+use crate::error::FlatpakError;
+use crate::format::FlatpakManifestFormat;
+use crate::module::FlatpakModuleItem;
+use crate::syntax::{
+    get_line_end, get_line_start, FlatpakManifestPath, FlatpakPathSegment, FlatpakScalarStyle, SyntaxNode,
+    SyntaxNodeKind,
+};
+
+const FINISH_ARGS: &str = "finish-args";
+const MODULES: &str = "modules";
+
+/// A Flatpak manifest document that can be edited in place.
+///
+/// Contrary to a parse and dump cycle, editing a document only modifies the parts of the
+/// document affected by the change, so the comments, the order of the keys and the
+/// formatting of the rest of the manifest are preserved.
+///
+/// Nodes of the document are addressed with paths like `modules[3].sources[1]`.
+pub struct FlatpakDocument {
+    format: FlatpakManifestFormat,
+    content: String,
+    root: SyntaxNode,
+}
+impl FlatpakDocument {
+    pub fn parse(format: FlatpakManifestFormat, content: &str) -> Result<FlatpakDocument, FlatpakError> {
+        let root = SyntaxNode::parse(&format, content)?;
+        Ok(FlatpakDocument {
+            format,
+            content: content.to_string(),
+            root,
+        })
+    }
+
+    pub fn load_from_file(path: String) -> Result<FlatpakDocument, FlatpakError> {
+        let file_path = path::Path::new(&path);
+        if !file_path.is_file() {
+            return Err(FlatpakError::Io {
+                path,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file"),
+            });
+        }
+
+        let manifest_format = match FlatpakManifestFormat::from_path(&path) {
+            Some(f) => f,
+            None => return Err(FlatpakError::UnsupportedExtension(path)),
+        };
+
+        let manifest_content = match fs::read_to_string(file_path) {
+            Ok(content) => content,
+            Err(e) => return Err(FlatpakError::Io { path, source: e }),
+        };
+        match FlatpakDocument::parse(manifest_format, &manifest_content) {
+            Ok(d) => Ok(d),
+            Err(e) => Err(e.in_file(path)),
+        }
+    }
+
+    pub fn get_format(&self) -> &FlatpakManifestFormat {
+        &self.format
+    }
+
+    /// Gets the current content of the document, with all the edits applied.
+    pub fn get_content(&self) -> &str {
+        &self.content
+    }
+
+    pub fn get_root(&self) -> &SyntaxNode {
+        &self.root
+    }
+
+    /// Gets the string value at the given path, if it exists.
+    pub fn get_string(&self, path: &str) -> Result<Option<String>, FlatpakError> {
+        let path = FlatpakManifestPath::parse(path)?;
+        Ok(self
+            .root
+            .get(&path)
+            .and_then(|n| n.as_str())
+            .map(|s| s.to_string()))
+    }
+
+    /// Sets the string value of `key` in the mapping at `path`, for example the `sha256`
+    /// of the source at `modules[2].sources[0]`. The key is added at the end of the mapping
+    /// if it does not exist.
+    pub fn set_string(&mut self, path: &str, key: &str, value: &str) -> Result<(), FlatpakError> {
+        let path = FlatpakManifestPath::parse(path)?;
+        let mapping = self.get_mapping(&path)?;
+        let entry = match mapping.get_entry(key) {
+            Some(e) => e,
+            None => {
+                let edits = self.insert_entry(mapping, key, &Value::String(value.to_string()))?;
+                return self.apply_edits(edits);
+            }
+        };
+
+        let edit = match &entry.value.kind {
+            SyntaxNodeKind::Scalar { style, .. } if !entry.value.span.is_empty() => {
+                (entry.value.span.clone(), self.render_scalar(value, Some(style)))
+            }
+            _ => {
+                let rendered_value = self.render_scalar(value, None);
+                if self.format == FlatpakManifestFormat::JSON {
+                    (entry.value.span.clone(), rendered_value)
+                } else {
+                    (entry.colon_end..entry.end(), format!(" {}", rendered_value))
+                }
+            }
+        };
+        self.apply_edits(vec![edit])
+    }
+
+    /// Removes `key` from the mapping at `path`, if it exists.
+    pub fn remove_key(&mut self, path: &str, key: &str) -> Result<(), FlatpakError> {
+        let path = FlatpakManifestPath::parse(path)?;
+        let mapping = self.get_mapping(&path)?;
+        let entries = match &mapping.kind {
+            SyntaxNodeKind::Mapping { entries, .. } => entries,
+            _ => return Err(FlatpakError::PathNotFound(path.to_string())),
+        };
+        let entry_index = match entries.iter().position(|e| e.key == key) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let elements: Vec<Element> = entries
+            .iter()
+            .map(|e| Element {
+                start: e.key_span.start,
+                end: e.end(),
+                separator: e.separator,
+            })
+            .collect();
+        let edits = self.remove_element(mapping, &path, &elements, entry_index, "{}")?;
+        self.apply_edits(edits)
+    }
+
+    /// Adds a module at the end of the modules of the application, or of the module
+    /// at `parent_path` if it is not empty.
+    pub fn add_module(&mut self, parent_path: &str, module: &FlatpakModuleItem) -> Result<(), FlatpakError> {
+        let parent_path = FlatpakManifestPath::parse(parent_path)?;
+        let value = to_value(module)?;
+        self.append_to_sequence(&parent_path, MODULES, &value)
+    }
+
+    /// Inserts a module at the given index in the modules of the application, or of the module
+    /// at `parent_path` if it is not empty.
+    pub fn insert_module(
+        &mut self,
+        parent_path: &str,
+        index: usize,
+        module: &FlatpakModuleItem,
+    ) -> Result<(), FlatpakError> {
+        let parent_path = FlatpakManifestPath::parse(parent_path)?;
+        let modules_path = parent_path.join_key(MODULES);
+        let modules_count = match self.root.get(&modules_path).map(|n| &n.kind) {
+            Some(SyntaxNodeKind::Sequence { items, .. }) => items.len(),
+            _ => 0,
+        };
+        let value = to_value(module)?;
+        if index >= modules_count {
+            return self.append_to_sequence(&parent_path, MODULES, &value);
+        }
+        let edits = self.insert_item(&modules_path, index, &value)?;
+        self.apply_edits(edits)
+    }
+
+    /// Removes the module at `module_path`, for example `modules[1].modules[0]`.
+    pub fn remove_module(&mut self, module_path: &str) -> Result<(), FlatpakError> {
+        let module_path = FlatpakManifestPath::parse(module_path)?;
+        let (modules_path, index) = match module_path.split_last() {
+            Some((p, FlatpakPathSegment::Index(i))) => (p, *i),
+            _ => return Err(FlatpakError::InvalidPath(module_path.to_string())),
+        };
+        self.remove_item(&modules_path, index)
+    }
+
+    /// Adds an argument to the finish-args of the application, if it is not already there.
+    pub fn add_finish_arg(&mut self, finish_arg: &str) -> Result<(), FlatpakError> {
+        let finish_args_path = FlatpakManifestPath::default().join_key(FINISH_ARGS);
+        if self.find_item(&finish_args_path, finish_arg).is_some() {
+            return Ok(());
+        }
+        let value = Value::String(finish_arg.to_string());
+        self.append_to_sequence(&FlatpakManifestPath::default(), FINISH_ARGS, &value)
+    }
+
+    /// Removes all the occurences of an argument from the finish-args of the application.
+    pub fn remove_finish_arg(&mut self, finish_arg: &str) -> Result<(), FlatpakError> {
+        let finish_args_path = FlatpakManifestPath::default().join_key(FINISH_ARGS);
+        while let Some(index) = self.find_item(&finish_args_path, finish_arg) {
+            self.remove_item(&finish_args_path, index)?;
+        }
+        Ok(())
+    }
+
+    fn find_item(&self, sequence_path: &FlatpakManifestPath, value: &str) -> Option<usize> {
+        match &self.root.get(sequence_path)?.kind {
+            SyntaxNodeKind::Sequence { items, .. } => {
+                items.iter().position(|i| i.value.as_str() == Some(value))
+            }
+            _ => None,
+        }
+    }
+
+    fn get_mapping(&self, path: &FlatpakManifestPath) -> Result<&SyntaxNode, FlatpakError> {
+        match self.root.get(path) {
+            Some(n) if matches!(n.kind, SyntaxNodeKind::Mapping { .. }) => Ok(n),
+            _ => Err(FlatpakError::PathNotFound(path.to_string())),
+        }
+    }
+
+    fn append_to_sequence(
+        &mut self,
+        parent_path: &FlatpakManifestPath,
+        key: &str,
+        value: &Value,
+    ) -> Result<(), FlatpakError> {
+        let parent = self.get_mapping(parent_path)?;
+        let sequence = match parent.get_entry(key) {
+            Some(e) => &e.value,
+            None => {
+                let edits = self.insert_entry(parent, key, &Value::Sequence(vec![value.clone()]))?;
+                return self.apply_edits(edits);
+            }
+        };
+        let items = match &sequence.kind {
+            SyntaxNodeKind::Sequence { items, .. } => items,
+            _ => return Err(FlatpakError::PathNotFound(parent_path.join_key(key).to_string())),
+        };
+
+        let edits = if sequence.is_flow() {
+            let elements: Vec<Element> = items
+                .iter()
+                .map(|i| Element {
+                    start: i.start,
+                    end: i.end(),
+                    separator: i.separator,
+                })
+                .collect();
+            self.append_flow_element(sequence, &elements, |document, indent| {
+                document.render_flow_value(value, indent)
+            })?
+        } else {
+            let last_item = items.last().unwrap();
+            let indent = self.get_column(last_item.start);
+            let rendered_item = self.render_block_item(value, indent);
+            let insert_position = get_line_end(&self.content, last_item.end());
+            vec![(
+                insert_position..insert_position,
+                format!("\n{}- {}", " ".repeat(indent), rendered_item),
+            )]
+        };
+        self.apply_edits(edits)
+    }
+
+    fn insert_item(
+        &self,
+        sequence_path: &FlatpakManifestPath,
+        index: usize,
+        value: &Value,
+    ) -> Result<Vec<(Range<usize>, String)>, FlatpakError> {
+        let sequence = self
+            .root
+            .get(sequence_path)
+            .ok_or_else(|| FlatpakError::PathNotFound(sequence_path.to_string()))?;
+        let items = match &sequence.kind {
+            SyntaxNodeKind::Sequence { items, .. } => items,
+            _ => return Err(FlatpakError::PathNotFound(sequence_path.to_string())),
+        };
+        let item = &items[index];
+
+        if sequence.is_flow() {
+            let multiline = self.get_line(item.start) != self.get_line(sequence.span.start);
+            let indent = self.get_line_indent(item.start);
+            let separator = match multiline {
+                true => format!("\n{}", " ".repeat(indent)),
+                false => " ".to_string(),
+            };
+            let rendered_item = self.render_flow_value(value, indent);
+            return Ok(vec![(
+                item.start..item.start,
+                format!("{},{}", rendered_item, separator),
+            )]);
+        }
+
+        let indent = self.get_column(item.start);
+        let rendered_item = self.render_block_item(value, indent);
+        if self.get_line_indent(item.start) == indent {
+            let line_start = get_line_start(&self.content, item.start);
+            return Ok(vec![(
+                line_start..line_start,
+                format!("{}- {}\n", " ".repeat(indent), rendered_item),
+            )]);
+        }
+        Ok(vec![(
+            item.start..item.start,
+            format!("- {}\n{}", rendered_item, " ".repeat(indent)),
+        )])
+    }
+
+    fn remove_item(&mut self, sequence_path: &FlatpakManifestPath, index: usize) -> Result<(), FlatpakError> {
+        let sequence = self
+            .root
+            .get(sequence_path)
+            .ok_or_else(|| FlatpakError::PathNotFound(sequence_path.to_string()))?;
+        let items = match &sequence.kind {
+            SyntaxNodeKind::Sequence { items, .. } => items,
+            _ => return Err(FlatpakError::PathNotFound(sequence_path.to_string())),
+        };
+        if index >= items.len() {
+            return Err(FlatpakError::PathNotFound(
+                sequence_path.join_index(index).to_string(),
+            ));
+        }
+        let elements: Vec<Element> = items
+            .iter()
+            .map(|i| Element {
+                start: i.start,
+                end: i.end(),
+                separator: i.separator,
+            })
+            .collect();
+        let edits = self.remove_element(sequence, sequence_path, &elements, index, "[]")?;
+        self.apply_edits(edits)
+    }
+
+    /// Removes an entry of a mapping or an item of a sequence. When removing the last element of a
+    /// collection, the collection is replaced by `empty_collection`.
+    fn remove_element(
+        &self,
+        collection: &SyntaxNode,
+        collection_path: &FlatpakManifestPath,
+        elements: &[Element],
+        index: usize,
+        empty_collection: &str,
+    ) -> Result<Vec<(Range<usize>, String)>, FlatpakError> {
+        let element = &elements[index];
+
+        if elements.len() == 1 {
+            if collection.is_flow() {
+                return Ok(vec![(collection.span.clone(), empty_collection.to_string())]);
+            }
+            // An empty block collection has to be replaced with its flow equivalent.
+            let parent_entry = collection_path.split_last().and_then(|(parent_path, segment)| {
+                match (self.root.get(&parent_path), segment) {
+                    (Some(parent), FlatpakPathSegment::Key(key)) => parent.get_entry(key),
+                    _ => None,
+                }
+            });
+            return match parent_entry {
+                Some(e) if !e.value.span.is_empty() => {
+                    Ok(vec![(e.colon_end..e.end(), format!(" {}", empty_collection))])
+                }
+                _ => Ok(vec![(collection.span.clone(), empty_collection.to_string())]),
+            };
+        }
+
+        if !collection.is_flow() {
+            let line_start = self.get_comments_start(get_line_start(&self.content, element.start));
+            let mut end = get_line_end(&self.content, element.end);
+            if end < self.content.len() {
+                end += 1;
+            }
+            if self.get_line_indent(element.start) == self.get_column(element.start) {
+                return Ok(vec![(line_start..end, "".to_string())]);
+            }
+            // The element does not start its line, like in `- - item`.
+            return Ok(vec![(element.start..elements[index + 1].start, "".to_string())]);
+        }
+
+        let mut edits: Vec<(Range<usize>, String)> = vec![];
+        let is_last = index == elements.len() - 1;
+        if is_last && element.separator.is_none() {
+            // Removing the last element also removes the separator of the previous one.
+            let previous_separator = elements[index - 1].separator.unwrap();
+            edits.push((previous_separator..previous_separator + 1, "".to_string()));
+        }
+
+        let mut start = element.start;
+        let mut end = match element.separator {
+            Some(s) => s + 1,
+            None => element.end,
+        };
+        let line_end = get_line_end(&self.content, end);
+        let rest_of_line = self.content[end..line_end].trim();
+        if rest_of_line.is_empty() && self.get_line_indent(start) == self.get_column(start) {
+            // The element is alone on its line, so we remove the whole line.
+            start = self.get_comments_start(get_line_start(&self.content, start));
+            end = std::cmp::min(line_end + 1, self.content.len());
+        } else if rest_of_line.is_empty() || is_last {
+            if rest_of_line.is_empty() {
+                end = line_end;
+            }
+            start = self.content[..start].trim_end_matches([' ', '\t']).len();
+        } else {
+            end += self.content[end..line_end].len() - self.content[end..line_end].trim_start().len();
+        }
+        edits.push((start..end, "".to_string()));
+        Ok(edits)
+    }
+
+    fn insert_entry(
+        &self,
+        mapping: &SyntaxNode,
+        key: &str,
+        value: &Value,
+    ) -> Result<Vec<(Range<usize>, String)>, FlatpakError> {
+        let entries = match &mapping.kind {
+            SyntaxNodeKind::Mapping { entries, .. } => entries,
+            _ => return Err(FlatpakError::PathNotFound(key.to_string())),
+        };
+
+        if mapping.is_flow() {
+            let elements: Vec<Element> = entries
+                .iter()
+                .map(|e| Element {
+                    start: e.key_span.start,
+                    end: e.end(),
+                    separator: e.separator,
+                })
+                .collect();
+            return self.append_flow_element(mapping, &elements, |document, indent| {
+                format!(
+                    "{}: {}",
+                    document.render_scalar(key, None),
+                    document.render_flow_value(value, indent)
+                )
+            });
+        }
+
+        let indent = self.get_column(entries[0].key_span.start);
+        let rendered_value = match value {
+            Value::Sequence(_) => {
+                let sequence_indent = indent + self.get_block_sequence_offset();
+                let mut rendered_items: Vec<String> = vec![];
+                for item in value.as_sequence().unwrap() {
+                    rendered_items.push(format!(
+                        "\n{}- {}",
+                        " ".repeat(sequence_indent),
+                        self.render_block_item(item, sequence_indent)
+                    ));
+                }
+                rendered_items.join("")
+            }
+            Value::Mapping(_) => {
+                let child_indent = indent + 2;
+                format!(
+                    "\n{}{}",
+                    " ".repeat(child_indent),
+                    self.render_block_item(value, child_indent)
+                )
+            }
+            _ => format!(" {}", self.render_flow_value(value, indent)),
+        };
+        let insert_position = get_line_end(&self.content, entries.last().unwrap().end());
+        Ok(vec![(
+            insert_position..insert_position,
+            format!(
+                "\n{}{}:{}",
+                " ".repeat(indent),
+                self.render_scalar(key, None),
+                rendered_value
+            ),
+        )])
+    }
+
+    /// Appends an element to a flow collection, using the same layout as the
+    /// existing elements.
+    fn append_flow_element<F>(
+        &self,
+        collection: &SyntaxNode,
+        elements: &[Element],
+        render_element: F,
+    ) -> Result<Vec<(Range<usize>, String)>, FlatpakError>
+    where
+        F: Fn(&FlatpakDocument, usize) -> String,
+    {
+        let closing_position = collection.span.end - 1;
+        let last_element = match elements.last() {
+            Some(e) => e,
+            None => {
+                let indent = self.get_line_indent(collection.span.start);
+                let rendered_element = render_element(self, indent);
+                let position = collection.span.start + 1;
+                return Ok(vec![(position..closing_position, rendered_element)]);
+            }
+        };
+
+        if self.get_line(last_element.start) == self.get_line(collection.span.start) {
+            let indent = self.get_line_indent(collection.span.start);
+            let rendered_element = render_element(self, indent);
+            let position = match last_element.separator {
+                Some(s) => s + 1,
+                None => last_element.end,
+            };
+            let separator = if last_element.separator.is_some() { "" } else { "," };
+            return Ok(vec![(
+                position..position,
+                format!("{} {}", separator, rendered_element),
+            )]);
+        }
+
+        let indent = self.get_column(last_element.start);
+        let rendered_element = render_element(self, indent);
+        let mut edits: Vec<(Range<usize>, String)> = vec![];
+        let mut insert_position =
+            std::cmp::min(get_line_end(&self.content, last_element.end), closing_position);
+        if let Some(separator) = last_element.separator {
+            insert_position = std::cmp::min(get_line_end(&self.content, separator), closing_position);
+        } else {
+            edits.push((last_element.end..last_element.end, ",".to_string()));
+        }
+        let trailing_separator = if last_element.separator.is_some() { "," } else { "" };
+        edits.push((
+            insert_position..insert_position,
+            format!(
+                "\n{}{}{}",
+                " ".repeat(indent),
+                rendered_element,
+                trailing_separator
+            ),
+        ));
+        Ok(edits)
+    }
+
+    /// Applies edits to the content of the document. Edits inserting text at the same
+    /// position are applied in order.
+    fn apply_edits(&mut self, mut edits: Vec<(Range<usize>, String)>) -> Result<(), FlatpakError> {
+        edits.reverse();
+        edits.sort_by_key(|e| std::cmp::Reverse(e.0.start));
+        let mut content = self.content.clone();
+        for (range, text) in edits {
+            content.replace_range(range, &text);
+        }
+        self.root = SyntaxNode::parse(&self.format, &content)?;
+        self.content = content;
+        Ok(())
+    }
+
+    /// Gets the start of the comment lines directly preceding the line starting at `line_start`,
+    /// since those comments usually describe the content of the line.
+    fn get_comments_start(&self, line_start: usize) -> usize {
+        let mut comments_start = line_start;
+        while comments_start != 0 {
+            let previous_line_start = get_line_start(&self.content, comments_start - 1);
+            let previous_line = self.content[previous_line_start..comments_start - 1].trim();
+            let is_comment = match self.format {
+                FlatpakManifestFormat::JSON => {
+                    previous_line.starts_with("//")
+                        || (previous_line.starts_with("/*") && previous_line.ends_with("*/"))
+                }
+                _ => previous_line.starts_with('#'),
+            };
+            if !is_comment {
+                break;
+            }
+            comments_start = previous_line_start;
+        }
+        comments_start
+    }
+
+    fn get_line(&self, position: usize) -> usize {
+        self.content[..position].matches('\n').count()
+    }
+
+    fn get_column(&self, position: usize) -> usize {
+        position - get_line_start(&self.content, position)
+    }
+
+    /// Gets the number of spaces at the start of the line containing `position`.
+    fn get_line_indent(&self, position: usize) -> usize {
+        let line = &self.content[get_line_start(&self.content, position)..];
+        line.len() - line.trim_start_matches(' ').len()
+    }
+
+    /// Gets the indentation of block sequences relative to their parent key in this document,
+    /// since both `key:\n- item` and `key:\n  - item` are common.
+    fn get_block_sequence_offset(&self) -> usize {
+        let mut nodes: Vec<&SyntaxNode> = vec![&self.root];
+        while let Some(node) = nodes.pop() {
+            match &node.kind {
+                SyntaxNodeKind::Mapping { entries, .. } => {
+                    for entry in entries {
+                        if let SyntaxNodeKind::Sequence { flow: false, items } = &entry.value.kind {
+                            let key_column = self.get_column(entry.key_span.start);
+                            let item_column = self.get_column(items[0].start);
+                            if self.get_line(entry.key_span.start) != self.get_line(items[0].start) {
+                                return item_column.saturating_sub(key_column);
+                            }
+                        }
+                        nodes.push(&entry.value);
+                    }
+                }
+                SyntaxNodeKind::Sequence { items, .. } => {
+                    for item in items {
+                        nodes.push(&item.value);
+                    }
+                }
+                SyntaxNodeKind::Scalar { .. } => {}
+            }
+        }
+        2
+    }
+
+    /// Gets the indentation unit of a JSON document.
+    fn get_json_indent_unit(&self) -> usize {
+        if let SyntaxNodeKind::Mapping { entries, .. } = &self.root.kind {
+            if let Some(first_entry) = entries.first() {
+                let unit = self
+                    .get_column(first_entry.key_span.start)
+                    .saturating_sub(self.get_line_indent(self.root.span.start));
+                if unit != 0 && self.get_line(first_entry.key_span.start) != self.get_line(self.root.span.start)
+                {
+                    return unit;
+                }
+            }
+        }
+        4
+    }
+
+    fn render_scalar(&self, value: &str, style: Option<&FlatpakScalarStyle>) -> String {
+        let double_quoted = serde_json::to_string(value).unwrap();
+        if self.format == FlatpakManifestFormat::JSON {
+            return double_quoted;
+        }
+        match style {
+            Some(FlatpakScalarStyle::DoubleQuoted) => double_quoted,
+            Some(FlatpakScalarStyle::SingleQuoted) if !value.contains('\n') => {
+                format!("'{}'", value.replace('\'', "''"))
+            }
+            _ => {
+                if is_plain_safe(value) {
+                    return value.to_string();
+                }
+                double_quoted
+            }
+        }
+    }
+
+    /// Renders a value in flow style (or in JSON), for a value whose line is indented
+    /// by `indent` spaces.
+    fn render_flow_value(&self, value: &Value, indent: usize) -> String {
+        if let Value::String(s) = value {
+            return self.render_scalar(s, None);
+        }
+        let indent_unit = " ".repeat(self.get_json_indent_unit());
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(indent_unit.as_bytes());
+        let mut buffer: Vec<u8> = vec![];
+        let mut serializer = serde_json::Serializer::with_formatter(&mut buffer, formatter);
+        value.serialize(&mut serializer).unwrap();
+        let rendered_value = String::from_utf8(buffer).unwrap();
+        indent_lines(&rendered_value, indent)
+    }
+
+    /// Renders a value as an item of a block sequence, where the `- ` indicator
+    /// is at the given indentation.
+    fn render_block_item(&self, value: &Value, indent: usize) -> String {
+        match value {
+            Value::Sequence(_) | Value::Mapping(_) => {
+                let rendered_value = serde_yaml::to_string(value).unwrap();
+                indent_lines(rendered_value.trim_end(), indent + 2)
+            }
+            Value::String(s) => self.render_scalar(s, None),
+            _ => serde_yaml::to_string(value).unwrap().trim_end().to_string(),
+        }
+    }
+}
+
+struct Element {
+    start: usize,
+    end: usize,
+    separator: Option<usize>,
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, FlatpakError> {
+    serde_yaml::to_value(value).map_err(|e| FlatpakError::Dump {
+        format: FlatpakManifestFormat::YAML,
+        source: Box::new(e),
+    })
+}
+
+/// Indents all the lines of a text except the first one.
+fn indent_lines(text: &str, indent: usize) -> String {
+    let indentation = " ".repeat(indent);
+    let mut lines = text.split('\n');
+    let mut indented_text = lines.next().unwrap_or("").to_string();
+    for line in lines {
+        indented_text.push('\n');
+        if !line.is_empty() {
+            indented_text.push_str(&indentation);
+        }
+        indented_text.push_str(line);
+    }
+    indented_text
+}
+
+/// Whether a string can be written as a YAML plain scalar without changing its meaning.
+fn is_plain_safe(value: &str) -> bool {
+    if value.is_empty() || value.trim() != value || value.contains('\n') {
+        return false;
+    }
+    if value.contains(": ") || value.contains(" #") || value.ends_with(':') {
+        return false;
+    }
+    if ",[]{}#&*!|>'\"%@`".contains(value.chars().next().unwrap()) {
+        return false;
+    }
+    // `-`, `?` and `:` are only indicators when followed by a space.
+    if ["-", "?", ":"].contains(&value) || ["- ", "? ", ": "].iter().any(|i| value.starts_with(i)) {
+        return false;
+    }
+    let reserved_values = ["true", "false", "yes", "no", "on", "off", "null", "~", "y", "n"];
+    if reserved_values.contains(&value.to_lowercase().as_str()) {
+        return false;
+    }
+    if value.parse::<f64>().is_ok() || value.starts_with("0x") || value.starts_with("0o") {
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::FlatpakApplication;
+    use crate::module::FlatpakModule;
+
+    fn get_diff(before: &str, after: &str) -> Vec<String> {
+        let before_lines: Vec<&str> = before.split('\n').collect();
+        let after_lines: Vec<&str> = after.split('\n').collect();
+        let mut diff: Vec<String> = vec![];
+        for line in &before_lines {
+            if !after_lines.contains(line) {
+                diff.push(format!("-{}", line.trim()));
+            }
+        }
+        for line in &after_lines {
+            if !before_lines.contains(line) {
+                diff.push(format!("+{}", line.trim()));
+            }
+        }
+        diff
+    }
+
+    const YAML_MANIFEST: &str = r###"
+app-id: net.louib.flatpak-rs # The main application.
+runtime: org.gnome.Platform
+runtime-version: "3.36"
+sdk: org.gnome.Sdk
+command: flatpak-rs
+finish-args:
+  # X11 access
+  - --socket=x11
+  - --share=ipc
+modules:
+  - shared-modules/linux-audio/lv2.json
+
+  # A library.
+  - name: libfoo
+    build-options: &default-build-options
+      cflags: -O2
+    sources:
+      - type: archive
+        url: https://example.com/libfoo-1.0.tar.gz
+        sha256: 'aaaa'
+
+  - name: flatpak-rs
+    buildsystem: simple
+    build-options: *default-build-options
+    sources:
+      - type: git
+        url: https://github.com/louib/flatpak-rs.git
+        tag: v0.1.0
+"###;
+
+    const JSON_MANIFEST: &str = r###"{
+    "app-id": "org.gnome.SoundJuicer",
+    "runtime": "org.gnome.Platform",
+    "runtime-version": "master",
+    "sdk": "org.gnome.Sdk",
+    "finish-args": [
+        /* X11 + XShm access */
+        "--share=ipc", "--socket=fallback-x11",
+        /* audio CDs */
+        "--device=all"
+    ],
+    "modules": [
+        /* gst-plugins-base needs cdparanoia to add support for cdda */
+        {
+            "name": "cdparanoia",
+            "sources": [
+                {
+                    "type": "archive",
+                    "url": "http://downloads.xiph.org/releases/cdparanoia/cdparanoia-III-10.2.src.tgz",
+                    "sha256": "005db45ef4ee017f5c32ec124f913a0546e77014266c6a1c50df902a55fe64df"
+                }
+            ]
+        },
+        /* To play cdda */
+        {
+            "name": "gst-plugins-base",
+            "sources": [ { "type": "git", "url": "https://gitlab.freedesktop.org/gstreamer/gst-plugins-base.git" } ]
+        }
+    ]
+}
+"###;
+
+    #[test]
+    pub fn test_set_source_fields_yaml() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::YAML, YAML_MANIFEST).unwrap();
+        document
+            .set_string("modules[1].sources[0]", "sha256", "bbbb")
+            .unwrap();
+        document
+            .set_string("modules[2].sources[0]", "tag", "v0.2.0")
+            .unwrap();
+        document
+            .set_string("modules[2].sources[0]", "commit", "0123456789abcdef")
+            .unwrap();
+
+        assert_eq!(
+            get_diff(YAML_MANIFEST, document.get_content()),
+            vec![
+                "-sha256: 'aaaa'",
+                "-tag: v0.1.0",
+                "+sha256: 'bbbb'",
+                "+tag: v0.2.0",
+                "+commit: 0123456789abcdef",
+            ]
+        );
+        assert_eq!(
+            document.get_string("modules[2].sources[0].commit").unwrap(),
+            Some("0123456789abcdef".to_string())
+        );
+        // The anchors and the comments are preserved.
+        assert!(document
+            .get_content()
+            .contains("build-options: *default-build-options"));
+        assert!(document.get_content().contains("# A library."));
+        FlatpakApplication::parse(FlatpakManifestFormat::YAML, document.get_content()).unwrap();
+
+        document.remove_key("modules[2].sources[0]", "tag").unwrap();
+        assert!(!document.get_content().contains("v0.2.0"));
+        assert!(document.get_content().contains("commit: 0123456789abcdef"));
+    }
+
+    #[test]
+    pub fn test_set_source_fields_json() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::JSON, JSON_MANIFEST).unwrap();
+        document
+            .set_string("modules[0].sources[0]", "sha256", "1234")
+            .unwrap();
+        document
+            .set_string("modules[1].sources[0]", "tag", "1.16.2")
+            .unwrap();
+        document
+            .set_string(
+                "modules[0].sources[0]",
+                "url",
+                "https://downloads.xiph.org/releases/cdparanoia/cdparanoia-III-10.2.src.tgz",
+            )
+            .unwrap();
+
+        assert_eq!(
+            get_diff(JSON_MANIFEST, document.get_content()),
+            vec![
+                "-\"url\": \"http://downloads.xiph.org/releases/cdparanoia/cdparanoia-III-10.2.src.tgz\",",
+                "-\"sha256\": \"005db45ef4ee017f5c32ec124f913a0546e77014266c6a1c50df902a55fe64df\"",
+                "-\"sources\": [ { \"type\": \"git\", \"url\": \"https://gitlab.freedesktop.org/gstreamer/gst-plugins-base.git\" } ]",
+                "+\"url\": \"https://downloads.xiph.org/releases/cdparanoia/cdparanoia-III-10.2.src.tgz\",",
+                "+\"sha256\": \"1234\"",
+                "+\"sources\": [ { \"type\": \"git\", \"url\": \"https://gitlab.freedesktop.org/gstreamer/gst-plugins-base.git\", \"tag\": \"1.16.2\" } ]",
+            ]
+        );
+        FlatpakApplication::parse(FlatpakManifestFormat::JSON, document.get_content()).unwrap();
+    }
+
+    #[test]
+    pub fn test_edit_finish_args() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::YAML, YAML_MANIFEST).unwrap();
+        document.add_finish_arg("--socket=wayland").unwrap();
+        document.add_finish_arg("--socket=x11").unwrap();
+        document.remove_finish_arg("--share=ipc").unwrap();
+        assert_eq!(
+            get_diff(YAML_MANIFEST, document.get_content()),
+            vec!["-- --share=ipc", "+- --socket=wayland"]
+        );
+        let application =
+            FlatpakApplication::parse(FlatpakManifestFormat::YAML, document.get_content()).unwrap();
+        assert_eq!(application.finish_args, vec!["--socket=x11", "--socket=wayland"]);
+
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::JSON, JSON_MANIFEST).unwrap();
+        document.remove_finish_arg("--share=ipc").unwrap();
+        document.remove_finish_arg("--device=all").unwrap();
+        document.add_finish_arg("--socket=pulseaudio").unwrap();
+        let application =
+            FlatpakApplication::parse(FlatpakManifestFormat::JSON, document.get_content()).unwrap();
+        assert_eq!(
+            application.finish_args,
+            vec!["--socket=fallback-x11", "--socket=pulseaudio"]
+        );
+        // The comment describing the removed argument is also removed.
+        assert!(!document.get_content().contains("/* audio CDs */"));
+        assert!(document.get_content().contains(
+            "/* X11 + XShm access */\n        \"--socket=fallback-x11\",\n        \"--socket=pulseaudio\"\n    ],"
+        ));
+
+        document.remove_finish_arg("--socket=fallback-x11").unwrap();
+        document.remove_finish_arg("--socket=pulseaudio").unwrap();
+        assert!(document.get_content().contains("\"finish-args\": [],"));
+    }
+
+    #[test]
+    pub fn test_add_finish_args_missing_key() {
+        let manifest = "app-id: net.louib.flatpak-rs\nmodules:\n- name: flatpak-rs\n";
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::YAML, manifest).unwrap();
+        document.add_finish_arg("--share=network").unwrap();
+        assert_eq!(
+            document.get_content(),
+            "app-id: net.louib.flatpak-rs\nmodules:\n- name: flatpak-rs\nfinish-args:\n- --share=network\n"
+        );
+    }
+
+    #[test]
+    pub fn test_edit_modules_yaml() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::YAML, YAML_MANIFEST).unwrap();
+        let module = FlatpakModule {
+            name: "libbar".to_string(),
+            config_opts: vec!["--disable-static".to_string()],
+            ..FlatpakModule::default()
+        };
+        document
+            .add_module("", &FlatpakModuleItem::Description(module))
+            .unwrap();
+        document
+            .insert_module("", 0, &FlatpakModuleItem::Path("libbaz.json".to_string()))
+            .unwrap();
+        document.remove_module("modules[1]").unwrap();
+
+        assert_eq!(
+            get_diff(YAML_MANIFEST, document.get_content()),
+            vec![
+                "-- shared-modules/linux-audio/lv2.json",
+                "+- libbaz.json",
+                "+- name: libbar",
+                "+config-opts:",
+                "+- --disable-static",
+            ]
+        );
+        assert!(document
+            .get_content()
+            .ends_with("        tag: v0.1.0\n  - name: libbar\n    config-opts:\n    - --disable-static\n"));
+
+        let application =
+            FlatpakApplication::parse(FlatpakManifestFormat::YAML, document.get_content()).unwrap();
+        assert_eq!(application.modules.len(), 4);
+    }
+
+    #[test]
+    pub fn test_edit_modules_json() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::JSON, JSON_MANIFEST).unwrap();
+        document.remove_module("modules[0]").unwrap();
+        document
+            .add_module(
+                "",
+                &FlatpakModuleItem::Path("shared-modules/lv2.json".to_string()),
+            )
+            .unwrap();
+        document
+            .add_module(
+                "modules[0]",
+                &FlatpakModuleItem::Path("shared-modules/cdparanoia.json".to_string()),
+            )
+            .unwrap();
+
+        let application =
+            FlatpakApplication::parse(FlatpakManifestFormat::JSON, document.get_content()).unwrap();
+        assert_eq!(application.modules.len(), 2);
+        assert!(document
+            .get_content()
+            .contains("        /* To play cdda */\n        {\n            \"name\": \"gst-plugins-base\","));
+        assert!(document.get_content().contains(
+            "gst-plugins-base.git\" } ],\n            \"modules\": [\n                \"shared-modules/cdparanoia.json\"\n            ]\n        },\n        \"shared-modules/lv2.json\"\n    ]\n}"
+        ));
+        assert!(!document.get_content().contains("needs cdparanoia"));
+    }
+
+    #[test]
+    pub fn test_invalid_paths() {
+        let mut document = FlatpakDocument::parse(FlatpakManifestFormat::YAML, YAML_MANIFEST).unwrap();
+        assert!(matches!(
+            document.set_string("modules[7]", "name", "foo"),
+            Err(FlatpakError::PathNotFound(_))
+        ));
+        assert!(matches!(
+            document.remove_module("modules"),
+            Err(FlatpakError::InvalidPath(_))
+        ));
+        assert_eq!(document.get_content(), YAML_MANIFEST);
+    }
+}
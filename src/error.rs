@@ -0,0 +1,683 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::checksum::FlatpakChecksumType;
+use crate::format::FlatpakManifestFormat;
+use crate::manifest_type::FlatpakManifestType;
+use crate::syntax::{
+    get_line_column, get_line_end, get_line_start, FlatpakManifestPath, FlatpakPathSegment, SyntaxNode,
+};
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+/// The location of a diagnostic in a manifest.
+pub struct FlatpakLocation {
+    /// The path of the manifest file, if the manifest was loaded from a file.
+    pub file: Option<String>,
+    /// The path of the node in the manifest, for example `modules[3].sources[1]`.
+    pub path: FlatpakManifestPath,
+    /// The byte offset of the location in the manifest.
+    pub offset: usize,
+    /// The 1-based line of the location.
+    pub line: usize,
+    /// The 1-based column of the location, in characters.
+    pub column: usize,
+}
+impl FlatpakLocation {
+    /// Gets the location of the node at `path` in the manifest. For the entries of a mapping,
+    /// this is the location of the key. If the node does not exist, the location of its closest
+    /// existing parent is returned.
+    pub fn from_path(
+        format: &FlatpakManifestFormat,
+        content: &str,
+        path: &FlatpakManifestPath,
+    ) -> Option<FlatpakLocation> {
+        let root = SyntaxNode::parse(format, content).ok()?;
+        let mut node_path = path.clone();
+        let offset = loop {
+            match node_path.split_last() {
+                Some((parent_path, FlatpakPathSegment::Key(key))) => {
+                    if let Some(entry) = root.get(&parent_path).and_then(|p| p.get_entry(key)) {
+                        break entry.key_span.start;
+                    }
+                }
+                _ => {
+                    if let Some(node) = root.get(&node_path) {
+                        break node.span.start;
+                    }
+                }
+            }
+            node_path = node_path.split_last()?.0;
+        };
+        let (line, column) = get_line_column(content, offset);
+        Some(FlatpakLocation {
+            file: None,
+            path: path.clone(),
+            offset,
+            line,
+            column,
+        })
+    }
+
+    /// Gets the location of a byte offset in the manifest.
+    pub fn from_offset(
+        format: &FlatpakManifestFormat,
+        content: &str,
+        offset: usize,
+    ) -> Option<FlatpakLocation> {
+        let (line, column) = get_line_column(content, offset);
+        FlatpakLocation::from_line_column(format, content, line, column)
+    }
+
+    /// Gets the location from a 1-based line and column, as reported by the
+    /// parsers.
+    pub fn from_line_column(
+        format: &FlatpakManifestFormat,
+        content: &str,
+        line: usize,
+        column: usize,
+    ) -> Option<FlatpakLocation> {
+        if line == 0 {
+            return None;
+        }
+        let line_start = match line {
+            1 => 0,
+            _ => content.match_indices('\n').nth(line - 2)?.0 + 1,
+        };
+        let line_end = get_line_end(content, line_start);
+        let offset = match content[line_start..line_end]
+            .char_indices()
+            .nth(column.saturating_sub(1))
+        {
+            Some((i, _)) => line_start + i,
+            None => line_end,
+        };
+        let path = match SyntaxNode::parse(format, content) {
+            Ok(root) => root.get_path_at(offset),
+            Err(_) => FlatpakManifestPath::default(),
+        };
+        let (line, column) = get_line_column(content, offset);
+        Some(FlatpakLocation {
+            file: None,
+            path,
+            offset,
+            line,
+            column,
+        })
+    }
+}
+impl fmt::Display for FlatpakLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)?;
+        if !self.path.is_root() {
+            write!(f, " ({})", self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+/// All the errors that can be returned when loading, parsing or dumping
+/// Flatpak manifests.
+pub enum FlatpakError {
+    /// The file at the given path could not be accessed.
+    Io { path: String, source: io::Error },
+
+    /// The file extension of the given path does not correspond to any
+    /// supported manifest format.
+    UnsupportedExtension(String),
+
+    /// The format of the manifest could not be detected from its content.
+    UnknownFormat,
+
+    /// The type of the manifest could not be detected from its content.
+    UnknownManifestType,
+
+    /// The content could not be parsed using the given manifest format.
+    Syntax {
+        format: FlatpakManifestFormat,
+        source: Box<dyn Error + Send + Sync>,
+        location: Option<FlatpakLocation>,
+    },
+
+    /// The manifest could not be dumped using the given manifest format.
+    Dump {
+        format: FlatpakManifestFormat,
+        source: Box<dyn Error + Send + Sync>,
+    },
+
+    /// A field required by the manifest type is missing or empty.
+    MissingField {
+        manifest_type: FlatpakManifestType,
+        field: String,
+        location: Option<FlatpakLocation>,
+    },
+
+    /// A value could not be converted to one of the enums of the library,
+    /// for example a build system or a source type.
+    InvalidValue {
+        value_type: String,
+        value: String,
+        location: Option<FlatpakLocation>,
+    },
+
+    /// A source description is not valid.
+    InvalidSource {
+        message: String,
+        location: Option<FlatpakLocation>,
+    },
+
+    /// A field is not supported by this library. This is only reported when
+    /// parsing in strict mode.
+    UnknownField {
+        field: String,
+        /// The name of a supported field that is close to the unknown field, if any.
+        suggestion: Option<String>,
+        location: Option<FlatpakLocation>,
+    },
+
+    /// Multiple errors were found in the manifest.
+    Multiple(Vec<FlatpakError>),
+
+    /// The manifest file at the given path could not be parsed.
+    InvalidFile { path: String, source: Box<FlatpakError> },
+
+    /// The manifest file at the given path includes itself, directly or
+    /// through other included manifests.
+    IncludeCycle(String),
+
+    /// The path is not a valid path to a node of a manifest.
+    InvalidPath(String),
+
+    /// The path does not point to a node of the manifest, or the node
+    /// is not of the expected type.
+    PathNotFound(String),
+
+    /// The manifest contains a comment, which cannot be preserved by the operation.
+    UnsupportedComment { location: Option<FlatpakLocation> },
+
+    /// The build commands of a module cannot be generated for its build system.
+    UnsupportedBuildSystem(String),
+
+    /// The digest of a file does not match the checksum of its source.
+    ChecksumMismatch {
+        /// The path or the url of the file.
+        path: String,
+        checksum_type: FlatpakChecksumType,
+        expected: String,
+        actual: String,
+    },
+
+    /// The size of a file does not match the size of its extra-data source.
+    SizeMismatch { expected: u64, actual: u64 },
+
+    /// The resource at the given url could not be downloaded.
+    Download { url: String, message: String },
+
+    /// The archive at the given path could not be extracted.
+    Extract { path: String, message: String },
+}
+impl FlatpakError {
+    pub fn invalid_value(value_type: &str, value: &str) -> FlatpakError {
+        FlatpakError::InvalidValue {
+            value_type: value_type.to_string(),
+            value: value.to_string(),
+            location: None,
+        }
+    }
+
+    pub fn missing_field(manifest_type: FlatpakManifestType, field: &str) -> FlatpakError {
+        FlatpakError::MissingField {
+            manifest_type,
+            field: field.to_string(),
+            location: None,
+        }
+    }
+
+    pub fn invalid_source(message: &str) -> FlatpakError {
+        FlatpakError::InvalidSource {
+            message: message.to_string(),
+            location: None,
+        }
+    }
+
+    pub fn download(url: &str, message: &str) -> FlatpakError {
+        FlatpakError::Download {
+            url: url.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn extract(path: &str, message: &str) -> FlatpakError {
+        FlatpakError::Extract {
+            path: path.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Builds a single error from a list of errors. Returns `None` if the list is empty.
+    pub fn from_errors(mut errors: Vec<FlatpakError>) -> Option<FlatpakError> {
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(FlatpakError::Multiple(errors)),
+        }
+    }
+
+    /// Locates the errors found at the given paths of a manifest, and builds a single error
+    /// from them. Returns `None` if there are no errors.
+    pub fn from_errors_at_paths(
+        errors: Vec<(FlatpakManifestPath, FlatpakError)>,
+        format: &FlatpakManifestFormat,
+        content: &str,
+    ) -> Option<FlatpakError> {
+        FlatpakError::from_errors(
+            errors
+                .into_iter()
+                .map(|(path, error)| error.at_path(format, content, &path))
+                .collect(),
+        )
+    }
+
+    /// Gets the location of the error in the manifest, if it is known.
+    pub fn get_location(&self) -> Option<&FlatpakLocation> {
+        match self {
+            FlatpakError::Syntax { location, .. }
+            | FlatpakError::MissingField { location, .. }
+            | FlatpakError::InvalidValue { location, .. }
+            | FlatpakError::InvalidSource { location, .. }
+            | FlatpakError::UnknownField { location, .. }
+            | FlatpakError::UnsupportedComment { location } => location.as_ref(),
+            FlatpakError::InvalidFile { source, .. } => source.get_location(),
+            _ => None,
+        }
+    }
+
+    fn get_location_mut(&mut self) -> Option<&mut Option<FlatpakLocation>> {
+        match self {
+            FlatpakError::Syntax { location, .. }
+            | FlatpakError::MissingField { location, .. }
+            | FlatpakError::InvalidValue { location, .. }
+            | FlatpakError::InvalidSource { location, .. }
+            | FlatpakError::UnknownField { location, .. }
+            | FlatpakError::UnsupportedComment { location } => Some(location),
+            _ => None,
+        }
+    }
+
+    /// Sets the location of the error, for the errors that can be located in a manifest.
+    pub fn with_location(mut self, new_location: Option<FlatpakLocation>) -> FlatpakError {
+        if let Some(location) = self.get_location_mut() {
+            *location = new_location;
+        }
+        self
+    }
+
+    /// Sets the location of the error to the node at `path` in the manifest.
+    pub fn at_path(
+        self,
+        format: &FlatpakManifestFormat,
+        content: &str,
+        path: &FlatpakManifestPath,
+    ) -> FlatpakError {
+        self.with_location(FlatpakLocation::from_path(format, content, path))
+    }
+
+    /// Wraps an error that occured while loading the manifest at `path`.
+    pub fn in_file(self, path: String) -> FlatpakError {
+        let mut error = self;
+        error.set_file(&path);
+        FlatpakError::InvalidFile {
+            path,
+            source: Box::new(error),
+        }
+    }
+
+    fn set_file(&mut self, path: &str) {
+        if let FlatpakError::Multiple(errors) = self {
+            for error in errors {
+                error.set_file(path);
+            }
+        } else if let Some(Some(location)) = self.get_location_mut() {
+            location.file = Some(path.to_string());
+        }
+    }
+
+    /// Renders the error with the snippet of the manifest where the error is located,
+    /// the way compilers do. `content` is the content of the manifest that was parsed.
+    ///
+    /// ```text
+    /// error: Invalid Flatpak source: Sources provided as strings cannot be URLs!
+    ///  --> net.louib.flatpak-rs.yaml:10:11
+    ///    |
+    /// 10 |         - https://github.com/louib/flatpak-rs.git
+    ///    |           ^
+    ///    = in modules[0].sources[0]
+    /// ```
+    pub fn render(&self, content: &str) -> String {
+        let mut error = self;
+        let mut file: Option<&str> = None;
+        while let FlatpakError::InvalidFile { path, source } = error {
+            file = Some(path);
+            error = source;
+        }
+        if let FlatpakError::Multiple(errors) = error {
+            // The file of the errors was already set when wrapping them in a file error.
+            let rendered_errors: Vec<String> = errors.iter().map(|e| e.render(content)).collect();
+            return rendered_errors.join("\n\n");
+        }
+
+        let mut message = "error: ".to_string();
+        error.fmt_message(&mut message).unwrap();
+        let location = match error.get_location() {
+            Some(l) => l,
+            None => {
+                if let Some(file) = file {
+                    message += &format!("\n--> {}", file);
+                }
+                return message;
+            }
+        };
+        if let Some(location_file) = &location.file {
+            file = Some(location_file);
+        }
+
+        let line_number = location.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let offset = std::cmp::min(location.offset, content.len());
+        let line = &content[get_line_start(content, offset)..get_line_end(content, offset)];
+        let caret_padding: String = line
+            .chars()
+            .take(location.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        message += &format!(
+            "\n{}--> {}:{}:{}",
+            gutter,
+            file.unwrap_or("<manifest>"),
+            location.line,
+            location.column
+        );
+        message += &format!("\n{} |", gutter);
+        message += &format!("\n{} | {}", line_number, line);
+        message += &format!("\n{} | {}^", gutter, caret_padding);
+        if !location.path.is_root() {
+            message += &format!("\n{} = in {}", gutter, location.path);
+        }
+        message
+    }
+
+    fn fmt_message(&self, f: &mut dyn fmt::Write) -> fmt::Result {
+        match self {
+            FlatpakError::Io { path, source } => write!(f, "Could not read {}: {}", path, source),
+            FlatpakError::UnsupportedExtension(path) => {
+                write!(f, "{} does not have a supported manifest extension.", path)
+            }
+            FlatpakError::UnknownFormat => write!(f, "Could not detect the format of the manifest."),
+            FlatpakError::UnknownManifestType => write!(f, "Could not detect the type of the manifest."),
+            FlatpakError::Syntax { format, source, .. } => {
+                write!(f, "Failed to parse the {:?} manifest: {}", format, source)
+            }
+            FlatpakError::Dump { format, source } => {
+                write!(f, "Failed to dump the {:?} manifest: {}", format, source)
+            }
+            FlatpakError::MissingField {
+                manifest_type, field, ..
+            } => write!(
+                f,
+                "Required field {} is missing from Flatpak {} manifest.",
+                field, manifest_type
+            ),
+            FlatpakError::InvalidValue {
+                value_type, value, ..
+            } => write!(f, "Invalid {} {}.", value_type, value),
+            FlatpakError::InvalidSource { message, .. } => write!(f, "Invalid Flatpak source: {}", message),
+            FlatpakError::UnknownField {
+                field, suggestion, ..
+            } => {
+                write!(f, "Unknown field {}.", field)?;
+                if let Some(suggestion) = suggestion {
+                    write!(f, " Did you mean {}?", suggestion)?;
+                }
+                Ok(())
+            }
+            FlatpakError::Multiple(errors) => {
+                for (error_index, error) in errors.iter().enumerate() {
+                    if error_index != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", error)?;
+                }
+                Ok(())
+            }
+            FlatpakError::InvalidFile { path, source } => {
+                write!(f, "Failed to load Flatpak manifest at {}: {}", path, source)
+            }
+            FlatpakError::IncludeCycle(path) => write!(f, "Manifest at {} includes itself.", path),
+            FlatpakError::InvalidPath(path) => write!(f, "Invalid manifest path {}.", path),
+            FlatpakError::PathNotFound(path) => write!(f, "Could not find {} in the manifest.", path),
+            FlatpakError::UnsupportedComment { .. } => {
+                write!(f, "Comments are not supported, and would be lost.")
+            }
+            FlatpakError::UnsupportedBuildSystem(build_system) => {
+                write!(f, "Build system {} is not supported.", build_system)
+            }
+            FlatpakError::ChecksumMismatch {
+                path,
+                checksum_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Invalid {} checksum for {}: expected {}, got {}.",
+                checksum_type, path, expected, actual
+            ),
+            FlatpakError::SizeMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Invalid size: expected {} bytes, got {} bytes.",
+                    expected, actual
+                )
+            }
+            FlatpakError::Download { url, message } => write!(f, "Could not download {}: {}", url, message),
+            FlatpakError::Extract { path, message } => write!(f, "Could not extract {}: {}", path, message),
+        }
+    }
+}
+
+impl fmt::Display for FlatpakError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The syntax errors of the parsers already include the line and column.
+        match self {
+            FlatpakError::MissingField {
+                location: Some(location),
+                ..
+            }
+            | FlatpakError::InvalidValue {
+                location: Some(location),
+                ..
+            }
+            | FlatpakError::InvalidSource {
+                location: Some(location),
+                ..
+            }
+            | FlatpakError::UnknownField {
+                location: Some(location),
+                ..
+            }
+            | FlatpakError::UnsupportedComment {
+                location: Some(location),
+            } => write!(f, "{}: ", location)?,
+            _ => {}
+        }
+        self.fmt_message(f)
+    }
+}
+
+impl Error for FlatpakError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlatpakError::Io { source, .. } => Some(source),
+            FlatpakError::Syntax { source, .. } => Some(source.as_ref()),
+            FlatpakError::Dump { source, .. } => Some(source.as_ref()),
+            FlatpakError::InvalidFile { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_source_chain() {
+        let error = FlatpakError::InvalidFile {
+            path: "com.example.App.yaml".to_string(),
+            source: Box::new(FlatpakError::missing_field(
+                FlatpakManifestType::Application,
+                "sdk",
+            )),
+        };
+        let source = error.source().unwrap();
+        assert_eq!(
+            source.to_string(),
+            "Required field sdk is missing from Flatpak application manifest."
+        );
+        assert!(source.source().is_none());
+    }
+
+    #[test]
+    pub fn test_render_yaml_validation_error() {
+        let module_manifest = r###"name: flatpak-rs
+sources:
+  - type: git
+    url: https://github.com/louib/flatpak-rs.git
+  - https://github.com/louib/flatpak-rs/archive/v0.1.0.tar.gz
+"###;
+        let error =
+            crate::module::FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!(location.path.to_string(), "sources[1]");
+        assert_eq!((location.line, location.column), (5, 5));
+        assert_eq!(
+            error.to_string(),
+            "5:5 (sources[1]): Invalid Flatpak source: Sources provided as strings cannot be URLs!"
+        );
+
+        let error = error.in_file("flatpak-rs.yaml".to_string());
+        assert_eq!(
+            error.render(module_manifest),
+            r###"error: Invalid Flatpak source: Sources provided as strings cannot be URLs!
+ --> flatpak-rs.yaml:5:5
+  |
+5 |   - https://github.com/louib/flatpak-rs/archive/v0.1.0.tar.gz
+  |     ^
+  = in sources[1]"###
+        );
+    }
+
+    #[test]
+    pub fn test_render_json_validation_error() {
+        let application_manifest = r###"{
+    /* The application. */
+    "id": "net.louib.flatpak-rs",
+    "runtime": "org.gnome.Platform",
+    "runtime-version": "3.36",
+    "sdk": "org.gnome.Sdk",
+    "modules": [
+        {
+            "name": "flatpak-rs",
+            "sources": [
+                {
+                    "type": "git",
+                    "url": "https://github.com/louib/flatpak-rs.git"
+                },
+                {
+                    "type": "file",
+                    "dest-filename": "flatpak-rs.desktop"
+                }
+            ]
+        }
+    ]
+}"###;
+        let error =
+            crate::application::FlatpakApplication::parse(FlatpakManifestFormat::JSON, application_manifest)
+                .unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!(location.path.to_string(), "modules[0].sources[1]");
+        assert_eq!((location.line, location.column), (15, 17));
+
+        let module_manifest = &application_manifest[application_manifest.find("        {").unwrap()..];
+        let module_manifest = &module_manifest[..module_manifest.find("\n    ]").unwrap()];
+        let error =
+            crate::module::FlatpakModule::parse(FlatpakManifestFormat::JSON, module_manifest).unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!(location.path.to_string(), "sources[1]");
+        assert_eq!((location.line, location.column), (8, 17));
+        assert_eq!(
+            error.render(module_manifest),
+            r###"error: Invalid Flatpak source: There should be at least a url, a path or inline commands in a source!
+ --> <manifest>:8:17
+  |
+8 |                 {
+  |                 ^
+  = in sources[1]"###
+        );
+    }
+
+    #[test]
+    pub fn test_syntax_error_location() {
+        let application_manifest = r###"{
+    /*
+     * The application.
+     */
+    "id": "net.louib.flatpak-rs",
+    "runtime": "org.gnome.Platform",
+    "runtime-version": "3.36",
+    "sdk": "org.gnome.Sdk",
+    "build-options": {
+        "cflags": [ "-O2" ]
+    }
+}"###;
+        let error =
+            crate::application::FlatpakApplication::parse(FlatpakManifestFormat::JSON, application_manifest)
+                .unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!(location.line, 10);
+        assert_eq!(location.path.to_string(), "build-options.cflags");
+
+        let application_manifest = r###"
+id: net.louib.flatpak-rs
+runtime: org.gnome.Platform
+runtime-version: "3.36"
+sdk: org.gnome.Sdk
+command: flatpak-rs
+finish-args: --share=network
+"###;
+        let error =
+            crate::application::FlatpakApplication::parse(FlatpakManifestFormat::YAML, application_manifest)
+                .unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!(location.line, 7);
+        assert_eq!(location.path.to_string(), "finish-args");
+
+        let application_manifest = r###"{
+    "id": "net.louib.flatpak-rs",
+    "runtime": "org.gnome.Platform",
+    "runtime-version": "3.36",
+    "sdk": "org.gnome.Sdk"
+}
+/* The end of the manifest.
+"###;
+        let error =
+            crate::application::FlatpakApplication::parse(FlatpakManifestFormat::JSON, application_manifest)
+                .unwrap_err();
+        let location = error.get_location().unwrap();
+        assert_eq!((location.line, location.column), (7, 1));
+        assert!(error.to_string().contains("Unterminated comment"));
+    }
+}
@@ -0,0 +1,254 @@
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+
+use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+use crate::error::FlatpakError;
+use crate::syntax::FlatpakManifestPath;
+
+/// The prefix used for custom fields in Flatpak manifests. flatpak-builder ignores
+/// those fields, and they are commonly used by other tools (GNOME Builder, the
+/// external data checker, etc.).
+pub const EXTENSION_FIELD_PREFIX: &str = "x-";
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+#[serde(transparent)]
+/// The fields of a manifest that are not supported by this library, in the order
+/// in which they were defined. This includes the extension fields prefixed with `x-`.
+///
+/// The extra fields are preserved when dumping a manifest.
+pub struct FlatpakExtraFields(IndexMap<String, Value>);
+impl FlatpakExtraFields {
+    /// Gets the names of all the extension fields, which are prefixed with `x-`.
+    pub fn get_extension_field_names(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter(|k| is_extension_field(k))
+            .map(|k| k.as_str())
+            .collect()
+    }
+
+    /// Gets the names of all the fields that are neither supported by this library
+    /// nor extension fields.
+    pub fn get_unknown_field_names(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .filter(|k| !is_extension_field(k))
+            .map(|k| k.as_str())
+            .collect()
+    }
+
+    /// Gets an error for each unknown field, with a suggestion based on the names of the
+    /// fields supported by the struct containing the extra fields. `path` is the path of
+    /// that struct in the manifest.
+    pub fn get_unknown_field_errors(
+        &self,
+        path: &FlatpakManifestPath,
+        field_names: &[&str],
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        self.get_unknown_field_names()
+            .into_iter()
+            .map(|field_name| {
+                let error = FlatpakError::UnknownField {
+                    field: field_name.to_string(),
+                    suggestion: get_closest_field_name(field_name, field_names).map(|s| s.to_string()),
+                    location: None,
+                };
+                (path.join_key(field_name), error)
+            })
+            .collect()
+    }
+
+    /// Gets an extension field and converts it to the requested type.
+    ///
+    /// ```
+    /// # use flatpak_rs::application::FlatpakApplication;
+    /// # use flatpak_rs::format::FlatpakManifestFormat;
+    /// let manifest = r###"
+    ///     app-id: net.louib.flatpak-rs
+    ///     runtime: org.gnome.Platform
+    ///     runtime-version: "3.36"
+    ///     sdk: org.gnome.Sdk
+    ///     x-run-args: [ "--verbose" ]
+    /// "###;
+    /// let application = FlatpakApplication::parse(FlatpakManifestFormat::YAML, manifest).unwrap();
+    /// let run_args: Option<Vec<String>> = application.extra_fields.get_extension_field("x-run-args").unwrap();
+    /// assert_eq!(run_args, Some(vec!["--verbose".to_string()]));
+    /// ```
+    pub fn get_extension_field<T>(&self, field_name: &str) -> Result<Option<T>, FlatpakError>
+    where
+        T: DeserializeOwned,
+    {
+        if !is_extension_field(field_name) {
+            return Err(FlatpakError::invalid_value("extension field name", field_name));
+        }
+        let value = match self.0.get(field_name) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        match serde_yaml::from_value::<T>(value.clone()) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => Err(FlatpakError::invalid_value(
+                &format!("value for extension field {}", field_name),
+                &e.to_string(),
+            )),
+        }
+    }
+
+    /// Sets an extension field. If the field already exists, its position in the manifest
+    /// is preserved.
+    pub fn set_extension_field<T>(&mut self, field_name: &str, value: &T) -> Result<(), FlatpakError>
+    where
+        T: Serialize,
+    {
+        if !is_extension_field(field_name) {
+            return Err(FlatpakError::invalid_value("extension field name", field_name));
+        }
+        let value = match serde_yaml::to_value(value) {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(FlatpakError::invalid_value(
+                    &format!("value for extension field {}", field_name),
+                    &e.to_string(),
+                ))
+            }
+        };
+        self.0.insert(field_name.to_string(), value);
+        Ok(())
+    }
+}
+impl Deref for FlatpakExtraFields {
+    type Target = IndexMap<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl DerefMut for FlatpakExtraFields {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+impl Hash for FlatpakExtraFields {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for (key, value) in &self.0 {
+            key.hash(state);
+            value.hash(state);
+        }
+    }
+}
+
+pub fn is_extension_field(field_name: &str) -> bool {
+    field_name.starts_with(EXTENSION_FIELD_PREFIX)
+}
+
+/// Gets the field name that is the closest to `field_name`, if one is close enough to
+/// be a likely typo.
+pub fn get_closest_field_name<'a>(field_name: &str, field_names: &[&'a str]) -> Option<&'a str> {
+    let max_distance = std::cmp::max(1, field_name.chars().count() / 3);
+    field_names
+        .iter()
+        .map(|candidate| (get_edit_distance(field_name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Gets the Levenshtein distance between two strings.
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous_row: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current_row = vec![i + 1];
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+            current_row.push(std::cmp::min(
+                std::cmp::min(previous_row[j + 1] + 1, current_row[j] + 1),
+                previous_row[j] + substitution_cost,
+            ));
+        }
+        previous_row = current_row;
+    }
+    previous_row[b_chars.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_extension_fields() {
+        let mut extra_fields: FlatpakExtraFields = serde_yaml::from_str(
+            r###"
+            x-team-owner: desktop
+            unknown-field: true
+            x-d-bus-activatable: true
+            "###,
+        )
+        .unwrap();
+        assert_eq!(
+            extra_fields.get_extension_field_names(),
+            vec!["x-team-owner", "x-d-bus-activatable"]
+        );
+        assert_eq!(extra_fields.get_unknown_field_names(), vec!["unknown-field"]);
+        assert_eq!(
+            extra_fields
+                .get_extension_field::<String>("x-team-owner")
+                .unwrap(),
+            Some("desktop".to_string())
+        );
+        assert_eq!(
+            extra_fields.get_extension_field::<bool>("x-missing").unwrap(),
+            None
+        );
+        assert!(extra_fields.get_extension_field::<u32>("x-team-owner").is_err());
+        assert!(extra_fields.get_extension_field::<bool>("unknown-field").is_err());
+
+        extra_fields
+            .set_extension_field("x-team-owner", &"platform")
+            .unwrap();
+        extra_fields
+            .set_extension_field("x-run-args", &vec!["--verbose"])
+            .unwrap();
+        assert_eq!(
+            serde_yaml::to_string(&extra_fields).unwrap(),
+            "x-team-owner: platform\nunknown-field: true\nx-d-bus-activatable: true\nx-run-args:\n- --verbose\n"
+        );
+    }
+
+    #[test]
+    pub fn test_get_closest_field_name() {
+        let field_names = [
+            "config-opts",
+            "buildsystem",
+            "sha256",
+            "sha512",
+            "builddir",
+            "url",
+        ];
+        assert_eq!(
+            get_closest_field_name("config-opt", &field_names),
+            Some("config-opts")
+        );
+        assert_eq!(
+            get_closest_field_name("buildsytem", &field_names),
+            Some("buildsystem")
+        );
+        assert_eq!(get_closest_field_name("sha-256", &field_names), Some("sha256"));
+        assert_eq!(
+            get_closest_field_name("config_opts", &field_names),
+            Some("config-opts")
+        );
+        assert_eq!(get_closest_field_name("uri", &field_names), Some("url"));
+        assert_eq!(get_closest_field_name("dest", &field_names), None);
+        assert_eq!(get_closest_field_name("something-else", &field_names), None);
+    }
+}
@@ -0,0 +1,581 @@
+use std::fs;
+use std::io;
+use std::path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::archive::FlatpakArchiveType;
+use crate::error::FlatpakError;
+use crate::source::{FlatpakSource, FlatpakSourceType};
+
+/// The number of leading components stripped from the paths of the archive entries,
+/// when the source does not define `strip-components`.
+pub const DEFAULT_STRIP_COMPONENTS: usize = 1;
+
+/// The prefix of the temporary directories in which the archives that are not tarballs
+/// are extracted, before their components are stripped.
+const UNCOMPRESS_DIR_PREFIX: &str = ".uncompress";
+
+/// Extracts an archive source, already available at `archive_path`, into the source
+/// directory of its module, like flatpak-builder does before a build. The archive type is
+/// guessed from `archive_path` when the source does not define `archive-type`.
+///
+/// Returns the directory in which the archive was extracted, which is the `dest` of the
+/// source inside the source directory.
+pub fn extract_archive_source(
+    source: &FlatpakSource,
+    archive_path: &str,
+    source_dir: &str,
+) -> Result<String, FlatpakError> {
+    if source.get_type() != Some(FlatpakSourceType::Archive) {
+        return Err(FlatpakError::invalid_source(
+            "Only the archive sources can be extracted.",
+        ));
+    }
+    let archive_type = match &source.archive_type {
+        Some(t) => t.clone(),
+        None => match FlatpakArchiveType::from_path(archive_path) {
+            Some(t) => t,
+            None => {
+                return Err(FlatpakError::extract(
+                    archive_path,
+                    "could not guess the archive type",
+                ))
+            }
+        },
+    };
+    let strip_components = match source.strip_components {
+        Some(s) if s < 0 => return Err(FlatpakError::invalid_value("strip-components", &s.to_string())),
+        Some(s) => s as usize,
+        None => DEFAULT_STRIP_COMPONENTS,
+    };
+
+    let dest = source.dest.as_deref().unwrap_or("");
+    if !is_safe_entry_path(dest) {
+        return Err(FlatpakError::invalid_source(
+            "The dest of a source must be a relative path inside the source directory.",
+        ));
+    }
+    let dest_dir = path::Path::new(source_dir).join(dest);
+    fs::create_dir_all(&dest_dir).map_err(|e| FlatpakError::Io {
+        path: dest_dir.to_string_lossy().to_string(),
+        source: e,
+    })?;
+    let dest_dir = dest_dir.to_string_lossy().to_string();
+
+    extract_archive(archive_path, &archive_type, strip_components, &dest_dir)?;
+    if source.git_init == Some(true) {
+        git_init(&dest_dir)?;
+    }
+    Ok(dest_dir)
+}
+
+/// Extracts an archive into an existing directory, using the same tools as flatpak-builder.
+/// All the archives are first extracted in a temporary directory. The tarballs are extracted
+/// with `tar --strip-components`, so the entries with fewer components are skipped, and are then
+/// merged into the destination directory, replacing the existing files like `tar` does. For the
+/// other archives, the entries with fewer components are moved to the destination directory as is.
+///
+/// The archive is refused if any of its entries has an absolute path or a `..` component,
+/// or if any of its symlinks points outside of the destination directory.
+pub fn extract_archive(
+    archive_path: &str,
+    archive_type: &FlatpakArchiveType,
+    strip_components: usize,
+    dest_dir: &str,
+) -> Result<(), FlatpakError> {
+    // The extraction commands are not run in the directory of the archive.
+    let archive_path = fs::canonicalize(archive_path)
+        .map_err(|e| FlatpakError::Io {
+            path: archive_path.to_string(),
+            source: e,
+        })?
+        .to_string_lossy()
+        .to_string();
+
+    for entry_path in get_archive_entries(&archive_path, archive_type)? {
+        if !is_safe_entry_path(&entry_path) {
+            return Err(FlatpakError::extract(
+                &archive_path,
+                &format!("the entry {} is outside of the destination directory", entry_path),
+            ));
+        }
+    }
+
+    let uncompress_dir = create_uncompress_dir(dest_dir)?;
+    if archive_type.is_tar() {
+        let mut command = Command::new("tar");
+        command.args(["xf", &archive_path, "--no-same-owner"]);
+        command.arg(format!("--strip-components={}", strip_components));
+        if let Some(decompress_flag) = archive_type.get_tar_decompress_flag() {
+            command.arg(decompress_flag);
+        }
+        command.current_dir(&uncompress_dir);
+        let extract_result = run_command(command, &archive_path)
+            .and_then(|_| check_symlinks(&archive_path, &uncompress_dir, 0, 0))
+            .and_then(|_| merge_into(path::Path::new(dest_dir), &uncompress_dir));
+        if extract_result.is_err() {
+            let _ = fs::remove_dir_all(&uncompress_dir);
+        }
+        return extract_result;
+    }
+
+    let mut command = match archive_type {
+        FlatpakArchiveType::Zip => {
+            let mut command = Command::new("unzip");
+            command.args(["-q", &archive_path]);
+            command
+        }
+        FlatpakArchiveType::SevenZip => {
+            let mut command = Command::new("7z");
+            command.args(["x", "-y", &archive_path]);
+            command
+        }
+        _ => {
+            let mut command = Command::new("/bin/sh");
+            command.args(["-c", "rpm2cpio \"$1\" | cpio -i -d --quiet", "sh", &archive_path]);
+            command
+        }
+    };
+    command.current_dir(&uncompress_dir);
+    let extract_result = run_command(command, &archive_path)
+        .and_then(|_| check_symlinks(&archive_path, &uncompress_dir, strip_components, 0))
+        .and_then(|_| strip_components_into(path::Path::new(dest_dir), &uncompress_dir, strip_components));
+    if extract_result.is_err() {
+        let _ = fs::remove_dir_all(&uncompress_dir);
+    }
+    extract_result
+}
+
+/// Gets the paths of the entries of an archive, as stored in the archive.
+fn get_archive_entries(
+    archive_path: &str,
+    archive_type: &FlatpakArchiveType,
+) -> Result<Vec<String>, FlatpakError> {
+    let command = match archive_type {
+        FlatpakArchiveType::Zip => {
+            let mut command = Command::new("unzip");
+            command.args(["-Z1", archive_path]);
+            command
+        }
+        FlatpakArchiveType::SevenZip => {
+            let mut command = Command::new("7z");
+            command.args(["l", "-ba", "-slt", archive_path]);
+            command
+        }
+        FlatpakArchiveType::Rpm => {
+            let mut command = Command::new("/bin/sh");
+            command.args(["-c", "rpm2cpio \"$1\" | cpio -t --quiet", "sh", archive_path]);
+            command
+        }
+        _ => {
+            let mut command = Command::new("tar");
+            command.args(["tf", archive_path]);
+            if let Some(decompress_flag) = archive_type.get_tar_decompress_flag() {
+                command.arg(decompress_flag);
+            }
+            command
+        }
+    };
+    let output = run_command(command, archive_path)?;
+    let entries = output.lines().filter(|l| !l.is_empty());
+    if *archive_type == FlatpakArchiveType::SevenZip {
+        return Ok(entries
+            .filter_map(|l| l.strip_prefix("Path = "))
+            .map(|l| l.to_string())
+            .collect());
+    }
+    Ok(entries.map(|l| l.to_string()).collect())
+}
+
+/// Returns `true` if the path cannot point outside of the directory it is relative to.
+fn is_safe_entry_path(entry_path: &str) -> bool {
+    !entry_path.starts_with('/') && !entry_path.split(['/', '\\']).any(|c| c == "..")
+}
+
+/// Checks that the symlinks extracted in `dir` do not point outside of the destination
+/// directory, once `level` leading directories are stripped like in [`strip_components_into`].
+/// `depth` is the depth of `dir` in the destination directory.
+fn check_symlinks(
+    archive_path: &str,
+    dir: &path::Path,
+    level: usize,
+    depth: usize,
+) -> Result<(), FlatpakError> {
+    let io_error = |path: &path::Path, e| FlatpakError::Io {
+        path: path.to_string_lossy().to_string(),
+        source: e,
+    };
+    for entry in fs::read_dir(dir).map_err(|e| io_error(dir, e))? {
+        let entry = entry.map_err(|e| io_error(dir, e))?;
+        let entry_path = entry.path();
+        let file_type = entry.file_type().map_err(|e| io_error(&entry_path, e))?;
+        if file_type.is_dir() {
+            match level {
+                0 => check_symlinks(archive_path, &entry_path, 0, depth + 1)?,
+                _ => check_symlinks(archive_path, &entry_path, level - 1, 0)?,
+            }
+            continue;
+        }
+        if !file_type.is_symlink() {
+            continue;
+        }
+        let target = fs::read_link(&entry_path).map_err(|e| io_error(&entry_path, e))?;
+        if !is_safe_symlink_target(&target, depth) {
+            return Err(FlatpakError::extract(
+                archive_path,
+                &format!(
+                    "the symlink {} points outside of the destination directory",
+                    target.to_string_lossy()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if the target of a symlink, found `depth` directories deep, cannot
+/// point outside of the directory the symlink is relative to.
+fn is_safe_symlink_target(target: &path::Path, depth: usize) -> bool {
+    let mut depth = depth;
+    for component in target.components() {
+        match component {
+            path::Component::Normal(_) => depth += 1,
+            path::Component::CurDir => {}
+            path::Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn create_uncompress_dir(dest_dir: &str) -> Result<path::PathBuf, FlatpakError> {
+    static UNCOMPRESS_DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let uncompress_dir = path::Path::new(dest_dir).join(format!(
+        "{}{}-{}",
+        UNCOMPRESS_DIR_PREFIX,
+        std::process::id(),
+        UNCOMPRESS_DIR_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir(&uncompress_dir).map_err(|e| FlatpakError::Io {
+        path: uncompress_dir.to_string_lossy().to_string(),
+        source: e,
+    })?;
+    Ok(uncompress_dir)
+}
+
+/// Moves the content of `src_dir` into `dest_dir`, stripping `level` leading directories
+/// like flatpak-builder does. The files and symlinks found before `level` directories were
+/// stripped are moved as is, and the existing files are never overwritten. `src_dir` is
+/// removed afterwards.
+fn strip_components_into(
+    dest_dir: &path::Path,
+    src_dir: &path::Path,
+    level: usize,
+) -> Result<(), FlatpakError> {
+    let io_error = |path: &path::Path, e| FlatpakError::Io {
+        path: path.to_string_lossy().to_string(),
+        source: e,
+    };
+    for entry in fs::read_dir(src_dir).map_err(|e| io_error(src_dir, e))? {
+        let entry = entry.map_err(|e| io_error(src_dir, e))?;
+        let entry_path = entry.path();
+        let file_type = entry.file_type().map_err(|e| io_error(&entry_path, e))?;
+        if file_type.is_dir() && level > 0 {
+            strip_components_into(dest_dir, &entry_path, level - 1)?;
+            continue;
+        }
+
+        let dest_path = dest_dir.join(entry.file_name());
+        if fs::symlink_metadata(&dest_path).is_ok() {
+            return Err(io_error(
+                &dest_path,
+                io::Error::new(io::ErrorKind::AlreadyExists, "the file already exists"),
+            ));
+        }
+        fs::rename(&entry_path, &dest_path).map_err(|e| io_error(&entry_path, e))?;
+    }
+    fs::remove_dir(src_dir).map_err(|e| io_error(src_dir, e))
+}
+
+/// Moves the content of `src_dir` into `dest_dir`, merging the directories found on both
+/// sides. The existing files and symlinks are replaced, but an existing directory is never
+/// replaced by a file. `src_dir` is removed afterwards.
+fn merge_into(dest_dir: &path::Path, src_dir: &path::Path) -> Result<(), FlatpakError> {
+    let io_error = |path: &path::Path, e| FlatpakError::Io {
+        path: path.to_string_lossy().to_string(),
+        source: e,
+    };
+    for entry in fs::read_dir(src_dir).map_err(|e| io_error(src_dir, e))? {
+        let entry = entry.map_err(|e| io_error(src_dir, e))?;
+        let entry_path = entry.path();
+        let file_type = entry.file_type().map_err(|e| io_error(&entry_path, e))?;
+
+        let dest_path = dest_dir.join(entry.file_name());
+        if let Ok(dest_metadata) = fs::symlink_metadata(&dest_path) {
+            if dest_metadata.is_dir() {
+                if !file_type.is_dir() {
+                    return Err(io_error(
+                        &dest_path,
+                        io::Error::new(io::ErrorKind::AlreadyExists, "the directory already exists"),
+                    ));
+                }
+                merge_into(&dest_path, &entry_path)?;
+                continue;
+            }
+            fs::remove_file(&dest_path).map_err(|e| io_error(&dest_path, e))?;
+        }
+        fs::rename(&entry_path, &dest_path).map_err(|e| io_error(&entry_path, e))?;
+    }
+    fs::remove_dir(src_dir).map_err(|e| io_error(src_dir, e))
+}
+
+/// Initializes a git repository with the extracted files, for the sources using `git-init`.
+fn git_init(dest_dir: &str) -> Result<(), FlatpakError> {
+    let git_commands: [&[&str]; 3] = [
+        &["init", "-q"],
+        &["add", "--ignore-errors", "."],
+        &[
+            "-c",
+            "user.name=flatpak-rs",
+            "-c",
+            "user.email=flatpak-rs@localhost",
+            "commit",
+            "-q",
+            "--no-verify",
+            "-m",
+            "Initial commit",
+        ],
+    ];
+    for git_args in git_commands {
+        let mut command = Command::new("git");
+        command.args(git_args);
+        command.current_dir(dest_dir);
+        run_command(command, dest_dir)?;
+    }
+    Ok(())
+}
+
+/// Runs a command, and returns its standard output.
+fn run_command(mut command: Command, archive_path: &str) -> Result<String, FlatpakError> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .map_err(|e| FlatpakError::extract(archive_path, &format!("could not run {}: {}", program, e)))?;
+    if !output.status.success() {
+        return Err(FlatpakError::extract(
+            archive_path,
+            &format!(
+                "{} failed: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FlatpakManifestFormat;
+    use crate::utils::TestDir;
+
+    fn create_tarball(test_dir: &path::Path, tar_args: &[&str]) {
+        let input_dir = test_dir.join("input");
+        fs::create_dir_all(input_dir.join("project-1.0").join("src")).unwrap();
+        fs::write(input_dir.join("project-1.0").join("README"), "flatpak").unwrap();
+        fs::write(input_dir.join("project-1.0").join("src").join("main.c"), "").unwrap();
+        fs::write(test_dir.join("evil.txt"), "evil").unwrap();
+        let status = Command::new("tar")
+            .args(tar_args)
+            .current_dir(&input_dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    pub fn test_extract_archive_source() {
+        let test_dir = TestDir::new("extract");
+        let source_dir = test_dir.join("source");
+        create_tarball(&test_dir, &["czf", "../project-1.0.tar.gz", "project-1.0"]);
+        let archive_path = test_dir.join("project-1.0.tar.gz");
+
+        let source_manifest = r###"
+            type: archive
+            path: project-1.0.tar.gz
+            dest: vendor/project
+            git-init: true
+        "###;
+        let source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        let dest_dir = extract_archive_source(
+            &source,
+            archive_path.to_str().unwrap(),
+            source_dir.to_str().unwrap(),
+        )
+        .unwrap();
+        let dest_dir = path::Path::new(&dest_dir);
+        assert_eq!(dest_dir, source_dir.join("vendor").join("project"));
+        assert_eq!(fs::read_to_string(dest_dir.join("README")).unwrap(), "flatpak");
+        assert!(dest_dir.join("src").join("main.c").is_file());
+        assert!(dest_dir.join(".git").is_dir());
+
+        // The archive type cannot be guessed without the extension.
+        let renamed_archive_path = test_dir.join("project-1.0");
+        fs::rename(&archive_path, &renamed_archive_path).unwrap();
+        let source_manifest = r###"
+            type: archive
+            path: project-1.0
+            strip-components: 0
+        "###;
+        let mut source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        assert!(matches!(
+            extract_archive_source(
+                &source,
+                renamed_archive_path.to_str().unwrap(),
+                source_dir.to_str().unwrap()
+            ),
+            Err(FlatpakError::Extract { .. })
+        ));
+        source.archive_type = Some(FlatpakArchiveType::TarGzip);
+        extract_archive_source(
+            &source,
+            renamed_archive_path.to_str().unwrap(),
+            source_dir.to_str().unwrap(),
+        )
+        .unwrap();
+        assert!(source_dir.join("project-1.0").join("README").is_file());
+
+        source.dest = Some("../outside".to_string());
+        assert!(matches!(
+            extract_archive_source(
+                &source,
+                renamed_archive_path.to_str().unwrap(),
+                source_dir.to_str().unwrap()
+            ),
+            Err(FlatpakError::InvalidSource { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_refuse_escaping_entries() {
+        let test_dir = TestDir::new("extract-escape");
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir_all(&dest_dir).unwrap();
+        create_tarball(&test_dir, &["cPf", "../evil.tar", "project-1.0", "../evil.txt"]);
+        fs::remove_file(test_dir.join("evil.txt")).unwrap();
+
+        let extract_result = extract_archive(
+            test_dir.join("evil.tar").to_str().unwrap(),
+            &FlatpakArchiveType::Tar,
+            0,
+            dest_dir.to_str().unwrap(),
+        );
+        assert!(matches!(extract_result, Err(FlatpakError::Extract { .. })));
+        assert!(!test_dir.join("evil.txt").exists());
+        assert!(!dest_dir.join("project-1.0").exists());
+    }
+
+    #[test]
+    pub fn test_refuse_escaping_tar_symlinks() {
+        let test_dir = TestDir::new("extract-escape-symlink");
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::write(dest_dir.join("README"), "previous").unwrap();
+        fs::create_dir_all(test_dir.join("input").join("project-1.0")).unwrap();
+        std::os::unix::fs::symlink(
+            "../evil.txt",
+            test_dir.join("input").join("project-1.0").join("evil.txt"),
+        )
+        .unwrap();
+        create_tarball(&test_dir, &["cf", "../evil.tar", "project-1.0"]);
+
+        let extract_result = extract_archive(
+            test_dir.join("evil.tar").to_str().unwrap(),
+            &FlatpakArchiveType::Tar,
+            1,
+            dest_dir.to_str().unwrap(),
+        );
+        assert!(matches!(extract_result, Err(FlatpakError::Extract { .. })));
+        assert!(fs::symlink_metadata(dest_dir.join("evil.txt")).is_err());
+        assert_eq!(fs::read_to_string(dest_dir.join("README")).unwrap(), "previous");
+        assert_eq!(fs::read_dir(&dest_dir).unwrap().count(), 1);
+
+        // The symlink stays inside of the destination directory when the leading directory
+        // is not stripped.
+        extract_archive(
+            test_dir.join("evil.tar").to_str().unwrap(),
+            &FlatpakArchiveType::Tar,
+            0,
+            dest_dir.to_str().unwrap(),
+        )
+        .unwrap();
+        assert!(fs::symlink_metadata(dest_dir.join("project-1.0").join("evil.txt")).is_ok());
+    }
+
+    #[test]
+    pub fn test_check_symlinks() {
+        let test_dir = TestDir::new("check-symlinks");
+        let src_dir = test_dir.join(".uncompress");
+        fs::create_dir_all(src_dir.join("project-1.0").join("docs")).unwrap();
+        std::os::unix::fs::symlink(
+            "../README",
+            src_dir.join("project-1.0").join("docs").join("README"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("docs/./../README", src_dir.join("project-1.0").join("LICENSE")).unwrap();
+        check_symlinks("project-1.0.zip", &src_dir, 1, 0).unwrap();
+
+        // The symlink stays inside of the uncompress directory, but not once the leading
+        // directory is stripped.
+        std::os::unix::fs::symlink("../evil.txt", src_dir.join("project-1.0").join("evil.txt")).unwrap();
+        check_symlinks("project-1.0.zip", &src_dir, 0, 0).unwrap();
+        assert!(matches!(
+            check_symlinks("project-1.0.zip", &src_dir, 1, 0),
+            Err(FlatpakError::Extract { .. })
+        ));
+        fs::remove_file(src_dir.join("project-1.0").join("evil.txt")).unwrap();
+
+        std::os::unix::fs::symlink(
+            "/etc/passwd",
+            src_dir.join("project-1.0").join("docs").join("passwd"),
+        )
+        .unwrap();
+        assert!(matches!(
+            check_symlinks("project-1.0.zip", &src_dir, 1, 0),
+            Err(FlatpakError::Extract { .. })
+        ));
+
+        assert!(is_safe_symlink_target(path::Path::new("a/../b"), 0));
+        assert!(!is_safe_symlink_target(path::Path::new("a/../../b"), 0));
+        assert!(is_safe_symlink_target(path::Path::new("../../b"), 2));
+        assert!(!is_safe_symlink_target(path::Path::new(".."), 0));
+    }
+
+    #[test]
+    pub fn test_strip_components_into() {
+        let test_dir = TestDir::new("strip-components");
+        let src_dir = test_dir.join(".uncompress");
+        let dest_dir = test_dir.join("dest");
+        fs::create_dir_all(src_dir.join("project-1.0").join("docs")).unwrap();
+        fs::create_dir_all(&dest_dir).unwrap();
+        fs::write(src_dir.join("NOTICE"), "").unwrap();
+        fs::write(src_dir.join("project-1.0").join("README"), "").unwrap();
+        fs::write(src_dir.join("project-1.0").join("docs").join("index.html"), "").unwrap();
+
+        strip_components_into(&dest_dir, &src_dir, 1).unwrap();
+        assert!(dest_dir.join("NOTICE").is_file());
+        assert!(dest_dir.join("README").is_file());
+        assert!(dest_dir.join("docs").join("index.html").is_file());
+        assert!(!src_dir.exists());
+
+        fs::create_dir_all(&src_dir).unwrap();
+        fs::write(src_dir.join("README"), "").unwrap();
+        assert!(matches!(
+            strip_components_into(&dest_dir, &src_dir, 1),
+            Err(FlatpakError::Io { .. })
+        ));
+    }
+}
@@ -0,0 +1,873 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::application::FlatpakApplication;
+use crate::architecture::FlatpakArchitecture;
+use crate::checksum::{FlatpakChecksumHasher, FlatpakChecksumType};
+use crate::error::FlatpakError;
+use crate::module::FlatpakModuleItem;
+use crate::source::{FlatpakSource, FlatpakSourceItem, FlatpakSourceType};
+
+/// The name of the directory of the download cache, inside the state directory.
+pub const DOWNLOADS_DIR_NAME: &str = "downloads";
+
+/// The default number of sources fetched at the same time.
+pub const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 4;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+/// The progress of a download, reported to the progress callback of the fetcher.
+pub struct FlatpakFetchProgress {
+    pub url: String,
+
+    /// The number of bytes downloaded so far.
+    pub downloaded_size: u64,
+
+    /// The size of the resource, if it is known.
+    pub total_size: Option<u64>,
+}
+
+/// The callback called by the fetcher every time a download progresses.
+pub type FlatpakFetchProgressCallback = Box<dyn Fn(&FlatpakFetchProgress) + Send + Sync>;
+
+/// Downloads resources for the [`FlatpakFetcher`]. Transports can be implemented for
+/// other url schemes, or on top of an HTTP client supporting TLS.
+pub trait FlatpakTransport: Send + Sync {
+    /// Returns `true` if the transport can download the resource at this url.
+    fn supports_url(&self, url: &str) -> bool;
+
+    /// Downloads the resource at the url into `writer`. `progress` is called with the number
+    /// of bytes downloaded so far, and with the size of the resource if it is known.
+    fn fetch(
+        &self,
+        url: &str,
+        writer: &mut dyn Write,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<(), FlatpakError>;
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+/// Copies the files at `file://` urls.
+pub struct FlatpakFileTransport {}
+impl FlatpakTransport for FlatpakFileTransport {
+    fn supports_url(&self, url: &str) -> bool {
+        url.starts_with("file://")
+    }
+
+    fn fetch(
+        &self,
+        url: &str,
+        writer: &mut dyn Write,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<(), FlatpakError> {
+        let file_path = match url.strip_prefix("file://") {
+            Some(p) => p,
+            None => return Err(FlatpakError::download(url, "not a file url")),
+        };
+        let io_error = |e: io::Error| FlatpakError::download(url, &e.to_string());
+        let mut file = fs::File::open(file_path).map_err(io_error)?;
+        let total_size = file.metadata().map_err(io_error)?.len();
+        copy_with_progress(&mut file, writer, Some(total_size), progress).map_err(io_error)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+/// Downloads the resources at `http://` urls, following redirections. This transport does
+/// not support TLS, and is mostly meant for local servers, like the ones used in tests.
+pub struct FlatpakHttpTransport {
+    pub max_redirects: usize,
+
+    /// The timeout of the connection, and of every read and write on the connection.
+    pub timeout: Option<Duration>,
+}
+impl Default for FlatpakHttpTransport {
+    fn default() -> Self {
+        FlatpakHttpTransport {
+            max_redirects: 10,
+            timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+impl FlatpakTransport for FlatpakHttpTransport {
+    fn supports_url(&self, url: &str) -> bool {
+        url.starts_with("http://")
+    }
+
+    fn fetch(
+        &self,
+        url: &str,
+        writer: &mut dyn Write,
+        progress: &mut dyn FnMut(u64, Option<u64>),
+    ) -> Result<(), FlatpakError> {
+        let mut current_url = url.to_string();
+        for _ in 0..=self.max_redirects {
+            let io_error = |e: io::Error| FlatpakError::download(&current_url, &e.to_string());
+            let (host, port, request_path) = parse_http_url(&current_url)?;
+
+            let stream = match self.timeout {
+                Some(timeout) => {
+                    let address = std::net::ToSocketAddrs::to_socket_addrs(&(host.as_str(), port))
+                        .map_err(io_error)?
+                        .next()
+                        .ok_or_else(|| FlatpakError::download(&current_url, "could not resolve the host"))?;
+                    TcpStream::connect_timeout(&address, timeout).map_err(io_error)?
+                }
+                None => TcpStream::connect((host.as_str(), port)).map_err(io_error)?,
+            };
+            stream.set_read_timeout(self.timeout).map_err(io_error)?;
+            stream.set_write_timeout(self.timeout).map_err(io_error)?;
+            write!(
+                &stream,
+                "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: flatpak-rs\r\nAccept-Encoding: identity\r\nConnection: close\r\n\r\n",
+                request_path, host, port
+            )
+            .map_err(io_error)?;
+
+            let mut reader = io::BufReader::new(stream);
+            let (status, headers) = read_http_head(&mut reader).map_err(io_error)?;
+            let get_header = |name: &str| headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+
+            if (300..400).contains(&status) {
+                if let Some(location) = get_header("location") {
+                    current_url = resolve_location(&host, port, &request_path, location);
+                    continue;
+                }
+            }
+            if status != 200 {
+                return Err(FlatpakError::download(
+                    &current_url,
+                    &format!("the server returned HTTP status {}", status),
+                ));
+            }
+
+            if get_header("transfer-encoding").map(|e| e.eq_ignore_ascii_case("chunked")) == Some(true) {
+                copy_chunked(&mut reader, writer, progress).map_err(io_error)?;
+                return Ok(());
+            }
+            let total_size = get_header("content-length").and_then(|l| l.parse::<u64>().ok());
+            let downloaded_size = match total_size {
+                Some(total_size) => {
+                    copy_with_progress(&mut reader.take(total_size), writer, Some(total_size), progress)
+                }
+                None => copy_with_progress(&mut reader, writer, None, progress),
+            }
+            .map_err(io_error)?;
+            if let Some(total_size) = total_size {
+                if downloaded_size != total_size {
+                    return Err(FlatpakError::download(
+                        &current_url,
+                        &format!("expected {} bytes, got {} bytes", total_size, downloaded_size),
+                    ));
+                }
+            }
+            return Ok(());
+        }
+        Err(FlatpakError::download(url, "too many redirections"))
+    }
+}
+
+/// Splits an `http://` url into its host, port and request path.
+fn parse_http_url(url: &str) -> Result<(String, u16, String), FlatpakError> {
+    let url_without_scheme = match url.strip_prefix("http://") {
+        Some(u) => u,
+        None => return Err(FlatpakError::download(url, "only http urls are supported")),
+    };
+    let (authority, request_path) = match url_without_scheme.find('/') {
+        Some(i) => (&url_without_scheme[..i], &url_without_scheme[i..]),
+        None => (url_without_scheme, "/"),
+    };
+    let request_path = request_path.split('#').next().unwrap_or("/");
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(FlatpakError::download(url, "invalid port")),
+        },
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(FlatpakError::download(url, "missing host"));
+    }
+    Ok((host.to_string(), port, request_path.to_string()))
+}
+
+/// Resolves the location of a redirection, relative to the url of the request.
+fn resolve_location(host: &str, port: u16, request_path: &str, location: &str) -> String {
+    if location.contains("://") {
+        return location.to_string();
+    }
+    let location_path = match location.starts_with('/') {
+        true => location.to_string(),
+        false => {
+            let request_dir = match request_path.split('?').next().unwrap_or("/").rfind('/') {
+                Some(i) => &request_path[..=i],
+                None => "/",
+            };
+            format!("{}{}", request_dir, location)
+        }
+    };
+
+    // Remove the `.` and `..` segments of the path.
+    let (location_path, query) = match location_path.split_once('?') {
+        Some((p, q)) => (p.to_string(), format!("?{}", q)),
+        None => (location_path, "".to_string()),
+    };
+    let mut segments: Vec<&str> = vec![];
+    for segment in location_path.split('/').skip(1) {
+        match segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    if location_path.ends_with("/.") || location_path.ends_with("/..") {
+        segments.push("");
+    }
+    format!("http://{}:{}/{}{}", host, port, segments.join("/"), query)
+}
+
+/// Reads the status code and the headers of an HTTP response. The names of the headers
+/// are converted to lowercase.
+fn read_http_head(reader: &mut dyn BufRead) -> io::Result<(u16, Vec<(String, String)>)> {
+    let invalid_response = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(invalid_response)?;
+
+    let mut headers = vec![];
+    loop {
+        let mut header_line = String::new();
+        if reader.read_line(&mut header_line)? == 0 {
+            return Err(invalid_response());
+        }
+        let header_line = header_line.trim_end();
+        if header_line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header_line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    Ok((status, headers))
+}
+
+/// Copies a body using the chunked transfer encoding.
+fn copy_chunked(
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> io::Result<u64> {
+    let invalid_chunk = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP chunk");
+    let mut downloaded_size: u64 = 0;
+    loop {
+        let mut chunk_header = String::new();
+        reader.read_line(&mut chunk_header)?;
+        let chunk_size = chunk_header.trim().split(';').next().unwrap_or("");
+        let chunk_size = u64::from_str_radix(chunk_size, 16).map_err(|_| invalid_chunk())?;
+        if chunk_size == 0 {
+            // Skip the trailers.
+            let mut trailer_line = String::new();
+            while reader.read_line(&mut trailer_line)? != 0 && !trailer_line.trim().is_empty() {
+                trailer_line.clear();
+            }
+            return Ok(downloaded_size);
+        }
+        let mut chunk_progress = |chunk_downloaded_size: u64, _: Option<u64>| {
+            progress(downloaded_size + chunk_downloaded_size, None)
+        };
+        let chunk_downloaded_size =
+            copy_with_progress(&mut reader.take(chunk_size), writer, None, &mut chunk_progress)?;
+        if chunk_downloaded_size != chunk_size {
+            return Err(invalid_chunk());
+        }
+        downloaded_size += chunk_size;
+        let mut chunk_end = String::new();
+        reader.read_line(&mut chunk_end)?;
+    }
+}
+
+fn copy_with_progress(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    total_size: Option<u64>,
+    progress: &mut dyn FnMut(u64, Option<u64>),
+) -> io::Result<u64> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut downloaded_size: u64 = 0;
+    progress(downloaded_size, total_size);
+    loop {
+        let read_size = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(downloaded_size),
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read_size])?;
+        downloaded_size += read_size as u64;
+        progress(downloaded_size, total_size);
+    }
+}
+
+/// Writes the downloaded content to the partial file while computing its checksums.
+struct DownloadWriter<'a> {
+    file: &'a mut fs::File,
+    hasher: &'a mut FlatpakChecksumHasher,
+}
+impl Write for DownloadWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written_size = self.file.write(buf)?;
+        self.hasher.update(&buf[..written_size]);
+        Ok(written_size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Returns `true` if the source is an archive, file or extra-data source with a url.
+pub fn is_fetchable(source: &FlatpakSource) -> bool {
+    let is_fetchable_type = matches!(
+        source.get_type(),
+        Some(FlatpakSourceType::Archive) | Some(FlatpakSourceType::File) | Some(FlatpakSourceType::ExtraData)
+    );
+    is_fetchable_type && source.url.is_some()
+}
+
+/// Checks that the file name of a url can be used as a file name in the download cache.
+fn is_valid_basename(basename: &str) -> bool {
+    !basename.is_empty()
+        && basename != "."
+        && basename != ".."
+        && !basename.contains(['/', '\\', '\0'])
+        && !basename.contains(path::MAIN_SEPARATOR)
+}
+
+/// Downloads the archive, file and extra-data sources into a download cache. The cache
+/// uses the same layout as the one of flatpak-builder, `downloads/<checksum>/<basename>`
+/// in the state directory, where the checksum is the strongest checksum of the source
+/// (usually the sha256), so that it can be shared with flatpak-builder.
+pub struct FlatpakFetcher {
+    /// The state directory, for example the `.flatpak-builder` directory used by
+    /// flatpak-builder.
+    pub state_dir: String,
+
+    /// The transports used to download the sources. The first transport supporting
+    /// a url is used.
+    pub transports: Vec<Box<dyn FlatpakTransport>>,
+
+    pub max_concurrent_fetches: usize,
+
+    pub progress_callback: Option<FlatpakFetchProgressCallback>,
+}
+impl FlatpakFetcher {
+    /// Creates a fetcher using the `file://` and `http://` transports.
+    pub fn new(state_dir: &str) -> FlatpakFetcher {
+        FlatpakFetcher {
+            state_dir: state_dir.to_string(),
+            transports: vec![
+                Box::new(FlatpakFileTransport::default()),
+                Box::new(FlatpakHttpTransport::default()),
+            ],
+            max_concurrent_fetches: DEFAULT_MAX_CONCURRENT_FETCHES,
+            progress_callback: None,
+        }
+    }
+
+    /// Adds a transport, which takes precedence over the transports already configured.
+    pub fn add_transport(&mut self, transport: Box<dyn FlatpakTransport>) {
+        self.transports.insert(0, transport);
+    }
+
+    pub fn set_progress_callback<F>(&mut self, progress_callback: F)
+    where
+        F: Fn(&FlatpakFetchProgress) + Send + Sync + 'static,
+    {
+        self.progress_callback = Some(Box::new(progress_callback));
+    }
+
+    /// Gets the path of the source in the download cache, which is laid out like the one of
+    /// flatpak-builder: `downloads/<sha256>/<basename>`. The source must have a sha256 checksum.
+    /// The path is always inside the downloads directory of the state directory.
+    pub fn get_download_path(&self, source: &FlatpakSource) -> Result<String, FlatpakError> {
+        let url = match &source.url {
+            Some(u) => u,
+            None => return Err(FlatpakError::invalid_source("The source does not have a url.")),
+        };
+        let checksum = match source
+            .get_checksums()
+            .into_iter()
+            .find(|(t, _)| *t == FlatpakChecksumType::SHA256)
+        {
+            Some((_, c)) => c,
+            None => {
+                return Err(FlatpakError::invalid_source(
+                    "The source does not have a sha256 checksum.",
+                ))
+            }
+        };
+        if !FlatpakChecksumType::SHA256.is_valid_digest(&checksum) {
+            return Err(FlatpakError::invalid_source(&format!(
+                "Invalid sha256 checksum {}.",
+                checksum
+            )));
+        }
+        let url_path = url.split(['?', '#']).next().unwrap_or("");
+        let basename = match url_path.rsplit('/').next() {
+            Some(b) if is_valid_basename(b) && url_path.contains("://") => b,
+            _ => {
+                return Err(FlatpakError::download(
+                    url,
+                    "could not get the file name from the url",
+                ))
+            }
+        };
+        let download_path = self.get_downloads_dir().join(checksum).join(basename);
+        Ok(download_path.to_string_lossy().to_string())
+    }
+
+    fn get_downloads_dir(&self) -> path::PathBuf {
+        path::Path::new(&self.state_dir).join(DOWNLOADS_DIR_NAME)
+    }
+
+    /// Checks that a path is inside the downloads directory, so that it can be removed.
+    fn is_in_downloads_dir(&self, file_path: &str) -> bool {
+        match path::Path::new(file_path).strip_prefix(self.get_downloads_dir()) {
+            Ok(p) => p.components().all(|c| matches!(c, path::Component::Normal(_))),
+            Err(_) => false,
+        }
+    }
+
+    /// Downloads a source into the download cache, if it is not already there, and returns
+    /// its path in the cache. The mirror urls are tried in order if the main url fails,
+    /// when the source type supports them.
+    pub fn fetch_source(&self, source: &FlatpakSource) -> Result<String, FlatpakError> {
+        if !is_fetchable(source) {
+            return Err(FlatpakError::invalid_source(
+                "Only the archive, file and extra-data sources with a url can be fetched.",
+            ));
+        }
+        let download_path = self.get_download_path(source)?;
+        if path::Path::new(&download_path).is_file() {
+            if source.verify_file(&download_path).is_ok() {
+                return Ok(download_path);
+            }
+            if !self.is_in_downloads_dir(&download_path) {
+                return Err(FlatpakError::invalid_source(&format!(
+                    "The download path {} is outside of the download cache.",
+                    download_path
+                )));
+            }
+            fs::remove_file(&download_path).map_err(|e| FlatpakError::Io {
+                path: download_path.to_string(),
+                source: e,
+            })?;
+        }
+
+        let mut urls = vec![source.url.clone().unwrap_or_default()];
+        if source.supports_mirror_urls() {
+            urls.append(&mut source.get_mirror_urls());
+        }
+        let mut errors = vec![];
+        for url in urls {
+            match self.fetch_url(source, &url, &download_path) {
+                Ok(()) => return Ok(download_path),
+                Err(e) => errors.push(e),
+            }
+        }
+        Err(FlatpakError::from_errors(errors).unwrap())
+    }
+
+    fn fetch_url(&self, source: &FlatpakSource, url: &str, download_path: &str) -> Result<(), FlatpakError> {
+        static PARTIAL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let transport = match self.transports.iter().find(|t| t.supports_url(url)) {
+            Some(t) => t,
+            None => return Err(FlatpakError::download(url, "the url scheme is not supported")),
+        };
+        let download_dir = path::Path::new(download_path).parent().unwrap();
+        fs::create_dir_all(download_dir).map_err(|e| FlatpakError::Io {
+            path: download_dir.to_string_lossy().to_string(),
+            source: e,
+        })?;
+
+        // Multiple fetchers can download the same file at the same time, so the partial
+        // files need unique names.
+        let partial_path = format!(
+            "{}.{}.{}.part",
+            download_path,
+            std::process::id(),
+            PARTIAL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let io_error = |e| FlatpakError::Io {
+            path: partial_path.to_string(),
+            source: e,
+        };
+        let mut partial_file = fs::File::create(&partial_path).map_err(io_error)?;
+        let mut hasher = source.get_checksum_hasher();
+        let mut writer = DownloadWriter {
+            file: &mut partial_file,
+            hasher: &mut hasher,
+        };
+        let mut progress = |downloaded_size: u64, total_size: Option<u64>| {
+            if let Some(progress_callback) = &self.progress_callback {
+                progress_callback(&FlatpakFetchProgress {
+                    url: url.to_string(),
+                    downloaded_size,
+                    total_size,
+                });
+            }
+        };
+
+        let fetch_result = transport
+            .fetch(url, &mut writer, &mut progress)
+            .and_then(|_| writer.flush().map_err(io_error))
+            .and_then(|_| source.verify_hasher(hasher, url))
+            .and_then(|_| fs::rename(&partial_path, download_path).map_err(io_error));
+        if fetch_result.is_err() {
+            let _ = fs::remove_file(&partial_path);
+        }
+        fetch_result
+    }
+
+    /// Fetches multiple sources, using up to `max_concurrent_fetches` threads. The results
+    /// are in the same order as the sources.
+    pub fn fetch_sources(&self, sources: &[&FlatpakSource]) -> Vec<Result<String, FlatpakError>> {
+        let next_source_index = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<Result<String, FlatpakError>>>> =
+            Mutex::new(sources.iter().map(|_| None).collect());
+        let workers_count = self.max_concurrent_fetches.max(1).min(sources.len());
+        std::thread::scope(|scope| {
+            for _ in 0..workers_count {
+                scope.spawn(|| loop {
+                    let source_index = next_source_index.fetch_add(1, Ordering::Relaxed);
+                    if source_index >= sources.len() {
+                        break;
+                    }
+                    let result = self.fetch_source(sources[source_index]);
+                    results.lock().unwrap()[source_index] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect()
+    }
+
+    /// Fetches all the archive, file and extra-data sources of the application that are built
+    /// for the architecture, including the sources of the nested modules. The disabled modules,
+    /// and the modules and sources excluded by `only-arches` or `skip-arches`, are not fetched.
+    /// The modules and sources defined in other files are not fetched.
+    pub fn fetch_application(
+        &self,
+        application: &FlatpakApplication,
+        arch: &FlatpakArchitecture,
+    ) -> Result<Vec<String>, FlatpakError> {
+        let application = application.filter_for_arch(arch);
+        let mut sources: Vec<&FlatpakSource> = vec![];
+        for module in application.get_all_modules_recursively() {
+            let module = match module {
+                FlatpakModuleItem::Description(m) => m,
+                FlatpakModuleItem::Path(_) => continue,
+            };
+            for source in &module.sources {
+                if let FlatpakSourceItem::Description(source) = source {
+                    if is_fetchable(source) {
+                        sources.push(source);
+                    }
+                }
+            }
+        }
+
+        let mut download_paths = vec![];
+        let mut errors = vec![];
+        for result in self.fetch_sources(&sources) {
+            match result {
+                Ok(p) => download_paths.push(p),
+                Err(e) => errors.push(e),
+            }
+        }
+        match FlatpakError::from_errors(errors) {
+            Some(e) => Err(e),
+            None => Ok(download_paths),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::FlatpakManifestFormat;
+    use crate::utils::TestDir;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    /// Serves the requests of the tests on a local port, and returns the url of the server.
+    fn start_http_server(requests_count: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming().take(requests_count) {
+                let mut stream = stream.unwrap();
+                let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header_line = String::from("-");
+                while !header_line.trim().is_empty() {
+                    header_line.clear();
+                    reader.read_line(&mut header_line).unwrap();
+                }
+                let response = match request_line.split_whitespace().nth(1).unwrap() {
+                    "/flatpak.txt" => "HTTP/1.1 200 OK\r\nContent-Length: 7\r\n\r\nflatpak".to_string(),
+                    "/chunked.txt" => {
+                        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nflat\r\n3\r\npak\r\n0\r\n\r\n"
+                            .to_string()
+                    }
+                    "/redirect/flatpak.txt" => "HTTP/1.1 302 Found\r\nLocation: ../flatpak.txt\r\n\r\n".to_string(),
+                    _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string(),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        server_url
+    }
+
+    fn get_file_source(source_type: &str, url: &str, mirror_urls: &[&str]) -> FlatpakSource {
+        let mut source = FlatpakSource::parse(
+            FlatpakManifestFormat::YAML,
+            &format!("type: {}\nurl: {}\nfilename: flatpak.txt\n", source_type, url),
+        )
+        .unwrap();
+        source.sha256 = Some(FlatpakChecksumType::SHA256.compute(b"flatpak"));
+        if !mirror_urls.is_empty() {
+            source.mirror_urls = Some(mirror_urls.iter().map(|u| u.to_string()).collect());
+        }
+        source
+    }
+
+    #[test]
+    pub fn test_fetch_file_urls() {
+        let test_dir = TestDir::new("fetch-files");
+        fs::write(test_dir.join("flatpak.txt"), "flatpak").unwrap();
+        let state_dir = test_dir.join(".flatpak-builder");
+        let file_url = format!("file://{}", test_dir.join("flatpak.txt").to_str().unwrap());
+        let missing_file_url = format!("file://{}", test_dir.join("missing.txt").to_str().unwrap());
+
+        let fetcher = FlatpakFetcher::new(state_dir.to_str().unwrap());
+        let source = get_file_source("file", &missing_file_url, &[&file_url]);
+        let download_path = fetcher.fetch_source(&source).unwrap();
+        assert_eq!(
+            download_path,
+            state_dir
+                .join("downloads")
+                .join(source.sha256.clone().unwrap())
+                .join("missing.txt")
+                .to_str()
+                .unwrap()
+        );
+        assert_eq!(fs::read_to_string(&download_path).unwrap(), "flatpak");
+
+        // The mirror urls are not supported by the extra-data sources.
+        let source = get_file_source("extra-data", &missing_file_url, &[&file_url]);
+        fs::remove_file(&download_path).unwrap();
+        assert!(matches!(
+            fetcher.fetch_source(&source),
+            Err(FlatpakError::Download { .. })
+        ));
+
+        // The cached files are not downloaded again.
+        let source = get_file_source("archive", &file_url, &[]);
+        let download_path = fetcher.fetch_source(&source).unwrap();
+        fs::remove_file(test_dir.join("flatpak.txt")).unwrap();
+        assert_eq!(fetcher.fetch_source(&source).unwrap(), download_path);
+
+        let mut source = get_file_source("file", &file_url, &[]);
+        source.sha256 = None;
+        assert!(matches!(
+            fetcher.fetch_source(&source),
+            Err(FlatpakError::InvalidSource { .. })
+        ));
+
+        // The cache is keyed on the sha256 checksum, even when stronger checksums are present.
+        let mut source = get_file_source("file", &file_url, &[]);
+        source.sha512 = Some(FlatpakChecksumType::SHA512.compute(b"flatpak"));
+        assert_eq!(
+            fetcher.get_download_path(&source).unwrap(),
+            state_dir
+                .join("downloads")
+                .join(source.sha256.clone().unwrap())
+                .join("flatpak.txt")
+                .to_str()
+                .unwrap()
+        );
+        source.sha256 = None;
+        assert!(matches!(
+            fetcher.get_download_path(&source),
+            Err(FlatpakError::InvalidSource { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_fetch_application() {
+        let test_dir = TestDir::new("fetch-application");
+        fs::write(test_dir.join("flatpak.txt"), "flatpak").unwrap();
+        let file_url = format!("file://{}", test_dir.join("flatpak.txt").to_str().unwrap());
+        let missing_file_url = format!("file://{}", test_dir.join("missing.txt").to_str().unwrap());
+        let checksum = FlatpakChecksumType::SHA256.compute(b"flatpak");
+        let application_manifest = format!(
+            r###"
+            app-id: org.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "44"
+            sdk: org.gnome.Sdk
+            modules:
+              - name: flatpak-rs
+                sources:
+                  - type: file
+                    url: {file_url}
+                    sha256: {checksum}
+                  - type: file
+                    only-arches: [aarch64]
+                    url: {missing_file_url}
+                    sha256: {checksum}
+                modules:
+                  - name: libfoo
+                    disabled: true
+                    sources:
+                      - type: file
+                        url: {missing_file_url}
+                        sha256: {checksum}
+              - name: libbar
+                skip-arches: [x86_64]
+                sources:
+                  - type: archive
+                    url: {missing_file_url}
+                    sha256: {checksum}
+            "###,
+            file_url = file_url,
+            missing_file_url = missing_file_url,
+            checksum = checksum,
+        );
+        let application =
+            FlatpakApplication::parse(FlatpakManifestFormat::YAML, &application_manifest).unwrap();
+        let fetcher = FlatpakFetcher::new(test_dir.join(".flatpak-builder").to_str().unwrap());
+        let download_paths = fetcher
+            .fetch_application(&application, &FlatpakArchitecture::X86)
+            .unwrap();
+        assert_eq!(download_paths.len(), 1);
+        assert_eq!(fs::read_to_string(&download_paths[0]).unwrap(), "flatpak");
+
+        assert!(fetcher
+            .fetch_application(&application, &FlatpakArchitecture::AARCH64)
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_get_hostile_download_path() {
+        let test_dir = TestDir::new("fetch-hostile");
+        let state_dir = test_dir.join(".flatpak-builder");
+        let victim_path = test_dir.join("victim.txt");
+        fs::write(&victim_path, "victim").unwrap();
+        let fetcher = FlatpakFetcher::new(state_dir.to_str().unwrap());
+
+        let victim_dir = test_dir.to_str().unwrap().to_string();
+        for checksum in [
+            victim_dir.to_string(),
+            format!("../../{}", "0".repeat(58)),
+            "../..".to_string(),
+            format!("{}/", "0".repeat(63)),
+        ] {
+            let mut source = get_file_source("file", "http://127.0.0.1:1/victim.txt", &[]);
+            source.sha256 = Some(checksum);
+            assert!(matches!(
+                fetcher.get_download_path(&source),
+                Err(FlatpakError::InvalidSource { .. })
+            ));
+            assert!(matches!(
+                fetcher.fetch_source(&source),
+                Err(FlatpakError::InvalidSource { .. })
+            ));
+        }
+        let mut source = get_file_source("file", "http://127.0.0.1:1/victim.txt", &[]);
+        source.sha256 = None;
+        source.md5 = Some(format!("../../../{}", victim_dir));
+        assert!(fetcher.fetch_source(&source).is_err());
+
+        for url in [
+            "http://127.0.0.1:1/..",
+            "http://127.0.0.1:1/.",
+            "http://127.0.0.1:1/a\\..\\b",
+        ] {
+            let source = get_file_source("file", url, &[]);
+            assert!(matches!(
+                fetcher.get_download_path(&source),
+                Err(FlatpakError::Download { .. })
+            ));
+        }
+        assert_eq!(fs::read_to_string(&victim_path).unwrap(), "victim");
+        assert!(!fetcher.is_in_downloads_dir(victim_path.to_str().unwrap()));
+        assert!(!fetcher.is_in_downloads_dir(state_dir.join("downloads/../../victim.txt").to_str().unwrap()));
+    }
+
+    #[test]
+    pub fn test_fetch_http_urls() {
+        let test_dir = TestDir::new("fetch-http");
+        let server_url = start_http_server(4);
+        let mut fetcher = FlatpakFetcher::new(test_dir.to_str().unwrap());
+        fetcher.max_concurrent_fetches = 2;
+        let progress_reports = Arc::new(Mutex::new(vec![]));
+        let callback_progress_reports = progress_reports.clone();
+        fetcher.set_progress_callback(move |p| callback_progress_reports.lock().unwrap().push(p.clone()));
+
+        let mut invalid_source = get_file_source("file", &format!("{}/flatpak.txt", server_url), &[]);
+        invalid_source.sha256 = Some(FlatpakChecksumType::SHA256.compute(b"flatpak-rs"));
+        let sources = [
+            get_file_source("archive", &format!("{}/redirect/flatpak.txt", server_url), &[]),
+            get_file_source("file", &format!("{}/chunked.txt", server_url), &[]),
+            invalid_source,
+        ];
+        let results = fetcher.fetch_sources(&sources.iter().collect::<Vec<&FlatpakSource>>());
+        assert_eq!(
+            fs::read_to_string(results[0].as_ref().unwrap()).unwrap(),
+            "flatpak"
+        );
+        assert_eq!(
+            fs::read_to_string(results[1].as_ref().unwrap()).unwrap(),
+            "flatpak"
+        );
+        assert!(matches!(
+            results[2],
+            Err(FlatpakError::ChecksumMismatch {
+                checksum_type: FlatpakChecksumType::SHA256,
+                ..
+            })
+        ));
+        assert!(!path::Path::new(&fetcher.get_download_path(&sources[2]).unwrap()).exists());
+        assert!(progress_reports.lock().unwrap().contains(&FlatpakFetchProgress {
+            url: format!("{}/flatpak.txt", server_url),
+            downloaded_size: 7,
+            total_size: Some(7),
+        }));
+    }
+}
@@ -1,11 +1,14 @@
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::error::{FlatpakError, FlatpakLocation};
+
 #[derive(Clone)]
 #[derive(Deserialize)]
 #[derive(Serialize)]
 #[derive(Hash)]
 #[derive(Debug)]
+#[derive(PartialEq)]
 /// All denominations of Flatpak manifests (app manifests, module manifests and source manifests)
 /// can use either YAML or JSON.
 pub enum FlatpakManifestFormat {
@@ -14,6 +17,22 @@ pub enum FlatpakManifestFormat {
     #[cfg(feature = "toml")]
     TOML,
 }
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+/// Options used when parsing manifests.
+pub struct FlatpakParseOptions {
+    /// Report the fields that are not supported by this library as errors, instead of
+    /// preserving them. Extension fields prefixed with `x-` are always accepted. The unknown
+    /// architectures of `only-arches` and `skip-arches` are also reported.
+    pub strict: bool,
+}
+impl FlatpakParseOptions {
+    pub fn strict() -> FlatpakParseOptions {
+        FlatpakParseOptions { strict: true }
+    }
+}
+
 impl Default for FlatpakManifestFormat {
     fn default() -> Self {
         FlatpakManifestFormat::YAML
@@ -34,32 +53,108 @@ impl FlatpakManifestFormat {
         None
     }
 
-    pub fn parse<T>(&self, content: &str) -> Result<T, String>
+    /// Detects the format of a manifest from its content. JSON is tried first, since
+    /// JSON documents are also valid YAML documents. Returns `None` if the content is
+    /// not a mapping or a sequence in any of the supported formats.
+    ///
+    /// ```
+    /// # use flatpak_rs::format::FlatpakManifestFormat;
+    /// assert_eq!(
+    ///     FlatpakManifestFormat::detect("{\"name\": \"flatpak-rs\"}"),
+    ///     Some(FlatpakManifestFormat::JSON)
+    /// );
+    /// assert_eq!(FlatpakManifestFormat::detect("name: flatpak-rs"), Some(FlatpakManifestFormat::YAML));
+    /// assert_eq!(FlatpakManifestFormat::detect("flatpak-rs"), None);
+    /// ```
+    pub fn detect(content: &str) -> Option<FlatpakManifestFormat> {
+        if let Ok(json_content_without_comments) = crate::utils::remove_comments_from_json(content) {
+            let json_content_start = json_content_without_comments.trim_start();
+            if (json_content_start.starts_with('{') || json_content_start.starts_with('['))
+                && serde_json::from_str::<serde_json::Value>(&json_content_without_comments).is_ok()
+            {
+                return Some(FlatpakManifestFormat::JSON);
+            }
+        }
+        #[cfg(feature = "toml")]
+        if let Ok(toml::Value::Table(table)) = toml::from_str::<toml::Value>(content) {
+            if !table.is_empty() {
+                return Some(FlatpakManifestFormat::TOML);
+            }
+        }
+        match serde_yaml::from_str::<serde_yaml::Value>(content) {
+            Ok(serde_yaml::Value::Mapping(_)) | Ok(serde_yaml::Value::Sequence(_)) => {
+                Some(FlatpakManifestFormat::YAML)
+            }
+            _ => None,
+        }
+    }
+
+    /// Gets the file extension conventionally used for this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FlatpakManifestFormat::YAML => "yaml",
+            FlatpakManifestFormat::JSON => "json",
+            #[cfg(feature = "toml")]
+            FlatpakManifestFormat::TOML => "toml",
+        }
+    }
+
+    pub fn parse<T>(&self, content: &str) -> Result<T, FlatpakError>
     where
         T: DeserializeOwned,
     {
+        let syntax_error =
+            |e: Box<dyn std::error::Error + Send + Sync>, line: usize, column: usize| FlatpakError::Syntax {
+                format: self.clone(),
+                source: e,
+                location: FlatpakLocation::from_line_column(self, content, line, column),
+            };
         match self {
-            FlatpakManifestFormat::YAML => serde_yaml::from_str::<T>(content).map_err(|e| e.to_string()),
+            FlatpakManifestFormat::YAML => serde_yaml::from_str::<T>(content).map_err(|e| {
+                let (line, column) = match e.location() {
+                    Some(l) => (l.line(), l.column()),
+                    None => (0, 0),
+                };
+                syntax_error(e.into(), line, column)
+            }),
             FlatpakManifestFormat::JSON => {
-                let json_content_without_comments = crate::utils::remove_comments_from_json(content);
-                serde_json::from_str::<T>(&json_content_without_comments).map_err(|e| e.to_string())
+                let json_content_without_comments = crate::utils::remove_comments_from_json(content)
+                    .map_err(|e| e.into_flatpak_error(self, content))?;
+                serde_json::from_str::<T>(&json_content_without_comments).map_err(|e| {
+                    let (line, column) = (e.line(), e.column());
+                    syntax_error(e.into(), line, column)
+                })
             }
             #[cfg(feature = "toml")]
-            FlatpakManifestFormat::TOML => toml::from_str::<T>(content).map_err(|e| e.to_string()),
+            FlatpakManifestFormat::TOML => toml::from_str::<T>(content).map_err(|e| {
+                let (line, column) = match e.line_col() {
+                    Some((line, column)) => (line + 1, column + 1),
+                    None => (0, 0),
+                };
+                syntax_error(e.into(), line, column)
+            }),
         }
     }
 
-    pub fn dump<T>(&self, manifest: &T) -> Result<String, String>
+    pub fn dump<T>(&self, manifest: &T) -> Result<String, FlatpakError>
     where
         T: Serialize,
     {
+        let dump_error = |e: Box<dyn std::error::Error + Send + Sync>| FlatpakError::Dump {
+            format: self.clone(),
+            source: e,
+        };
         match self {
-            FlatpakManifestFormat::YAML => serde_yaml::to_string::<T>(manifest).map_err(|e| e.to_string()),
+            FlatpakManifestFormat::YAML => {
+                serde_yaml::to_string::<T>(manifest).map_err(|e| dump_error(e.into()))
+            }
             FlatpakManifestFormat::JSON => {
-                serde_json::to_string_pretty::<T>(manifest).map_err(|e| e.to_string())
+                serde_json::to_string_pretty::<T>(manifest).map_err(|e| dump_error(e.into()))
             }
             #[cfg(feature = "toml")]
-            FlatpakManifestFormat::TOML => toml::to_string_pretty::<T>(manifest).map_err(|e| e.to_string()),
+            FlatpakManifestFormat::TOML => {
+                toml::to_string_pretty::<T>(manifest).map_err(|e| dump_error(e.into()))
+            }
         }
     }
 }
@@ -0,0 +1,725 @@
+//! Canonical formatting of Flatpak manifests.
+//!
+//! The formatter rewrites a manifest with its keys in a canonical order, loosely following the
+//! order in which the fields are documented in `man flatpak-manifest`, and with the indentation
+//! used when dumping manifests with this library. Fields that are not known by the formatter,
+//! including the `x-` extension fields, are kept in their original order after the known fields,
+//! but before the nested sources and modules.
+//!
+//! The YAML and JSON manifests that contain comments are reordered through their syntax tree
+//! instead, so that the comments are kept. The comments placed right before a field, or after
+//! it on the same line, are moved with the field. The indentation of those manifests is kept.
+use std::fs;
+use std::ops::Range;
+
+use serde_yaml::{Mapping, Value};
+
+use crate::error::FlatpakError;
+#[cfg(feature = "toml")]
+use crate::error::FlatpakLocation;
+use crate::format::FlatpakManifestFormat;
+use crate::manifest_type::FlatpakManifestType;
+use crate::syntax::{get_line_end, get_line_start, SyntaxEntry, SyntaxNode, SyntaxNodeKind};
+
+/// The canonical order of the fields in an application manifest.
+pub const APPLICATION_KEY_ORDER: &[&str] = &[
+    "id",
+    "app-id",
+    "app-name",
+    "branch",
+    "default-branch",
+    "collection-id",
+    "extension-tag",
+    "token-type",
+    "runtime",
+    "runtime-version",
+    "sdk",
+    "sdk-extensions",
+    "platform-extensions",
+    "base",
+    "base-version",
+    "base-extensions",
+    "inherit-extensions",
+    "inherit-sdk-extensions",
+    "build-runtime",
+    "build-extension",
+    "separate-locales",
+    "id-platform",
+    "metadata-platform",
+    "writable-sdk",
+    "appstream-compose",
+    "var",
+    "metadata",
+    "command",
+    "tags",
+    "rename-desktop-file",
+    "rename-appdata-file",
+    "rename-mime-file",
+    "rename-icon",
+    "rename-mime-icons",
+    "appdata-license",
+    "copy-icon",
+    "desktop-file-name-prefix",
+    "desktop-file-name-suffix",
+    "finish-args",
+    "add-extensions",
+    "add-build-extensions",
+    "cleanup",
+    "cleanup-commands",
+    "cleanup-platform",
+    "cleanup-platform-commands",
+    "prepare-platform-commands",
+    "build-options",
+];
+/// The fields of an application manifest that are placed after the unknown fields.
+pub const APPLICATION_TRAILING_KEYS: &[&str] = &["modules"];
+
+/// The canonical order of the fields in a module manifest.
+pub const MODULE_KEY_ORDER: &[&str] = &[
+    "name",
+    "disabled",
+    "buildsystem",
+    "builddir",
+    "subdir",
+    "only-arches",
+    "skip-arches",
+    "cmake",
+    "rm-configure",
+    "no-autogen",
+    "no-parallel-make",
+    "no-make-install",
+    "no-python-timestamp-fix",
+    "install-rule",
+    "config-opts",
+    "make-args",
+    "make-install-args",
+    "build-options",
+    "build-commands",
+    "post-install",
+    "run-tests",
+    "test-rule",
+    "test-commands",
+    "ensure-writable",
+    "cleanup",
+    "cleanup-platform",
+];
+/// The fields of a module manifest that are placed after the unknown fields.
+pub const MODULE_TRAILING_KEYS: &[&str] = &["sources", "modules"];
+
+/// The canonical order of the fields in a source manifest.
+pub const SOURCE_KEY_ORDER: &[&str] = &[
+    "type",
+    "only-arches",
+    "skip-arches",
+    "url",
+    "mirror-urls",
+    "path",
+    "paths",
+    "archive-type",
+    "dest-filename",
+    "filename",
+    "branch",
+    "tag",
+    "commit",
+    "revision",
+    "md5",
+    "sha1",
+    "sha256",
+    "sha512",
+    "size",
+    "installed-size",
+    "git-init",
+    "strip-components",
+    "disable-fsckobjects",
+    "disable-shallow-clone",
+    "disable-submodules",
+    "use-git",
+    "use-git-am",
+    "options",
+    "commands",
+    "contents",
+    "base64",
+    "skip",
+    "dest",
+    "x-checker-data",
+];
+
+/// The canonical order of the fields in build options. The same order is used for the
+/// architecture-specific build options.
+pub const BUILD_OPTIONS_KEY_ORDER: &[&str] = &[
+    "cflags",
+    "cflags-override",
+    "cppflags",
+    "cppflags-override",
+    "cxxflags",
+    "cxxflags-override",
+    "ldflags",
+    "ldflags-override",
+    "prefix",
+    "libdir",
+    "append-path",
+    "prepend-path",
+    "append-ld-library-path",
+    "prepend-ld-library-path",
+    "append-pkg-config-path",
+    "prepend-pkg-config-path",
+    "env",
+    "secret-env",
+    "build-args",
+    "test-args",
+    "config-opts",
+    "secret-opts",
+    "make-args",
+    "make-install-args",
+    "strip",
+    "no-debuginfo",
+    "no-debuginfo-compression",
+];
+/// The fields of build options that are placed after the unknown fields.
+pub const BUILD_OPTIONS_TRAILING_KEYS: &[&str] = &["arch"];
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum FlatpakNodeType {
+    Application,
+    Module,
+    Source,
+    BuildOptions,
+    /// The `arch` field of build options, which maps architectures to build options.
+    Arches,
+    Other,
+}
+
+impl FlatpakManifestFormat {
+    /// Rewrites a manifest in its canonical layout. The manifest can be an application,
+    /// module or source manifest. The output always ends with a newline.
+    ///
+    /// ```
+    /// # use flatpak_rs::format::FlatpakManifestFormat;
+    /// let manifest = r###"
+    ///     modules: []
+    ///     sdk: org.gnome.Sdk
+    ///     id: net.louib.flatpak-rs
+    /// "###;
+    /// let formatted_manifest = FlatpakManifestFormat::YAML.reformat(manifest).unwrap();
+    /// assert_eq!(formatted_manifest, "id: net.louib.flatpak-rs\nsdk: org.gnome.Sdk\nmodules: []\n");
+    /// ```
+    pub fn reformat(&self, manifest_content: &str) -> Result<String, FlatpakError> {
+        let mut manifest: Value = self.parse(manifest_content)?;
+        let node_type = match FlatpakManifestType::detect_from_value(&manifest) {
+            Some(FlatpakManifestType::Application) => FlatpakNodeType::Application,
+            Some(FlatpakManifestType::Module) => FlatpakNodeType::Module,
+            Some(FlatpakManifestType::Source) | Some(FlatpakManifestType::Sources) => FlatpakNodeType::Source,
+            None => FlatpakNodeType::Other,
+        };
+
+        let mut formatted_manifest = match self.get_commented_syntax_tree(manifest_content)? {
+            Some(root) => {
+                let edits = get_reorder_edits(manifest_content, &root, node_type, 0);
+                splice(manifest_content, 0..manifest_content.len(), &edits)
+            }
+            None => {
+                reorder_value(&mut manifest, node_type);
+                self.dump(&manifest)?
+            }
+        };
+        if !formatted_manifest.ends_with('\n') {
+            formatted_manifest.push('\n');
+        }
+        Ok(formatted_manifest)
+    }
+
+    /// Gets the syntax tree of a manifest if it contains comments, in which case the manifest
+    /// is reordered through its syntax tree instead of being dumped again.
+    fn get_commented_syntax_tree(&self, manifest_content: &str) -> Result<Option<SyntaxNode>, FlatpakError> {
+        match self {
+            FlatpakManifestFormat::YAML | FlatpakManifestFormat::JSON => {
+                let root = SyntaxNode::parse(self, manifest_content)?;
+                if root.get_comments(self, manifest_content).is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(root))
+            }
+            // The syntax tree does not support TOML, so any `#` is assumed to start a comment,
+            // which would be lost.
+            #[cfg(feature = "toml")]
+            FlatpakManifestFormat::TOML => match manifest_content.find('#') {
+                Some(comment_start) => Err(FlatpakError::UnsupportedComment {
+                    location: FlatpakLocation::from_offset(self, manifest_content, comment_start),
+                }),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Returns `true` if the manifest is already in its canonical layout.
+    pub fn is_formatted(&self, manifest_content: &str) -> Result<bool, FlatpakError> {
+        Ok(self.reformat(manifest_content)? == manifest_content)
+    }
+}
+
+/// Formats the manifest file at `path` in place. If `check` is `true`, the file is never
+/// modified and the function only reports whether it is already formatted.
+///
+/// Returns `true` if the file is already formatted, `false` if it was (or, in check mode,
+/// would have been) rewritten.
+pub fn format_file(path: &str, check: bool) -> Result<bool, FlatpakError> {
+    let manifest_format = match FlatpakManifestFormat::from_path(path) {
+        Some(f) => f,
+        None => return Err(FlatpakError::UnsupportedExtension(path.to_string())),
+    };
+    let manifest_content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            return Err(FlatpakError::Io {
+                path: path.to_string(),
+                source: e,
+            })
+        }
+    };
+
+    let formatted_manifest = match manifest_format.reformat(&manifest_content) {
+        Ok(m) => m,
+        Err(e) => return Err(e.in_file(path.to_string())),
+    };
+    if formatted_manifest == manifest_content {
+        return Ok(true);
+    }
+    if !check {
+        if let Err(e) = fs::write(path, formatted_manifest) {
+            return Err(FlatpakError::Io {
+                path: path.to_string(),
+                source: e,
+            });
+        }
+    }
+    Ok(false)
+}
+
+fn reorder_value(value: &mut Value, node_type: FlatpakNodeType) {
+    match value {
+        Value::Sequence(items) => {
+            for item in items {
+                reorder_value(item, node_type);
+            }
+        }
+        Value::Mapping(mapping) => reorder_mapping(mapping, node_type),
+        _ => {}
+    }
+}
+
+fn reorder_mapping(mapping: &mut Mapping, node_type: FlatpakNodeType) {
+    if node_type == FlatpakNodeType::Other {
+        return;
+    }
+
+    let mut entries: Vec<(Value, Value)> = std::mem::take(mapping).into_iter().collect();
+    // The sort is stable, so the unknown fields keep their original order.
+    entries.sort_by_key(|(key, _)| get_key_rank(key.as_str().unwrap_or(""), node_type));
+
+    for (key, mut value) in entries {
+        reorder_value(
+            &mut value,
+            get_child_node_type(key.as_str().unwrap_or(""), node_type),
+        );
+        mapping.insert(key, value);
+    }
+}
+
+/// Gets the rank of a field in the canonical order. The fields with the same rank keep
+/// their original order.
+fn get_key_rank(key: &str, node_type: FlatpakNodeType) -> (usize, usize) {
+    let (key_order, trailing_keys): (&[&str], &[&str]) = match node_type {
+        FlatpakNodeType::Application => (APPLICATION_KEY_ORDER, APPLICATION_TRAILING_KEYS),
+        FlatpakNodeType::Module => (MODULE_KEY_ORDER, MODULE_TRAILING_KEYS),
+        FlatpakNodeType::Source => (SOURCE_KEY_ORDER, &[]),
+        FlatpakNodeType::BuildOptions => (BUILD_OPTIONS_KEY_ORDER, BUILD_OPTIONS_TRAILING_KEYS),
+        FlatpakNodeType::Arches | FlatpakNodeType::Other => return (1, 0),
+    };
+    if let Some(index) = key_order.iter().position(|k| *k == key) {
+        return (0, index);
+    }
+    if let Some(index) = trailing_keys.iter().position(|k| *k == key) {
+        return (2, index);
+    }
+    (1, 0)
+}
+
+fn get_child_node_type(key: &str, node_type: FlatpakNodeType) -> FlatpakNodeType {
+    match (node_type, key) {
+        (FlatpakNodeType::Other, _) => FlatpakNodeType::Other,
+        (FlatpakNodeType::Arches, _) => FlatpakNodeType::BuildOptions,
+        (_, "modules") => FlatpakNodeType::Module,
+        (_, "sources") => FlatpakNodeType::Source,
+        (_, "build-options") => FlatpakNodeType::BuildOptions,
+        (FlatpakNodeType::BuildOptions, "arch") => FlatpakNodeType::Arches,
+        _ => FlatpakNodeType::Other,
+    }
+}
+
+/// Gets the edits reordering the mappings of a syntax node, as byte ranges of `content`
+/// and their replacements. The comment lines found after `min_start` and right before the
+/// node belong to its first field.
+fn get_reorder_edits(
+    content: &str,
+    node: &SyntaxNode,
+    node_type: FlatpakNodeType,
+    min_start: usize,
+) -> Vec<(Range<usize>, String)> {
+    match &node.kind {
+        SyntaxNodeKind::Scalar { .. } => vec![],
+        SyntaxNodeKind::Sequence { items, .. } => items
+            .iter()
+            .flat_map(|item| get_reorder_edits(content, &item.value, node_type, item.start + 1))
+            .collect(),
+        SyntaxNodeKind::Mapping { flow, entries } => {
+            if node_type == FlatpakNodeType::Other || entries.is_empty() {
+                return vec![];
+            }
+            let child_edits: Vec<Vec<(Range<usize>, String)>> = entries
+                .iter()
+                .map(|e| {
+                    get_reorder_edits(
+                        content,
+                        &e.value,
+                        get_child_node_type(&e.key, node_type),
+                        e.colon_end,
+                    )
+                })
+                .collect();
+            let mut order: Vec<usize> = (0..entries.len()).collect();
+            order.sort_by_key(|i| get_key_rank(&entries[*i].key, node_type));
+            if order.iter().enumerate().all(|(position, i)| position == *i) {
+                return child_edits.concat();
+            }
+            vec![reorder_entries(
+                content,
+                node,
+                entries,
+                *flow,
+                &order,
+                &child_edits,
+                min_start,
+            )]
+        }
+    }
+}
+
+/// Reorders the entries of a mapping, and gets the edit replacing the mapping. When every
+/// entry starts its own line, the entries are moved with the comments preceding them and
+/// the comment ending their last line. Otherwise, only the entries are moved, and the text
+/// separating them is kept in place.
+fn reorder_entries(
+    content: &str,
+    node: &SyntaxNode,
+    entries: &[SyntaxEntry],
+    flow: bool,
+    order: &[usize],
+    child_edits: &[Vec<(Range<usize>, String)>],
+    min_start: usize,
+) -> (Range<usize>, String) {
+    let is_line_start = |position: usize| {
+        content[get_line_start(content, position)..position]
+            .trim()
+            .is_empty()
+    };
+    let last_entry = &entries[entries.len() - 1];
+    let is_line_layout = entries[1..].iter().all(|e| is_line_start(e.key_span.start))
+        && entries
+            .iter()
+            .all(|e| e.separator.iter().all(|s| *s < get_line_end(content, e.end())))
+        && (!flow || get_line_end(content, last_entry.end()) < node.span.end - 1);
+
+    if !is_line_layout {
+        let mut text = String::new();
+        for (position, index) in order.iter().enumerate() {
+            let entry = &entries[*index];
+            text.push_str(&splice(
+                content,
+                entry.key_span.start..entry.end(),
+                &child_edits[*index],
+            ));
+            if let Some(next_entry) = entries.get(position + 1) {
+                text.push_str(&content[entries[position].end()..next_entry.key_span.start]);
+            }
+        }
+        return (entries[0].key_span.start..last_entry.end(), text);
+    }
+
+    let first_key_start = entries[0].key_span.start;
+    let is_first_line_start = is_line_start(first_key_start);
+    let start = match is_first_line_start {
+        true => get_leading_comments_start(content, get_line_start(content, first_key_start), min_start),
+        false => first_key_start,
+    };
+    let second_key_start = entries[1].key_span.start;
+    let indent = &content[get_line_start(content, second_key_start)..second_key_start];
+
+    let mut lines: Vec<String> = vec![];
+    for (position, index) in order.iter().enumerate() {
+        let entry = &entries[*index];
+        let line_start = get_line_start(content, entry.key_span.start);
+        let leading_text = match index {
+            0 if is_first_line_start => &content[start..line_start],
+            0 => "",
+            _ => &content[get_line_end(content, entries[index - 1].end()) + 1..line_start],
+        };
+        let mut edits = child_edits[*index].clone();
+        if flow {
+            if let Some(separator) = entry.separator {
+                edits.push((separator..separator + 1, String::new()));
+            }
+            if position + 1 < order.len() {
+                edits.push((entry.end()..entry.end(), ",".to_string()));
+            }
+        }
+        edits.sort_by_key(|(range, _)| (range.start, range.end));
+        let entry_text = splice(
+            content,
+            entry.key_span.start..get_line_end(content, entry.end()),
+            &edits,
+        );
+
+        if position == 0 && !is_first_line_start {
+            // The first entry follows the opening of its mapping or sequence item on the same
+            // line, so its comments are placed right after them.
+            match leading_text.is_empty() {
+                true => lines.push(entry_text),
+                false => lines.push(format!("{}{}{}", leading_text.trim_start(), indent, entry_text)),
+            }
+            continue;
+        }
+        lines.push(format!("{}{}{}", leading_text, indent, entry_text));
+    }
+    (start..get_line_end(content, last_entry.end()), lines.join("\n"))
+}
+
+/// Gets the start of the comment lines right before the line starting at `line_start`.
+/// The lines starting before `min_start` are never included.
+fn get_leading_comments_start(content: &str, line_start: usize, min_start: usize) -> usize {
+    let mut start = line_start;
+    while start > 0 {
+        let previous_line_start = get_line_start(content, start - 1);
+        if previous_line_start < min_start {
+            break;
+        }
+        let line = content[previous_line_start..start - 1].trim();
+        let is_comment =
+            line.starts_with('#') || line.starts_with("//") || (line.starts_with("/*") && line.ends_with("*/"));
+        if !is_comment {
+            break;
+        }
+        start = previous_line_start;
+    }
+    start
+}
+
+/// Gets `content[range]` with the edits applied. The edits are sorted and inside `range`.
+fn splice(content: &str, range: Range<usize>, edits: &[(Range<usize>, String)]) -> String {
+    let mut text = String::new();
+    let mut position = range.start;
+    for (edit_range, replacement) in edits {
+        text.push_str(&content[position..edit_range.start]);
+        text.push_str(replacement);
+        position = edit_range.end;
+    }
+    text.push_str(&content[position..range.end]);
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TestDir;
+
+    #[test]
+    pub fn test_reformat_yaml() {
+        let manifest = r###"
+modules:
+  - sources:
+      - sha256: 9cbddee5e8c87e43a5fe627a19cd2aa4c36552156eb4edcf6c5a30bd4934fe58
+        url: https://github.com/louib/flatpak-rs/archive/v0.18.0.tar.gz
+        type: archive
+      - shared-modules/linux-audio/lv2.json
+    x-team-owner: desktop
+    config-opts: [ "-Dtests=false" ]
+    buildsystem: meson
+    name: flatpak-rs
+  - shared-modules/linux-audio/lv2.json
+finish-args:
+    - --share=ipc
+    - --socket=x11
+x-run-args: [--verbose]
+command: flatpak-rs
+build-options:
+    arch:
+        aarch64:
+            ldflags: -Wl,--as-needed
+            cflags: -O1
+    cflags: -O2
+sdk: org.gnome.Sdk
+runtime-version: "3.36"
+runtime: org.gnome.Platform
+id: net.louib.flatpak-rs
+"###;
+        let expected_manifest = r###"id: net.louib.flatpak-rs
+runtime: org.gnome.Platform
+runtime-version: '3.36'
+sdk: org.gnome.Sdk
+command: flatpak-rs
+finish-args:
+- --share=ipc
+- --socket=x11
+build-options:
+  cflags: -O2
+  arch:
+    aarch64:
+      cflags: -O1
+      ldflags: -Wl,--as-needed
+x-run-args:
+- --verbose
+modules:
+- name: flatpak-rs
+  buildsystem: meson
+  config-opts:
+  - -Dtests=false
+  x-team-owner: desktop
+  sources:
+  - type: archive
+    url: https://github.com/louib/flatpak-rs/archive/v0.18.0.tar.gz
+    sha256: 9cbddee5e8c87e43a5fe627a19cd2aa4c36552156eb4edcf6c5a30bd4934fe58
+  - shared-modules/linux-audio/lv2.json
+- shared-modules/linux-audio/lv2.json
+"###;
+        let formatted_manifest = FlatpakManifestFormat::YAML.reformat(manifest).unwrap();
+        assert_eq!(formatted_manifest, expected_manifest);
+        assert!(!FlatpakManifestFormat::YAML.is_formatted(manifest).unwrap());
+        assert!(FlatpakManifestFormat::YAML
+            .is_formatted(&formatted_manifest)
+            .unwrap());
+    }
+
+    #[test]
+    pub fn test_reformat_json() {
+        let manifest = r###"{
+            "sources": [{"url": "https://github.com/louib/flatpak-rs.git", "type": "git", "tag": "v0.18.0"}],
+            "buildsystem": "simple",
+            "name": "flatpak-rs"
+        }"###;
+        let expected_manifest = r###"{
+  "name": "flatpak-rs",
+  "buildsystem": "simple",
+  "sources": [
+    {
+      "type": "git",
+      "url": "https://github.com/louib/flatpak-rs.git",
+      "tag": "v0.18.0"
+    }
+  ]
+}
+"###;
+        let formatted_manifest = FlatpakManifestFormat::JSON.reformat(manifest).unwrap();
+        assert_eq!(formatted_manifest, expected_manifest);
+        assert!(FlatpakManifestFormat::JSON
+            .is_formatted(&formatted_manifest)
+            .unwrap());
+    }
+
+    #[test]
+    pub fn test_reformat_with_comments() {
+        let manifest = r###"# The flatpak-rs module.
+
+sources:
+  # The sources are fetched from git.
+  - url: https://github.com/louib/flatpak-rs.git#main
+    type: git # Not an archive.
+buildsystem: meson
+build-options:
+  cflags: -O2
+
+  # Only for debugging.
+  no-debuginfo: true
+  arch: { aarch64: { ldflags: "-Wl,--as-needed", cflags: -O1 } }
+  # Kept before the architectures.
+  env: { V: "1" }
+name: flatpak-rs # The name.
+"###;
+        let expected_manifest = r###"# The flatpak-rs module.
+
+name: flatpak-rs # The name.
+buildsystem: meson
+build-options:
+  cflags: -O2
+  # Kept before the architectures.
+  env: { V: "1" }
+
+  # Only for debugging.
+  no-debuginfo: true
+  arch: { aarch64: { cflags: -O1, ldflags: "-Wl,--as-needed" } }
+sources:
+  # The sources are fetched from git.
+  - type: git # Not an archive.
+    url: https://github.com/louib/flatpak-rs.git#main
+"###;
+        let formatted_manifest = FlatpakManifestFormat::YAML.reformat(manifest).unwrap();
+        assert_eq!(formatted_manifest, expected_manifest);
+        assert!(FlatpakManifestFormat::YAML
+            .is_formatted(&formatted_manifest)
+            .unwrap());
+
+        let manifest = r###"{
+    /* The name. */
+    "name": "flatpak-rs",
+    "sources": [
+        { "url": "https://github.com/louib/flatpak-rs.git", "type": "git" }
+    ],
+    "buildsystem": "simple" // No build system.
+}
+"###;
+        let expected_manifest = r###"{
+    /* The name. */
+    "name": "flatpak-rs",
+    "buildsystem": "simple", // No build system.
+    "sources": [
+        { "type": "git", "url": "https://github.com/louib/flatpak-rs.git" }
+    ]
+}
+"###;
+        let formatted_manifest = FlatpakManifestFormat::JSON.reformat(manifest).unwrap();
+        assert_eq!(formatted_manifest, expected_manifest);
+        assert!(FlatpakManifestFormat::JSON
+            .is_formatted(&formatted_manifest)
+            .unwrap());
+        assert_eq!(
+            FlatpakManifestFormat::JSON
+                .parse::<Value>(&formatted_manifest)
+                .unwrap(),
+            FlatpakManifestFormat::JSON.parse::<Value>(manifest).unwrap()
+        );
+
+        let manifest = "{\"name\": \"flatpak-rs\", \"build-commands\": [\"echo '# /* not a comment */'\"]}";
+        assert!(FlatpakManifestFormat::JSON.reformat(manifest).is_ok());
+    }
+
+    #[test]
+    pub fn test_format_file() {
+        let test_dir = TestDir::new("format-file");
+        let manifest_path = test_dir.join("manifest.json");
+        let manifest_path = manifest_path.to_str().unwrap();
+        fs::write(
+            manifest_path,
+            r#"[{"path": "flatpak-rs.patch", "type": "patch"}]"#,
+        )
+        .unwrap();
+
+        assert!(!format_file(manifest_path, true).unwrap());
+        assert!(!format_file(manifest_path, false).unwrap());
+        assert!(format_file(manifest_path, true).unwrap());
+        assert_eq!(
+            fs::read_to_string(manifest_path).unwrap(),
+            "[\n  {\n    \"type\": \"patch\",\n    \"path\": \"flatpak-rs.patch\"\n  }\n]\n"
+        );
+    }
+}
@@ -5,10 +5,22 @@ mod utils;
 pub mod application;
 pub mod architecture;
 pub mod archive;
+pub mod build_env;
+pub mod build_plan;
 pub mod build_system;
+pub mod checksum;
+pub mod cleanup;
+pub mod document;
+pub mod error;
+pub mod extra_fields;
+pub mod extract;
+pub mod fetch;
 pub mod filename;
 pub mod format;
+pub mod formatter;
+pub mod manifest;
 pub mod manifest_type;
 pub mod module;
 pub mod reverse_dns;
 pub mod source;
+pub mod syntax;
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::path;
+
+use crate::application::FlatpakApplication;
+use crate::error::FlatpakError;
+use crate::format::FlatpakManifestFormat;
+use crate::manifest_type::FlatpakManifestType;
+use crate::module::FlatpakModule;
+use crate::source::FlatpakSource;
+
+#[derive(Clone)]
+#[derive(Debug)]
+/// A parsed Flatpak manifest of any type. Source manifests can contain a single source
+/// or an array of sources, which are kept apart so that they can be dumped in the same shape.
+pub enum FlatpakManifest {
+    Application(Box<FlatpakApplication>),
+    Module(Box<FlatpakModule>),
+    Source(Box<FlatpakSource>),
+    Sources(Vec<FlatpakSource>),
+}
+impl FlatpakManifest {
+    pub fn get_type(&self) -> FlatpakManifestType {
+        match self {
+            FlatpakManifest::Application(_) => FlatpakManifestType::Application,
+            FlatpakManifest::Module(_) => FlatpakManifestType::Module,
+            FlatpakManifest::Source(_) => FlatpakManifestType::Source,
+            FlatpakManifest::Sources(_) => FlatpakManifestType::Sources,
+        }
+    }
+
+    /// Loads a manifest of any type. The format is detected from the file extension, or
+    /// from the content of the file if the extension is not supported.
+    pub fn load_from_file(path: String) -> Result<FlatpakManifest, FlatpakError> {
+        let file_path = path::Path::new(&path);
+        if !file_path.is_file() {
+            return Err(FlatpakError::Io {
+                path,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file"),
+            });
+        }
+
+        let manifest_content = match fs::read_to_string(file_path) {
+            Ok(content) => content,
+            Err(e) => return Err(FlatpakError::Io { path, source: e }),
+        };
+
+        let manifest_format = match FlatpakManifestFormat::from_path(&path) {
+            Some(f) => f,
+            None => match FlatpakManifestFormat::detect(&manifest_content) {
+                Some(f) => f,
+                None => return Err(FlatpakError::UnknownFormat.in_file(path)),
+            },
+        };
+        match FlatpakManifest::parse(manifest_format, &manifest_content) {
+            Ok(m) => Ok(m),
+            Err(e) => Err(e.in_file(path)),
+        }
+    }
+
+    /// Parses a manifest of any type, detecting its type from its content.
+    pub fn parse(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+    ) -> Result<FlatpakManifest, FlatpakError> {
+        let manifest: serde_yaml::Value = format.parse(manifest_content)?;
+        match FlatpakManifestType::detect_from_value(&manifest) {
+            Some(FlatpakManifestType::Application) => Ok(FlatpakManifest::Application(Box::new(
+                FlatpakApplication::parse(format, manifest_content)?,
+            ))),
+            Some(FlatpakManifestType::Module) => Ok(FlatpakManifest::Module(Box::new(FlatpakModule::parse(
+                format,
+                manifest_content,
+            )?))),
+            Some(FlatpakManifestType::Source) => Ok(FlatpakManifest::Source(Box::new(FlatpakSource::parse(
+                format,
+                manifest_content,
+            )?))),
+            Some(FlatpakManifestType::Sources) => Ok(FlatpakManifest::Sources(FlatpakSource::parse_many(
+                format,
+                manifest_content,
+            )?)),
+            None => Err(FlatpakError::UnknownManifestType),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_detect_format() {
+        let json_manifest = r###"
+            // A JSON manifest with comments.
+            {
+                "id": "net.louib.flatpak-rs",
+                "sdk": "org.gnome.Sdk"
+            }
+        "###;
+        assert_eq!(
+            FlatpakManifestFormat::detect(json_manifest),
+            Some(FlatpakManifestFormat::JSON)
+        );
+        assert_eq!(
+            FlatpakManifestFormat::detect("[{\"type\": \"file\", \"path\": \"data.txt\"}]"),
+            Some(FlatpakManifestFormat::JSON)
+        );
+        assert_eq!(
+            FlatpakManifestFormat::detect("# A YAML manifest.\nid: net.louib.flatpak-rs\n"),
+            Some(FlatpakManifestFormat::YAML)
+        );
+        assert_eq!(
+            FlatpakManifestFormat::detect("- type: file\n  path: data.txt\n"),
+            Some(FlatpakManifestFormat::YAML)
+        );
+        assert_eq!(
+            FlatpakManifestFormat::detect("{ name: flatpak-rs, sources: [] }"),
+            Some(FlatpakManifestFormat::YAML)
+        );
+        assert_eq!(FlatpakManifestFormat::detect(""), None);
+        assert_eq!(FlatpakManifestFormat::detect("{ \"id\": "), None);
+    }
+
+    #[test]
+    pub fn test_detect_manifest_type() {
+        let application_manifest = r###"
+            app-id: net.louib.flatpak-rs
+            runtime: org.gnome.Platform
+            runtime-version: "3.36"
+            sdk: org.gnome.Sdk
+            modules: []
+        "###;
+        let manifest = FlatpakManifestType::detect(application_manifest).unwrap();
+        assert_eq!(manifest.get_type(), FlatpakManifestType::Application);
+
+        let module_manifest = r###"{
+            "name": "flatpak-rs",
+            "sources": [{"type": "git", "url": "https://github.com/louib/flatpak-rs.git"}]
+        }"###;
+        match FlatpakManifestType::detect(module_manifest).unwrap() {
+            FlatpakManifest::Module(module) => {
+                assert_eq!(module.name, "flatpak-rs");
+                assert_eq!(module.format, FlatpakManifestFormat::JSON);
+            }
+            m => panic!("Expected a module manifest, got {:?}", m.get_type()),
+        }
+
+        let source_manifest = "type: file\npath: data.txt\n";
+        match FlatpakManifestType::detect(source_manifest).unwrap() {
+            FlatpakManifest::Source(source) => {
+                assert_eq!(source.path, Some("data.txt".to_string()));
+                assert_eq!(
+                    FlatpakManifestFormat::YAML.dump(&source).unwrap(),
+                    source_manifest
+                );
+            }
+            m => panic!("Expected a single source manifest, got {:?}", m.get_type()),
+        }
+
+        let sources_manifest = r###"[
+            {"type": "file", "path": "data.txt"},
+            {"type": "patch", "path": "fix.patch"}
+        ]"###;
+        let manifest = FlatpakManifestType::detect(sources_manifest).unwrap();
+        assert_eq!(manifest.get_type(), FlatpakManifestType::Sources);
+        match manifest {
+            FlatpakManifest::Sources(sources) => assert_eq!(sources.len(), 2),
+            m => panic!("Expected a source array manifest, got {:?}", m.get_type()),
+        }
+        let sources_manifest = "- type: file\n  path: data.txt\n";
+        match FlatpakManifestType::detect(sources_manifest).unwrap() {
+            FlatpakManifest::Sources(sources) => {
+                assert_eq!(sources.len(), 1);
+                assert_eq!(
+                    FlatpakManifestFormat::YAML.dump(&sources).unwrap(),
+                    sources_manifest
+                );
+            }
+            m => panic!("Expected a source array manifest, got {:?}", m.get_type()),
+        }
+        assert_eq!(
+            FlatpakManifestType::from_string("sources").unwrap(),
+            FlatpakManifestType::Sources
+        );
+        assert_eq!(FlatpakManifestType::Sources.to_string(), "sources");
+
+        assert!(matches!(
+            FlatpakManifestType::detect("cleanup: [\"/include\"]"),
+            Err(FlatpakError::UnknownManifestType)
+        ));
+        assert!(matches!(
+            FlatpakManifestType::detect("flatpak-rs"),
+            Err(FlatpakError::UnknownFormat)
+        ));
+        // The manifest type is detected, but the manifest is not valid.
+        assert!(matches!(
+            FlatpakManifestType::detect("name: flatpak-rs"),
+            Err(FlatpakError::MissingField { .. })
+        ));
+    }
+}
@@ -1,29 +1,43 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
+use crate::error::FlatpakError;
+use crate::format::FlatpakManifestFormat;
+use crate::manifest::FlatpakManifest;
+
 pub const APPLICATION: &str = "application";
 pub const MODULE: &str = "module";
 pub const SOURCE: &str = "source";
+pub const SOURCES: &str = "sources";
 
 /// All the Flatpak manifest types supported.
 #[derive(Clone)]
 #[derive(Deserialize)]
 #[derive(Serialize)]
 #[derive(Debug)]
+#[derive(PartialEq)]
 pub enum FlatpakManifestType {
     Application,
     Module,
+    /// A manifest containing a single source.
     Source,
+    /// A manifest containing an array of sources.
+    Sources,
 }
-impl FlatpakManifestType {
-    pub fn to_string(&self) -> String {
-        match &self {
-            FlatpakManifestType::Application => APPLICATION.to_string(),
-            FlatpakManifestType::Module => MODULE.to_string(),
-            FlatpakManifestType::Source => SOURCE.to_string(),
-        }
+impl fmt::Display for FlatpakManifestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let manifest_type = match &self {
+            FlatpakManifestType::Application => APPLICATION,
+            FlatpakManifestType::Module => MODULE,
+            FlatpakManifestType::Source => SOURCE,
+            FlatpakManifestType::Sources => SOURCES,
+        };
+        write!(f, "{}", manifest_type)
     }
-
-    pub fn from_string(manifest_type: &str) -> Result<FlatpakManifestType, String> {
+}
+impl FlatpakManifestType {
+    pub fn from_string(manifest_type: &str) -> Result<FlatpakManifestType, FlatpakError> {
         if manifest_type == APPLICATION {
             return Ok(FlatpakManifestType::Application);
         }
@@ -33,6 +47,55 @@ impl FlatpakManifestType {
         if manifest_type == SOURCE {
             return Ok(FlatpakManifestType::Source);
         }
-        Err(format!("Invalid manifest type {}.", manifest_type))
+        if manifest_type == SOURCES {
+            return Ok(FlatpakManifestType::Sources);
+        }
+        Err(FlatpakError::invalid_value("manifest type", manifest_type))
+    }
+
+    /// Detects the format and the type of a manifest from its content, and parses it.
+    ///
+    /// ```
+    /// # use flatpak_rs::manifest::FlatpakManifest;
+    /// # use flatpak_rs::manifest_type::FlatpakManifestType;
+    /// let manifest = r###"
+    ///     name: flatpak-rs
+    ///     sources:
+    ///       - type: git
+    ///         url: https://github.com/louib/flatpak-rs.git
+    /// "###;
+    /// match FlatpakManifestType::detect(manifest).unwrap() {
+    ///     FlatpakManifest::Module(module) => assert_eq!(module.name, "flatpak-rs"),
+    ///     _ => panic!("Expected a module manifest."),
+    /// }
+    /// ```
+    pub fn detect(manifest_content: &str) -> Result<FlatpakManifest, FlatpakError> {
+        let format = match FlatpakManifestFormat::detect(manifest_content) {
+            Some(f) => f,
+            None => return Err(FlatpakError::UnknownFormat),
+        };
+        FlatpakManifest::parse(format, manifest_content)
+    }
+
+    /// Detects the type of a manifest from the fields of its root node. An array
+    /// is detected as a manifest containing an array of sources.
+    pub fn detect_from_value(manifest: &serde_yaml::Value) -> Option<FlatpakManifestType> {
+        let mapping = match manifest {
+            serde_yaml::Value::Mapping(m) => m,
+            serde_yaml::Value::Sequence(_) => return Some(FlatpakManifestType::Sources),
+            _ => return None,
+        };
+        for application_field in ["id", "app-id", "runtime", "sdk"] {
+            if mapping.contains_key(application_field) {
+                return Some(FlatpakManifestType::Application);
+            }
+        }
+        if mapping.contains_key("type") {
+            return Some(FlatpakManifestType::Source);
+        }
+        if mapping.contains_key("name") {
+            return Some(FlatpakManifestType::Module);
+        }
+        None
     }
 }
@@ -1,14 +1,23 @@
 use std::collections::BTreeMap;
-use std::ffi::OsStr;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::path;
 use std::process::Command;
 
-use serde::{Deserialize, Serialize};
+use indexmap::IndexMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::architecture::{get_unknown_arch_errors, FlatpakArchitecture};
+use crate::build_env::{FlatpakBuildContext, FlatpakBuildEnv};
+use crate::build_plan::{FlatpakBuildPlan, FlatpakBuildStep, FlatpakBuildStepKind};
 use crate::build_system::FlatpakBuildSystem;
-use crate::format::FlatpakManifestFormat;
-use crate::source::{FlatpakSourceItem, FlatpakSourceType};
+use crate::error::FlatpakError;
+use crate::extra_fields::FlatpakExtraFields;
+use crate::format::{FlatpakManifestFormat, FlatpakParseOptions};
+use crate::manifest_type::FlatpakManifestType;
+use crate::source::{FlatpakSource, FlatpakSourceItem, FlatpakSourceType};
+use crate::syntax::FlatpakManifestPath;
 
 #[derive(Clone)]
 #[derive(Deserialize)]
@@ -40,6 +49,11 @@ pub struct FlatpakModule {
     #[serde(skip_serializing)]
     pub format: FlatpakManifestFormat,
 
+    /// The path of the manifest file this module was defined in, if known.
+    /// This is only populated when resolving external modules.
+    #[serde(skip)]
+    pub manifest_path: Option<String>,
+
     /// The name of the module, used in e.g. build logs. The name is also
     /// used for constructing filenames and commandline arguments,
     /// therefore using spaces or '/' in this string is a bad idea.
@@ -156,8 +170,8 @@ pub struct FlatpakModule {
 
     /// The target to build when running the tests. Defaults to "check" for make and "test" for ninja.
     /// Set to empty to disable.
-    #[serde(skip_serializing_if = "String::is_empty")]
-    pub test_rule: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test_rule: Option<String>,
 
     /// Array of commands to run during the tests.
     #[serde(skip_serializing_if = "Vec::is_empty")]
@@ -168,8 +182,45 @@ pub struct FlatpakModule {
     /// yaml file that contains a module.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub modules: Vec<FlatpakModuleItem>,
+
+    /// The fields that are not supported by this library, including the
+    /// extension fields prefixed with `x-`. They are preserved when dumping the manifest.
+    #[serde(flatten)]
+    pub extra_fields: FlatpakExtraFields,
 }
 impl FlatpakModule {
+    /// The names of the fields supported in module manifests.
+    pub const FIELD_NAMES: &'static [&'static str] = &[
+        "name",
+        "disabled",
+        "sources",
+        "config-opts",
+        "make-args",
+        "make-install-args",
+        "rm-configure",
+        "no-autogen",
+        "no-parallel-make",
+        "install-rule",
+        "no-make-install",
+        "no-python-timestamp-fix",
+        "cmake",
+        "buildsystem",
+        "builddir",
+        "subdir",
+        "build-options",
+        "build-commands",
+        "post-install",
+        "cleanup",
+        "ensure-writable",
+        "only-arches",
+        "skip-arches",
+        "cleanup-platform",
+        "run-tests",
+        "test-rule",
+        "test-commands",
+        "modules",
+    ];
+
     pub fn uses_external_data_checker(&self) -> bool {
         for source in &self.sources {
             let source_description = match source {
@@ -223,76 +274,215 @@ impl FlatpakModule {
         false
     }
 
-    pub fn load_from_file(path: String) -> Result<FlatpakModule, String> {
+    pub fn load_from_file(path: String) -> Result<FlatpakModule, FlatpakError> {
+        FlatpakModule::load_from_file_with_options(path, &FlatpakParseOptions::default())
+    }
+
+    pub fn load_from_file_with_options(
+        path: String,
+        options: &FlatpakParseOptions,
+    ) -> Result<FlatpakModule, FlatpakError> {
         let file_path = path::Path::new(&path);
         if !file_path.is_file() {
-            return Err(format!("{} is not a file.", path));
+            return Err(FlatpakError::Io {
+                path,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file"),
+            });
         }
 
         let manifest_format = match FlatpakManifestFormat::from_path(&path) {
             Some(f) => f,
-            None => return Err(format!("{} is not a Flatpak module manifest.", path)),
+            None => return Err(FlatpakError::UnsupportedExtension(path)),
         };
 
         let manifest_content = match fs::read_to_string(file_path) {
             Ok(content) => content,
-            Err(e) => {
-                return Err(format!("Could not read module manifest at {}: {}", &path, e));
-            }
+            Err(e) => return Err(FlatpakError::Io { path, source: e }),
         };
-        match FlatpakModule::parse(manifest_format, &manifest_content) {
+        match FlatpakModule::parse_with_options(manifest_format, &manifest_content, options) {
             Ok(m) => Ok(m),
-            Err(e) => Err(format!(
-                "Failed to parse Flatpak module manifest at {}: {}",
-                path, e
-            )),
+            Err(e) => Err(e.in_file(path)),
         }
     }
 
-    pub fn parse(format: FlatpakManifestFormat, manifest_content: &str) -> Result<FlatpakModule, String> {
-        let mut flatpak_module: FlatpakModule = match format.parse(manifest_content) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(format!("Failed to parse the Flatpak module manifest: {}.", e));
+    pub fn parse(format: FlatpakManifestFormat, manifest_content: &str) -> Result<FlatpakModule, FlatpakError> {
+        FlatpakModule::parse_with_options(format, manifest_content, &FlatpakParseOptions::default())
+    }
+
+    pub fn parse_with_options(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+        options: &FlatpakParseOptions,
+    ) -> Result<FlatpakModule, FlatpakError> {
+        let mut flatpak_module: FlatpakModule = format.parse(manifest_content)?;
+        let root_path = FlatpakManifestPath::default();
+
+        let mut errors = flatpak_module.get_validation_errors(&root_path);
+        if flatpak_module.sources.is_empty() {
+            errors.push((
+                root_path.clone(),
+                FlatpakError::missing_field(FlatpakManifestType::Module, "sources"),
+            ));
+        }
+        if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
+            return Err(e);
+        }
+
+        if options.strict {
+            let errors = flatpak_module.get_unknown_field_errors(&root_path);
+            if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
+                return Err(e);
             }
-        };
+        }
         flatpak_module.format = format;
+        Ok(flatpak_module)
+    }
 
-        if flatpak_module.name.is_empty() {
-            return Err("Required top-level field name is missing from Flatpak module.".to_string());
-        }
-        if flatpak_module.sources.is_empty() {
-            return Err("Required sources were not found in Flatpak module.".to_string());
+    /// Gets an error for each invalid field of the module, of its sources and of its nested
+    /// modules, with the path of the field. `path` is the path of the module in the manifest.
+    pub fn get_validation_errors(
+        &self,
+        path: &FlatpakManifestPath,
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        let mut errors = vec![];
+        if self.name.is_empty() {
+            errors.push((
+                path.clone(),
+                FlatpakError::missing_field(FlatpakManifestType::Module, "name"),
+            ));
         }
-        for source in &flatpak_module.sources {
-            let source_path = match source {
-                FlatpakSourceItem::Description(_) => continue,
-                FlatpakSourceItem::Path(p) => p,
-            };
-            // The string elements of the source array should only be FS paths, not
-            // URLs or anything else.
-            if source_path.starts_with("http://") || source_path.starts_with("https://") {
-                return Err("Sources provided as strings cannot be URLs!".to_string());
+        for (source_index, source) in self.sources.iter().enumerate() {
+            let source_path = path.join_key("sources").join_index(source_index);
+            match source {
+                FlatpakSourceItem::Path(p) => {
+                    // The string elements of the source array should only be FS paths, not
+                    // URLs or anything else.
+                    if p.starts_with("http://") || p.starts_with("https://") {
+                        errors.push((
+                            source_path,
+                            FlatpakError::invalid_source("Sources provided as strings cannot be URLs!"),
+                        ));
+                    }
+                }
+                FlatpakSourceItem::Description(d) => {
+                    if let Err(e) = d.is_valid() {
+                        errors.push((source_path, e));
+                    }
+                }
             }
         }
-        for source in &flatpak_module.sources {
-            let source_description = match source {
-                FlatpakSourceItem::Path(_) => continue,
-                FlatpakSourceItem::Description(d) => d,
-            };
-            if let Err(e) = source_description.is_valid() {
-                return Err(e);
+        errors.append(&mut get_module_items_validation_errors(
+            &self.modules,
+            &path.join_key("modules"),
+        ));
+        errors
+    }
+
+    /// Gets an error for each field of the module that is not supported by this library,
+    /// with the path of the field. `path` is the path of the module in the manifest.
+    pub fn get_unknown_field_errors(
+        &self,
+        path: &FlatpakManifestPath,
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        let mut errors = self
+            .extra_fields
+            .get_unknown_field_errors(path, FlatpakModule::FIELD_NAMES);
+        errors.append(&mut get_unknown_arch_errors(
+            &self.only_arches,
+            &path.join_key("only-arches"),
+        ));
+        errors.append(&mut get_unknown_arch_errors(
+            &self.skip_arches,
+            &path.join_key("skip-arches"),
+        ));
+        if let Some(build_options) = &self.build_options {
+            errors.append(&mut build_options.get_unknown_field_errors(&path.join_key("build-options")));
+        }
+        for (source_index, source) in self.sources.iter().enumerate() {
+            if let FlatpakSourceItem::Description(source_description) = source {
+                let source_path = path.join_key("sources").join_index(source_index);
+                errors.append(&mut source_description.get_unknown_field_errors(&source_path));
             }
         }
+        errors.append(&mut get_module_items_unknown_field_errors(
+            &self.modules,
+            &path.join_key("modules"),
+        ));
+        errors
+    }
+
+    pub fn dump(&self) -> Result<String, FlatpakError> {
+        self.format.dump(self)
+    }
+
+    /// Returns a copy of the module in which all the external modules and sources are
+    /// loaded and inlined. Paths are resolved relative to `manifest_path`, which should be
+    /// the path of the file the module was loaded from. Nested includes are resolved relative
+    /// to the file that contains them.
+    pub fn resolve(&self, manifest_path: &str) -> Result<FlatpakModule, FlatpakError> {
+        let mut include_stack: Vec<path::PathBuf> = vec![];
+        if let Ok(canonical_path) = fs::canonicalize(manifest_path) {
+            include_stack.push(canonical_path);
+        }
+        self.resolve_includes(manifest_path, &mut include_stack)
+    }
 
-        Ok(flatpak_module)
+    /// Moves the inline child modules of this module to their own manifest files, in `modules_dir`,
+    /// and replaces them with paths to those files. `modules_dir` is relative to `manifest_path`,
+    /// which should be the path of the file this module is defined in.
+    ///
+    /// If `module_names` is provided, only the child modules with those names are moved.
+    /// The new files use the format of this module, unless another `format` is requested.
+    /// The files are not written to disk, and are returned instead.
+    pub fn externalize_modules(
+        &mut self,
+        manifest_path: &str,
+        modules_dir: &str,
+        module_names: Option<Vec<String>>,
+        format: Option<FlatpakManifestFormat>,
+    ) -> Result<Vec<FlatpakManifestFile>, FlatpakError> {
+        let format = format.unwrap_or_else(|| self.format.clone());
+        externalize_module_items(
+            &mut self.modules,
+            manifest_path,
+            modules_dir,
+            module_names,
+            format,
+        )
     }
 
-    pub fn dump(&self) -> Result<String, String> {
-        match self.format.dump(self) {
-            Ok(d) => Ok(d),
-            Err(e) => return Err(format!("Failed to dump the Flatpak manifest: {}.", e)),
+    fn resolve_includes(
+        &self,
+        manifest_path: &str,
+        include_stack: &mut Vec<path::PathBuf>,
+    ) -> Result<FlatpakModule, FlatpakError> {
+        let base_dir = get_base_dir(manifest_path);
+
+        let mut resolved_module = self.clone();
+        resolved_module.manifest_path = Some(manifest_path.to_string());
+        resolved_module.sources = vec![];
+        for source in &self.sources {
+            match source {
+                FlatpakSourceItem::Description(d) => {
+                    let mut source_description = d.clone();
+                    source_description.manifest_path = Some(manifest_path.to_string());
+                    resolved_module
+                        .sources
+                        .push(FlatpakSourceItem::Description(source_description));
+                }
+                FlatpakSourceItem::Path(p) => {
+                    let source_path = base_dir.join(p).to_string_lossy().to_string();
+                    for mut source_description in FlatpakSource::load_from_file(source_path.clone())? {
+                        source_description.manifest_path = Some(source_path.clone());
+                        resolved_module
+                            .sources
+                            .push(FlatpakSourceItem::Description(source_description));
+                    }
+                }
+            }
         }
+        resolved_module.modules = resolve_module_items(&self.modules, manifest_path, include_stack)?;
+        Ok(resolved_module)
     }
 
     pub fn file_path_matches(path: &str) -> bool {
@@ -382,6 +572,38 @@ impl FlatpakModule {
         all_modules
     }
 
+    /// Returns `true` if the module is built for the architecture, i.e. if it is not disabled
+    /// and not excluded by `only-arches` or `skip-arches`.
+    pub fn is_built_for_arch(&self, arch: &FlatpakArchitecture) -> bool {
+        if self.disabled == Some(true) {
+            return false;
+        }
+        if !self.only_arches.is_empty() && !self.only_arches.iter().any(|a| arch.is_named(a)) {
+            return false;
+        }
+        !self.skip_arches.iter().any(|a| arch.is_named(a))
+    }
+
+    /// Returns a copy of the module as it is built for the architecture, or `None` if the module
+    /// is not built for the architecture. The nested modules and the sources that are not built
+    /// for the architecture are removed, and the architecture-specific build options are resolved.
+    ///
+    /// External modules and sources are kept as is, so they should be loaded with
+    /// [`FlatpakModule::resolve`] first.
+    pub fn filter_for_arch(&self, arch: &FlatpakArchitecture) -> Option<FlatpakModule> {
+        if !self.is_built_for_arch(arch) {
+            return None;
+        }
+        let mut filtered_module = self.clone();
+        filtered_module.sources.retain(|source| match source {
+            FlatpakSourceItem::Description(d) => d.is_built_for_arch(arch),
+            FlatpakSourceItem::Path(_) => true,
+        });
+        filtered_module.build_options = self.build_options.as_ref().map(|b| b.resolve_for_arch(arch));
+        filtered_module.modules = filter_module_items_for_arch(&self.modules, arch);
+        Some(filtered_module)
+    }
+
     /// A module is composite if it links to multiple software projects.
     /// This is determined by the type of the sources contained in the module.
     pub fn is_composite(&self) -> bool {
@@ -407,146 +629,890 @@ impl FlatpakModule {
         false
     }
 
-    /// Gets the build commands associated with a module.
-    pub fn get_commands<I, S>(
+    /// Gets the build system used to build the module. Modules without a `buildsystem` are
+    /// built with autotools, unless they use the deprecated `cmake` field.
+    pub fn get_build_system(&self) -> FlatpakBuildSystem {
+        if let Some(build_system) = &self.buildsystem {
+            return build_system.clone();
+        }
+        if self.cmake == Some(true) {
+            return FlatpakBuildSystem::CMake;
+        }
+        FlatpakBuildSystem::Autotools
+    }
+
+    /// Gets the plan to build and install the module from its source directory, using only the
+    /// build options of the module itself. Use
+    /// [`crate::application::FlatpakApplication::get_module_build_plan`] to also inherit the
+    /// build options of the application and of the enclosing modules.
+    pub fn get_build_plan(
         &self,
-        args: I,
-        reconfigure: bool,
-        root_path: &str,
-        build_path: &str,
-        out_path: Option<&str>,
-        num_cpus: i64,
-    ) -> Vec<Command>
-    where
-        I: IntoIterator<Item = S>,
-        S: AsRef<OsStr>,
-    {
-        let args: Vec<_> = args.into_iter().collect();
+        source_dir: &str,
+        context: &FlatpakBuildContext,
+    ) -> Result<FlatpakBuildPlan, FlatpakError> {
+        let build_options = match &self.build_options {
+            Some(b) => b.resolve_for_arch(&context.arch),
+            None => FlatpakBuildOptions::default(),
+        };
+        self.get_build_plan_with_build_options(source_dir, &build_options, context)
+    }
+
+    /// Gets the plan to build and install the module from its source directory, with the given
+    /// effective build options. The steps follow the ones run by flatpak-builder:
+    /// * the build happens in the `subdir` of the source directory, or in a `_flatpak_build`
+    ///   directory inside of it when `builddir` is set (or when using meson);
+    /// * with autotools, `configure` is removed when `rm-configure` is set, and generated by the
+    ///   first of `autogen`, `autogen.sh`, `bootstrap` and `bootstrap.sh` when it does not exist,
+    ///   unless `no-autogen` is set;
+    /// * the build runs in parallel unless `no-parallel-make` is set, and the install uses the
+    ///   `install-rule` unless `no-make-install` is set;
+    /// * the `post-install` commands are run after the install, and are followed by the tests
+    ///   when `run-tests` is set.
+    ///
+    /// The `config-opts`, `make-args` and `make-install-args` of the build options are passed after
+    /// the ones of the module, and every step of the plan is run in the environment computed by
+    /// [`FlatpakBuildEnv::from_build_options`].
+    pub fn get_build_plan_with_build_options(
+        &self,
+        source_dir: &str,
+        build_options: &FlatpakBuildOptions,
+        context: &FlatpakBuildContext,
+    ) -> Result<FlatpakBuildPlan, FlatpakError> {
+        let mut plan = FlatpakBuildPlan::default();
+        self.add_build_steps(&mut plan, source_dir, build_options, context)?;
+        let build_dir = self.get_build_dir(source_dir);
+        for post_install_command in &self.post_install {
+            plan.add_step(FlatpakBuildStep::shell(
+                FlatpakBuildStepKind::PostInstall,
+                post_install_command,
+                &build_dir,
+            ));
+        }
+        if self.run_tests == Some(true) {
+            self.add_test_steps(&mut plan, source_dir);
+        }
+
+        plan.set_env(&FlatpakBuildEnv::from_build_options(build_options, context));
+        for step in &mut plan.steps {
+            step.flatpak_build_args = match step.kind {
+                FlatpakBuildStepKind::Test => build_options.test_args.clone(),
+                _ => build_options.build_args.clone(),
+            };
+        }
+        Ok(plan)
+    }
+
+    /// Gets the directory in which the module is built, from the directory in which its
+    /// sources are extracted.
+    fn get_build_dir(&self, source_dir: &str) -> String {
+        let source_subdir = self.get_source_subdir(source_dir);
+        if self.uses_builddir() {
+            return format!("{}/{}", source_subdir, BUILDDIR_NAME);
+        }
+        source_subdir
+    }
+
+    fn get_source_subdir(&self, source_dir: &str) -> String {
+        match self.subdir.trim_matches('/') {
+            "" | "." => source_dir.to_string(),
+            subdir => format!("{}/{}", source_dir, subdir),
+        }
+    }
+
+    fn uses_builddir(&self) -> bool {
+        // Meson does not support building in the source directory.
+        self.builddir == Some(true) || self.get_build_system() == FlatpakBuildSystem::Meson
+    }
+
+    fn add_build_steps(
+        &self,
+        plan: &mut FlatpakBuildPlan,
+        source_dir: &str,
+        build_options: &FlatpakBuildOptions,
+        context: &FlatpakBuildContext,
+    ) -> Result<(), FlatpakError> {
+        let build_system = self.get_build_system();
+        let source_subdir = self.get_source_subdir(source_dir);
+        let use_builddir = self.uses_builddir();
+        let build_dir = self.get_build_dir(source_dir);
+
+        let prefix = if build_options.prefix.is_empty() {
+            &context.dest
+        } else {
+            &build_options.prefix
+        };
+
+        if build_system == FlatpakBuildSystem::Simple {
+            if use_builddir {
+                plan.add_step(make_dir_step(&source_subdir, &build_dir));
+            }
+            for build_command in &self.build_commands {
+                plan.add_step(FlatpakBuildStep::shell(
+                    FlatpakBuildStepKind::Build,
+                    build_command,
+                    &build_dir,
+                ));
+            }
+            return Ok(());
+        }
+
+        #[cfg(feature = "extended_build_systems")]
+        if matches!(
+            build_system,
+            FlatpakBuildSystem::Cabal | FlatpakBuildSystem::Make | FlatpakBuildSystem::Cargo
+        ) {
+            if use_builddir {
+                return Err(FlatpakError::UnsupportedBuildSystem(format!(
+                    "{} with builddir",
+                    build_system.to_string()
+                )));
+            }
+            self.add_extended_build_system_steps(
+                plan,
+                &build_system,
+                prefix,
+                build_options,
+                &build_dir,
+                context.n_jobs,
+            );
+            return Ok(());
+        }
+
+        let (configure_program, prefix_flag, libdir_flag, make_program) = match build_system {
+            FlatpakBuildSystem::Autotools if use_builddir => {
+                ("../configure", "--prefix=", Some("--libdir="), "make")
+            }
+            FlatpakBuildSystem::Autotools => ("./configure", "--prefix=", Some("--libdir="), "make"),
+            FlatpakBuildSystem::CMake => (
+                "cmake",
+                "-DCMAKE_INSTALL_PREFIX:PATH=",
+                Some("-DCMAKE_INSTALL_LIBDIR:PATH="),
+                "make",
+            ),
+            FlatpakBuildSystem::CMakeNinja => (
+                "cmake",
+                "-DCMAKE_INSTALL_PREFIX:PATH=",
+                Some("-DCMAKE_INSTALL_LIBDIR:PATH="),
+                "ninja",
+            ),
+            FlatpakBuildSystem::Meson => ("meson", "--prefix=", Some("--libdir="), "ninja"),
+            FlatpakBuildSystem::QMake => ("qmake", "PREFIX=", None, "make"),
+            build_system => return Err(FlatpakError::UnsupportedBuildSystem(build_system.to_string())),
+        };
+
+        if build_system == FlatpakBuildSystem::Autotools {
+            self.add_autogen_steps(plan, &source_subdir);
+        }
+        if use_builddir {
+            plan.add_step(make_dir_step(&source_subdir, &build_dir));
+        }
 
-        let out_path = out_path.unwrap_or("/app");
+        let mut configure_args = vec![format!("{}{}", prefix_flag, prefix)];
+        if let Some(libdir_flag) = libdir_flag {
+            if !build_options.libdir.is_empty() {
+                configure_args.push(format!("{}{}", libdir_flag, build_options.libdir));
+            }
+        }
+        if build_system == FlatpakBuildSystem::CMakeNinja {
+            configure_args.extend(["-G".to_string(), "Ninja".to_string()]);
+        }
+        configure_args.extend(
+            concat_args(&self.config_opts, &build_options.config_opts)
+                .iter()
+                .map(|a| a.to_string()),
+        );
+        match build_system {
+            FlatpakBuildSystem::Autotools => {}
+            // qmake finds the project file in the working directory by itself.
+            FlatpakBuildSystem::QMake if !use_builddir => {}
+            _ => configure_args.push(if use_builddir { ".." } else { "." }.to_string()),
+        }
+        plan.add_step(FlatpakBuildStep::new(
+            FlatpakBuildStepKind::Configure,
+            configure_program,
+            &configure_args.iter().map(String::as_str).collect::<Vec<&str>>(),
+            &build_dir,
+        ));
+
+        self.add_make_steps(plan, make_program, build_options, &build_dir, context.n_jobs);
+        Ok(())
+    }
 
-        let build_system = self
-            .buildsystem
-            .to_owned()
-            .unwrap_or(FlatpakBuildSystem::default());
+    /// Adds the steps running the tests of the module, after it was installed. The `test-rule`
+    /// defaults to `check` for the build systems using make, to `test` for cmake-ninja, and
+    /// meson runs `meson test`. An empty `test-rule` disables the default test step, and the
+    /// `test-commands` are always run afterwards.
+    fn add_test_steps(&self, plan: &mut FlatpakBuildPlan, source_dir: &str) {
+        let build_system = self.get_build_system();
+        let build_dir = self.get_build_dir(source_dir);
+        let make_program = match build_system {
+            FlatpakBuildSystem::CMakeNinja | FlatpakBuildSystem::Meson => "ninja",
+            _ => "make",
+        };
+        let test_step = match (&build_system, self.test_rule.as_deref()) {
+            (_, Some("")) | (FlatpakBuildSystem::Simple, _) => None,
+            (FlatpakBuildSystem::Meson, None) => Some(("meson", vec!["test"])),
+            (FlatpakBuildSystem::CMakeNinja, None) => Some(("ninja", vec!["test"])),
+            #[cfg(feature = "extended_build_systems")]
+            (FlatpakBuildSystem::Cabal, _) => Some(("runhaskell", vec!["Setup", "test"])),
+            #[cfg(feature = "extended_build_systems")]
+            (FlatpakBuildSystem::Cargo, _) => Some(("cargo", vec!["--offline", "test", "--release"])),
+            (_, None) => Some(("make", vec!["check"])),
+            (_, Some(test_rule)) => Some((make_program, vec![test_rule])),
+        };
+        if let Some((test_program, test_args)) = test_step {
+            plan.add_step(FlatpakBuildStep::new(
+                FlatpakBuildStepKind::Test,
+                test_program,
+                &test_args,
+                &build_dir,
+            ));
+        }
+        for test_command in &self.test_commands {
+            plan.add_step(FlatpakBuildStep::shell(
+                FlatpakBuildStepKind::Test,
+                test_command,
+                &build_dir,
+            ));
+        }
+    }
 
+    /// Adds the steps of the build systems that are not supported by flatpak-builder:
+    /// * with cabal, the `Setup` script is run with `runhaskell`;
+    /// * with make, there is no configure step, and the prefix is available to the makefile
+    ///   in the `FLATPAK_DEST` variable;
+    /// * with cargo, the crate is built and installed offline, and the `config-opts` are
+    ///   passed to both `cargo build` and `cargo install`.
+    #[cfg(feature = "extended_build_systems")]
+    fn add_extended_build_system_steps(
+        &self,
+        plan: &mut FlatpakBuildPlan,
+        build_system: &FlatpakBuildSystem,
+        prefix: &str,
+        build_options: &FlatpakBuildOptions,
+        build_dir: &str,
+        n_jobs: usize,
+    ) {
+        let config_opts = concat_args(&self.config_opts, &build_options.config_opts);
+        let make_args = concat_args(&self.make_args, &build_options.make_args);
+        let make_install_args = concat_args(&self.make_install_args, &build_options.make_install_args);
+        let no_make_install = self.no_make_install == Some(true);
         match build_system {
-            FlatpakBuildSystem::Autotools => {
-                let mut commands = Vec::new();
-
-                if !reconfigure {
-                    let mut cmd = Command::new("./configure");
-                    cmd.arg(format!("--prefix={}", out_path));
-                    cmd.args(self.config_opts.clone());
-                    cmd.current_dir(root_path);
-                    commands.push(cmd);
+            FlatpakBuildSystem::Cabal => {
+                let prefix_arg = format!("--prefix={}", prefix);
+                plan.add_step(FlatpakBuildStep::new(
+                    FlatpakBuildStepKind::Configure,
+                    "runhaskell",
+                    &[
+                        &["Setup", "configure", prefix_arg.as_str()],
+                        config_opts.as_slice(),
+                    ]
+                    .concat(),
+                    build_dir,
+                ));
+                plan.add_step(FlatpakBuildStep::new(
+                    FlatpakBuildStepKind::Build,
+                    "runhaskell",
+                    &[&["Setup", "build"], make_args.as_slice()].concat(),
+                    build_dir,
+                ));
+                if !no_make_install {
+                    plan.add_step(FlatpakBuildStep::new(
+                        FlatpakBuildStepKind::Install,
+                        "runhaskell",
+                        &[&["Setup", "install"], make_install_args.as_slice()].concat(),
+                        build_dir,
+                    ));
                 }
-
-                let mut cmd = Command::new("make");
-                cmd.args(&["-p", "-n", "-s"]);
-                cmd.current_dir(root_path);
-                commands.push(cmd);
-
-                let mut cmd = Command::new("make");
-                cmd.arg("V=0");
-                cmd.arg(format!("-j{}", num_cpus));
-                cmd.arg("install");
-                cmd.args(args);
-                cmd.current_dir(root_path);
-                commands.push(cmd);
-
-                commands
-            }
-            FlatpakBuildSystem::CMake | FlatpakBuildSystem::CMakeNinja => {
-                let mut commands = Vec::new();
-
-                if !reconfigure {
-                    let mut cmd = Command::new("mkdir");
-                    cmd.arg("-p").arg(build_path);
-                    commands.push(cmd);
-
-                    let mut cmd = Command::new("cmake");
-                    cmd.args(&["-G", "Ninja", "..", "."]);
-                    cmd.arg("-DCMAKE_EXPORT_COMPILE_COMMANDS=1");
-                    cmd.arg("-DCMAKE_BUILD_TYPE=RelWithDebInfo");
-                    cmd.arg(format!("-DCMAKE_INSTALL_PREFIX={}", out_path));
-                    cmd.args(self.config_opts.clone());
-                    cmd.current_dir(build_path);
-                    commands.push(cmd);
+            }
+            FlatpakBuildSystem::Make => {
+                self.add_make_steps(plan, "make", build_options, build_dir, n_jobs);
+            }
+            FlatpakBuildSystem::Cargo => {
+                let jobs = if self.no_parallel_make == Some(true) {
+                    "1".to_string()
+                } else {
+                    n_jobs.to_string()
+                };
+                plan.add_step(FlatpakBuildStep::new(
+                    FlatpakBuildStepKind::Build,
+                    "cargo",
+                    &[
+                        &["--offline", "build", "--release", "--jobs", jobs.as_str()],
+                        config_opts.as_slice(),
+                        make_args.as_slice(),
+                    ]
+                    .concat(),
+                    build_dir,
+                ));
+                if !no_make_install {
+                    plan.add_step(FlatpakBuildStep::new(
+                        FlatpakBuildStepKind::Install,
+                        "cargo",
+                        &[
+                            &[
+                                "--offline",
+                                "install",
+                                "--path",
+                                ".",
+                                "--root",
+                                prefix,
+                                "--no-track",
+                                "--jobs",
+                                jobs.as_str(),
+                            ],
+                            config_opts.as_slice(),
+                            make_install_args.as_slice(),
+                        ]
+                        .concat(),
+                        build_dir,
+                    ));
                 }
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds the steps removing and generating the `configure` script of an autotools module.
+    /// The autogen script is looked up when the step is run, as flatpak-builder does, so that
+    /// the plan does not depend on the state of the source directory when it is generated.
+    fn add_autogen_steps(&self, plan: &mut FlatpakBuildPlan, source_subdir: &str) {
+        if self.rm_configure == Some(true) {
+            plan.add_step(FlatpakBuildStep::new(
+                FlatpakBuildStepKind::Configure,
+                "rm",
+                &["-f", "configure"],
+                source_subdir,
+            ));
+        }
+        if self.no_autogen == Some(true) {
+            return;
+        }
+
+        let autogen_command = format!(
+            "[ -e configure ] && exit 0; for autogen in {}; do [ -e \"$autogen\" ] && exec \"./$autogen\"; done; \
+             echo \"Can't find autogen, autogen.sh or bootstrap\" >&2; exit 1",
+            AUTOGEN_NAMES.join(" ")
+        );
+        let mut autogen_step =
+            FlatpakBuildStep::shell(FlatpakBuildStepKind::Configure, &autogen_command, source_subdir);
+        autogen_step.env.set("NOCONFIGURE", "1");
+        plan.add_step(autogen_step);
+    }
+
+    /// Adds the steps building and installing a module with `make` or `ninja`.
+    fn add_make_steps(
+        &self,
+        plan: &mut FlatpakBuildPlan,
+        make_program: &str,
+        build_options: &FlatpakBuildOptions,
+        build_dir: &str,
+        n_jobs: usize,
+    ) {
+        let mut make_args = vec![];
+        if self.no_parallel_make != Some(true) {
+            make_args.push(format!("-j{}", n_jobs));
+            make_args.push(format!("-l{}", 2 * n_jobs));
+        } else if make_program == "ninja" {
+            // ninja builds in parallel by default.
+            make_args.push("-j1".to_string());
+        }
+        make_args.extend(
+            concat_args(&self.make_args, &build_options.make_args)
+                .iter()
+                .map(|a| a.to_string()),
+        );
+        plan.add_step(FlatpakBuildStep::new(
+            FlatpakBuildStepKind::Build,
+            make_program,
+            &make_args.iter().map(String::as_str).collect::<Vec<&str>>(),
+            build_dir,
+        ));
+
+        if self.no_make_install == Some(true) {
+            return;
+        }
+        let install_rule = if self.install_rule.is_empty() {
+            "install"
+        } else {
+            &self.install_rule
+        };
+        plan.add_step(FlatpakBuildStep::new(
+            FlatpakBuildStepKind::Install,
+            make_program,
+            &[
+                &[install_rule],
+                concat_args(&self.make_install_args, &build_options.make_install_args).as_slice(),
+            ]
+            .concat(),
+            build_dir,
+        ));
+    }
+
+    /// Gets the commands to build and install the module from its source directory. See
+    /// [`FlatpakModule::get_build_plan`] to inspect the commands before running them.
+    pub fn get_commands(
+        &self,
+        source_dir: &str,
+        context: &FlatpakBuildContext,
+    ) -> Result<Vec<Command>, FlatpakError> {
+        Ok(self.get_build_plan(source_dir, context)?.to_commands())
+    }
+}
 
-                let mut cmd = Command::new("ninja");
-                cmd.current_dir(build_path);
-                commands.push(cmd);
+/// The name of the directory in which the modules using `builddir` are built.
+pub const BUILDDIR_NAME: &str = "_flatpak_build";
 
-                let mut cmd = Command::new("ninja");
-                cmd.arg("install");
-                cmd.current_dir(build_path);
-                commands.push(cmd);
+/// The scripts used to generate the `configure` script of autotools modules, in the order
+/// in which flatpak-builder looks for them.
+pub const AUTOGEN_NAMES: [&str; 4] = ["autogen", "autogen.sh", "bootstrap", "bootstrap.sh"];
 
-                commands
+fn make_dir_step(working_dir: &str, dir: &str) -> FlatpakBuildStep {
+    FlatpakBuildStep::new(
+        FlatpakBuildStepKind::Configure,
+        "mkdir",
+        &["-p", dir],
+        working_dir,
+    )
+}
+
+/// Concatenates the arguments of a module with the arguments of its build options.
+fn concat_args<'a>(module_args: &'a [String], build_options_args: &'a [String]) -> Vec<&'a str> {
+    module_args
+        .iter()
+        .chain(build_options_args.iter())
+        .map(String::as_str)
+        .collect()
+}
+
+fn get_base_dir(manifest_path: &str) -> &path::Path {
+    match path::Path::new(manifest_path).parent() {
+        Some(p) => p,
+        None => path::Path::new(""),
+    }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+/// A manifest file generated from a Flatpak manifest, which has not been
+/// written to disk yet.
+pub struct FlatpakManifestFile {
+    /// The path of the file on disk.
+    pub path: String,
+    /// The content of the file.
+    pub content: String,
+}
+impl FlatpakManifestFile {
+    /// Writes the file to disk, creating the parent directories if needed.
+    pub fn write(&self) -> Result<(), FlatpakError> {
+        let io_error = |e| FlatpakError::Io {
+            path: self.path.to_string(),
+            source: e,
+        };
+        if let Some(parent_dir) = path::Path::new(&self.path).parent() {
+            fs::create_dir_all(parent_dir).map_err(io_error)?;
+        }
+        fs::write(&self.path, &self.content).map_err(io_error)
+    }
+}
+
+fn get_module_filename(module_name: &str) -> String {
+    let filename: String = module_name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    let filename = filename.trim_matches('.').to_string();
+    if filename.is_empty() {
+        return "module".to_string();
+    }
+    filename
+}
+
+/// Moves the inline modules of a module list to their own manifest files, and replaces them with
+/// paths to those files. `manifest_path` is the path of the file in which the module list is defined,
+/// and `modules_dir` is the directory, relative to that file, in which to put the new files.
+/// If `module_names` is provided, only the modules with those names are externalized.
+pub(crate) fn externalize_module_items(
+    modules: &mut [FlatpakModuleItem],
+    manifest_path: &str,
+    modules_dir: &str,
+    module_names: Option<Vec<String>>,
+    format: FlatpakManifestFormat,
+) -> Result<Vec<FlatpakManifestFile>, FlatpakError> {
+    let base_dir = get_base_dir(manifest_path);
+
+    let mut files: Vec<FlatpakManifestFile> = vec![];
+    for module in modules.iter_mut() {
+        let module_description = match module {
+            FlatpakModuleItem::Description(d) => d,
+            FlatpakModuleItem::Path(_) => continue,
+        };
+        if let Some(names) = &module_names {
+            if !names.contains(&module_description.name) {
+                continue;
             }
-            FlatpakBuildSystem::Meson => {
-                let mut commands = Vec::new();
+        }
 
-                if !reconfigure {
-                    let mut cmd = Command::new("meson");
-                    cmd.arg(format!("--prefix={}", out_path));
-                    cmd.arg(build_path);
-                    cmd.args(self.config_opts.clone());
-                    cmd.current_dir(root_path);
-                    commands.push(cmd);
-                }
+        // We never overwrite an existing file, or a file that we are about to create
+        // for another module with the same name.
+        let filename = get_module_filename(&module_description.name);
+        let mut suffix = 1;
+        let mut module_path = format!("{}/{}.{}", modules_dir, filename, format.extension());
+        let mut file_path = base_dir.join(&module_path).to_string_lossy().to_string();
+        while path::Path::new(&file_path).exists() || files.iter().any(|f| f.path == file_path) {
+            suffix += 1;
+            module_path = format!("{}/{}-{}.{}", modules_dir, filename, suffix, format.extension());
+            file_path = base_dir.join(&module_path).to_string_lossy().to_string();
+        }
+
+        // Relative paths in the module are relative to the manifest file, so they have to be
+        // rebased on the directory of the new file.
+        let mut external_module = module_description.clone();
+        rebase_module_paths(&mut external_module, modules_dir)?;
+        external_module.format = format.clone();
+        files.push(FlatpakManifestFile {
+            path: file_path,
+            content: external_module.dump()?,
+        });
+        *module = FlatpakModuleItem::Path(module_path);
+    }
+    Ok(files)
+}
 
-                let mut cmd = Command::new("ninja");
-                cmd.arg("-C");
-                cmd.arg(build_path);
-                cmd.current_dir(root_path);
-                commands.push(cmd);
+/// Rebases all the relative paths of a module, including the paths of its sources and of its nested
+/// modules, so that they stay valid when the module is moved to `modules_dir`.
+fn rebase_module_paths(module: &mut FlatpakModule, modules_dir: &str) -> Result<(), FlatpakError> {
+    let mut depth = 0;
+    for component in path::Path::new(modules_dir).components() {
+        match component {
+            path::Component::Normal(_) => depth += 1,
+            path::Component::CurDir => continue,
+            // We cannot know where an absolute or a parent directory is relative to
+            // the manifest file without resolving symlinks.
+            _ => {
+                if has_relative_paths(module) {
+                    return Err(FlatpakError::invalid_value("modules directory", modules_dir));
+                }
+                return Ok(());
+            }
+        }
+    }
+    if depth == 0 {
+        return Ok(());
+    }
+    for_each_relative_path(module, &mut |p| *p = rebase_path(p, depth));
+    Ok(())
+}
 
-                let mut cmd = Command::new("meson");
-                cmd.args(args);
-                cmd.args(&["install", "-C"]);
-                cmd.arg(build_path);
-                cmd.current_dir(root_path);
-                commands.push(cmd);
+fn has_relative_paths(module: &mut FlatpakModule) -> bool {
+    let mut has_relative_paths = false;
+    for_each_relative_path(module, &mut |_| has_relative_paths = true);
+    has_relative_paths
+}
 
-                commands
+fn for_each_relative_path(module: &mut FlatpakModule, callback: &mut dyn FnMut(&mut String)) {
+    let mut paths: Vec<&mut String> = vec![];
+    for source in module.sources.iter_mut() {
+        match source {
+            FlatpakSourceItem::Path(p) => paths.push(p),
+            FlatpakSourceItem::Description(s) => {
+                paths.extend(s.path.iter_mut());
+                paths.extend(s.paths.iter_mut().flatten());
             }
-            FlatpakBuildSystem::QMake => panic!("Not implemented yet."),
-            FlatpakBuildSystem::Simple => self
-                .build_commands
-                .iter()
-                .map(|step| {
-                    let mut cmd = Command::new("/bin/sh");
-                    cmd.arg("-c");
-                    cmd.arg(step);
-                    cmd.current_dir(root_path);
-                    cmd
-                })
-                .collect(),
         }
     }
+    for child in module.modules.iter_mut() {
+        match child {
+            FlatpakModuleItem::Path(p) => paths.push(p),
+            FlatpakModuleItem::Description(m) => for_each_relative_path(m, callback),
+        }
+    }
+    for p in paths {
+        if !path::Path::new(p.as_str()).is_absolute() {
+            callback(p);
+        }
+    }
+}
+
+/// Prefixes a relative path with `depth` parent directories.
+fn rebase_path(relative_path: &str, depth: usize) -> String {
+    let mut components: Vec<&str> = vec![".."; depth];
+    components.extend(relative_path.split('/').filter(|c| !c.is_empty() && *c != "."));
+    components.join("/")
+}
+
+pub(crate) fn filter_module_items_for_arch(
+    modules: &[FlatpakModuleItem],
+    arch: &FlatpakArchitecture,
+) -> Vec<FlatpakModuleItem> {
+    modules
+        .iter()
+        .filter_map(|module| match module {
+            FlatpakModuleItem::Description(d) => d.filter_for_arch(arch).map(FlatpakModuleItem::Description),
+            FlatpakModuleItem::Path(p) => Some(FlatpakModuleItem::Path(p.to_string())),
+        })
+        .collect()
+}
+
+/// Gets the architectures, among `arches`, for which each inline module is built, keyed by
+/// the manifest path of the module under `modules_path`. A nested module is only built for
+/// the architectures of its parents, and the parent modules are listed before their nested
+/// modules. The external modules are skipped.
+pub(crate) fn get_module_items_arches(
+    modules: &[FlatpakModuleItem],
+    modules_path: &FlatpakManifestPath,
+    arches: &[FlatpakArchitecture],
+) -> Vec<(FlatpakManifestPath, Vec<FlatpakArchitecture>)> {
+    let mut all_module_arches = vec![];
+    for (module_index, module) in modules.iter().enumerate() {
+        let module = match module {
+            FlatpakModuleItem::Description(d) => d,
+            FlatpakModuleItem::Path(_) => continue,
+        };
+        let module_path = modules_path.join_index(module_index);
+        let module_arches: Vec<FlatpakArchitecture> = arches
+            .iter()
+            .filter(|arch| module.is_built_for_arch(arch))
+            .cloned()
+            .collect();
+        let mut nested_module_arches =
+            get_module_items_arches(&module.modules, &module_path.join_key("modules"), &module_arches);
+        all_module_arches.push((module_path, module_arches));
+        all_module_arches.append(&mut nested_module_arches);
+    }
+    all_module_arches
+}
+
+pub(crate) fn get_module_items_validation_errors(
+    modules: &[FlatpakModuleItem],
+    modules_path: &FlatpakManifestPath,
+) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+    let mut errors = vec![];
+    for (module_index, module) in modules.iter().enumerate() {
+        if let FlatpakModuleItem::Description(module_description) = module {
+            errors
+                .append(&mut module_description.get_validation_errors(&modules_path.join_index(module_index)));
+        }
+    }
+    errors
+}
+
+pub(crate) fn get_module_items_unknown_field_errors(
+    modules: &[FlatpakModuleItem],
+    modules_path: &FlatpakManifestPath,
+) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+    let mut errors = vec![];
+    for (module_index, module) in modules.iter().enumerate() {
+        if let FlatpakModuleItem::Description(module_description) = module {
+            errors.append(
+                &mut module_description.get_unknown_field_errors(&modules_path.join_index(module_index)),
+            );
+        }
+    }
+    errors
+}
+
+/// Loads and inlines all the external modules of a module list, recursively.
+/// `manifest_path` is the path of the file in which the module list is defined.
+pub(crate) fn resolve_module_items(
+    modules: &[FlatpakModuleItem],
+    manifest_path: &str,
+    include_stack: &mut Vec<path::PathBuf>,
+) -> Result<Vec<FlatpakModuleItem>, FlatpakError> {
+    let base_dir = get_base_dir(manifest_path);
+
+    let mut resolved_modules: Vec<FlatpakModuleItem> = vec![];
+    for module in modules {
+        let resolved_module = match module {
+            FlatpakModuleItem::Description(d) => d.resolve_includes(manifest_path, include_stack)?,
+            FlatpakModuleItem::Path(p) => {
+                let module_path = base_dir.join(p);
+                let module_path_str = module_path.to_string_lossy().to_string();
+                let canonical_path = match fs::canonicalize(&module_path) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        return Err(FlatpakError::Io {
+                            path: module_path_str,
+                            source: e,
+                        })
+                    }
+                };
+                if include_stack.contains(&canonical_path) {
+                    return Err(FlatpakError::IncludeCycle(module_path_str));
+                }
+
+                let module = FlatpakModule::load_from_file(module_path_str.clone())?;
+                include_stack.push(canonical_path);
+                let resolved_module = module.resolve_includes(&module_path_str, include_stack);
+                include_stack.pop();
+                resolved_module?
+            }
+        };
+        resolved_modules.push(FlatpakModuleItem::Description(resolved_module));
+    }
+    Ok(resolved_modules)
 }
 
 #[derive(Clone)]
-#[derive(Deserialize)]
-#[derive(Serialize)]
+#[derive(Copy)]
 #[derive(Debug)]
 #[derive(Hash)]
+#[derive(PartialEq)]
+/// The shapes in which the environment variables of build options can be defined.
+pub enum FlatpakBuildOptionsEnvShape {
+    /// A dictionary of variable names to values, as documented in `man flatpak-manifest`.
+    Dict,
+    /// An array of `NAME=value` strings, as commonly found in manifests.
+    Array,
+}
+
+#[derive(Clone)]
+#[derive(Deserialize)]
+#[derive(Serialize)]
 #[serde(untagged)]
+enum FlatpakBuildOptionsEnvValue {
+    Dict(IndexMap<String, Option<String>>),
+    Array(Vec<String>),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
 /// This is a dictionary defining environment variables to be set during the build.
 /// Elements in this override the properties that set the environment, like
 /// cflags and ldflags. Keys with a null value unset the corresponding variable.
-/// FIXME the doc says this should be an object, but when defined in the modules,
-/// it is actually an array with values like PPC_CONFIG_PATH=/app/etc.
-pub enum FlatpakBuildOptionsEnv {
-    Dict(BTreeMap<String, String>),
-    Array(Vec<String>),
+///
+/// The environment can also be defined as an array of `NAME=value` strings. The shape in which
+/// it was defined is preserved when dumping the manifest, except that the variables to unset
+/// can only be represented in the dictionary shape.
+pub struct FlatpakBuildOptionsEnv {
+    pub shape: FlatpakBuildOptionsEnvShape,
+    /// The variables, in the order in which they were defined. The variables to unset
+    /// have a `None` value.
+    variables: IndexMap<String, Option<String>>,
 }
 impl Default for FlatpakBuildOptionsEnv {
     fn default() -> Self {
-        FlatpakBuildOptionsEnv::Array(vec![])
+        FlatpakBuildOptionsEnv {
+            shape: FlatpakBuildOptionsEnvShape::Dict,
+            variables: IndexMap::new(),
+        }
+    }
+}
+impl Hash for FlatpakBuildOptionsEnv {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.shape.hash(state);
+        for (name, value) in &self.variables {
+            name.hash(state);
+            value.hash(state);
+        }
+    }
+}
+impl<'de> Deserialize<'de> for FlatpakBuildOptionsEnv {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let env = match Option::<FlatpakBuildOptionsEnvValue>::deserialize(deserializer)? {
+            Some(FlatpakBuildOptionsEnvValue::Dict(variables)) => FlatpakBuildOptionsEnv {
+                shape: FlatpakBuildOptionsEnvShape::Dict,
+                variables,
+            },
+            Some(FlatpakBuildOptionsEnvValue::Array(variables)) => {
+                let mut env = FlatpakBuildOptionsEnv {
+                    shape: FlatpakBuildOptionsEnvShape::Array,
+                    variables: IndexMap::new(),
+                };
+                for variable in variables {
+                    // flatpak-builder sets the variables without a value to an empty string.
+                    match variable.split_once('=') {
+                        Some((name, value)) => env.set(name, value),
+                        None => env.set(&variable, ""),
+                    }
+                }
+                env
+            }
+            None => FlatpakBuildOptionsEnv::default(),
+        };
+        Ok(env)
+    }
+}
+impl Serialize for FlatpakBuildOptionsEnv {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let has_unset_variables = self.variables.values().any(|v| v.is_none());
+        if self.shape == FlatpakBuildOptionsEnvShape::Array && !has_unset_variables {
+            let variables: Vec<String> = self
+                .variables
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value.as_deref().unwrap_or("")))
+                .collect();
+            return FlatpakBuildOptionsEnvValue::Array(variables).serialize(serializer);
+        }
+        FlatpakBuildOptionsEnvValue::Dict(self.variables.clone()).serialize(serializer)
+    }
+}
+impl FlatpakBuildOptionsEnv {
+    pub fn is_empty(&self) -> bool {
+        self.variables.is_empty()
+    }
+
+    /// Gets the value of a variable. Returns `None` if the variable is not defined,
+    /// or if it is unset.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        match self.variables.get(name) {
+            Some(Some(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the variable is unset by the build options.
+    pub fn is_unset(&self, name: &str) -> bool {
+        matches!(self.variables.get(name), Some(None))
+    }
+
+    /// Sets a variable. If the variable is already defined, its position is preserved.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), Some(value.to_string()));
+    }
+
+    /// Unsets a variable in the build environment. If the variable is already defined,
+    /// its position is preserved.
+    pub fn unset(&mut self, name: &str) {
+        self.variables.insert(name.to_string(), None);
+    }
+
+    /// Removes the definition of a variable from the build options. Unlike
+    /// [`FlatpakBuildOptionsEnv::unset`], the variable keeps the value it has in the
+    /// environment of the build.
+    pub fn remove(&mut self, name: &str) -> Option<Option<String>> {
+        self.variables.shift_remove(name)
+    }
+
+    /// Iterates over the variables in the order in which they were defined. The variables
+    /// to unset have a `None` value.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_deref()))
+    }
+
+    /// Merges the environment with a more specific environment, whose variables take
+    /// precedence. The shape of the environment is kept, unless it is empty.
+    pub fn merge(&self, overrides: &FlatpakBuildOptionsEnv) -> FlatpakBuildOptionsEnv {
+        let mut merged_env = self.clone();
+        if self.is_empty() {
+            merged_env.shape = overrides.shape;
+        }
+        for (name, value) in &overrides.variables {
+            merged_env.variables.insert(name.to_string(), value.clone());
+        }
+        merged_env
     }
 }
 
@@ -631,6 +1597,8 @@ pub struct FlatpakBuildOptions {
     #[serde(skip_serializing_if = "String::is_empty")]
     pub prepend_pkg_config_path: String,
 
+    /// The environment variables to set during the build.
+    #[serde(skip_serializing_if = "FlatpakBuildOptionsEnv::is_empty")]
     pub env: FlatpakBuildOptionsEnv,
 
     /// This is an array containing extra options to pass to flatpak build.
@@ -671,11 +1639,233 @@ pub struct FlatpakBuildOptions {
     /// This is a dictionary defining for each arch a separate build options object that override the main one.
     #[serde(skip_serializing_if = "BTreeMap::is_empty")]
     pub arch: BTreeMap<String, FlatpakBuildOptions>,
+
+    /// The fields that are not supported by this library, including the
+    /// extension fields prefixed with `x-`. They are preserved when dumping the manifest.
+    #[serde(flatten)]
+    pub extra_fields: FlatpakExtraFields,
+}
+impl FlatpakBuildOptions {
+    /// The names of the fields supported in build options.
+    pub const FIELD_NAMES: &'static [&'static str] = &[
+        "cflags",
+        "cflags-override",
+        "cppflags",
+        "cppflags-override",
+        "cxxflags",
+        "cxxflags-override",
+        "ldflags",
+        "ldflags-override",
+        "prefix",
+        "libdir",
+        "append-path",
+        "prepend-path",
+        "append-ld-library-path",
+        "prepend-ld-library-path",
+        "append-pkg-config-path",
+        "prepend-pkg-config-path",
+        "env",
+        "build-args",
+        "test-args",
+        "config-opts",
+        "make-args",
+        "make-install-args",
+        "strip",
+        "no-debuginfo",
+        "no-debuginfo-compression",
+        "arch",
+    ];
+
+    /// Gets the effective build options for an architecture, by merging the options defined
+    /// for that architecture in `arch` into the base options. The returned options do not
+    /// have any architecture-specific options.
+    ///
+    /// ```
+    /// # use flatpak_rs::architecture::FlatpakArchitecture;
+    /// # use flatpak_rs::format::FlatpakManifestFormat;
+    /// # use flatpak_rs::module::FlatpakBuildOptions;
+    /// let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML.parse(r###"
+    ///     cflags: -O2 -g
+    ///     arch:
+    ///       aarch64:
+    ///         cflags: -march=armv8-a
+    /// "###).unwrap();
+    /// let build_options = build_options.resolve_for_arch(&FlatpakArchitecture::AARCH64);
+    /// assert_eq!(build_options.cflags, "-O2 -g -march=armv8-a");
+    /// ```
+    pub fn resolve_for_arch(&self, arch: &FlatpakArchitecture) -> FlatpakBuildOptions {
+        let mut resolved_build_options = match self.get_arch_entry(&arch.to_string()) {
+            Some((_, arch_build_options)) => self.merge(arch_build_options),
+            None => self.clone(),
+        };
+        resolved_build_options.arch.clear();
+        resolved_build_options
+    }
+
+    /// Gets the entry of `arch` for an architecture. The architectures can also be named
+    /// using their aliases, for example `arm64` for `aarch64`.
+    fn get_arch_entry(&self, arch_name: &str) -> Option<(&String, &FlatpakBuildOptions)> {
+        if let Some(entry) = self.arch.get_key_value(arch_name) {
+            return Some(entry);
+        }
+        let arch = FlatpakArchitecture::from_string(arch_name).ok()?;
+        self.arch
+            .iter()
+            .find(|(name, _)| FlatpakArchitecture::from_string(name).ok() == Some(arch))
+    }
+
+    /// Merges build options with more specific build options, using the same semantics as
+    /// flatpak-builder:
+    /// * compiler and linker flags are concatenated, separated by spaces, unless the
+    ///   corresponding `*-override` flag is set in the more specific options;
+    /// * `prefix`, `libdir` and the boolean options are replaced when they are set;
+    /// * the paths to append and prepend are concatenated, separated by `:`;
+    /// * the environment variables are merged, the more specific ones taking precedence;
+    /// * the arrays of arguments are appended.
+    pub fn merge(&self, overrides: &FlatpakBuildOptions) -> FlatpakBuildOptions {
+        let (cflags, cflags_override) = merge_flags(
+            &self.cflags,
+            self.cflags_override,
+            &overrides.cflags,
+            overrides.cflags_override,
+        );
+        let (cppflags, cppflags_override) = merge_flags(
+            &self.cppflags,
+            self.cppflags_override,
+            &overrides.cppflags,
+            overrides.cppflags_override,
+        );
+        let (cxxflags, cxxflags_override) = merge_flags(
+            &self.cxxflags,
+            self.cxxflags_override,
+            &overrides.cxxflags,
+            overrides.cxxflags_override,
+        );
+        let (ldflags, ldflags_override) = merge_flags(
+            &self.ldflags,
+            self.ldflags_override,
+            &overrides.ldflags,
+            overrides.ldflags_override,
+        );
+
+        let mut arch = self.arch.clone();
+        for (arch_name, arch_build_options) in &overrides.arch {
+            match self.get_arch_entry(arch_name) {
+                Some((base_arch_name, base_build_options)) => arch.insert(
+                    base_arch_name.to_string(),
+                    base_build_options.merge(arch_build_options),
+                ),
+                None => arch.insert(arch_name.to_string(), arch_build_options.clone()),
+            };
+        }
+
+        let mut extra_fields = self.extra_fields.clone();
+        for (field_name, field_value) in overrides.extra_fields.iter() {
+            extra_fields.insert(field_name.to_string(), field_value.clone());
+        }
+
+        FlatpakBuildOptions {
+            cflags,
+            cflags_override,
+            cppflags,
+            cppflags_override,
+            cxxflags,
+            cxxflags_override,
+            ldflags,
+            ldflags_override,
+            prefix: replace_string(&self.prefix, &overrides.prefix),
+            libdir: replace_string(&self.libdir, &overrides.libdir),
+            append_path: join_paths(&self.append_path, &overrides.append_path),
+            prepend_path: join_paths(&overrides.prepend_path, &self.prepend_path),
+            append_ld_library_path: join_paths(&self.append_ld_library_path, &overrides.append_ld_library_path),
+            prepend_ld_library_path: join_paths(
+                &overrides.prepend_ld_library_path,
+                &self.prepend_ld_library_path,
+            ),
+            append_pkg_config_path: join_paths(&self.append_pkg_config_path, &overrides.append_pkg_config_path),
+            prepend_pkg_config_path: join_paths(
+                &overrides.prepend_pkg_config_path,
+                &self.prepend_pkg_config_path,
+            ),
+            env: self.env.merge(&overrides.env),
+            build_args: [self.build_args.clone(), overrides.build_args.clone()].concat(),
+            test_args: [self.test_args.clone(), overrides.test_args.clone()].concat(),
+            config_opts: [self.config_opts.clone(), overrides.config_opts.clone()].concat(),
+            make_args: [self.make_args.clone(), overrides.make_args.clone()].concat(),
+            make_install_args: [
+                self.make_install_args.clone(),
+                overrides.make_install_args.clone(),
+            ]
+            .concat(),
+            strip: overrides.strip.or(self.strip),
+            no_debuginfo: overrides.no_debuginfo.or(self.no_debuginfo),
+            no_debuginfo_compression: overrides
+                .no_debuginfo_compression
+                .or(self.no_debuginfo_compression),
+            arch,
+            extra_fields,
+        }
+    }
+
+    /// Gets an error for each field of the build options that is not supported by this library,
+    /// with the path of the field. `path` is the path of the build options in the manifest.
+    pub fn get_unknown_field_errors(
+        &self,
+        path: &FlatpakManifestPath,
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        let mut errors = self
+            .extra_fields
+            .get_unknown_field_errors(path, FlatpakBuildOptions::FIELD_NAMES);
+        for (arch, arch_build_options) in &self.arch {
+            errors.append(
+                &mut arch_build_options.get_unknown_field_errors(&path.join_key("arch").join_key(arch)),
+            );
+        }
+        errors
+    }
+}
+
+/// Merges compiler or linker flags. The flags are concatenated unless the more specific
+/// flags override the base flags. The override flag is kept in the result, so that the
+/// merged flags also override less specific flags when merged again.
+fn merge_flags(
+    base_flags: &str,
+    base_override: Option<bool>,
+    flags: &str,
+    flags_override: Option<bool>,
+) -> (String, Option<bool>) {
+    if flags_override == Some(true) {
+        return (flags.to_string(), flags_override);
+    }
+    let merged_flags = [base_flags, flags]
+        .iter()
+        .filter(|f| !f.is_empty())
+        .cloned()
+        .collect::<Vec<&str>>()
+        .join(" ");
+    (merged_flags, flags_override.or(base_override))
+}
+
+fn replace_string(base_value: &str, value: &str) -> String {
+    if value.is_empty() {
+        return base_value.to_string();
+    }
+    value.to_string()
+}
+
+fn join_paths(first_path: &str, second_path: &str) -> String {
+    [first_path, second_path]
+        .iter()
+        .filter(|p| !p.is_empty())
+        .cloned()
+        .collect::<Vec<&str>>()
+        .join(":")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::TestDir;
 
     #[test]
     pub fn test_parse_build_options() {
@@ -702,12 +1892,10 @@ mod tests {
             Ok(module) => {
                 assert_eq!(module.name, "flatpak-rs");
                 assert!(module.build_options.is_some());
-                let env: BTreeMap<String, String> = match module.build_options.unwrap().env {
-                    FlatpakBuildOptionsEnv::Dict(env) => env,
-                    FlatpakBuildOptionsEnv::Array(env) => panic!("Env should be a dict."),
-                };
-                assert_eq!(env.get("V").unwrap(), "1");
-                assert_eq!(env.get("X").unwrap(), "2");
+                let env = module.build_options.unwrap().env;
+                assert_eq!(env.shape, FlatpakBuildOptionsEnvShape::Dict);
+                assert_eq!(env.get("V"), Some("1"));
+                assert_eq!(env.get("X"), Some("2"));
             }
         }
     }
@@ -737,12 +1925,205 @@ mod tests {
             Ok(module) => {
                 assert_eq!(module.name, "flatpak-rs");
                 assert!(module.build_options.is_some());
-                let env: Vec<String> = match module.build_options.unwrap().env {
-                    FlatpakBuildOptionsEnv::Array(env) => env,
-                    FlatpakBuildOptionsEnv::Dict(env) => panic!("Env should be an array."),
-                };
-                assert_eq!(env, vec!["V=1", "Y=2"]);
+                let env = module.build_options.unwrap().env;
+                assert_eq!(env.shape, FlatpakBuildOptionsEnvShape::Array);
+                assert_eq!(env.get("V"), Some("1"));
+                assert_eq!(env.get("Y"), Some("2"));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_build_options_env() {
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse("env:\n  V: \"1\"\n  LANG: null\n  X: \"2\"\n")
+            .unwrap();
+        assert_eq!(build_options.env.shape, FlatpakBuildOptionsEnvShape::Dict);
+        assert_eq!(build_options.env.get("V"), Some("1"));
+        assert_eq!(build_options.env.get("LANG"), None);
+        assert!(build_options.env.is_unset("LANG"));
+        assert!(!build_options.env.is_unset("V"));
+        assert_eq!(
+            FlatpakManifestFormat::YAML.dump(&build_options).unwrap(),
+            "env:\n  V: '1'\n  LANG: null\n  X: '2'\n"
+        );
+
+        let mut build_options: FlatpakBuildOptions = FlatpakManifestFormat::JSON
+            .parse(r#"{"env": ["V=1", "PATH=/app/bin:/usr/bin", "EMPTY"]}"#)
+            .unwrap();
+        assert_eq!(build_options.env.shape, FlatpakBuildOptionsEnvShape::Array);
+        assert_eq!(build_options.env.get("PATH"), Some("/app/bin:/usr/bin"));
+        assert_eq!(build_options.env.get("EMPTY"), Some(""));
+        build_options.env.set("V", "2");
+        build_options.env.remove("EMPTY");
+        assert_eq!(
+            FlatpakManifestFormat::JSON.dump(&build_options).unwrap(),
+            "{\n  \"env\": [\n    \"V=2\",\n    \"PATH=/app/bin:/usr/bin\"\n  ]\n}"
+        );
+        // The variables to unset can only be represented in the dictionary shape.
+        build_options.env.unset("PATH");
+        assert_eq!(
+            FlatpakManifestFormat::JSON.dump(&build_options).unwrap(),
+            "{\n  \"env\": {\n    \"V\": \"2\",\n    \"PATH\": null\n  }\n}"
+        );
+
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML.parse("env: null").unwrap();
+        assert!(build_options.env.is_empty());
+        assert_eq!(FlatpakManifestFormat::YAML.dump(&build_options).unwrap(), "{}\n");
+
+        let base_env: FlatpakBuildOptionsEnv = FlatpakManifestFormat::YAML.parse("[V=1, LANG=C]").unwrap();
+        let env: FlatpakBuildOptionsEnv = FlatpakManifestFormat::YAML.parse("{X: '2', LANG: null}").unwrap();
+        let merged_env = base_env.merge(&env);
+        assert_eq!(merged_env.shape, FlatpakBuildOptionsEnvShape::Array);
+        assert_eq!(
+            merged_env.iter().collect::<Vec<(&str, Option<&str>)>>(),
+            vec![("V", Some("1")), ("LANG", None), ("X", Some("2"))]
+        );
+        let merged_env = FlatpakBuildOptionsEnv::default().merge(&env);
+        assert_eq!(merged_env.shape, FlatpakBuildOptionsEnvShape::Dict);
+    }
+
+    #[test]
+    pub fn test_resolve_build_options_for_arch() {
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse(
+                r###"
+                cflags: "-O2 -g"
+                cxxflags: "-O2 -g"
+                ldflags: "-Wl,--as-needed"
+                prefix: /app
+                append-path: /app/sdk/bin
+                prepend-pkg-config-path: /app/sdk/lib/pkgconfig
+                env:
+                    - "V=1"
+                    - "Y=2"
+                config-opts: [ "--disable-docs" ]
+                strip: false
+                arch:
+                    aarch64:
+                        cflags: "-march=armv8-a"
+                        cxxflags: "-O3"
+                        cxxflags-override: true
+                        prefix: /app/aarch64
+                        append-path: /app/aarch64/bin
+                        prepend-pkg-config-path: /app/aarch64/lib/pkgconfig
+                        env:
+                            Y: "3"
+                            Z: "4"
+                        config-opts: [ "--disable-asm" ]
+                        strip: true
+            "###,
+            )
+            .unwrap();
+
+        let aarch64_build_options = build_options.resolve_for_arch(&FlatpakArchitecture::AARCH64);
+        assert_eq!(aarch64_build_options.cflags, "-O2 -g -march=armv8-a");
+        assert_eq!(aarch64_build_options.cflags_override, None);
+        assert_eq!(aarch64_build_options.cxxflags, "-O3");
+        assert_eq!(aarch64_build_options.cxxflags_override, Some(true));
+        assert_eq!(aarch64_build_options.ldflags, "-Wl,--as-needed");
+        assert_eq!(aarch64_build_options.prefix, "/app/aarch64");
+        assert_eq!(aarch64_build_options.append_path, "/app/sdk/bin:/app/aarch64/bin");
+        assert_eq!(
+            aarch64_build_options.prepend_pkg_config_path,
+            "/app/aarch64/lib/pkgconfig:/app/sdk/lib/pkgconfig"
+        );
+        assert_eq!(
+            aarch64_build_options
+                .env
+                .iter()
+                .collect::<Vec<(&str, Option<&str>)>>(),
+            vec![("V", Some("1")), ("Y", Some("3")), ("Z", Some("4"))]
+        );
+        assert_eq!(
+            aarch64_build_options.env.shape,
+            FlatpakBuildOptionsEnvShape::Array
+        );
+        assert_eq!(
+            aarch64_build_options.config_opts,
+            vec!["--disable-docs", "--disable-asm"]
+        );
+        assert_eq!(aarch64_build_options.strip, Some(true));
+        assert!(aarch64_build_options.arch.is_empty());
+
+        let x86_build_options = build_options.resolve_for_arch(&FlatpakArchitecture::X86);
+        assert_eq!(x86_build_options.cflags, "-O2 -g");
+        assert_eq!(x86_build_options.prefix, "/app");
+        assert_eq!(x86_build_options.config_opts, vec!["--disable-docs"]);
+        assert_eq!(x86_build_options.strip, Some(false));
+        assert!(x86_build_options.arch.is_empty());
+    }
+
+    #[test]
+    pub fn test_resolve_for_arch_aliases() {
+        let build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse(
+                r###"
+                cflags: "-O2"
+                arch:
+                    arm64:
+                        cflags: "-march=armv8-a"
+                    amd64:
+                        cflags: "-march=x86-64-v2"
+            "###,
+            )
+            .unwrap();
+        let module_build_options: FlatpakBuildOptions = FlatpakManifestFormat::YAML
+            .parse(
+                r###"
+                arch:
+                    aarch64:
+                        config-opts: [ "--disable-simd" ]
+            "###,
+            )
+            .unwrap();
+
+        let x86_build_options = build_options.resolve_for_arch(&FlatpakArchitecture::X86);
+        assert_eq!(x86_build_options.cflags, "-O2 -march=x86-64-v2");
+
+        let build_options = build_options.merge(&module_build_options);
+        assert_eq!(build_options.arch.len(), 2);
+        let aarch64_build_options = build_options.resolve_for_arch(&FlatpakArchitecture::AARCH64);
+        assert_eq!(aarch64_build_options.cflags, "-O2 -march=armv8-a");
+        assert_eq!(aarch64_build_options.config_opts, vec!["--disable-simd"]);
+    }
+
+    #[test]
+    pub fn test_parse_unknown_arches() {
+        let module_manifest = r###"
+            name: flatpak-rs
+            only-arches: [amd64, s390x]
+            sources:
+              - type: file
+                path: flatpak-rs.txt
+                skip-arches: [arm64, sparc64]
+        "###;
+        let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+        assert_eq!(module.only_arches, vec!["amd64", "s390x"]);
+        assert!(module.is_built_for_arch(&FlatpakArchitecture::X86));
+        assert!(!module.is_built_for_arch(&FlatpakArchitecture::AARCH64));
+        let dumped_module: FlatpakModule = FlatpakManifestFormat::YAML
+            .parse(&FlatpakManifestFormat::YAML.dump(&module).unwrap())
+            .unwrap();
+        assert_eq!(dumped_module.only_arches, vec!["amd64", "s390x"]);
+        match &module.sources[0] {
+            FlatpakSourceItem::Description(source) => {
+                assert!(!source.is_built_for_arch(&FlatpakArchitecture::AARCH64));
+                assert!(source.is_built_for_arch(&FlatpakArchitecture::X86));
+            }
+            FlatpakSourceItem::Path(_) => panic!("Expected an inline source."),
+        }
+
+        let options = FlatpakParseOptions { strict: true };
+        match FlatpakModule::parse_with_options(FlatpakManifestFormat::YAML, module_manifest, &options) {
+            Err(FlatpakError::Multiple(errors)) => {
+                let paths: Vec<String> = errors
+                    .iter()
+                    .map(|e| e.get_location().unwrap().path.to_string())
+                    .collect();
+                assert_eq!(paths, vec!["only-arches[1]", "sources[0].skip-arches[1]"]);
             }
+            r => panic!("Expected the unknown architectures to be reported, got {:?}", r),
         }
     }
 
@@ -770,6 +2151,45 @@ mod tests {
         }
     }
 
+    #[test]
+    pub fn test_resolve_include_cycle() {
+        let test_dir = TestDir::new("resolve-cycle");
+        fs::create_dir_all(test_dir.join("nested")).unwrap();
+
+        fs::write(
+            test_dir.join("module.yaml"),
+            r###"
+            name: module
+            sources:
+              - type: dir
+                path: .
+            modules:
+              - nested/nested.yaml
+            "###,
+        )
+        .unwrap();
+        fs::write(
+            test_dir.join("nested/nested.yaml"),
+            r###"
+            name: nested
+            sources:
+              - type: dir
+                path: .
+            modules:
+              - ../module.yaml
+            "###,
+        )
+        .unwrap();
+
+        let module_path = test_dir.join("module.yaml");
+        let module_path = module_path.to_str().unwrap();
+        let module = FlatpakModule::load_from_file(module_path.to_string()).unwrap();
+        match module.resolve(module_path) {
+            Err(FlatpakError::IncludeCycle(path)) => assert!(path.ends_with("module.yaml")),
+            _ => panic!("The include cycle should have been detected."),
+        }
+    }
+
     #[test]
     pub fn test_parse_extra_data() {
         let module_manifest = r###"
@@ -862,4 +2282,343 @@ mod tests {
         let application_dump = flatpak_application.dump().unwrap();
         assert!(!application_dump.contains("buildsystem"))
     }
+
+    #[test]
+    pub fn test_get_build_plan() {
+        let module_manifest = r###"
+            name: flatpak-rs
+            buildsystem: meson
+            config-opts:
+              - -Dtests=false
+            build-options:
+              cflags: -O2
+              config-opts:
+                - -Ddocs=false
+            sources:
+              - type: git
+                url: https://github.com/louib/flatpak-rs.git
+        "###;
+        let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+        let mut context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::X86);
+        context.n_jobs = 4;
+
+        let build_plan = module.get_build_plan("/run/build/flatpak-rs", &context).unwrap();
+        let command_lines: Vec<(FlatpakBuildStepKind, String, &str)> = build_plan
+            .steps
+            .iter()
+            .map(|s| (s.kind, s.get_command_line(), s.working_dir.as_str()))
+            .collect();
+        assert_eq!(
+            command_lines,
+            vec![
+                (
+                    FlatpakBuildStepKind::Configure,
+                    "mkdir -p /run/build/flatpak-rs/_flatpak_build".to_string(),
+                    "/run/build/flatpak-rs"
+                ),
+                (
+                    FlatpakBuildStepKind::Configure,
+                    "meson --prefix=/app -Dtests=false -Ddocs=false ..".to_string(),
+                    "/run/build/flatpak-rs/_flatpak_build"
+                ),
+                (
+                    FlatpakBuildStepKind::Build,
+                    "ninja -j4 -l8".to_string(),
+                    "/run/build/flatpak-rs/_flatpak_build"
+                ),
+                (
+                    FlatpakBuildStepKind::Install,
+                    "ninja install".to_string(),
+                    "/run/build/flatpak-rs/_flatpak_build"
+                ),
+            ]
+        );
+        assert!(build_plan
+            .steps
+            .iter()
+            .all(|s| s.env.get("CFLAGS") == Some("-O2")));
+        assert_eq!(
+            module
+                .get_commands("/run/build/flatpak-rs", &context)
+                .unwrap()
+                .len(),
+            4
+        );
+
+        let module = FlatpakModule::parse(
+            FlatpakManifestFormat::YAML,
+            "name: flatpak-rs\nsources: [{type: dir, path: .}]",
+        )
+        .unwrap();
+        assert_eq!(module.get_build_system(), FlatpakBuildSystem::Autotools);
+    }
+
+    #[test]
+    pub fn test_get_autotools_build_plan() {
+        let mut context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::X86);
+        context.n_jobs = 8;
+        let get_command_lines = |module_manifest: &str| -> Vec<String> {
+            let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+            let build_plan = module.get_build_plan("/run/build/libfoo", &context).unwrap();
+            build_plan
+                .steps
+                .iter()
+                .map(|s| {
+                    format!("{}: {}", s.working_dir, s.get_command_line()).replace("/run/build/libfoo", ".")
+                })
+                .collect()
+        };
+        let autogen_command_line = "/bin/sh -c '[ -e configure ] && exit 0; \
+            for autogen in autogen autogen.sh bootstrap bootstrap.sh; do [ -e \"$autogen\" ] && exec \"./$autogen\"; done; \
+            echo \"Can'\\''t find autogen, autogen.sh or bootstrap\" >&2; exit 1'";
+
+        // A release tarball, with a configure script.
+        let module_manifest = r###"
+            name: libfoo
+            config-opts: [--disable-static]
+            make-args: [V=1]
+            build-options:
+              libdir: /app/lib64
+              make-install-args: [DESTDIR=/tmp/dest]
+            sources:
+              - type: archive
+                url: https://download.louib.net/libfoo-1.0.tar.xz
+                sha256: 5e9e0d84bf66c8bb0d7a2da4fa0ac2e1a0cf4e4be8b3aa9ebd2a6a0b2ba5f0f4
+        "###;
+        assert_eq!(
+            get_command_lines(module_manifest),
+            vec![
+                format!(".: {}", autogen_command_line),
+                ".: ./configure --prefix=/app --libdir=/app/lib64 --disable-static".to_string(),
+                ".: make -j8 -l16 V=1".to_string(),
+                ".: make install DESTDIR=/tmp/dest".to_string(),
+            ]
+        );
+
+        // A git checkout in a subdirectory, where configure has to be generated.
+        let module_manifest = r###"
+            name: libfoo
+            subdir: src
+            builddir: true
+            rm-configure: true
+            no-parallel-make: true
+            install-rule: install-strip
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert_eq!(
+            get_command_lines(module_manifest),
+            vec![
+                "./src: rm -f configure".to_string(),
+                format!("./src: {}", autogen_command_line),
+                "./src: mkdir -p ./src/_flatpak_build".to_string(),
+                "./src/_flatpak_build: ../configure --prefix=/app".to_string(),
+                "./src/_flatpak_build: make".to_string(),
+                "./src/_flatpak_build: make install-strip".to_string(),
+            ]
+        );
+        let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+        let build_plan = module.get_build_plan("/run/build/libfoo", &context).unwrap();
+        assert_eq!(build_plan.steps[1].env.get("NOCONFIGURE"), Some("1"));
+
+        let module_manifest = r###"
+            name: libfoo
+            no-autogen: true
+            no-make-install: true
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert_eq!(
+            get_command_lines(module_manifest),
+            vec![".: ./configure --prefix=/app", ".: make -j8 -l16"]
+        );
+    }
+
+    #[test]
+    pub fn test_get_qmake_and_extended_build_plans() {
+        let mut context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::X86);
+        context.n_jobs = 2;
+        let get_command_lines = |module_manifest: &str| -> Result<Vec<String>, FlatpakError> {
+            let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+            let build_plan = module.get_build_plan("/run/build/module", &context)?;
+            Ok(build_plan.steps.iter().map(|s| s.get_command_line()).collect())
+        };
+
+        let module_manifest = r###"
+            name: qtfoo
+            buildsystem: qmake
+            config-opts: [CONFIG+=release]
+            sources:
+              - type: git
+                url: https://github.com/louib/qtfoo.git
+        "###;
+        assert_eq!(
+            get_command_lines(module_manifest).unwrap(),
+            vec![
+                "qmake PREFIX=/app CONFIG+=release",
+                "make -j2 -l4",
+                "make install"
+            ]
+        );
+
+        #[cfg(feature = "extended_build_systems")]
+        {
+            let module_manifest = r###"
+                name: hsfoo
+                buildsystem: cabal
+                sources:
+                  - type: git
+                    url: https://github.com/louib/hsfoo.git
+            "###;
+            assert_eq!(
+                get_command_lines(module_manifest).unwrap(),
+                vec![
+                    "runhaskell Setup configure --prefix=/app",
+                    "runhaskell Setup build",
+                    "runhaskell Setup install"
+                ]
+            );
+
+            let module_manifest = r###"
+                name: foo
+                buildsystem: make
+                make-install-args: [PREFIX=/app]
+                sources:
+                  - type: git
+                    url: https://github.com/louib/foo.git
+            "###;
+            assert_eq!(
+                get_command_lines(module_manifest).unwrap(),
+                vec!["make -j2 -l4", "make install PREFIX=/app"]
+            );
+
+            let module_manifest = r###"
+                name: flatpak-rs
+                buildsystem: cargo
+                config-opts: [--features, toml]
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+            "###;
+            assert_eq!(
+                get_command_lines(module_manifest).unwrap(),
+                vec![
+                    "cargo --offline build --release --jobs 2 --features toml",
+                    "cargo --offline install --path . --root /app --no-track --jobs 2 --features toml"
+                ]
+            );
+
+            let module_manifest = r###"
+                name: flatpak-rs
+                buildsystem: cargo
+                builddir: true
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+            "###;
+            assert!(matches!(
+                get_command_lines(module_manifest),
+                Err(FlatpakError::UnsupportedBuildSystem(_))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_get_test_steps() {
+        let context = FlatpakBuildContext::new("net.louib.flatpak-rs", FlatpakArchitecture::X86);
+        let get_test_steps = |module_manifest: &str| -> Vec<(String, String, Vec<String>)> {
+            let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+            let build_plan = module.get_build_plan("/run/build/module", &context).unwrap();
+            build_plan
+                .get_steps(FlatpakBuildStepKind::Test)
+                .iter()
+                .map(|s| {
+                    (
+                        s.working_dir.to_string(),
+                        s.get_command_line(),
+                        s.flatpak_build_args.clone(),
+                    )
+                })
+                .collect()
+        };
+
+        let module_manifest = r###"
+            name: libfoo
+            no-autogen: true
+            run-tests: true
+            build-options:
+              build-args: [--share=ipc]
+              test-args: [--share=network]
+            test-commands:
+              - ./run-integration-tests.sh
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert_eq!(
+            get_test_steps(module_manifest),
+            vec![
+                (
+                    "/run/build/module".to_string(),
+                    "make check".to_string(),
+                    vec!["--share=network".to_string()]
+                ),
+                (
+                    "/run/build/module".to_string(),
+                    "/bin/sh -c ./run-integration-tests.sh".to_string(),
+                    vec!["--share=network".to_string()]
+                ),
+            ]
+        );
+        let module = FlatpakModule::parse(FlatpakManifestFormat::YAML, module_manifest).unwrap();
+        let build_plan = module.get_build_plan("/run/build/module", &context).unwrap();
+        assert_eq!(build_plan.steps.last().unwrap().kind, FlatpakBuildStepKind::Test);
+        assert_eq!(build_plan.steps[0].flatpak_build_args, vec!["--share=ipc"]);
+
+        let module_manifest = r###"
+            name: libfoo
+            buildsystem: meson
+            run-tests: true
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        let test_steps = get_test_steps(module_manifest);
+        assert_eq!(test_steps.len(), 1);
+        assert_eq!(test_steps[0].0, "/run/build/module/_flatpak_build");
+        assert_eq!(test_steps[0].1, "meson test");
+
+        let module_manifest = r###"
+            name: libfoo
+            buildsystem: cmake-ninja
+            run-tests: true
+            test-rule: test-unit
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert_eq!(get_test_steps(module_manifest)[0].1, "ninja test-unit");
+
+        let module_manifest = r###"
+            name: libfoo
+            buildsystem: cmake-ninja
+            run-tests: true
+            test-rule: ""
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert!(get_test_steps(module_manifest).is_empty());
+
+        let module_manifest = r###"
+            name: libfoo
+            buildsystem: cmake-ninja
+            sources:
+              - type: git
+                url: https://github.com/louib/libfoo.git
+        "###;
+        assert!(get_test_steps(module_manifest).is_empty());
+    }
 }
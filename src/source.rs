@@ -1,12 +1,18 @@
 use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path;
 
 use lazy_static::lazy_static;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::architecture::{get_unknown_arch_errors, FlatpakArchitecture};
 use crate::archive::FlatpakArchiveType;
-use crate::format::FlatpakManifestFormat;
+use crate::checksum::{FlatpakChecksumHasher, FlatpakChecksumType, ALL_CHECKSUM_TYPES};
+use crate::error::FlatpakError;
+use crate::extra_fields::FlatpakExtraFields;
+use crate::format::{FlatpakManifestFormat, FlatpakParseOptions};
+use crate::syntax::FlatpakManifestPath;
 
 pub const ARCHIVE: &str = "archive";
 pub const GIT: &str = "git";
@@ -108,7 +114,7 @@ impl FlatpakSourceType {
         }
     }
 
-    pub fn from_string(source_type: &str) -> Result<FlatpakSourceType, String> {
+    pub fn from_string(source_type: &str) -> Result<FlatpakSourceType, FlatpakError> {
         if source_type == ARCHIVE {
             return Ok(FlatpakSourceType::Archive);
         }
@@ -139,7 +145,7 @@ impl FlatpakSourceType {
         if source_type == EXTRA_DATA {
             return Ok(FlatpakSourceType::ExtraData);
         }
-        Err(format!("Invalid source type {}.", source_type))
+        Err(FlatpakError::invalid_value("source type", source_type))
     }
 
     pub fn serialize<S>(x: &Option<FlatpakSourceType>, s: S) -> Result<S::Ok, S::Error>
@@ -160,7 +166,7 @@ impl FlatpakSourceType {
 
         match FlatpakSourceType::from_string(&buf) {
             Ok(b) => Ok(Some(b)),
-            Err(e) => Err(e).map_err(serde::de::Error::custom),
+            Err(e) => Err(serde::de::Error::custom(e)),
         }
     }
 }
@@ -367,8 +373,54 @@ pub struct FlatpakSource {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub x_checker_data: Option<FlatpakDataCheckerConfig>,
+
+    /// The path of the manifest file this source was defined in, if known.
+    /// This is only populated when resolving external sources.
+    #[serde(skip)]
+    pub manifest_path: Option<String>,
+
+    /// The fields that are not supported by this library, including the
+    /// extension fields prefixed with `x-`. They are preserved when dumping the manifest.
+    #[serde(flatten)]
+    pub extra_fields: FlatpakExtraFields,
 }
 impl FlatpakSource {
+    /// The names of the fields supported in source manifests.
+    pub const FIELD_NAMES: &'static [&'static str] = &[
+        "type",
+        "commands",
+        "dest-filename",
+        "filename",
+        "url",
+        "mirror-urls",
+        "md5",
+        "sha1",
+        "sha256",
+        "sha512",
+        "size",
+        "git-init",
+        "installed-size",
+        "revision",
+        "branch",
+        "archive-type",
+        "commit",
+        "tag",
+        "path",
+        "paths",
+        "use-git",
+        "use-git-am",
+        "options",
+        "disable-fsckobjects",
+        "disable-shallow-clone",
+        "disable-submodules",
+        "strip-components",
+        "skip",
+        "only-arches",
+        "skip-arches",
+        "dest",
+        "x-checker-data",
+    ];
+
     /// Get the type for the Flatpak source.
     pub fn get_type(&self) -> Option<FlatpakSourceType> {
         self.r#type.clone()
@@ -380,50 +432,66 @@ impl FlatpakSource {
         crate::filename::extension_is_valid(path)
     }
 
-    pub fn load_from_file(path: String) -> Result<Vec<FlatpakSource>, String> {
+    pub fn load_from_file(path: String) -> Result<Vec<FlatpakSource>, FlatpakError> {
+        FlatpakSource::load_from_file_with_options(path, &FlatpakParseOptions::default())
+    }
+
+    pub fn load_from_file_with_options(
+        path: String,
+        options: &FlatpakParseOptions,
+    ) -> Result<Vec<FlatpakSource>, FlatpakError> {
         let file_path = path::Path::new(&path);
         if !file_path.is_file() {
-            return Err(format!("{} is not a file.", path));
+            return Err(FlatpakError::Io {
+                path,
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file"),
+            });
         }
 
         let manifest_format = match FlatpakManifestFormat::from_path(&path) {
             Some(f) => f,
-            None => return Err(format!("{} is not a Flatpak source manifest.", path)),
+            None => return Err(FlatpakError::UnsupportedExtension(path)),
         };
 
         let manifest_content = match fs::read_to_string(file_path) {
             Ok(content) => content,
-            Err(e) => {
-                return Err(format!(
-                    "Could not read file {}: {}!",
-                    file_path.to_str().unwrap(),
-                    e
-                ))
-            }
+            Err(e) => return Err(FlatpakError::Io { path, source: e }),
         };
 
         // A standalone source manifest can contain a single source, or an array
         // of sources!!
-        if let Ok(source) = FlatpakSource::parse(manifest_format.clone(), &manifest_content) {
-            return Ok(vec![source]);
-        }
-        if let Ok(sources) = FlatpakSource::parse_many(manifest_format, &manifest_content) {
+        let parse_error =
+            match FlatpakSource::parse_with_options(manifest_format.clone(), &manifest_content, options) {
+                Ok(source) => return Ok(vec![source]),
+                Err(e) => e,
+            };
+        if let Ok(sources) = FlatpakSource::parse_many_with_options(manifest_format, &manifest_content, options)
+        {
             return Ok(sources);
         }
 
-        return Err(format!("Failed to parse Flatpak source manifest at {}.", path));
+        Err(parse_error.in_file(path))
     }
 
-    pub fn parse(format: FlatpakManifestFormat, manifest_content: &str) -> Result<FlatpakSource, String> {
-        let flatpak_source: FlatpakSource = match format.parse(manifest_content) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(format!("Failed to parse the Flatpak source manifest: {}.", e));
-            }
-        };
+    pub fn parse(format: FlatpakManifestFormat, manifest_content: &str) -> Result<FlatpakSource, FlatpakError> {
+        FlatpakSource::parse_with_options(format, manifest_content, &FlatpakParseOptions::default())
+    }
 
+    pub fn parse_with_options(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+        options: &FlatpakParseOptions,
+    ) -> Result<FlatpakSource, FlatpakError> {
+        let flatpak_source: FlatpakSource = format.parse(manifest_content)?;
+        let root_path = FlatpakManifestPath::default();
         if let Err(e) = flatpak_source.is_valid() {
-            return Err(e);
+            return Err(e.at_path(&format, manifest_content, &root_path));
+        }
+        if options.strict {
+            let errors = flatpak_source.get_unknown_field_errors(&root_path);
+            if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
+                return Err(e);
+            }
         }
         Ok(flatpak_source)
     }
@@ -431,33 +499,95 @@ impl FlatpakSource {
     pub fn parse_many(
         format: FlatpakManifestFormat,
         manifest_content: &str,
-    ) -> Result<Vec<FlatpakSource>, String> {
-        let flatpak_sources: Vec<FlatpakSource> = match format.parse(manifest_content) {
-            Ok(m) => m,
-            Err(e) => {
-                return Err(format!("Failed to parse the Flatpak source manifest: {}.", e));
-            }
-        };
+    ) -> Result<Vec<FlatpakSource>, FlatpakError> {
+        FlatpakSource::parse_many_with_options(format, manifest_content, &FlatpakParseOptions::default())
+    }
 
-        if flatpak_sources.len() == 0 {
-            return Err("Empty array is not a valid source manifest!".to_string());
+    pub fn parse_many_with_options(
+        format: FlatpakManifestFormat,
+        manifest_content: &str,
+        options: &FlatpakParseOptions,
+    ) -> Result<Vec<FlatpakSource>, FlatpakError> {
+        let flatpak_sources: Vec<FlatpakSource> = format.parse(manifest_content)?;
+
+        if flatpak_sources.is_empty() {
+            return Err(
+                FlatpakError::invalid_source("Empty array is not a valid source manifest!").at_path(
+                    &format,
+                    manifest_content,
+                    &FlatpakManifestPath::default(),
+                ),
+            );
         }
 
-        for flatpak_source in &flatpak_sources {
+        for (source_index, flatpak_source) in flatpak_sources.iter().enumerate() {
             if let Err(e) = flatpak_source.is_valid() {
+                let source_path = FlatpakManifestPath::default().join_index(source_index);
+                return Err(e.at_path(&format, manifest_content, &source_path));
+            }
+        }
+        if options.strict {
+            let mut errors = vec![];
+            for (source_index, flatpak_source) in flatpak_sources.iter().enumerate() {
+                let source_path = FlatpakManifestPath::default().join_index(source_index);
+                errors.append(&mut flatpak_source.get_unknown_field_errors(&source_path));
+            }
+            if let Some(e) = FlatpakError::from_errors_at_paths(errors, &format, manifest_content) {
                 return Err(e);
             }
         }
         Ok(flatpak_sources)
     }
 
-    pub fn is_valid(&self) -> Result<(), String> {
-        if self.url.is_none() && self.path.is_none() && self.commands.is_none() {
-            return Err("There should be at least a url, a path or inline commands in a source!".to_string());
+    /// Gets an error for each field of the source that is not supported by this library,
+    /// with the path of the field. `path` is the path of the source in the manifest.
+    pub fn get_unknown_field_errors(
+        &self,
+        path: &FlatpakManifestPath,
+    ) -> Vec<(FlatpakManifestPath, FlatpakError)> {
+        let mut errors = self
+            .extra_fields
+            .get_unknown_field_errors(path, FlatpakSource::FIELD_NAMES);
+        if let Some(only_arches) = &self.only_arches {
+            errors.append(&mut get_unknown_arch_errors(
+                only_arches,
+                &path.join_key("only-arches"),
+            ));
+        }
+        if let Some(skip_arches) = &self.skip_arches {
+            errors.append(&mut get_unknown_arch_errors(
+                skip_arches,
+                &path.join_key("skip-arches"),
+            ));
+        }
+        errors
+    }
+
+    pub fn is_valid(&self) -> Result<(), FlatpakError> {
+        if self.url.is_none() && self.path.is_none() && self.paths.is_none() && self.commands.is_none() {
+            return Err(FlatpakError::invalid_source(
+                "There should be at least a url, a path or inline commands in a source!",
+            ));
         }
         Ok(())
     }
 
+    /// Returns `true` if the source is used when building for the architecture, i.e. if it is
+    /// not excluded by `only-arches` or `skip-arches`.
+    pub fn is_built_for_arch(&self, arch: &FlatpakArchitecture) -> bool {
+        if let Some(only_arches) = &self.only_arches {
+            if !only_arches.is_empty() && !only_arches.iter().any(|a| arch.is_named(a)) {
+                return false;
+            }
+        }
+        if let Some(skip_arches) = &self.skip_arches {
+            if skip_arches.iter().any(|a| arch.is_named(a)) {
+                return false;
+            }
+        }
+        true
+    }
+
     pub fn get_url(&self) -> Option<String> {
         match &self.url {
             Some(s) => Some(s.to_string()),
@@ -516,6 +646,150 @@ impl FlatpakSource {
         }
         return false;
     }
+
+    /// Gets the checksums of the source, from the weakest to the strongest.
+    pub fn get_checksums(&self) -> Vec<(FlatpakChecksumType, String)> {
+        let checksums = [&self.md5, &self.sha1, &self.sha256, &self.sha512];
+        ALL_CHECKSUM_TYPES
+            .iter()
+            .zip(checksums.iter())
+            .filter_map(|(t, c)| c.as_ref().map(|c| (*t, c.trim().to_lowercase())))
+            .collect()
+    }
+
+    /// Gets a hasher computing all the checksums of the source. The content to verify
+    /// can be streamed to the hasher, and then verified with [`FlatpakSource::verify_hasher`].
+    pub fn get_checksum_hasher(&self) -> FlatpakChecksumHasher {
+        let checksum_types: Vec<FlatpakChecksumType> = self.get_checksums().iter().map(|(t, _)| *t).collect();
+        FlatpakChecksumHasher::new(&checksum_types)
+    }
+
+    /// Verifies the content streamed to the hasher against every checksum of the source,
+    /// and against the size of the extra-data sources. `path` is the path or the url of the
+    /// content, which is reported in the errors.
+    ///
+    /// The archive, file and extra-data sources that are downloaded from a url must have
+    /// a checksum, so the verification fails if they do not.
+    pub fn verify_hasher(&self, hasher: FlatpakChecksumHasher, path: &str) -> Result<(), FlatpakError> {
+        let size = hasher.get_size();
+        self.verify_digests(&hasher.finalize(), size, path)
+    }
+
+    /// Verifies content against the checksums of the source. See [`FlatpakSource::verify_hasher`].
+    pub fn verify_bytes(&self, content: &[u8]) -> Result<(), FlatpakError> {
+        let mut hasher = self.get_checksum_hasher();
+        hasher.update(content);
+        let path = self
+            .url
+            .as_ref()
+            .or(self.path.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        self.verify_hasher(hasher, &path)
+    }
+
+    /// Verifies a file against the checksums of the source. See [`FlatpakSource::verify_hasher`].
+    pub fn verify_file(&self, file_path: &str) -> Result<(), FlatpakError> {
+        let mut hasher = self.get_checksum_hasher();
+        hash_file(file_path, &mut hasher)?;
+        self.verify_hasher(hasher, file_path)
+    }
+
+    fn verify_digests(
+        &self,
+        digests: &[(FlatpakChecksumType, String)],
+        size: u64,
+        path: &str,
+    ) -> Result<(), FlatpakError> {
+        let checksums = self.get_checksums();
+        let is_downloaded = matches!(
+            self.get_type(),
+            Some(FlatpakSourceType::Archive)
+                | Some(FlatpakSourceType::File)
+                | Some(FlatpakSourceType::ExtraData)
+        ) && self.url.is_some();
+        if is_downloaded && checksums.is_empty() {
+            return Err(FlatpakError::invalid_source(&format!(
+                "The source downloaded from {} does not have any checksum.",
+                self.url.as_ref().unwrap()
+            )));
+        }
+        if self.get_type() == Some(FlatpakSourceType::ExtraData) {
+            if let Some(expected_size) = self.size {
+                if expected_size < 0 || expected_size as u64 != size {
+                    return Err(FlatpakError::SizeMismatch {
+                        expected: expected_size.max(0) as u64,
+                        actual: size,
+                    });
+                }
+            }
+        }
+        for (checksum_type, expected) in checksums {
+            let actual = match digests.iter().find(|(t, _)| *t == checksum_type) {
+                Some((_, d)) => d,
+                None => continue,
+            };
+            if *actual != expected {
+                return Err(FlatpakError::ChecksumMismatch {
+                    path: path.to_string(),
+                    checksum_type,
+                    expected,
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Computes the sha256 and sha512 checksums of a local archive or file source, if they are
+    /// missing. The checksums already present are verified first. The `path` of the source is
+    /// relative to `base_dir`, which is usually the directory of the manifest.
+    ///
+    /// Returns `true` if a checksum was added.
+    pub fn fill_missing_checksums(&mut self, base_dir: &str) -> Result<bool, FlatpakError> {
+        let source_path = match &self.path {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        if !matches!(
+            self.get_type(),
+            Some(FlatpakSourceType::Archive) | Some(FlatpakSourceType::File)
+        ) {
+            return Ok(false);
+        }
+        if self.sha256.is_some() && self.sha512.is_some() {
+            return Ok(false);
+        }
+
+        let file_path = path::Path::new(base_dir)
+            .join(source_path)
+            .to_string_lossy()
+            .to_string();
+        let mut hasher = FlatpakChecksumHasher::new(ALL_CHECKSUM_TYPES);
+        hash_file(&file_path, &mut hasher)?;
+        let size = hasher.get_size();
+        let digests = hasher.finalize();
+        self.verify_digests(&digests, size, &file_path)?;
+
+        for (checksum_type, digest) in digests {
+            match checksum_type {
+                FlatpakChecksumType::SHA256 if self.sha256.is_none() => self.sha256 = Some(digest),
+                FlatpakChecksumType::SHA512 if self.sha512.is_none() => self.sha512 = Some(digest),
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn hash_file(file_path: &str, hasher: &mut FlatpakChecksumHasher) -> Result<(), FlatpakError> {
+    let io_error = |e| FlatpakError::Io {
+        path: file_path.to_string(),
+        source: e,
+    };
+    let mut file = fs::File::open(file_path).map_err(io_error)?;
+    io::copy(&mut file, hasher).map_err(io_error)?;
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -550,6 +824,7 @@ pub struct FlatpakDataCheckerConfig {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::utils::TestDir;
 
     #[test]
     pub fn test_parse_single_source_manifest() {
@@ -617,6 +892,7 @@ mod tests {
                 panic!("We should not be able to parse a source manifest with an invalid source type");
             }
             Err(e) => {
+                assert!(matches!(e, FlatpakError::Syntax { .. }));
                 assert!(e.to_string().contains("Invalid source type"));
             }
         }
@@ -724,4 +1000,96 @@ mod tests {
             panic!("We should not parse an empty json array as many source manifests",);
         }
     }
+
+    #[test]
+    pub fn test_verify_checksums() {
+        let source_manifest = r###"
+            type: file
+            url: https://example.com/flatpak.txt
+            md5: 9cb6e3099d15ec27d79a619bce76e802
+            sha256: 0000000000000000000000000000000000000000000000000000000000000000
+        "###;
+        let source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        match source.verify_bytes(b"flatpak") {
+            Err(FlatpakError::ChecksumMismatch {
+                path,
+                checksum_type,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(path, "https://example.com/flatpak.txt");
+                assert_eq!(checksum_type, FlatpakChecksumType::SHA256);
+                assert_eq!(expected, source.sha256.clone().unwrap());
+                assert_eq!(actual, FlatpakChecksumType::SHA256.compute(b"flatpak"));
+            }
+            _ => panic!("The sha256 checksum should not match."),
+        }
+        assert!(source.verify_bytes(b"flatpak-rs").is_err());
+
+        // A downloaded file cannot be verified without a checksum.
+        let source_manifest = r###"
+            type: archive
+            url: https://example.com/flatpak.tar.gz
+        "###;
+        let source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        assert!(matches!(
+            source.verify_bytes(b"flatpak"),
+            Err(FlatpakError::InvalidSource { .. })
+        ));
+        let source_manifest = r###"
+            type: file
+            path: flatpak.txt
+        "###;
+        let source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        source.verify_bytes(b"flatpak").unwrap();
+
+        let source_manifest = r###"
+            type: extra-data
+            filename: flatpak.txt
+            url: https://example.com/flatpak.txt
+            sha256: 6f4f2b0a9f1b3ed2e3c1e2c8a7e4b9d1c84b7c56b9b5f1a4a0b2f6a8d7f2c0e1
+            size: 8
+        "###;
+        let source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        assert!(matches!(
+            source.verify_bytes(b"flatpak"),
+            Err(FlatpakError::SizeMismatch {
+                expected: 8,
+                actual: 7
+            })
+        ));
+    }
+
+    #[test]
+    pub fn test_fill_missing_checksums() {
+        let test_dir = TestDir::new("checksums");
+        fs::write(test_dir.join("flatpak.txt"), "flatpak").unwrap();
+        let source_manifest = r###"
+            type: file
+            path: flatpak.txt
+            md5: 9cb6e3099d15ec27d79a619bce76e802
+        "###;
+        let mut source = FlatpakSource::parse(FlatpakManifestFormat::YAML, source_manifest).unwrap();
+        assert!(source.fill_missing_checksums(test_dir.to_str().unwrap()).unwrap());
+        assert_eq!(
+            source.sha256,
+            Some(FlatpakChecksumType::SHA256.compute(b"flatpak"))
+        );
+        assert_eq!(
+            source.sha512,
+            Some(FlatpakChecksumType::SHA512.compute(b"flatpak"))
+        );
+        assert!(!source.fill_missing_checksums(test_dir.to_str().unwrap()).unwrap());
+        source
+            .verify_file(test_dir.join("flatpak.txt").to_str().unwrap())
+            .unwrap();
+
+        source.sha256 = None;
+        source.md5 = Some("00000000000000000000000000000000".to_string());
+        match source.fill_missing_checksums(test_dir.to_str().unwrap()) {
+            Err(FlatpakError::ChecksumMismatch { path, .. }) => assert!(path.ends_with("flatpak.txt")),
+            _ => panic!("The md5 checksum should not match."),
+        }
+        assert!(source.sha256.is_none());
+    }
 }
@@ -0,0 +1,1451 @@
+//! A minimal syntax tree for Flatpak manifests, which keeps track of where each node
+//! is located in the original document.
+//!
+//! This is not meant to replace the serde-based parsing of the manifests, but to complement
+//! it when the layout of the document matters, for example when editing a manifest in place.
+//! Only the subset of YAML that is used in Flatpak manifests is supported.
+use std::fmt;
+use std::ops::Range;
+
+use crate::error::{FlatpakError, FlatpakLocation};
+use crate::format::FlatpakManifestFormat;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FlatpakPathSegment {
+    Key(String),
+    Index(usize),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Default)]
+#[derive(PartialEq)]
+/// A path to a node in a manifest, for example `modules[3].sources[1]`.
+/// The empty path designates the root of the manifest.
+pub struct FlatpakManifestPath {
+    pub segments: Vec<FlatpakPathSegment>,
+}
+impl FlatpakManifestPath {
+    pub fn parse(path: &str) -> Result<FlatpakManifestPath, FlatpakError> {
+        let invalid_path = || FlatpakError::InvalidPath(path.to_string());
+
+        let mut segments: Vec<FlatpakPathSegment> = vec![];
+        if path.is_empty() {
+            return Ok(FlatpakManifestPath { segments });
+        }
+        for (part_index, part) in path.split('.').enumerate() {
+            let key_end = part.find('[').unwrap_or(part.len());
+            let key = &part[..key_end];
+            if !key.is_empty() {
+                segments.push(FlatpakPathSegment::Key(key.to_string()));
+            } else if part_index != 0 || key_end == part.len() {
+                // Only the first part of a path can start with an index, like
+                // in `[0].url`.
+                return Err(invalid_path());
+            }
+
+            let mut indexes = &part[key_end..];
+            while !indexes.is_empty() {
+                if !indexes.starts_with('[') {
+                    return Err(invalid_path());
+                }
+                let index_end = indexes.find(']').ok_or_else(invalid_path)?;
+                let index = indexes[1..index_end]
+                    .parse::<usize>()
+                    .map_err(|_| invalid_path())?;
+                segments.push(FlatpakPathSegment::Index(index));
+                indexes = &indexes[index_end + 1..];
+            }
+        }
+        Ok(FlatpakManifestPath { segments })
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn join_key(&self, key: &str) -> FlatpakManifestPath {
+        let mut segments = self.segments.clone();
+        segments.push(FlatpakPathSegment::Key(key.to_string()));
+        FlatpakManifestPath { segments }
+    }
+
+    pub fn join_index(&self, index: usize) -> FlatpakManifestPath {
+        let mut segments = self.segments.clone();
+        segments.push(FlatpakPathSegment::Index(index));
+        FlatpakManifestPath { segments }
+    }
+
+    /// Splits the path into the path of the parent node and the last segment.
+    pub fn split_last(&self) -> Option<(FlatpakManifestPath, &FlatpakPathSegment)> {
+        let (last, parent_segments) = self.segments.split_last()?;
+        let parent = FlatpakManifestPath {
+            segments: parent_segments.to_vec(),
+        };
+        Some((parent, last))
+    }
+}
+impl fmt::Display for FlatpakManifestPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (segment_index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                FlatpakPathSegment::Key(key) => {
+                    if segment_index != 0 {
+                        write!(f, ".")?;
+                    }
+                    write!(f, "{}", key)?;
+                }
+                FlatpakPathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum FlatpakScalarStyle {
+    Plain,
+    SingleQuoted,
+    DoubleQuoted,
+    Literal,
+    Folded,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+/// A node of the syntax tree. The span of the node is the byte range of the
+/// node's content in the original document, without the surrounding whitespace
+/// and comments. Empty values (for example `key:` in YAML) have an empty span.
+pub struct SyntaxNode {
+    pub span: Range<usize>,
+    pub kind: SyntaxNodeKind,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum SyntaxNodeKind {
+    Mapping {
+        flow: bool,
+        entries: Vec<SyntaxEntry>,
+    },
+    Sequence {
+        flow: bool,
+        items: Vec<SyntaxItem>,
+    },
+    Scalar {
+        value: String,
+        style: FlatpakScalarStyle,
+    },
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SyntaxEntry {
+    pub key: String,
+    pub key_span: Range<usize>,
+    /// The position right after the `:` separating the key from the value.
+    pub colon_end: usize,
+    pub value: SyntaxNode,
+    /// The position of the `,` following the entry, in flow mappings.
+    pub separator: Option<usize>,
+}
+impl SyntaxEntry {
+    pub fn end(&self) -> usize {
+        std::cmp::max(self.value.span.end, self.colon_end)
+    }
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct SyntaxItem {
+    /// The start of the item, which is the position of the `-` indicator in block sequences.
+    pub start: usize,
+    pub value: SyntaxNode,
+    /// The position of the `,` following the item, in flow sequences.
+    pub separator: Option<usize>,
+}
+impl SyntaxItem {
+    pub fn end(&self) -> usize {
+        std::cmp::max(self.value.span.end, self.start + 1)
+    }
+}
+
+impl SyntaxNode {
+    pub fn parse(format: &FlatpakManifestFormat, content: &str) -> Result<SyntaxNode, FlatpakError> {
+        let result = match format {
+            FlatpakManifestFormat::JSON => JsonParser::new(content).parse_document(),
+            FlatpakManifestFormat::YAML => YamlParser::new(content).parse_document(),
+            #[cfg(feature = "toml")]
+            FlatpakManifestFormat::TOML => Err(SyntaxError {
+                message: "The TOML format is not supported.".to_string(),
+                offset: 0,
+            }),
+        };
+        result.map_err(|e| e.into_flatpak_error(format, content))
+    }
+
+    pub fn is_flow(&self) -> bool {
+        match &self.kind {
+            SyntaxNodeKind::Mapping { flow, .. } => *flow,
+            SyntaxNodeKind::Sequence { flow, .. } => *flow,
+            SyntaxNodeKind::Scalar { .. } => false,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.kind {
+            SyntaxNodeKind::Scalar { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_entry(&self, key: &str) -> Option<&SyntaxEntry> {
+        match &self.kind {
+            SyntaxNodeKind::Mapping { entries, .. } => entries.iter().find(|e| e.key == key),
+            _ => None,
+        }
+    }
+
+    pub fn get_item(&self, index: usize) -> Option<&SyntaxItem> {
+        match &self.kind {
+            SyntaxNodeKind::Sequence { items, .. } => items.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, path: &FlatpakManifestPath) -> Option<&SyntaxNode> {
+        let mut node = self;
+        for segment in &path.segments {
+            node = match segment {
+                FlatpakPathSegment::Key(key) => &node.get_entry(key)?.value,
+                FlatpakPathSegment::Index(index) => &node.get_item(*index)?.value,
+            };
+        }
+        Some(node)
+    }
+
+    /// Gets the byte ranges of the comments of the document, which are the only parts of the
+    /// document that are not represented in the syntax tree. `content` is the document that
+    /// was parsed to build the tree.
+    pub fn get_comments(&self, format: &FlatpakManifestFormat, content: &str) -> Vec<Range<usize>> {
+        let mut token_spans: Vec<Range<usize>> = vec![];
+        self.collect_token_spans(&mut token_spans);
+        token_spans.sort_by_key(|span| span.start);
+
+        let bytes = content.as_bytes();
+        let mut comments: Vec<Range<usize>> = vec![];
+        let mut token_spans = token_spans.iter().peekable();
+        let mut position = 0;
+        while position < bytes.len() {
+            if let Some(span) = token_spans.peek() {
+                if span.start <= position {
+                    position = std::cmp::max(position, span.end);
+                    token_spans.next();
+                    continue;
+                }
+            }
+            let comment_end = match format {
+                FlatpakManifestFormat::JSON if content[position..].starts_with("//") => {
+                    Some(get_line_end(content, position))
+                }
+                FlatpakManifestFormat::JSON if content[position..].starts_with("/*") => {
+                    match content[position + 2..].find("*/") {
+                        Some(i) => Some(position + i + 4),
+                        None => Some(content.len()),
+                    }
+                }
+                FlatpakManifestFormat::YAML
+                    if bytes[position] == b'#'
+                        && (position == 0 || bytes[position - 1].is_ascii_whitespace()) =>
+                {
+                    Some(get_line_end(content, position))
+                }
+                _ => None,
+            };
+            match comment_end {
+                Some(comment_end) => {
+                    comments.push(position..comment_end);
+                    position = comment_end;
+                }
+                None => position += 1,
+            }
+        }
+        comments
+    }
+
+    fn collect_token_spans(&self, token_spans: &mut Vec<Range<usize>>) {
+        match &self.kind {
+            SyntaxNodeKind::Mapping { entries, .. } => {
+                for entry in entries {
+                    token_spans.push(entry.key_span.clone());
+                    entry.value.collect_token_spans(token_spans);
+                }
+            }
+            SyntaxNodeKind::Sequence { items, .. } => {
+                for item in items {
+                    item.value.collect_token_spans(token_spans);
+                }
+            }
+            SyntaxNodeKind::Scalar { .. } => token_spans.push(self.span.clone()),
+        }
+    }
+
+    /// Gets the path of the innermost node containing the byte offset.
+    pub fn get_path_at(&self, offset: usize) -> FlatpakManifestPath {
+        let mut path = FlatpakManifestPath::default();
+        let mut node = self;
+        loop {
+            let next_node = match &node.kind {
+                SyntaxNodeKind::Mapping { entries, .. } => entries
+                    .iter()
+                    .find(|e| e.key_span.start <= offset && offset <= e.end())
+                    .map(|e| (FlatpakPathSegment::Key(e.key.to_string()), &e.value)),
+                SyntaxNodeKind::Sequence { items, .. } => items
+                    .iter()
+                    .enumerate()
+                    .find(|(_, i)| i.start <= offset && offset <= i.end())
+                    .map(|(index, i)| (FlatpakPathSegment::Index(index), &i.value)),
+                SyntaxNodeKind::Scalar { .. } => None,
+            };
+            match next_node {
+                Some((segment, next_node)) => {
+                    path.segments.push(segment);
+                    node = next_node;
+                }
+                None => return path,
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub message: String,
+    /// The byte offset at which the error occured.
+    pub offset: usize,
+}
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+impl std::error::Error for SyntaxError {}
+impl SyntaxError {
+    /// Converts the error to a [`FlatpakError`], located in `content`.
+    pub fn into_flatpak_error(self, format: &FlatpakManifestFormat, content: &str) -> FlatpakError {
+        let (line, column) = get_line_column(content, self.offset);
+        FlatpakError::Syntax {
+            format: format.clone(),
+            location: Some(FlatpakLocation {
+                file: None,
+                path: FlatpakManifestPath::default(),
+                offset: self.offset,
+                line,
+                column,
+            }),
+            source: Box::new(self),
+        }
+    }
+}
+
+/// Gets the 1-based line and column of a byte offset in a document.
+/// The column is counted in characters.
+pub fn get_line_column(content: &str, offset: usize) -> (usize, usize) {
+    let offset = std::cmp::min(offset, content.len());
+    let line_start = get_line_start(content, offset);
+    let line = content[..line_start].matches('\n').count() + 1;
+    let column = content[line_start..offset].chars().count() + 1;
+    (line, column)
+}
+
+pub fn get_line_start(content: &str, offset: usize) -> usize {
+    match content[..offset].rfind('\n') {
+        Some(i) => i + 1,
+        None => 0,
+    }
+}
+
+pub fn get_line_end(content: &str, offset: usize) -> usize {
+    match content[offset..].find('\n') {
+        Some(i) => offset + i,
+        None => content.len(),
+    }
+}
+
+fn empty_scalar(position: usize) -> SyntaxNode {
+    SyntaxNode {
+        span: position..position,
+        kind: SyntaxNodeKind::Scalar {
+            value: "".to_string(),
+            style: FlatpakScalarStyle::Plain,
+        },
+    }
+}
+
+struct JsonParser<'a> {
+    content: &'a str,
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> JsonParser<'a> {
+    fn new(content: &'a str) -> JsonParser<'a> {
+        JsonParser {
+            content,
+            bytes: content.as_bytes(),
+            position: 0,
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, SyntaxError> {
+        Err(SyntaxError {
+            message: message.to_string(),
+            offset: self.position,
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    /// Skips the whitespace and the comments.
+    fn skip_trivia(&mut self) -> Result<(), SyntaxError> {
+        while let Some(c) = self.peek() {
+            if c.is_ascii_whitespace() {
+                self.position += 1;
+            } else if self.content[self.position..].starts_with("//") {
+                self.position = get_line_end(self.content, self.position);
+            } else if self.content[self.position..].starts_with("/*") {
+                match self.content[self.position + 2..].find("*/") {
+                    Some(i) => self.position += i + 4,
+                    None => return self.error("Unterminated comment"),
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_document(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        self.skip_trivia()?;
+        let root = self.parse_value()?;
+        self.skip_trivia()?;
+        if self.position != self.bytes.len() {
+            return self.error("Unexpected content after the end of the document");
+        }
+        Ok(root)
+    }
+
+    fn parse_value(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        match self.peek() {
+            Some(b'{') => self.parse_object(),
+            Some(b'[') => self.parse_array(),
+            Some(b'"') => {
+                let start = self.position;
+                let value = self.parse_string()?;
+                Ok(SyntaxNode {
+                    span: start..self.position,
+                    kind: SyntaxNodeKind::Scalar {
+                        value,
+                        style: FlatpakScalarStyle::DoubleQuoted,
+                    },
+                })
+            }
+            Some(_) => {
+                let start = self.position;
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == b'-' || c == b'+' || c == b'.' {
+                        self.position += 1;
+                    } else {
+                        break;
+                    }
+                }
+                if start == self.position {
+                    return self.error("Unexpected character");
+                }
+                Ok(SyntaxNode {
+                    span: start..self.position,
+                    kind: SyntaxNodeKind::Scalar {
+                        value: self.content[start..self.position].to_string(),
+                        style: FlatpakScalarStyle::Plain,
+                    },
+                })
+            }
+            None => self.error("Unexpected end of the document"),
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, SyntaxError> {
+        // Skipping the opening quote.
+        self.position += 1;
+        let mut value = String::new();
+        loop {
+            let c = match self.content[self.position..].chars().next() {
+                Some(c) => c,
+                None => return self.error("Unterminated string"),
+            };
+            self.position += c.len_utf8();
+            match c {
+                '"' => return Ok(value),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        Some(e) => e,
+                        None => return self.error("Unterminated string"),
+                    };
+                    self.position += 1;
+                    match escaped {
+                        b'"' => value.push('"'),
+                        b'\\' => value.push('\\'),
+                        b'/' => value.push('/'),
+                        b'b' => value.push('\u{8}'),
+                        b'f' => value.push('\u{c}'),
+                        b'n' => value.push('\n'),
+                        b'r' => value.push('\r'),
+                        b't' => value.push('\t'),
+                        b'u' => value.push(self.parse_unicode_escape()?),
+                        _ => return self.error("Invalid escape sequence"),
+                    }
+                }
+                _ => value.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, SyntaxError> {
+        let hex = match self.content.get(self.position..self.position + 4) {
+            Some(h) => h,
+            None => return self.error("Invalid unicode escape sequence"),
+        };
+        match u32::from_str_radix(hex, 16) {
+            Ok(code) => {
+                self.position += 4;
+                Ok(code)
+            }
+            Err(_) => self.error("Invalid unicode escape sequence"),
+        }
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, SyntaxError> {
+        let mut code = self.parse_hex4()?;
+        if (0xD800..0xDC00).contains(&code) && self.content[self.position..].starts_with("\\u") {
+            self.position += 2;
+            let low = self.parse_hex4()?;
+            code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+        }
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("Invalid unicode escape sequence"),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        self.position += 1;
+        let mut entries: Vec<SyntaxEntry> = vec![];
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some(b'}') {
+                self.position += 1;
+                break;
+            }
+            if self.peek() != Some(b'"') {
+                return self.error("Expected a string key");
+            }
+            let key_start = self.position;
+            let key = self.parse_string()?;
+            let key_span = key_start..self.position;
+            self.skip_trivia()?;
+            if self.peek() != Some(b':') {
+                return self.error("Expected ':'");
+            }
+            self.position += 1;
+            let colon_end = self.position;
+            self.skip_trivia()?;
+            let value = self.parse_value()?;
+            self.skip_trivia()?;
+
+            let mut separator = None;
+            match self.peek() {
+                Some(b',') => {
+                    separator = Some(self.position);
+                    self.position += 1;
+                }
+                Some(b'}') => {}
+                _ => return self.error("Expected ',' or '}'"),
+            }
+            entries.push(SyntaxEntry {
+                key,
+                key_span,
+                colon_end,
+                value,
+                separator,
+            });
+        }
+        Ok(SyntaxNode {
+            span: start..self.position,
+            kind: SyntaxNodeKind::Mapping { flow: true, entries },
+        })
+    }
+
+    fn parse_array(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        self.position += 1;
+        let mut items: Vec<SyntaxItem> = vec![];
+        loop {
+            self.skip_trivia()?;
+            if self.peek() == Some(b']') {
+                self.position += 1;
+                break;
+            }
+            let item_start = self.position;
+            let value = self.parse_value()?;
+            self.skip_trivia()?;
+
+            let mut separator = None;
+            match self.peek() {
+                Some(b',') => {
+                    separator = Some(self.position);
+                    self.position += 1;
+                }
+                Some(b']') => {}
+                _ => return self.error("Expected ',' or ']'"),
+            }
+            items.push(SyntaxItem {
+                start: item_start,
+                value,
+                separator,
+            });
+        }
+        Ok(SyntaxNode {
+            span: start..self.position,
+            kind: SyntaxNodeKind::Sequence { flow: true, items },
+        })
+    }
+}
+
+struct YamlParser<'a> {
+    content: &'a str,
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> YamlParser<'a> {
+    fn new(content: &'a str) -> YamlParser<'a> {
+        YamlParser {
+            content,
+            bytes: content.as_bytes(),
+            position: 0,
+        }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, SyntaxError> {
+        Err(SyntaxError {
+            message: message.to_string(),
+            offset: self.position,
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn peek_at(&self, position: usize) -> Option<u8> {
+        self.bytes.get(position).copied()
+    }
+
+    fn is_eof(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    fn column(&self, position: usize) -> isize {
+        (position - get_line_start(self.content, position)) as isize
+    }
+
+    fn is_blank_at(&self, position: usize) -> bool {
+        match self.peek_at(position) {
+            None => true,
+            Some(c) => c == b' ' || c == b'\t' || c == b'\n' || c == b'\r',
+        }
+    }
+
+    fn is_comment_at(&self, position: usize) -> bool {
+        self.peek_at(position) == Some(b'#') && (position == 0 || self.is_blank_at(position - 1))
+    }
+
+    fn is_sequence_indicator_at(&self, position: usize) -> bool {
+        self.peek_at(position) == Some(b'-') && self.is_blank_at(position + 1)
+    }
+
+    fn is_document_marker_at(&self, position: usize) -> bool {
+        self.column(position) == 0
+            && (self.content[position..].starts_with("---") || self.content[position..].starts_with("..."))
+            && self.is_blank_at(position + 3)
+    }
+
+    /// Skips the spaces and tabs on the current line.
+    fn skip_spaces(&mut self) {
+        while let Some(c) = self.peek() {
+            if c != b' ' && c != b'\t' {
+                break;
+            }
+            self.position += 1;
+        }
+    }
+
+    /// Whether the rest of the current line is empty or a comment.
+    fn is_at_line_end(&self) -> bool {
+        match self.peek() {
+            None | Some(b'\n') | Some(b'\r') => true,
+            Some(_) => self.is_comment_at(self.position),
+        }
+    }
+
+    /// Skips whitespace, newlines and comments until the next token.
+    fn skip_trivia(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.position += 1,
+                Some(b'#') if self.is_comment_at(self.position) => {
+                    self.position = get_line_end(self.content, self.position);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        self.skip_trivia();
+        while self.peek() == Some(b'%') && self.column(self.position) == 0 {
+            self.position = get_line_end(self.content, self.position);
+            self.skip_trivia();
+        }
+        if self.content[self.position..].starts_with("---") && self.is_blank_at(self.position + 3) {
+            self.position += 3;
+            self.skip_trivia();
+        }
+        if self.is_eof() {
+            return Ok(empty_scalar(self.position));
+        }
+
+        let root = self.parse_block_node(-1)?;
+        self.skip_trivia();
+        if !self.is_eof() && !self.is_document_marker_at(self.position) {
+            return self.error("Unexpected content after the end of the document");
+        }
+        Ok(root)
+    }
+
+    fn skip_properties(&mut self) {
+        while let Some(c) = self.peek() {
+            if c != b'&' && c != b'!' {
+                break;
+            }
+            while !self.is_blank_at(self.position) {
+                self.position += 1;
+            }
+            self.skip_spaces();
+        }
+    }
+
+    /// Parses a node in block context. The parser must be positioned on the first token
+    /// of the node, and the node has to be indented more than `parent_indent`.
+    fn parse_block_node(&mut self, parent_indent: isize) -> Result<SyntaxNode, SyntaxError> {
+        self.skip_properties();
+        if self.is_at_line_end() {
+            let position = self.position;
+            self.skip_trivia();
+            if self.is_eof() || self.column(self.position) <= parent_indent {
+                self.position = position;
+                return Ok(empty_scalar(position));
+            }
+        }
+
+        let indent = self.column(self.position);
+        match self.peek() {
+            Some(b'-') if self.is_sequence_indicator_at(self.position) => self.parse_block_sequence(indent),
+            Some(b'[') | Some(b'{') => self.parse_flow_node(),
+            Some(b'|') | Some(b'>') => self.parse_block_scalar(parent_indent),
+            Some(b'?') if self.is_blank_at(self.position + 1) => self.error("Complex keys are not supported"),
+            _ => {
+                if self.is_mapping_key() {
+                    return self.parse_block_mapping(indent);
+                }
+                match self.peek() {
+                    Some(b'"') | Some(b'\'') => self.parse_quoted_scalar(),
+                    _ => self.parse_plain_scalar(parent_indent),
+                }
+            }
+        }
+    }
+
+    /// Whether the parser is positioned on an implicit key of a block mapping.
+    fn is_mapping_key(&mut self) -> bool {
+        let start = self.position;
+        let is_key = match self.parse_key() {
+            Ok(_) => self.peek() == Some(b':') && self.is_blank_at(self.position + 1),
+            Err(_) => false,
+        };
+        self.position = start;
+        is_key
+    }
+
+    /// Parses a key of a block mapping. The parser is positioned right before
+    /// the `:` indicator after the key, or at the end of the line if there is no
+    /// indicator.
+    fn parse_key(&mut self) -> Result<(String, Range<usize>), SyntaxError> {
+        let start = self.position;
+        let key = match self.peek() {
+            Some(b'"') | Some(b'\'') => {
+                let node = self.parse_quoted_scalar()?;
+                let key_end = self.position;
+                self.skip_spaces();
+                return match node.kind {
+                    SyntaxNodeKind::Scalar { value, .. } => Ok((value, start..key_end)),
+                    _ => self.error("Invalid key"),
+                };
+            }
+            _ => {
+                let line_end = get_line_end(self.content, self.position);
+                let mut key_end = line_end;
+                let mut position = self.position;
+                while position < line_end {
+                    if self.bytes[position] == b':' && self.is_blank_at(position + 1) {
+                        key_end = position;
+                        break;
+                    }
+                    if self.is_comment_at(position) {
+                        key_end = position;
+                        break;
+                    }
+                    position += 1;
+                }
+                self.content[start..key_end].trim_end().to_string()
+            }
+        };
+        let key_end = start + key.len();
+        self.position = key_end;
+        self.skip_spaces();
+        Ok((key, start..key_end))
+    }
+
+    fn parse_block_mapping(&mut self, indent: isize) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        let mut entries: Vec<SyntaxEntry> = vec![];
+        loop {
+            let (key, key_span) = self.parse_key()?;
+            if self.peek() != Some(b':') {
+                return self.error("Expected ':'");
+            }
+            self.position += 1;
+            let colon_end = self.position;
+            self.skip_spaces();
+
+            let value = if self.is_at_line_end() {
+                self.skip_trivia();
+                let column = self.column(self.position);
+                let is_value = !self.is_eof()
+                    && !self.is_document_marker_at(self.position)
+                    && (column > indent || (column == indent && self.is_sequence_indicator_at(self.position)));
+                if is_value {
+                    self.parse_block_node(indent)?
+                } else {
+                    self.position = colon_end;
+                    empty_scalar(colon_end)
+                }
+            } else {
+                self.parse_block_node(indent)?
+            };
+            entries.push(SyntaxEntry {
+                key,
+                key_span,
+                colon_end,
+                value,
+                separator: None,
+            });
+
+            let end = self.position;
+            self.skip_trivia();
+            let is_next_entry = !self.is_eof()
+                && self.column(self.position) == indent
+                && !self.is_sequence_indicator_at(self.position)
+                && !self.is_document_marker_at(self.position);
+            if !is_next_entry {
+                self.position = end;
+                break;
+            }
+        }
+        let end = entries.last().map(|e| e.end()).unwrap_or(start);
+        Ok(SyntaxNode {
+            span: start..end,
+            kind: SyntaxNodeKind::Mapping { flow: false, entries },
+        })
+    }
+
+    fn parse_block_sequence(&mut self, indent: isize) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        let mut items: Vec<SyntaxItem> = vec![];
+        loop {
+            let item_start = self.position;
+            self.position += 1;
+            self.skip_spaces();
+
+            let value = if self.is_at_line_end() {
+                let position = self.position;
+                self.skip_trivia();
+                if !self.is_eof() && self.column(self.position) > indent {
+                    self.parse_block_node(indent)?
+                } else {
+                    self.position = position;
+                    empty_scalar(item_start + 1)
+                }
+            } else {
+                self.parse_block_node(indent)?
+            };
+            items.push(SyntaxItem {
+                start: item_start,
+                value,
+                separator: None,
+            });
+
+            let end = self.position;
+            self.skip_trivia();
+            let is_next_item = !self.is_eof()
+                && self.column(self.position) == indent
+                && self.is_sequence_indicator_at(self.position);
+            if !is_next_item {
+                self.position = end;
+                break;
+            }
+        }
+        let end = items.last().map(|i| i.end()).unwrap_or(start);
+        Ok(SyntaxNode {
+            span: start..end,
+            kind: SyntaxNodeKind::Sequence { flow: false, items },
+        })
+    }
+
+    fn parse_plain_scalar(&mut self, parent_indent: isize) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        let mut lines: Vec<&str> = vec![];
+        let mut end;
+        loop {
+            let line_end = get_line_end(self.content, self.position);
+            let mut value_end = line_end;
+            let mut position = self.position;
+            while position < line_end {
+                if self.is_comment_at(position) {
+                    value_end = position;
+                    break;
+                }
+                position += 1;
+            }
+            let line = self.content[self.position..value_end].trim_end();
+            end = self.position + line.len();
+            lines.push(line);
+            self.position = end;
+            if value_end != line_end {
+                // A comment ends the scalar.
+                break;
+            }
+
+            // A plain scalar can continue on the following lines, as long as they are
+            // indented more than the parent node.
+            let mut next_position = line_end;
+            let mut empty_lines = 0;
+            loop {
+                if next_position >= self.bytes.len() {
+                    break;
+                }
+                let next_line_end = get_line_end(self.content, next_position + 1);
+                if self.content[next_position + 1..next_line_end].trim().is_empty() {
+                    empty_lines += 1;
+                    next_position = next_line_end;
+                    continue;
+                }
+                break;
+            }
+            if next_position >= self.bytes.len() {
+                break;
+            }
+            let next_line_start = next_position + 1;
+            let next_line = &self.content[next_line_start..get_line_end(self.content, next_line_start)];
+            let next_token = next_line_start + (next_line.len() - next_line.trim_start().len());
+            if self.column(next_token) <= parent_indent
+                || self.is_comment_at(next_token)
+                || self.is_document_marker_at(next_token)
+            {
+                break;
+            }
+            lines.extend(std::iter::repeat_n("\n", empty_lines));
+            self.position = next_token;
+        }
+
+        let mut value = String::new();
+        for line in lines {
+            if line == "\n" {
+                value.push('\n');
+                continue;
+            }
+            if !value.is_empty() && !value.ends_with('\n') {
+                value.push(' ');
+            }
+            value.push_str(line);
+        }
+        Ok(SyntaxNode {
+            span: start..end,
+            kind: SyntaxNodeKind::Scalar {
+                value,
+                style: FlatpakScalarStyle::Plain,
+            },
+        })
+    }
+
+    fn parse_quoted_scalar(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        let quote = self.bytes[start] as char;
+        self.position += 1;
+        let mut value = String::new();
+        loop {
+            let c = match self.content[self.position..].chars().next() {
+                Some(c) => c,
+                None => return self.error("Unterminated quoted scalar"),
+            };
+            self.position += c.len_utf8();
+            if c == quote {
+                if quote == '\'' && self.peek() == Some(b'\'') {
+                    self.position += 1;
+                    value.push('\'');
+                    continue;
+                }
+                break;
+            }
+            if c == '\n' {
+                // Line breaks in quoted scalars are folded into spaces.
+                let trimmed_length = value.trim_end_matches([' ', '\t']).len();
+                value.truncate(trimmed_length);
+                while let Some(b' ') | Some(b'\t') = self.peek() {
+                    self.position += 1;
+                }
+                value.push(' ');
+                continue;
+            }
+            if c == '\\' && quote == '"' {
+                let escaped = match self.content[self.position..].chars().next() {
+                    Some(e) => e,
+                    None => return self.error("Unterminated quoted scalar"),
+                };
+                self.position += escaped.len_utf8();
+                match escaped {
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '0' => value.push('\0'),
+                    ' ' => value.push(' '),
+                    '/' => value.push('/'),
+                    '"' => value.push('"'),
+                    '\\' => value.push('\\'),
+                    '\n' => {
+                        while let Some(b' ') | Some(b'\t') = self.peek() {
+                            self.position += 1;
+                        }
+                    }
+                    'x' | 'u' | 'U' => {
+                        let length = match escaped {
+                            'x' => 2,
+                            'u' => 4,
+                            _ => 8,
+                        };
+                        let code = self
+                            .content
+                            .get(self.position..self.position + length)
+                            .and_then(|h| u32::from_str_radix(h, 16).ok())
+                            .and_then(char::from_u32);
+                        match code {
+                            Some(c) => value.push(c),
+                            None => return self.error("Invalid escape sequence"),
+                        }
+                        self.position += length;
+                    }
+                    _ => return self.error("Invalid escape sequence"),
+                }
+                continue;
+            }
+            value.push(c);
+        }
+        let style = if quote == '"' {
+            FlatpakScalarStyle::DoubleQuoted
+        } else {
+            FlatpakScalarStyle::SingleQuoted
+        };
+        Ok(SyntaxNode {
+            span: start..self.position,
+            kind: SyntaxNodeKind::Scalar { value, style },
+        })
+    }
+
+    fn parse_block_scalar(&mut self, parent_indent: isize) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        let literal = self.peek() == Some(b'|');
+        self.position += 1;
+
+        let mut explicit_indent: Option<usize> = None;
+        let mut chomping: Option<u8> = None;
+        while let Some(c) = self.peek() {
+            match c {
+                b'-' | b'+' => chomping = Some(c),
+                b'1'..=b'9' => explicit_indent = Some((c - b'0') as usize),
+                _ => break,
+            }
+            self.position += 1;
+        }
+        self.skip_spaces();
+        if !self.is_at_line_end() {
+            return self.error("Invalid block scalar header");
+        }
+        let header_end = self.position;
+
+        let minimum_indent = std::cmp::max(parent_indent + 1, 0) as usize;
+        let mut content_indent = explicit_indent.map(|i| std::cmp::max(parent_indent, 0) as usize + i);
+        let mut lines: Vec<&str> = vec![];
+        let mut end = header_end;
+        let mut line_start = get_line_end(self.content, header_end) + 1;
+        while line_start <= self.bytes.len() {
+            let line_end = get_line_end(self.content, line_start);
+            let line = &self.content[line_start..line_end];
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            let line_indent = line.len() - line.trim_start_matches(' ').len();
+            if line.trim().is_empty() {
+                lines.push("");
+            } else {
+                let indent = match content_indent {
+                    Some(i) => i,
+                    None => {
+                        if line_indent < minimum_indent {
+                            break;
+                        }
+                        content_indent = Some(line_indent);
+                        line_indent
+                    }
+                };
+                if line_indent < indent {
+                    break;
+                }
+                lines.push(&line[indent..]);
+                end = line_start + line.len();
+            }
+            line_start = line_end + 1;
+        }
+        while lines.last() == Some(&"") {
+            lines.pop();
+        }
+        self.position = end;
+
+        let mut value = String::new();
+        for (line_index, line) in lines.iter().enumerate() {
+            if line_index != 0 {
+                let folded = !literal && !line.is_empty() && !lines[line_index - 1].is_empty();
+                value.push(if folded { ' ' } else { '\n' });
+            }
+            value.push_str(line);
+        }
+        if chomping != Some(b'-') && !value.is_empty() {
+            value.push('\n');
+        }
+        let style = if literal {
+            FlatpakScalarStyle::Literal
+        } else {
+            FlatpakScalarStyle::Folded
+        };
+        Ok(SyntaxNode {
+            span: start..end,
+            kind: SyntaxNodeKind::Scalar { value, style },
+        })
+    }
+
+    fn parse_flow_node(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        self.skip_properties();
+        match self.peek() {
+            Some(b'[') => self.parse_flow_sequence(),
+            Some(b'{') => self.parse_flow_mapping(),
+            Some(b'"') | Some(b'\'') => self.parse_quoted_scalar(),
+            Some(_) => {
+                let start = self.position;
+                let end = self.get_flow_scalar_end();
+                self.position = end;
+                let value = self.content[start..end].to_string();
+                if value.is_empty() {
+                    return self.error("Unexpected character");
+                }
+                Ok(SyntaxNode {
+                    span: start..end,
+                    kind: SyntaxNodeKind::Scalar {
+                        value,
+                        style: FlatpakScalarStyle::Plain,
+                    },
+                })
+            }
+            None => self.error("Unexpected end of the document"),
+        }
+    }
+
+    /// Gets the end of a plain scalar in flow context.
+    fn get_flow_scalar_end(&self) -> usize {
+        let line_end = get_line_end(self.content, self.position);
+        let mut position = self.position;
+        while position < line_end {
+            let c = self.bytes[position];
+            if c == b',' || c == b']' || c == b'}' || c == b'[' || c == b'{' || c == b'\r' {
+                break;
+            }
+            if c == b':' && (self.is_blank_at(position + 1) || b",]}".contains(&self.bytes[position + 1])) {
+                break;
+            }
+            if self.is_comment_at(position) {
+                break;
+            }
+            position += 1;
+        }
+        self.position + self.content[self.position..position].trim_end().len()
+    }
+
+    fn parse_flow_separator(&mut self, closing: u8) -> Result<Option<usize>, SyntaxError> {
+        self.skip_trivia();
+        match self.peek() {
+            Some(b',') => {
+                self.position += 1;
+                Ok(Some(self.position - 1))
+            }
+            Some(c) if c == closing => Ok(None),
+            _ => self.error(&format!("Expected ',' or '{}'", closing as char)),
+        }
+    }
+
+    fn parse_flow_sequence(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        self.position += 1;
+        let mut items: Vec<SyntaxItem> = vec![];
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                Some(b']') => {
+                    self.position += 1;
+                    break;
+                }
+                None => return self.error("Unterminated flow sequence"),
+                _ => {}
+            }
+            let item_start = self.position;
+            let value = self.parse_flow_node()?;
+            let separator = self.parse_flow_separator(b']')?;
+            items.push(SyntaxItem {
+                start: item_start,
+                value,
+                separator,
+            });
+        }
+        Ok(SyntaxNode {
+            span: start..self.position,
+            kind: SyntaxNodeKind::Sequence { flow: true, items },
+        })
+    }
+
+    fn parse_flow_mapping(&mut self) -> Result<SyntaxNode, SyntaxError> {
+        let start = self.position;
+        self.position += 1;
+        let mut entries: Vec<SyntaxEntry> = vec![];
+        loop {
+            self.skip_trivia();
+            match self.peek() {
+                Some(b'}') => {
+                    self.position += 1;
+                    break;
+                }
+                None => return self.error("Unterminated flow mapping"),
+                _ => {}
+            }
+            let key_start = self.position;
+            let key_node = self.parse_flow_node()?;
+            let key = match key_node.kind {
+                SyntaxNodeKind::Scalar { value, .. } => value,
+                _ => return self.error("Invalid key"),
+            };
+            let key_span = key_start..key_node.span.end;
+            self.skip_trivia();
+            if self.peek() != Some(b':') {
+                return self.error("Expected ':'");
+            }
+            self.position += 1;
+            let colon_end = self.position;
+            self.skip_trivia();
+            let value = match self.peek() {
+                Some(b',') | Some(b'}') => empty_scalar(colon_end),
+                _ => self.parse_flow_node()?,
+            };
+            let separator = self.parse_flow_separator(b'}')?;
+            entries.push(SyntaxEntry {
+                key,
+                key_span,
+                colon_end,
+                value,
+                separator,
+            });
+        }
+        Ok(SyntaxNode {
+            span: start..self.position,
+            kind: SyntaxNodeKind::Mapping { flow: true, entries },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_str<'a>(root: &'a SyntaxNode, path: &str) -> &'a str {
+        root.get(&FlatpakManifestPath::parse(path).unwrap())
+            .unwrap()
+            .as_str()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn test_parse_path() {
+        let path = FlatpakManifestPath::parse("modules[3].sources[1]").unwrap();
+        assert_eq!(
+            path.segments,
+            vec![
+                FlatpakPathSegment::Key("modules".to_string()),
+                FlatpakPathSegment::Index(3),
+                FlatpakPathSegment::Key("sources".to_string()),
+                FlatpakPathSegment::Index(1),
+            ]
+        );
+        assert_eq!(path.to_string(), "modules[3].sources[1]");
+        assert_eq!(
+            FlatpakManifestPath::parse("[0].url").unwrap().to_string(),
+            "[0].url"
+        );
+        assert!(FlatpakManifestPath::parse("").unwrap().is_root());
+        assert!(FlatpakManifestPath::parse("modules[a]").is_err());
+        assert!(FlatpakManifestPath::parse("modules.[0]").is_err());
+    }
+
+    #[test]
+    pub fn test_parse_yaml() {
+        let manifest = r###"
+            # The main manifest.
+            app-id: net.louib.flatpak-rs # inline comment
+            runtime-version: "3.36"
+            sdk: 'org.gnome.Sdk'
+            finish-args: [ "--share=ipc", --socket=x11 ]
+            empty:
+            modules:
+              -
+                name: "flatpak-rs"
+                sources:
+                  - type: git
+                    url: https://github.com/louib/flatpak-rs.git
+              - shared-modules/linux-audio/lv2.json
+              - name: &name libfoo
+                build-commands:
+                - |
+                  make
+                  make install
+        "###;
+        let root = SyntaxNode::parse(&FlatpakManifestFormat::YAML, manifest).unwrap();
+        assert_eq!(get_str(&root, "app-id"), "net.louib.flatpak-rs");
+        assert_eq!(get_str(&root, "runtime-version"), "3.36");
+        assert_eq!(get_str(&root, "sdk"), "org.gnome.Sdk");
+        assert_eq!(get_str(&root, "finish-args[1]"), "--socket=x11");
+        assert_eq!(get_str(&root, "empty"), "");
+        assert_eq!(get_str(&root, "modules[0].name"), "flatpak-rs");
+        assert_eq!(
+            get_str(&root, "modules[0].sources[0].url"),
+            "https://github.com/louib/flatpak-rs.git"
+        );
+        assert_eq!(
+            get_str(&root, "modules[1]"),
+            "shared-modules/linux-audio/lv2.json"
+        );
+        assert_eq!(get_str(&root, "modules[2].name"), "libfoo");
+        assert_eq!(
+            get_str(&root, "modules[2].build-commands[0]"),
+            "make\nmake install\n"
+        );
+
+        let url = root
+            .get(&FlatpakManifestPath::parse("modules[0].sources[0].url").unwrap())
+            .unwrap();
+        assert_eq!(
+            &manifest[url.span.clone()],
+            "https://github.com/louib/flatpak-rs.git"
+        );
+        let app_id = root.get_entry("app-id").unwrap();
+        assert_eq!(get_line_column(manifest, app_id.key_span.start), (3, 13));
+    }
+
+    #[test]
+    pub fn test_parse_json() {
+        let manifest = r###"
+            {
+                "app-id": "org.gnome.SoundJuicer",
+                /* Comments are allowed. */
+                "finish-args": [
+                    "--share=ipc", // So are line comments.
+                    "--talk-name=org.gtk.vfs.*"
+                ],
+                "modules": [ { "name": "cdparanoia", "no-autogen": true } ]
+            }
+        "###;
+        let root = SyntaxNode::parse(&FlatpakManifestFormat::JSON, manifest).unwrap();
+        assert_eq!(get_str(&root, "app-id"), "org.gnome.SoundJuicer");
+        assert_eq!(get_str(&root, "finish-args[1]"), "--talk-name=org.gtk.vfs.*");
+        assert_eq!(get_str(&root, "modules[0].no-autogen"), "true");
+        assert!(root.get_item(0).is_none());
+        assert!(SyntaxNode::parse(&FlatpakManifestFormat::JSON, "{ \"a\": 1 ").is_err());
+
+        let comments: Vec<&str> = root
+            .get_comments(&FlatpakManifestFormat::JSON, manifest)
+            .into_iter()
+            .map(|span| &manifest[span])
+            .collect();
+        assert_eq!(
+            comments,
+            vec!["/* Comments are allowed. */", "// So are line comments."]
+        );
+    }
+
+    #[test]
+    pub fn test_get_comments() {
+        let manifest = r###"# The main manifest.
+app-id: net.louib.flatpak-rs # inline comment
+url: https://github.com/louib/flatpak-rs.git#main
+command: "flatpak-rs # not a comment"
+finish-args: [ --share=ipc, # the IPC
+  --socket=x11 ]
+build-commands:
+  - |
+    # not a comment
+    make
+"###;
+        let root = SyntaxNode::parse(&FlatpakManifestFormat::YAML, manifest).unwrap();
+        let comments: Vec<&str> = root
+            .get_comments(&FlatpakManifestFormat::YAML, manifest)
+            .into_iter()
+            .map(|span| &manifest[span])
+            .collect();
+        assert_eq!(
+            comments,
+            vec!["# The main manifest.", "# inline comment", "# the IPC"]
+        );
+
+        let manifest = r###"{ "command": "/* not a comment */", "url": "http://example.com//path" }"###;
+        let root = SyntaxNode::parse(&FlatpakManifestFormat::JSON, manifest).unwrap();
+        assert!(root
+            .get_comments(&FlatpakManifestFormat::JSON, manifest)
+            .is_empty());
+    }
+}
@@ -1,24 +1,194 @@
-pub fn remove_comments_from_json(json_content: &str) -> String {
-    let mut json_content_without_comments = "".to_string();
-    let mut is_in_a_comment = false;
-    for manifest_line in json_content.split('\n') {
-        if manifest_line.trim().starts_with("/*") && manifest_line.trim().ends_with("*/") {
-            continue;
+use crate::syntax::SyntaxError;
+
+/// Removes the comments from a JSON document, as supported by json-glib which is used by
+/// flatpak-builder. Both `/* */` and `//` comments are supported, anywhere outside of
+/// string literals.
+///
+/// The comments are replaced with spaces, so that the line numbers and columns
+/// reported by the JSON parser are still valid for the original document.
+/// A `/*` comment that is never closed is a syntax error, like it is for json-glib.
+pub fn remove_comments_from_json(json_content: &str) -> Result<String, SyntaxError> {
+    let bytes = json_content.as_bytes();
+    let mut json_content_without_comments: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut position = 0;
+    while position < bytes.len() {
+        match bytes[position] {
+            b'"' => {
+                let string_start = position;
+                position += 1;
+                while position < bytes.len() && bytes[position] != b'"' {
+                    if bytes[position] == b'\\' {
+                        position += 1;
+                    }
+                    position += 1;
+                }
+                position = std::cmp::min(position + 1, bytes.len());
+                json_content_without_comments.extend_from_slice(&bytes[string_start..position]);
+            }
+            b'/' if bytes.get(position + 1) == Some(&b'/') => {
+                while position < bytes.len() && bytes[position] != b'\n' {
+                    json_content_without_comments.push(b' ');
+                    position += 1;
+                }
+            }
+            b'/' if bytes.get(position + 1) == Some(&b'*') => {
+                let comment_end = match json_content[position + 2..].find("*/") {
+                    Some(i) => position + 2 + i + 2,
+                    None => {
+                        return Err(SyntaxError {
+                            message: "Unterminated comment".to_string(),
+                            offset: position,
+                        })
+                    }
+                };
+                for c in &bytes[position..comment_end] {
+                    match c {
+                        b'\n' | b'\r' => json_content_without_comments.push(*c),
+                        _ => json_content_without_comments.push(b' '),
+                    }
+                }
+                position = comment_end;
+            }
+            c => {
+                json_content_without_comments.push(c);
+                position += 1;
+            }
         }
-        if manifest_line.trim().starts_with("/*") && !is_in_a_comment {
-            is_in_a_comment = true;
-            continue;
-        }
-        if manifest_line.trim().ends_with("*/") && is_in_a_comment {
-            is_in_a_comment = false;
-            continue;
-        }
-        if is_in_a_comment {
-            continue;
-        }
-        // TODO should we also filter out comments at the end of the lines?
-        json_content_without_comments += manifest_line;
-        json_content_without_comments += "\n";
     }
-    return json_content_without_comments;
+    // Only ASCII characters were replaced, on character boundaries, so the content is still valid UTF-8.
+    Ok(String::from_utf8(json_content_without_comments).unwrap())
+}
+
+#[cfg(test)]
+/// A temporary directory for the tests, with a unique name so that concurrent test runs do
+/// not share it. The directory is removed when dropped.
+pub(crate) struct TestDir {
+    path: std::path::PathBuf,
+}
+#[cfg(test)]
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        static TEST_DIR_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "flatpak-rs-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            TEST_DIR_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+}
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.path
+    }
+}
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_parses_to(json_content: &str, expected_value: serde_json::Value) {
+        let json_content_without_comments = remove_comments_from_json(json_content).unwrap();
+        assert_eq!(json_content_without_comments.len(), json_content.len());
+        assert_eq!(
+            json_content_without_comments.matches('\n').count(),
+            json_content.matches('\n').count()
+        );
+        let value: serde_json::Value = serde_json::from_str(&json_content_without_comments).unwrap();
+        assert_eq!(value, expected_value);
+    }
+
+    #[test]
+    pub fn test_remove_line_comments() {
+        assert_parses_to(
+            r###"{
+                // The application id.
+                "id": "net.louib.flatpak-rs", // trailing comment
+                "url": "https://github.com/louib/flatpak-rs.git" //no space
+            }"###,
+            serde_json::json!({
+                "id": "net.louib.flatpak-rs",
+                "url": "https://github.com/louib/flatpak-rs.git",
+            }),
+        );
+    }
+
+    #[test]
+    pub fn test_remove_block_comments() {
+        assert_parses_to(
+            r###"/* leading comment */{
+                /*
+                 * A multi-line comment, with "quotes" and // slashes.
+                 */
+                "finish-args": [ /* X11 */ "--socket=x11", "--share=ipc" /* IPC */ ],
+                "sdk": /* inline */ "org.gnome.Sdk" /* trailing */
+            } /* final comment */"###,
+            serde_json::json!({
+                "finish-args": ["--socket=x11", "--share=ipc"],
+                "sdk": "org.gnome.Sdk",
+            }),
+        );
+        assert_parses_to("[1/**/, 2 /***/, 3 /* ** / */]", serde_json::json!([1, 2, 3]));
+    }
+
+    #[test]
+    pub fn test_comments_in_strings() {
+        assert_parses_to(
+            r###"{
+                "cleanup": [ "/include", "/lib/*.la", "/*", "*/" ],
+                "url": "http://example.com//path",
+                "comment": "// not a comment",
+                "command": "echo \"/* not a comment */\"", // a comment
+                "escaped": "C:\\", /* a comment */
+                "unicode": "\u0022/*\u0022 é" // é
+            }"###,
+            serde_json::json!({
+                "cleanup": ["/include", "/lib/*.la", "/*", "*/"],
+                "url": "http://example.com//path",
+                "comment": "// not a comment",
+                "command": "echo \"/* not a comment */\"",
+                "escaped": "C:\\",
+                "unicode": "\"/*\" é",
+            }),
+        );
+    }
+
+    #[test]
+    pub fn test_error_line_preserved() {
+        let json_content = r###"{
+            /* The
+               application. */
+            "id": "net.louib.flatpak-rs", // id
+            "sdk" "org.gnome.Sdk"
+        }"###;
+        let json_content_without_comments = remove_comments_from_json(json_content).unwrap();
+        let error = serde_json::from_str::<serde_json::Value>(&json_content_without_comments).unwrap_err();
+        assert_eq!(error.line(), 5);
+        assert_eq!(error.column(), 19);
+    }
+
+    #[test]
+    pub fn test_unterminated_comments() {
+        let error = remove_comments_from_json("[1]\n  /* comment */ /* comment").unwrap_err();
+        assert_eq!(error.message, "Unterminated comment");
+        assert_eq!(error.offset, 20);
+        assert_eq!(
+            remove_comments_from_json("[1] // comment").unwrap(),
+            "[1]           "
+        );
+        assert_eq!(remove_comments_from_json("\"/* string").unwrap(), "\"/* string");
+    }
 }